@@ -0,0 +1,15 @@
+fn main() {
+    #[cfg(feature = "grpc")]
+    grpc();
+}
+
+// protoc が無くても生成できるように protox でコンパイルする
+#[cfg(feature = "grpc")]
+fn grpc() {
+    println!("cargo:rerun-if-changed=proto/lock.proto");
+
+    let fds = protox::compile(["lock.proto"], ["proto"]).expect("failed to compile lock.proto");
+    tonic_build::configure()
+        .compile_fds(fds)
+        .expect("failed to generate the gRPC service");
+}
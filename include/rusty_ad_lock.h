@@ -0,0 +1,42 @@
+/*
+ * C API of rusty_ad_lock, built with
+ * `cargo rustc --release --features ffi,sqlx-mysql,sqlx-postgres,runtime-tokio-rustls --crate-type cdylib`.
+ */
+
+#ifndef RUSTY_AD_LOCK_H
+#define RUSTY_AD_LOCK_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define RAL_OK 0
+#define RAL_FAILED_TO_GET_LOCK 1
+#define RAL_ERROR 2
+#define RAL_INVALID_ARGUMENT 3
+
+typedef struct RalPool RalPool;
+typedef struct RalLock RalLock;
+
+/* connect to a mysql://, mariadb:// or postgres:// URL, NULL on failure */
+RalPool *ral_connect(const char *url);
+void ral_disconnect(RalPool *pool);
+
+/* a timeout_ms of 0 or less fails immediately if the key is held */
+int32_t ral_acquire(const RalPool *pool, const char *key, int64_t timeout_ms, RalLock **out);
+int32_t ral_release(RalLock *lock);
+
+/* call f(user_data) on the calling thread while the key is locked */
+int32_t ral_with_locking(const RalPool *pool, const char *key, int64_t timeout_ms,
+                         void (*f)(void *user_data), void *user_data);
+
+/* message of the last failure on this thread, NULL if nothing failed yet */
+const char *ral_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
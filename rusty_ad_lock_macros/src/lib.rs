@@ -0,0 +1,336 @@
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{
+    Data, DeriveInput, Expr, Ident, Index, ItemFn, LitStr, Member, ReturnType, Token, Type,
+    parse::{Parse, ParseStream},
+    parse_macro_input,
+};
+
+/// Run the body of an async fn while a key is locked, see `rusty_ad_lock::Locker::with_locking`.
+///
+/// ```ignore
+/// #[locked(locker = MySqlLocker, key = "report:{report_id}", timeout = "5s")]
+/// async fn build_report(pool: &MySqlPool, report_id: i64) -> Result<Report, AppError> {
+///     // ...
+/// }
+/// ```
+///
+/// * `locker` - type implementing `Locker`
+/// * `key` - format string of the key, the arguments of the fn can be used in it
+/// * `timeout` - `"500ms"`, `"5s"` or `"1m"`. if omitted and a conflict occurs, the fn fails immediately.
+/// * `pool` - expression of the pool to lock on, `pool` if omitted
+/// * `tx` - name binding the lock-holding transaction in the body, not bound if omitted
+///
+/// the fn has to return a `Result` whose error implements `From<rusty_ad_lock::Error>`.
+#[proc_macro_attribute]
+pub fn locked(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as Args);
+    let item = parse_macro_input!(item as ItemFn);
+
+    expand(args, item)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+struct Args {
+    locker: Type,
+    key: LitStr,
+    timeout: Option<std::time::Duration>,
+    pool: Expr,
+    tx: Option<Ident>,
+}
+
+impl Parse for Args {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut locker = None;
+        let mut key = None;
+        let mut timeout = None;
+        let mut pool = None;
+        let mut tx = None;
+
+        while !input.is_empty() {
+            let name: Ident = input.parse()?;
+            input.parse::<Token![=]>()?;
+
+            match name.to_string().as_str() {
+                "locker" => locker = Some(input.parse()?),
+                "key" => key = Some(input.parse()?),
+                "timeout" => {
+                    let lit: LitStr = input.parse()?;
+                    timeout = Some(parse_duration(&lit)?);
+                }
+                "pool" => pool = Some(input.parse()?),
+                "tx" => tx = Some(input.parse()?),
+                _ => {
+                    return Err(syn::Error::new(
+                        name.span(),
+                        "expected `locker`, `key`, `timeout`, `pool` or `tx`",
+                    ));
+                }
+            }
+
+            if !input.is_empty() {
+                input.parse::<Token![,]>()?;
+            }
+        }
+
+        Ok(Self {
+            locker: locker
+                .ok_or_else(|| syn::Error::new(Span::call_site(), "`locker` is required"))?,
+            key: key.ok_or_else(|| syn::Error::new(Span::call_site(), "`key` is required"))?,
+            timeout,
+            pool: pool.unwrap_or_else(|| syn::parse_quote!(pool)),
+            tx,
+        })
+    }
+}
+
+// "500ms" / "5s" / "1m"
+fn parse_duration(lit: &LitStr) -> syn::Result<std::time::Duration> {
+    let value = lit.value();
+    let (digits, unit) = value.split_at(
+        value
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(value.len()),
+    );
+
+    let n: u64 = digits
+        .parse()
+        .map_err(|_| syn::Error::new(lit.span(), "expected a duration like \"5s\""))?;
+    match unit {
+        "ms" => Ok(std::time::Duration::from_millis(n)),
+        "s" => Ok(std::time::Duration::from_secs(n)),
+        "m" => Ok(std::time::Duration::from_secs(n * 60)),
+        _ => Err(syn::Error::new(
+            lit.span(),
+            "expected the unit `ms`, `s` or `m`",
+        )),
+    }
+}
+
+fn expand(args: Args, item: ItemFn) -> syn::Result<proc_macro2::TokenStream> {
+    if item.sig.asyncness.is_none() {
+        return Err(syn::Error::new_spanned(
+            item.sig.fn_token,
+            "#[locked] can only be used on async fn",
+        ));
+    }
+    let ReturnType::Type(_, ret) = &item.sig.output else {
+        return Err(syn::Error::new_spanned(
+            &item.sig,
+            "#[locked] fn has to return a Result",
+        ));
+    };
+
+    let ItemFn {
+        attrs,
+        vis,
+        sig,
+        block,
+    } = &item;
+    let Args {
+        locker,
+        key,
+        timeout,
+        pool,
+        tx,
+    } = args;
+
+    let timeout = match timeout {
+        Some(d) => {
+            let millis = d.as_millis() as u64;
+            quote!(::std::option::Option::Some(::std::time::Duration::from_millis(#millis)))
+        }
+        None => quote!(::std::option::Option::None),
+    };
+    let tx = match tx {
+        Some(tx) => quote!(#tx),
+        None => quote!(_),
+    };
+
+    // クロージャの戻り値は捨てられるので、本体の結果は外の変数に書き出す
+    Ok(quote! {
+        #(#attrs)*
+        #vis #sig {
+            let __rusty_ad_lock_key = ::std::format!(#key);
+            let mut __rusty_ad_lock_out: ::std::option::Option<#ret> = ::std::option::Option::None;
+
+            <#locker as ::rusty_ad_lock::Locker>::with_locking(
+                &#pool,
+                &__rusty_ad_lock_key,
+                #timeout,
+                async |#tx| {
+                    __rusty_ad_lock_out = ::std::option::Option::Some(async #block.await);
+                },
+            )
+            .await?;
+
+            __rusty_ad_lock_out.expect("with_locking returned Ok without running the body")
+        }
+    })
+}
+
+/// A static lock key checked at compile time, expanding to the `&'static str` literal.
+///
+/// ```ignore
+/// const NIGHTLY_REPORT: &str = lock_key!("report:nightly");
+/// ```
+///
+/// the key has to be 1 to 64 printable ASCII characters without `{` or `}`, so every backend locks
+/// it as written: MySQL doesn't shorten it with a hash and no Unicode normalization changes it.
+#[proc_macro]
+pub fn lock_key(input: TokenStream) -> TokenStream {
+    let key = parse_macro_input!(input as LitStr);
+
+    match validate_key(&key.value()) {
+        Ok(()) => quote!(#key).into(),
+        Err(message) => syn::Error::new(key.span(), message)
+            .into_compile_error()
+            .into(),
+    }
+}
+
+// MySQL の GET_LOCK が受け付ける長さ。これより長いキーはハッシュで縮められる
+const MAX_KEY_LEN: usize = 64;
+
+fn validate_key(key: &str) -> Result<(), String> {
+    if key.is_empty() {
+        return Err("lock key can't be empty".to_owned());
+    }
+    if key.len() > MAX_KEY_LEN {
+        return Err(format!(
+            "lock key is {} characters long, at most {MAX_KEY_LEN} are locked as written",
+            key.len()
+        ));
+    }
+    if let Some(c) = key.chars().find(|c| !c.is_ascii_graphic()) {
+        return Err(format!(
+            "lock key contains {c:?}, only printable ASCII characters are allowed"
+        ));
+    }
+    if key.contains(['{', '}']) {
+        return Err("lock key contains a placeholder, use format! for dynamic keys".to_owned());
+    }
+
+    Ok(())
+}
+
+/// Derive `rusty_ad_lock::LockKey`, locking `{prefix}:{field}:{field}..` in the order of the fields.
+///
+/// ```ignore
+/// #[derive(LockKey)]
+/// #[lock_key(prefix = "order")]
+/// struct OrderKey {
+///     tenant: String,
+///     id: i64,
+///     #[lock_key(skip)]
+///     note: String,
+/// }
+///
+/// // "order:acme:42"
+/// MySqlLocker::with_key_lock(&pool, OrderKey { .. }, None, async |_| {}).await?;
+/// ```
+///
+/// * `prefix` - first part of the key, the name of the type if omitted
+/// * `skip` - leave the field out of the key
+///
+/// the fields are formatted with `Display`, with `%` and `:` escaped as `%25` and `%3A`, so two
+/// values can't produce the same key.
+#[proc_macro_derive(LockKey, attributes(lock_key))]
+pub fn derive_lock_key(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    expand_lock_key(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand_lock_key(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let Data::Struct(data) = &input.data else {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "#[derive(LockKey)] can only be used on structs",
+        ));
+    };
+
+    let mut prefix = None;
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("lock_key")) {
+        attr.parse_nested_meta(|meta| {
+            if !meta.path.is_ident("prefix") {
+                return Err(meta.error("expected `prefix`"));
+            }
+            let lit: LitStr = meta.value()?.parse()?;
+            if lit.value().is_empty() {
+                return Err(syn::Error::new(lit.span(), "prefix can't be empty"));
+            }
+            prefix = Some(lit.value());
+            Ok(())
+        })?;
+    }
+    let prefix = prefix.unwrap_or_else(|| input.ident.to_string());
+
+    let mut parts = Vec::new();
+    for (i, field) in data.fields.iter().enumerate() {
+        let mut skip = false;
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("lock_key")) {
+            attr.parse_nested_meta(|meta| {
+                if !meta.path.is_ident("skip") {
+                    return Err(meta.error("expected `skip`"));
+                }
+                skip = true;
+                Ok(())
+            })?;
+        }
+        if skip {
+            continue;
+        }
+
+        let member = match &field.ident {
+            Some(ident) => Member::Named(ident.clone()),
+            None => Member::Unnamed(Index::from(i)),
+        };
+        parts.push(quote!(::rusty_ad_lock::push_key_part(&mut key, &self.#member);));
+    }
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::rusty_ad_lock::LockKey for #ident #ty_generics #where_clause {
+            fn lock_key(&self) -> ::rusty_ad_lock::KeyRepr<'_> {
+                let mut key = ::std::string::String::from(#prefix);
+                #(#parts)*
+                ::rusty_ad_lock::KeyRepr::Text(::std::borrow::Cow::Owned(key))
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn durations_are_parsed_with_their_unit() {
+        let parse = |s: &str| parse_duration(&LitStr::new(s, Span::call_site())).ok();
+
+        assert_eq!(parse("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse("5s"), Some(Duration::from_secs(5)));
+        assert_eq!(parse("2m"), Some(Duration::from_secs(120)));
+        assert_eq!(parse("5"), None);
+        assert_eq!(parse("s"), None);
+    }
+
+    #[test]
+    fn malformed_keys_are_rejected() {
+        assert_eq!(validate_key("report:nightly"), Ok(()));
+        assert_eq!(validate_key(&"k".repeat(64)), Ok(()));
+        assert!(validate_key("").is_err());
+        assert!(validate_key(&"k".repeat(65)).is_err());
+        assert!(validate_key("report nightly").is_err());
+        assert!(validate_key("caf\u{e9}").is_err());
+        assert!(validate_key("report:{id}").is_err());
+    }
+}
@@ -1 +1 @@
-cargo test --features sqlx-std-collection,runtime-tokio-rustls -- --no-capture
+cargo test --features sqlx-std-collection,sqlx-sqlite,test-util,prometheus,serde,macros,tower,axum,actix-web,blocking,grpc,http,runtime-tokio-rustls,runtime-async-std-rustls -- --no-capture
@@ -0,0 +1,160 @@
+//! List, wait on, and break the advisory locks of a MySQL or PostgreSQL database.
+//!
+//! keys are normalized exactly as [`MySqlLocker`] and [`PostgresLocker`] do.
+//! exits with 1 if the key could not be acquired or is not held, and 2 on errors.
+
+use std::{process::ExitCode, time::Duration};
+
+use clap::{Parser, Subcommand};
+use rusty_ad_lock::{Error, ForceRelease, MySqlLocker, PostgresLocker};
+
+#[derive(Parser)]
+#[command(
+    name = "rusty-ad-lock",
+    version,
+    about = "list, wait on, and break the advisory locks of a MySQL or PostgreSQL database"
+)]
+struct Cli {
+    /// mysql://, mariadb:// or postgres:// URL of the database
+    #[arg(long, env = "DATABASE_URL")]
+    database_url: String,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// list the held and waited-for keys with their holders
+    List {
+        /// show only this key
+        #[arg(long)]
+        key: Option<String>,
+    },
+    /// wait until the key is acquired, hold it, then release it
+    Wait {
+        key: String,
+        /// how long to wait, like 500ms, 10s or 1m. fails immediately if omitted
+        #[arg(long, value_parser = parse_duration)]
+        timeout: Option<Duration>,
+        /// how long to hold the key once acquired
+        #[arg(long, value_parser = parse_duration, default_value = "0s")]
+        hold: Duration,
+    },
+    /// terminate the session holding the key
+    Release { key: String },
+}
+
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let (digits, unit) = value.split_at(
+        value
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(value.len()),
+    );
+
+    let n: u64 = digits
+        .parse()
+        .map_err(|_| "expected a duration like \"5s\"".to_owned())?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(n)),
+        "s" => Ok(Duration::from_secs(n)),
+        "m" => Ok(Duration::from_secs(n * 60)),
+        _ => Err("expected the unit `ms`, `s` or `m`".to_owned()),
+    }
+}
+
+async fn run<L: ForceRelease>(url: &str, command: Command) -> rusty_ad_lock::Result<ExitCode> {
+    let pool = sqlx::Pool::<L::DB>::connect(url).await?;
+
+    match command {
+        Command::List { key } => {
+            let key = match key {
+                Some(key) => Some(L::listed_key(&pool, &key).await?),
+                None => None,
+            };
+
+            println!("{:<64}  {:>10}  {:>7}", "KEY", "HOLDER", "WAITERS");
+            for lock in L::list_locks(&pool)
+                .await?
+                .into_iter()
+                .filter(|lock| key.as_ref().is_none_or(|key| lock.key == *key))
+            {
+                println!(
+                    "{:<64}  {:>10}  {:>7}",
+                    lock.key,
+                    lock.holder.as_deref().unwrap_or("-"),
+                    lock.waiters
+                );
+            }
+
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Wait { key, timeout, hold } => {
+            let r = L::with_locking(&pool, &key, timeout, async |_| {
+                println!("acquired {key}");
+                tokio::time::sleep(hold).await;
+            })
+            .await;
+
+            match r {
+                Ok(()) => {
+                    println!("released {key}");
+                    Ok(ExitCode::SUCCESS)
+                }
+                Err(Error::FailedToGetLock(_)) => {
+                    println!("could not acquire {key}");
+                    Ok(ExitCode::from(1))
+                }
+                Err(e) => Err(e),
+            }
+        }
+        Command::Release { key } => match L::force_release(&pool, &key).await? {
+            Some(holder) => {
+                println!("terminated session {holder} holding {key}");
+                Ok(ExitCode::SUCCESS)
+            }
+            None => {
+                println!("{key} is not held");
+                Ok(ExitCode::from(1))
+            }
+        },
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    let r = match cli.database_url.split_once("://").map(|(scheme, _)| scheme) {
+        Some("mysql" | "mariadb") => run::<MySqlLocker>(&cli.database_url, cli.command).await,
+        Some("postgres" | "postgresql") => {
+            run::<PostgresLocker>(&cli.database_url, cli.command).await
+        }
+        _ => {
+            eprintln!("unsupported database URL: {}", cli.database_url);
+            return ExitCode::from(2);
+        }
+    };
+
+    r.unwrap_or_else(|e| {
+        eprintln!("error: {e}");
+        ExitCode::from(2)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn durations_are_parsed_with_their_unit() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("10s"), Ok(Duration::from_secs(10)));
+        assert_eq!(parse_duration("1m"), Ok(Duration::from_secs(60)));
+        assert!(parse_duration("5").is_err());
+        assert!(parse_duration("s").is_err());
+    }
+}
@@ -1,3 +1,10 @@
+// #[locked] の展開先は ::rusty_ad_lock を指すので、クレート内のテストからも解決できるようにする
+#[cfg(feature = "macros")]
+extern crate self as rusty_ad_lock;
+
 mod lock;
 
 pub use lock::*;
+
+#[cfg(feature = "macros")]
+pub use rusty_ad_lock_macros::{LockKey, lock_key, locked};
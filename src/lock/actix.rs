@@ -0,0 +1,268 @@
+use std::{
+    future::{Future, Ready, ready},
+    pin::Pin,
+    rc::Rc,
+    sync::Arc,
+    time::Duration,
+};
+
+use actix_web::{
+    HttpResponse,
+    body::{EitherBody, MessageBody},
+    dev::{Service, ServiceRequest, ServiceResponse, Transform, forward_ready},
+};
+
+use crate::{Error, LockClient, Locker, RetryPolicy};
+
+type KeyFn = Arc<dyn Fn(&ServiceRequest) -> String + Send + Sync>;
+type ConflictFn = Arc<dyn Fn() -> HttpResponse + Send + Sync>;
+type ErrorFn = Arc<dyn Fn(&Error, &RetryPolicy) -> HttpResponse + Send + Sync>;
+
+/// actix-web middleware running each request under a lock on a key taken from the request.
+///
+/// ```ignore
+/// let lock = ActixLock::new(LockClient::<MySqlLocker>::new(pool), |req: &ServiceRequest| {
+///     format!("order:{}", req.match_info().query("id"))
+/// })
+/// .timeout(Duration::from_secs(5))
+/// .on_conflict(|| HttpResponse::Locked().finish());
+///
+/// App::new().service(web::resource("/orders/{id}").wrap(lock).route(web::post().to(update_order)))
+/// ```
+pub struct ActixLock<L: Locker> {
+    client: LockClient<L>,
+    key: KeyFn,
+    timeout: Option<Duration>,
+    conflict: ConflictFn,
+    error: Option<ErrorFn>,
+}
+
+impl<L: Locker> Clone for ActixLock<L> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            key: Arc::clone(&self.key),
+            timeout: self.timeout,
+            conflict: Arc::clone(&self.conflict),
+            error: self.error.clone(),
+        }
+    }
+}
+
+impl<L: Locker> ActixLock<L> {
+    /// lock on the key `key` returns for each request
+    ///
+    /// without [`ActixLock::timeout`], a request fails immediately if the key is held.
+    pub fn new(
+        client: LockClient<L>,
+        key: impl Fn(&ServiceRequest) -> String + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            client,
+            key: Arc::new(key),
+            timeout: None,
+            conflict: Arc::new(|| HttpResponse::Conflict().finish()),
+            error: None,
+        }
+    }
+
+    /// wait up to `timeout` for the key
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// respond with `conflict` instead of 409 when the key couldn't be locked
+    pub fn on_conflict(
+        mut self,
+        conflict: impl Fn() -> HttpResponse + Send + Sync + 'static,
+    ) -> Self {
+        self.conflict = Arc::new(conflict);
+        self
+    }
+
+    /// respond to every error of the locker with `respond`, overriding [`ActixLock::on_conflict`]
+    ///
+    /// `respond` is given the [`LockClient::retry_policy`] of the client, e.g. for a
+    /// `Retry-After` header from [`RetryPolicy::retry_after`]. the errors of the service are
+    /// passed on as they are.
+    ///
+    /// ```ignore
+    /// let lock = ActixLock::new(client, order_key).on_error(|e, policy| match e {
+    ///     Error::ShuttingDown => HttpResponse::ServiceUnavailable().finish(),
+    ///     _ => HttpResponse::Locked()
+    ///         .insert_header((RETRY_AFTER, policy.retry_after().as_secs()))
+    ///         .finish(),
+    /// });
+    /// ```
+    pub fn on_error(
+        mut self,
+        respond: impl Fn(&Error, &RetryPolicy) -> HttpResponse + Send + Sync + 'static,
+    ) -> Self {
+        self.error = Some(Arc::new(respond));
+        self
+    }
+}
+
+impl<S, B, L> Transform<S, ServiceRequest> for ActixLock<L>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+    L: Locker + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = ActixLockMiddleware<S, L>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ActixLockMiddleware {
+            service: Rc::new(service),
+            lock: self.clone(),
+        }))
+    }
+}
+
+/// Middleware created by [`ActixLock`].
+pub struct ActixLockMiddleware<S, L: Locker> {
+    service: Rc<S>,
+    lock: ActixLock<L>,
+}
+
+impl<S, B, L> Service<ServiceRequest> for ActixLockMiddleware<S, L>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+    L: Locker + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let key = (self.lock.key)(&req);
+        let http_req = req.request().clone();
+        let service = Rc::clone(&self.service);
+        let lock = self.lock.clone();
+
+        Box::pin(async move {
+            let r = lock
+                .client
+                .run_locked(&key, lock.timeout, async move { service.call(req).await })
+                .await;
+
+            match (r, &lock.error) {
+                (Ok(res), _) => res.map(ServiceResponse::map_into_left_body),
+                (Err(e), Some(respond)) => {
+                    let res = respond(&e, &lock.client.retry_policy());
+                    Ok(ServiceResponse::new(http_req, res).map_into_right_body())
+                }
+                (Err(Error::FailedToGetLock(_) | Error::RetriesExhausted { .. }), None) => {
+                    Ok(ServiceResponse::new(http_req, (lock.conflict)()).map_into_right_body())
+                }
+                (Err(e @ (Error::ShuttingDown | Error::PoolSaturated { .. })), None) => {
+                    Err(actix_web::error::ErrorServiceUnavailable(e))
+                }
+                (Err(e), None) => Err(actix_web::error::ErrorInternalServerError(e)),
+            }
+        })
+    }
+}
+
+#[cfg(all(test, feature = "sqlx-std-collection"))]
+mod tests {
+    use actix_web::{App, http::StatusCode, test, web};
+    use pretty_assertions::assert_eq;
+    use tokio::time::sleep;
+
+    use super::*;
+
+    use crate::StdCollectionLocker;
+    use sqlx::{Sqlite, SqlitePool};
+
+    #[sqlx::test]
+    async fn contended_requests_get_the_conflict_response(pool: SqlitePool) -> sqlx::Result<()> {
+        let lock = ActixLock::new(
+            LockClient::<StdCollectionLocker<Sqlite>>::new(pool),
+            |req: &ServiceRequest| {
+                format!(
+                    "Lp3Ko5Ji7Hu9Gy1Ft3Dr5Se7Aw9Qz1Xc3Vb5Nm7Kl9Jh1Gf3Ds5Ap7Oi9Uy1Tr:{}",
+                    req.match_info().query("id")
+                )
+            },
+        )
+        .on_conflict(|| HttpResponse::Locked().finish());
+        let app = test::init_service(
+            App::new().service(
+                web::resource("/orders/{id}")
+                    .wrap(lock)
+                    .route(web::get().to(async || {
+                        sleep(Duration::from_millis(300)).await;
+                        HttpResponse::Ok().finish()
+                    })),
+            ),
+        )
+        .await;
+
+        let (r1, r2, r3) = tokio::join!(
+            test::call_service(&app, test::TestRequest::get().uri("/orders/1").to_request()),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                test::call_service(&app, test::TestRequest::get().uri("/orders/1").to_request())
+                    .await
+            },
+            async {
+                sleep(Duration::from_millis(100)).await;
+                test::call_service(&app, test::TestRequest::get().uri("/orders/2").to_request())
+                    .await
+            }
+        );
+
+        assert_eq!(r1.status(), StatusCode::OK);
+        assert_eq!(r2.status(), StatusCode::LOCKED);
+        assert_eq!(r3.status(), StatusCode::OK);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn error_response_overrides_the_conflict_one(pool: SqlitePool) -> sqlx::Result<()> {
+        let client = LockClient::<StdCollectionLocker<Sqlite>>::new(pool).retry(RetryPolicy {
+            backoff: Duration::from_secs(3),
+            ..RetryPolicy::default()
+        });
+        let lock = ActixLock::new(client, |_: &ServiceRequest| {
+            "Hb4Gc6Fd8Ee0Df2Cg4Bh6Ai8Zj0Yk2Xl4Wm6Vn8Uo0Tp2Sq4Rr6Qs8Pt0Ou2Nv4M".to_owned()
+        })
+        .on_conflict(|| HttpResponse::Conflict().finish())
+        .on_error(|_, policy| {
+            HttpResponse::Locked()
+                .insert_header(("retry-after", policy.retry_after().as_secs()))
+                .finish()
+        });
+        let app = test::init_service(App::new().service(web::resource("/").wrap(lock).route(
+            web::get().to(async || {
+                sleep(Duration::from_millis(300)).await;
+                HttpResponse::Ok().finish()
+            }),
+        )))
+        .await;
+
+        let (r1, r2) = tokio::join!(
+            test::call_service(&app, test::TestRequest::get().uri("/").to_request()),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await
+            }
+        );
+
+        assert_eq!(r1.status(), StatusCode::OK);
+        assert_eq!(r2.status(), StatusCode::LOCKED);
+        assert_eq!(r2.headers().get("retry-after").unwrap(), "3");
+
+        Ok(())
+    }
+}
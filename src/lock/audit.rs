@@ -0,0 +1,182 @@
+use std::{future::Future, pin::Pin, sync::Arc, time::Duration};
+
+use crate::Result;
+
+/// Database the audit log of [`crate::LockClient::audit`] can be appended to.
+///
+/// the `lock_audit` table has to exist beforehand, add [`AuditDatabase::AUDIT_TABLE`] to the
+/// migrations of the application.
+pub trait AuditDatabase: sqlx::Database {
+    /// DDL of the `lock_audit` table
+    const AUDIT_TABLE: &'static str;
+
+    /// append a record to the `lock_audit` table
+    fn append_audit(
+        pool: &sqlx::Pool<Self>,
+        record: &AuditRecord,
+    ) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// Row of the `lock_audit` table.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditRecord {
+    pub key: String,
+    pub owner: String,
+    pub outcome: AuditOutcome,
+    /// time spent waiting for the lock, or holding it for [`AuditOutcome::Released`]
+    pub duration: Duration,
+}
+
+/// What happened to the lock, stored in the `outcome` column.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditOutcome {
+    Acquired,
+    Released,
+    TimedOut,
+    Failed,
+}
+
+impl AuditOutcome {
+    /// value of the `outcome` column
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditOutcome::Acquired => "acquired",
+            AuditOutcome::Released => "released",
+            AuditOutcome::TimedOut => "timeout",
+            AuditOutcome::Failed => "failed",
+        }
+    }
+}
+
+type AppendFuture<'a> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+
+// LockClient は L::DB: AuditDatabase を要求しないので、書き込み関数を関数ポインタで持っておく
+pub(crate) struct AuditLog<DB: sqlx::Database> {
+    owner: Arc<str>,
+    append: for<'a> fn(&'a sqlx::Pool<DB>, AuditRecord) -> AppendFuture<'a>,
+}
+
+impl<DB: sqlx::Database> Clone for AuditLog<DB> {
+    fn clone(&self) -> Self {
+        Self {
+            owner: Arc::clone(&self.owner),
+            append: self.append,
+        }
+    }
+}
+
+impl<DB: AuditDatabase> AuditLog<DB> {
+    pub(crate) fn new(owner: String) -> Self {
+        fn append<DB: AuditDatabase>(
+            pool: &sqlx::Pool<DB>,
+            record: AuditRecord,
+        ) -> AppendFuture<'_> {
+            Box::pin(async move { DB::append_audit(pool, &record).await })
+        }
+
+        Self {
+            owner: owner.into(),
+            append: append::<DB>,
+        }
+    }
+}
+
+impl<DB: sqlx::Database> AuditLog<DB> {
+    pub(crate) async fn append(
+        &self,
+        pool: &sqlx::Pool<DB>,
+        key: &str,
+        outcome: AuditOutcome,
+        duration: Duration,
+    ) -> Result<()> {
+        let record = AuditRecord {
+            key: key.to_owned(),
+            owner: self.owner.to_string(),
+            outcome,
+            duration,
+        };
+
+        (self.append)(pool, record).await
+    }
+}
+
+#[cfg(feature = "sqlx-mysql")]
+impl AuditDatabase for sqlx::MySql {
+    const AUDIT_TABLE: &'static str = "CREATE TABLE IF NOT EXISTS lock_audit (
+    id BIGINT AUTO_INCREMENT PRIMARY KEY,
+    lock_key VARCHAR(255) NOT NULL,
+    owner VARCHAR(255) NOT NULL,
+    outcome VARCHAR(16) NOT NULL,
+    duration_ms BIGINT NOT NULL,
+    recorded_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3),
+    INDEX lock_audit_key_recorded_at (lock_key, recorded_at)
+)";
+
+    async fn append_audit(pool: &sqlx::Pool<Self>, record: &AuditRecord) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO lock_audit (lock_key, owner, outcome, duration_ms) VALUES (?, ?, ?, ?)",
+        )
+        .bind(&record.key)
+        .bind(&record.owner)
+        .bind(record.outcome.as_str())
+        .bind(record.duration.as_millis() as i64)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sqlx-postgres")]
+impl AuditDatabase for sqlx::Postgres {
+    const AUDIT_TABLE: &'static str = "CREATE TABLE IF NOT EXISTS lock_audit (
+    id BIGSERIAL PRIMARY KEY,
+    lock_key TEXT NOT NULL,
+    owner TEXT NOT NULL,
+    outcome VARCHAR(16) NOT NULL,
+    duration_ms BIGINT NOT NULL,
+    recorded_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE INDEX IF NOT EXISTS lock_audit_key_recorded_at ON lock_audit (lock_key, recorded_at);";
+
+    async fn append_audit(pool: &sqlx::Pool<Self>, record: &AuditRecord) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO lock_audit (lock_key, owner, outcome, duration_ms) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(&record.key)
+        .bind(&record.owner)
+        .bind(record.outcome.as_str())
+        .bind(record.duration.as_millis() as i64)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sqlx-sqlite")]
+impl AuditDatabase for sqlx::Sqlite {
+    const AUDIT_TABLE: &'static str = "CREATE TABLE IF NOT EXISTS lock_audit (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    lock_key TEXT NOT NULL,
+    owner TEXT NOT NULL,
+    outcome TEXT NOT NULL,
+    duration_ms INTEGER NOT NULL,
+    recorded_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now'))
+);
+CREATE INDEX IF NOT EXISTS lock_audit_key_recorded_at ON lock_audit (lock_key, recorded_at);";
+
+    async fn append_audit(pool: &sqlx::Pool<Self>, record: &AuditRecord) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO lock_audit (lock_key, owner, outcome, duration_ms) VALUES (?, ?, ?, ?)",
+        )
+        .bind(&record.key)
+        .bind(&record.owner)
+        .bind(record.outcome.as_str())
+        .bind(record.duration.as_millis() as i64)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+}
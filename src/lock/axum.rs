@@ -0,0 +1,348 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Arc,
+    time::Duration,
+};
+
+use ::axum::{
+    extract::{FromRequestParts, RawPathParams, Request, State},
+    http::{StatusCode, request::Parts},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+use super::{after_release, trace};
+use crate::{Error, LockClient, Locker, RetryPolicy};
+
+type ErrorFn = Arc<dyn Fn(&Error, &RetryPolicy) -> Response + Send + Sync>;
+
+/// State of the [`lock_request`] middleware.
+///
+/// the key is built from a template whose `{name}` placeholders are replaced by the path
+/// parameters of the route.
+///
+/// ```ignore
+/// let lock = AxumLock::new(LockClient::<PostgresLocker>::new(pool), "order:{id}")
+///     .timeout(Duration::from_secs(5));
+///
+/// let app = Router::new()
+///     .route("/orders/{id}", post(update_order))
+///     .route_layer(middleware::from_fn_with_state(lock, lock_request::<PostgresLocker>));
+///
+/// async fn update_order(mut tx: LockedTx<Postgres>) -> StatusCode {
+///     // only one request per order id runs here at a time
+/// }
+/// ```
+pub struct AxumLock<L: Locker> {
+    client: LockClient<L>,
+    key: Arc<str>,
+    timeout: Option<Duration>,
+    respond: ErrorFn,
+}
+
+impl<L: Locker> Clone for AxumLock<L> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            key: Arc::clone(&self.key),
+            timeout: self.timeout,
+            respond: Arc::clone(&self.respond),
+        }
+    }
+}
+
+impl<L: Locker> AxumLock<L> {
+    /// lock on the key `key` renders for each request
+    ///
+    /// without [`AxumLock::timeout`], a request fails immediately with 409 if the key is held.
+    pub fn new(client: LockClient<L>, key: &str) -> Self {
+        Self {
+            client,
+            key: key.into(),
+            timeout: None,
+            respond: Arc::new(|e, _| lock_error_response(e)),
+        }
+    }
+
+    /// wait up to `timeout` for the key
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// respond to the errors of the locker with `respond` instead of [`lock_error_response`]
+    ///
+    /// `respond` is given the [`LockClient::retry_policy`] of the client, e.g. for a
+    /// `Retry-After` header from [`RetryPolicy::retry_after`].
+    ///
+    /// ```ignore
+    /// let lock = AxumLock::new(client, "order:{id}").on_error(|e, policy| match e {
+    ///     Error::FailedToGetLock(_) | Error::RetriesExhausted { .. } => (
+    ///         StatusCode::LOCKED,
+    ///         [(RETRY_AFTER, policy.retry_after().as_secs().to_string())],
+    ///     )
+    ///         .into_response(),
+    ///     e => lock_error_response(e),
+    /// });
+    /// ```
+    pub fn on_error(
+        mut self,
+        respond: impl Fn(&Error, &RetryPolicy) -> Response + Send + Sync + 'static,
+    ) -> Self {
+        self.respond = Arc::new(respond);
+        self
+    }
+}
+
+// ハンドラに貸し出すトランザクション。ハンドラが返った後にロックの解放のために取り戻す
+struct TxSlot<DB: sqlx::Database>(Arc<Mutex<sqlx::Transaction<'static, DB>>>);
+
+impl<DB: sqlx::Database> Clone for TxSlot<DB> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+/// axum middleware running the rest of the request while a key is locked, see [`AxumLock`]
+///
+/// responds to the errors of the locker with [`lock_error_response`], or the function given to
+/// [`AxumLock::on_error`].
+pub async fn lock_request<L: Locker + 'static>(
+    State(lock): State<AxumLock<L>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let (mut parts, body) = req.into_parts();
+    let params = match RawPathParams::from_request_parts(&mut parts, &()).await {
+        Ok(params) => params,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let key = render_key(&lock.key, &params);
+    let mut req = Request::from_parts(parts, body);
+    let respond = |e: &Error| (lock.respond)(e, &lock.client.retry_policy());
+
+    let fut = async {
+        let (mut held, lock_tx) = match lock.client.lock(&key, lock.timeout).await {
+            Ok(locked) => locked,
+            Err(e) => return respond(&e),
+        };
+
+        // 専用のロック用プールがあれば、ハンドラには本来のプールのトランザクションを渡す
+        let (mut lock_tx, tx) = if lock.client.shares_session() {
+            (None, lock_tx)
+        } else {
+            match lock.client.pool().begin().await {
+                Ok(tx) => (Some(lock_tx), tx),
+                Err(e) => {
+                    let mut lock_tx = lock_tx;
+                    let _ = lock.client.unlock(held, &mut lock_tx).await;
+                    return respond(&e.into());
+                }
+            }
+        };
+
+        let slot = TxSlot(Arc::new(Mutex::new(tx)));
+        req.extensions_mut().insert(slot.clone());
+        let res = match &mut lock_tx {
+            Some(lock_tx) => held
+                .holding(lock.client.pinging(&key, lock_tx, next.run(req)))
+                .await
+                .and_then(|r| r),
+            None => held.holding(next.run(req)).await,
+        };
+
+        // ハンドラが LockedTx を手放すまで待つ
+        let mut tx = Arc::clone(&slot.0).lock_owned().await;
+        let released = match lock_tx {
+            Some(mut lock_tx) => lock.client.unlock(held, &mut lock_tx).await,
+            None => lock.client.unlock(held, &mut tx).await,
+        };
+
+        match after_release(released, res) {
+            Ok(res) => res,
+            Err(e) => respond(&e),
+        }
+    };
+
+    trace::instrument(fut, L::NAME, &key).await
+}
+
+/// 409 if the key couldn't be locked, 503 while shutting down or with the lock pool saturated and
+/// 500 on the other errors of the locker, the default response of [`lock_request`]
+pub fn lock_error_response(e: &Error) -> Response {
+    match e {
+        Error::FailedToGetLock(_) | Error::RetriesExhausted { .. } => {
+            (StatusCode::CONFLICT, e.to_string()).into_response()
+        }
+        Error::ShuttingDown | Error::PoolSaturated { .. } => {
+            (StatusCode::SERVICE_UNAVAILABLE, e.to_string()).into_response()
+        }
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+// "order:{id}" の {id} をパスパラメータで置き換える。無い名前はそのまま残す
+fn render_key(template: &str, params: &RawPathParams) -> String {
+    let mut key = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else {
+            break;
+        };
+        let name = &rest[start + 1..start + len];
+
+        key.push_str(&rest[..start]);
+        match params.iter().find(|(n, _)| *n == name) {
+            Some((_, value)) => key.push_str(value),
+            None => key.push_str(&rest[start..=start + len]),
+        }
+        rest = &rest[start + len + 1..];
+    }
+    key.push_str(rest);
+
+    key
+}
+
+/// Extractor of the transaction on the session holding the lock of [`lock_request`].
+///
+/// like [`Locker::with_locking`], the transaction isn't committed.
+pub struct LockedTx<DB: sqlx::Database>(OwnedMutexGuard<sqlx::Transaction<'static, DB>>);
+
+impl<DB: sqlx::Database> Deref for LockedTx<DB> {
+    type Target = sqlx::Transaction<'static, DB>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<DB: sqlx::Database> DerefMut for LockedTx<DB> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl<S: Send + Sync, DB: sqlx::Database> FromRequestParts<S> for LockedTx<DB> {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let slot = parts.extensions.get::<TxSlot<DB>>().cloned().ok_or((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "LockedTx requires the lock_request middleware",
+        ))?;
+
+        slot.0.try_lock_owned().map(LockedTx).map_err(|_| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "LockedTx can be extracted only once per request",
+            )
+        })
+    }
+}
+
+#[cfg(all(test, feature = "sqlx-std-collection"))]
+mod tests {
+    use ::axum::{Router, body::Body, middleware, routing::get};
+    use pretty_assertions::assert_eq;
+    use tokio::time::sleep;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    use crate::StdCollectionLocker;
+    use sqlx::{Sqlite, SqlitePool};
+
+    async fn handler(mut tx: LockedTx<Sqlite>) -> StatusCode {
+        sqlx::query("SELECT 1").execute(&mut **tx).await.unwrap();
+        sleep(Duration::from_millis(300)).await;
+        StatusCode::OK
+    }
+
+    #[sqlx::test]
+    async fn requests_on_the_same_path_parameter_conflict(pool: SqlitePool) -> sqlx::Result<()> {
+        let lock = AxumLock::new(
+            LockClient::<StdCollectionLocker<Sqlite>>::new(pool),
+            "Mk2Nj4Bh6Vg8Cf0Xd2Sz4Aq6Wx8Ec0Rv2Tb4Yn6Um8Ik0Ol2Pz4Xc6Vb8Nm0Qa:{id}",
+        );
+        let app = Router::new()
+            .route("/orders/{id}", get(handler))
+            .route_layer(middleware::from_fn_with_state(
+                lock,
+                lock_request::<StdCollectionLocker<Sqlite>>,
+            ));
+        let request = |id: u32| {
+            ::axum::http::Request::builder()
+                .uri(format!("/orders/{id}"))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let (r1, r2, r3) = tokio::join!(
+            app.clone().oneshot(request(1)),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                app.clone().oneshot(request(1)).await
+            },
+            async {
+                sleep(Duration::from_millis(100)).await;
+                app.clone().oneshot(request(2)).await
+            }
+        );
+
+        assert_eq!(r1.unwrap().status(), StatusCode::OK);
+        assert_eq!(r2.unwrap().status(), StatusCode::CONFLICT);
+        assert_eq!(r3.unwrap().status(), StatusCode::OK);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn conflict_response_is_built_from_the_retry_policy(
+        pool: SqlitePool,
+    ) -> sqlx::Result<()> {
+        let client = LockClient::<StdCollectionLocker<Sqlite>>::new(pool).retry(RetryPolicy {
+            backoff: Duration::from_millis(1500),
+            ..RetryPolicy::default()
+        });
+        let lock = AxumLock::new(
+            client,
+            "Rz5Ya7Xb9Wc1Vd3Ue5Tf7Sg9Rh1Qi3Pj5Ok7Nl9Mm1Ln3Ko5Jp7Iq9Hr1Gs3Ft5E",
+        )
+        .on_error(|e, policy| match e {
+            Error::FailedToGetLock(_) | Error::RetriesExhausted { .. } => (
+                StatusCode::LOCKED,
+                [("retry-after", policy.retry_after().as_secs().to_string())],
+            )
+                .into_response(),
+            e => lock_error_response(e),
+        });
+        let app =
+            Router::new()
+                .route("/", get(handler))
+                .route_layer(middleware::from_fn_with_state(
+                    lock,
+                    lock_request::<StdCollectionLocker<Sqlite>>,
+                ));
+        let request = || {
+            ::axum::http::Request::builder()
+                .uri("/")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let (r1, r2) = tokio::join!(app.clone().oneshot(request()), async {
+            sleep(Duration::from_millis(100)).await;
+            app.clone().oneshot(request()).await
+        });
+
+        assert_eq!(r1.unwrap().status(), StatusCode::OK);
+        let r2 = r2.unwrap();
+        assert_eq!(r2.status(), StatusCode::LOCKED);
+        // 1.5 秒は切り上げて 2 秒
+        assert_eq!(r2.headers()["retry-after"], "2");
+
+        Ok(())
+    }
+}
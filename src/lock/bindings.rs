@@ -0,0 +1,100 @@
+use std::time::Duration;
+
+use tokio::sync::oneshot;
+
+use crate::{LockClient, Locker, Result};
+
+#[cfg(feature = "sqlx-mysql")]
+use crate::MySqlLocker;
+#[cfg(feature = "sqlx-postgres")]
+use crate::PostgresLocker;
+
+// C と Python のバインディングが共有する、URL で選ぶプールと別タスクで持ち続けるロック
+
+/// pool of the backend chosen by the scheme of the database URL
+#[derive(Clone)]
+pub(crate) enum Pool {
+    #[cfg(feature = "sqlx-mysql")]
+    MySql(sqlx::MySqlPool),
+    #[cfg(feature = "sqlx-postgres")]
+    Postgres(sqlx::PgPool),
+}
+
+impl Pool {
+    /// connect to the mysql://, mariadb:// or postgres:// URL
+    ///
+    /// only the backends the crate was built with are supported.
+    pub(crate) async fn connect(url: &str) -> Result<Self> {
+        match url.split_once("://").map(|(scheme, _)| scheme) {
+            #[cfg(feature = "sqlx-mysql")]
+            Some("mysql" | "mariadb") => Ok(Self::MySql(sqlx::MySqlPool::connect(url).await?)),
+            #[cfg(feature = "sqlx-postgres")]
+            Some("postgres" | "postgresql") => {
+                Ok(Self::Postgres(sqlx::PgPool::connect(url).await?))
+            }
+            _ => Err(
+                sqlx::Error::Configuration(format!("unsupported database URL: {url}").into())
+                    .into(),
+            ),
+        }
+    }
+
+    /// hold the key on a task of the current runtime until [`HeldLock::release`]
+    pub(crate) async fn acquire(&self, key: &str, timeout: Option<Duration>) -> Result<HeldLock> {
+        match self {
+            #[cfg(feature = "sqlx-mysql")]
+            Self::MySql(pool) => HeldLock::acquire::<MySqlLocker>(pool.clone(), key, timeout).await,
+            #[cfg(feature = "sqlx-postgres")]
+            Self::Postgres(pool) => {
+                HeldLock::acquire::<PostgresLocker>(pool.clone(), key, timeout).await
+            }
+        }
+    }
+}
+
+/// key held by a task until it is released
+pub(crate) struct HeldLock {
+    release: oneshot::Sender<()>,
+    released: oneshot::Receiver<Result<()>>,
+}
+
+impl HeldLock {
+    // 別タスクでロックを持ち続け、取れたかどうかだけを先に返す
+    async fn acquire<L: Locker + 'static>(
+        pool: sqlx::Pool<L::DB>,
+        key: &str,
+        timeout: Option<Duration>,
+    ) -> Result<Self> {
+        let (acquired, acquisition) = oneshot::channel();
+        let (release, release_rx) = oneshot::channel();
+        let (released_tx, released) = oneshot::channel();
+        let key = key.to_owned();
+
+        tokio::spawn(async move {
+            let client = LockClient::<L>::new(pool);
+            let r = client
+                .run_locked(&key, timeout, async move {
+                    let _ = acquired.send(());
+                    let _ = release_rx.await;
+                })
+                .await;
+            let _ = released_tx.send(r);
+        });
+
+        match acquisition.await {
+            Ok(()) => Ok(Self { release, released }),
+            Err(_) => match released.await {
+                Ok(Err(e)) => Err(e),
+                _ => unreachable!("the lock task ended without acquiring the key or failing"),
+            },
+        }
+    }
+
+    /// release the key and wait for the task to finish
+    pub(crate) async fn release(self) -> Result<()> {
+        let _ = self.release.send(());
+        self.released
+            .await
+            .expect("the lock task ended without reporting the release")
+    }
+}
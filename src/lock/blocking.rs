@@ -0,0 +1,103 @@
+//! Runtime behind [`crate::Locker::with_locking_blocking`].
+
+use std::{
+    panic::{AssertUnwindSafe, catch_unwind, resume_unwind},
+    sync::LazyLock,
+    time::Duration,
+};
+
+use tokio::runtime::Runtime;
+
+use super::trace;
+use crate::{DefaultClock, Locker, Result, ShutdownRegistry};
+
+static RUNTIME: LazyLock<Runtime> = LazyLock::new(|| {
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .thread_name("rusty_ad_lock-blocking")
+        .enable_all()
+        .build()
+        .expect("failed to start the runtime of rusty_ad_lock::blocking")
+});
+
+/// runtime the blocking API drives the lockers on
+///
+/// pools used with [`crate::Locker::with_locking_blocking`] should be created on it, e.g.
+/// `runtime().block_on(MySqlPool::connect(url))`.
+pub fn runtime() -> &'static Runtime {
+    &RUNTIME
+}
+
+pub(crate) fn with_locking<L: Locker + ?Sized, T>(
+    pool: &sqlx::Pool<L::DB>,
+    key: &str,
+    timeout: Option<Duration>,
+    f: impl FnOnce() -> T,
+) -> Result<T> {
+    let rt = runtime();
+    // トランザクションの drop もランタイム上で行う
+    let _enter = rt.enter();
+
+    let registration = ShutdownRegistry::global().register()?;
+    let mut lifecycle = trace::Lifecycle::start::<L, DefaultClock>(key);
+    let mut tx = rt.block_on(pool.begin())?;
+
+    let r = rt.block_on(trace::instrument(
+        L::acquire(pool, &mut tx, key, timeout),
+        L::NAME,
+        key,
+    ));
+    lifecycle.acquisition(&r);
+    r?;
+
+    // クロージャが panic してもロックを解放してから panic を続ける
+    let out = catch_unwind(AssertUnwindSafe(f));
+
+    let r = rt.block_on(trace::instrument(
+        L::release(pool, &mut tx, key),
+        L::NAME,
+        key,
+    ));
+    lifecycle.release(&r);
+    drop(registration);
+
+    match out {
+        Ok(out) => r.map(|()| out),
+        Err(panic) => resume_unwind(panic),
+    }
+}
+
+#[cfg(all(test, feature = "sqlx-std-collection"))]
+mod tests {
+    use pretty_assertions::assert_matches;
+
+    use super::*;
+
+    use crate::{Error, StdCollectionLocker};
+    use sqlx::{SqlitePool, sqlite::SqliteConnectOptions};
+
+    #[test]
+    fn closure_runs_on_the_calling_thread_under_the_lock() {
+        let key = "Ce7Vr9Bt1Ny3Mu5Ki7Lo9Pa1Sq3Dw5Fe7Gr9Ht1Jy3Ku5Li7Oo9Pp1Aa3Ss5Dd7F";
+        let _enter = runtime().enter();
+        let pool = runtime()
+            .block_on(SqlitePool::connect_with(
+                SqliteConnectOptions::new()
+                    .filename(std::env::temp_dir().join("rusty_ad_lock_blocking.db"))
+                    .create_if_missing(true),
+            ))
+            .unwrap();
+
+        let r = StdCollectionLocker::with_locking_blocking(&pool, key, None, || {
+            let nested = StdCollectionLocker::with_locking_blocking(&pool, key, None, || ());
+            assert_matches!(nested, Err(Error::FailedToGetLock(_)));
+            std::thread::current().id()
+        });
+
+        assert_matches!(r, Ok(id) if id == std::thread::current().id());
+        assert_matches!(
+            StdCollectionLocker::with_locking_blocking(&pool, key, None, || ()),
+            Ok(())
+        );
+    }
+}
@@ -0,0 +1,277 @@
+use std::{
+    future::Future,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Serialize, de::DeserializeOwned};
+use sqlx::TransactionManager;
+
+use crate::Result;
+
+/// Database the results of [`crate::Locker::with_locking_cached`] are kept in.
+///
+/// the `lock_results` table has to exist beforehand, add [`CacheDatabase::CACHE_TABLE`] to the
+/// migrations of the application.
+pub trait CacheDatabase: sqlx::Database {
+    /// DDL of the `lock_results` table
+    const CACHE_TABLE: &'static str;
+
+    /// JSON of the result stored under `key`, unless it expired at `now_ms`
+    fn cached_result(
+        conn: &mut Self::Connection,
+        key: &str,
+        now_ms: i64,
+    ) -> impl Future<Output = Result<Option<String>>> + Send;
+
+    /// store the JSON of the result under `key` until `expires_ms`, replacing the previous one
+    fn store_result(
+        conn: &mut Self::Connection,
+        key: &str,
+        result: &str,
+        expires_ms: i64,
+    ) -> impl Future<Output = Result<()>> + Send;
+}
+
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+// 期限内の結果があれば読む
+pub(crate) async fn cached<DB: CacheDatabase, T: DeserializeOwned>(
+    conn: &mut DB::Connection,
+    key: &str,
+) -> Result<Option<T>> {
+    match DB::cached_result(conn, key, now_ms()).await? {
+        Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+        None => Ok(None),
+    }
+}
+
+// ロックのトランザクションは解放で巻き戻されるので、結果を書いたら確定させ、同じセッションで始め
+// 直す。ロックはセッションのものなので確定しても外れない
+pub(crate) async fn store<DB: CacheDatabase, T: Serialize>(
+    tx: &mut sqlx::Transaction<'static, DB>,
+    key: &str,
+    result: &T,
+    ttl: Duration,
+) -> Result<()> {
+    let json = serde_json::to_string(result)?;
+    DB::store_result(&mut **tx, key, &json, now_ms() + ttl.as_millis() as i64).await?;
+    DB::TransactionManager::commit(&mut **tx).await?;
+    DB::TransactionManager::begin(&mut **tx, None).await?;
+    Ok(())
+}
+
+#[cfg(feature = "sqlx-mysql")]
+impl CacheDatabase for sqlx::MySql {
+    const CACHE_TABLE: &'static str = "CREATE TABLE IF NOT EXISTS lock_results (
+    lock_key VARCHAR(255) NOT NULL PRIMARY KEY,
+    result LONGTEXT NOT NULL,
+    expires_ms BIGINT NOT NULL,
+    stored_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3) ON UPDATE CURRENT_TIMESTAMP(3)
+)";
+
+    async fn cached_result(
+        conn: &mut Self::Connection,
+        key: &str,
+        now_ms: i64,
+    ) -> Result<Option<String>> {
+        let result = sqlx::query_scalar(
+            "SELECT result FROM lock_results WHERE lock_key = ? AND expires_ms > ?",
+        )
+        .bind(key)
+        .bind(now_ms)
+        .fetch_optional(conn)
+        .await?;
+
+        Ok(result)
+    }
+
+    async fn store_result(
+        conn: &mut Self::Connection,
+        key: &str,
+        result: &str,
+        expires_ms: i64,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO lock_results (lock_key, result, expires_ms) VALUES (?, ?, ?) \
+             ON DUPLICATE KEY UPDATE result = VALUES(result), expires_ms = VALUES(expires_ms)",
+        )
+        .bind(key)
+        .bind(result)
+        .bind(expires_ms)
+        .execute(conn)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sqlx-postgres")]
+impl CacheDatabase for sqlx::Postgres {
+    const CACHE_TABLE: &'static str = "CREATE TABLE IF NOT EXISTS lock_results (
+    lock_key TEXT NOT NULL PRIMARY KEY,
+    result TEXT NOT NULL,
+    expires_ms BIGINT NOT NULL,
+    stored_at TIMESTAMPTZ NOT NULL DEFAULT now()
+)";
+
+    async fn cached_result(
+        conn: &mut Self::Connection,
+        key: &str,
+        now_ms: i64,
+    ) -> Result<Option<String>> {
+        let result = sqlx::query_scalar(
+            "SELECT result FROM lock_results WHERE lock_key = $1 AND expires_ms > $2",
+        )
+        .bind(key)
+        .bind(now_ms)
+        .fetch_optional(conn)
+        .await?;
+
+        Ok(result)
+    }
+
+    async fn store_result(
+        conn: &mut Self::Connection,
+        key: &str,
+        result: &str,
+        expires_ms: i64,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO lock_results (lock_key, result, expires_ms) VALUES ($1, $2, $3) \
+             ON CONFLICT (lock_key) DO UPDATE SET result = EXCLUDED.result, \
+               expires_ms = EXCLUDED.expires_ms, stored_at = now()",
+        )
+        .bind(key)
+        .bind(result)
+        .bind(expires_ms)
+        .execute(conn)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sqlx-sqlite")]
+impl CacheDatabase for sqlx::Sqlite {
+    const CACHE_TABLE: &'static str = "CREATE TABLE IF NOT EXISTS lock_results (
+    lock_key TEXT NOT NULL PRIMARY KEY,
+    result TEXT NOT NULL,
+    expires_ms INTEGER NOT NULL,
+    stored_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now'))
+)";
+
+    async fn cached_result(
+        conn: &mut Self::Connection,
+        key: &str,
+        now_ms: i64,
+    ) -> Result<Option<String>> {
+        let result = sqlx::query_scalar(
+            "SELECT result FROM lock_results WHERE lock_key = ? AND expires_ms > ?",
+        )
+        .bind(key)
+        .bind(now_ms)
+        .fetch_optional(conn)
+        .await?;
+
+        Ok(result)
+    }
+
+    async fn store_result(
+        conn: &mut Self::Connection,
+        key: &str,
+        result: &str,
+        expires_ms: i64,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO lock_results (lock_key, result, expires_ms) VALUES (?, ?, ?) \
+             ON CONFLICT (lock_key) DO UPDATE SET result = excluded.result, \
+               expires_ms = excluded.expires_ms, \
+               stored_at = strftime('%Y-%m-%d %H:%M:%f', 'now')",
+        )
+        .bind(key)
+        .bind(result)
+        .bind(expires_ms)
+        .execute(conn)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "sqlx-std-collection", feature = "sqlx-sqlite"))]
+mod tests {
+    use pretty_assertions::{assert_eq, assert_matches};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use tokio::time::sleep;
+
+    use super::*;
+
+    use crate::{Locker, StdCollectionLocker};
+    use sqlx::{Sqlite, SqlitePool};
+
+    type Collection = StdCollectionLocker<Sqlite>;
+
+    #[sqlx::test]
+    async fn followers_read_the_result_of_the_winner(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Rc2Sd4Te6Uf8Vg0Wh2Xi4Yj6Zk8Al0Bm2Cn4Do6Ep8Fq0Gr2Hs4It6Ju8Kv0Lw2M";
+        sqlx::raw_sql(Sqlite::CACHE_TABLE).execute(&pool).await?;
+        let runs = AtomicU32::new(0);
+        let compute = async |_: &mut sqlx::Transaction<'static, Sqlite>| {
+            let run = runs.fetch_add(1, Ordering::Relaxed);
+            sleep(Duration::from_millis(200)).await;
+            vec![format!("report-{run}")]
+        };
+        let ttl = Duration::from_millis(500);
+        let timeout = Some(Duration::from_secs(1));
+
+        // 同時に来た 3 つのうち 1 つだけが計算する
+        let (r1, r2, r3) = tokio::join!(
+            Collection::with_locking_cached(&pool, key, ttl, timeout, compute),
+            Collection::with_locking_cached(&pool, key, ttl, timeout, compute),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                Collection::with_locking_cached(&pool, key, ttl, timeout, compute).await
+            }
+        );
+        assert_eq!(r1.unwrap(), ["report-0"]);
+        assert_eq!(r2.unwrap(), ["report-0"]);
+        assert_eq!(r3.unwrap(), ["report-0"]);
+        assert_eq!(runs.load(Ordering::Relaxed), 1);
+
+        // 期限が切れたら計算し直す
+        sleep(ttl).await;
+        assert_matches!(
+            Collection::with_locking_cached(&pool, key, ttl, timeout, compute)
+                .await
+                .as_deref(),
+            Ok([report]) if report == "report-1"
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn winner_needs_no_second_connection(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Oc1Nc3Ow5In7Ne9Rc2Ac4Ch6Ed8Re0Su2Lt4Sa6Mt8Ix0Ma2Xc4On6Nn8Oe0Ct2I";
+        sqlx::raw_sql(Sqlite::CACHE_TABLE).execute(&pool).await?;
+        let single = sqlx::pool::PoolOptions::<Sqlite>::new()
+            .max_connections(1)
+            .acquire_timeout(Duration::from_secs(1))
+            .connect_with((*pool.connect_options()).clone())
+            .await?;
+        let ttl = Duration::from_secs(60);
+
+        // 結果はロックの接続で読み書きするので、接続が 1 つでも詰まらない
+        let r = Collection::with_locking_cached(&single, key, ttl, None, async |_| 42).await;
+        assert_matches!(r, Ok(42));
+        let r = Collection::with_locking_cached(&single, key, ttl, None, async |_| 0).await;
+        assert_matches!(r, Ok(42));
+
+        Ok(())
+    }
+}
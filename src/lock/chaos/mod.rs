@@ -0,0 +1,338 @@
+use std::{
+    collections::HashMap,
+    marker::PhantomData,
+    pin::pin,
+    sync::{LazyLock, Mutex},
+    task::Poll,
+    time::Duration,
+};
+
+use crate::{Capabilities, Clock, DefaultClock, Error, LockFn, Locker};
+
+/// Locker decorator that injects faults into the wrapped backend `L`.
+///
+/// faults are configured per key with [`ChaosLocker::inject`], keys without faults behave exactly
+/// like `L`.
+///
+/// ```ignore
+/// ChaosLocker::<MySqlLocker>::inject(
+///     "key",
+///     Faults {
+///         lose_after: Some(Duration::from_millis(100)),
+///         ..Default::default()
+///     },
+/// );
+///
+/// // the lock is released 100ms after it was acquired, while the closure keeps running
+/// let r = ChaosLocker::<MySqlLocker>::with_locking(&pool, "key", None, async |_| {
+///     sleep(Duration::from_secs(1)).await;
+/// })
+/// .await;
+/// ```
+pub struct ChaosLocker<L: Locker> {
+    _marker: PhantomData<L>,
+}
+
+/// Faults injected by [`ChaosLocker`] for a key.
+#[derive(Clone, Debug, Default)]
+pub struct Faults {
+    /// extra delay before every acquisition reaches the backend
+    pub acquire_delay: Duration,
+    /// skip the backend release, leaving the lock held by the session
+    pub drop_release: bool,
+    /// release the lock, then fail as if the backend returned an error
+    pub fail_release: bool,
+    /// release the lock this long after it was acquired, while the closure may still be running.
+    /// the closure then gets its own transaction instead of the one holding the lock.
+    pub lose_after: Option<Duration>,
+}
+
+static FAULTS: LazyLock<Mutex<HashMap<String, Faults>>> = LazyLock::new(Mutex::default);
+
+fn faults(key: &str) -> Faults {
+    FAULTS.lock().unwrap().get(key).cloned().unwrap_or_default()
+}
+
+impl<L: Locker> ChaosLocker<L> {
+    /// inject `faults` into every following lock call of `key`
+    pub fn inject(key: &str, faults: Faults) {
+        FAULTS.lock().unwrap().insert(key.to_owned(), faults);
+    }
+
+    /// stop injecting faults into `key`
+    pub fn clear(key: &str) {
+        FAULTS.lock().unwrap().remove(key);
+    }
+}
+
+impl<L: Locker> Locker for ChaosLocker<L> {
+    type DB = L::DB;
+
+    const NAME: &'static str = L::NAME;
+
+    const MAX_HOLD: Option<Duration> = L::MAX_HOLD;
+
+    const MAX_KEY_LEN: Option<usize> = L::MAX_KEY_LEN;
+
+    fn capabilities() -> Capabilities {
+        L::capabilities()
+    }
+
+    async fn acquire(
+        pool: &sqlx::Pool<Self::DB>,
+        tx: &mut sqlx::Transaction<'static, Self::DB>,
+        key: &str,
+        timeout: Option<Duration>,
+    ) -> crate::Result<()> {
+        let faults = faults(key);
+        if !faults.acquire_delay.is_zero() {
+            DefaultClock::sleep(faults.acquire_delay).await;
+        }
+
+        L::acquire(pool, tx, key, timeout).await
+    }
+
+    fn set_owner(
+        pool: &sqlx::Pool<Self::DB>,
+        tx: &mut sqlx::Transaction<'static, Self::DB>,
+        key: &str,
+        owner: &str,
+    ) -> impl Future<Output = crate::Result<()>> + Send {
+        L::set_owner(pool, tx, key, owner)
+    }
+
+    fn lock_lost(key: &str) -> impl Future<Output = ()> + Send {
+        L::lock_lost(key)
+    }
+
+    fn extend_hold(key: &str, by: Duration) -> impl Future<Output = crate::Result<()>> + Send {
+        L::extend_hold(key, by)
+    }
+
+    async fn release(
+        pool: &sqlx::Pool<Self::DB>,
+        tx: &mut sqlx::Transaction<'static, Self::DB>,
+        key: &str,
+    ) -> crate::Result<()> {
+        let faults = faults(key);
+        if faults.drop_release {
+            return Ok(());
+        }
+
+        L::release(pool, tx, key).await?;
+        if faults.fail_release {
+            return Err(Error::Sqlx(sqlx::Error::Protocol(
+                "injected release failure".to_owned(),
+            )));
+        }
+        Ok(())
+    }
+
+    async fn with_locking<T, F>(
+        pool: &sqlx::Pool<Self::DB>,
+        key: &str,
+        timeout: Option<Duration>,
+        f: F,
+    ) -> crate::Result<()>
+    where
+        F: LockFn<Self::DB, T>,
+    {
+        let Some(lose_after) = faults(key).lose_after else {
+            let mut tx = pool.begin().await?;
+            Self::acquire(pool, &mut tx, key, timeout).await?;
+            f(&mut tx).await;
+            return Self::release(pool, &mut tx, key).await;
+        };
+
+        let mut lock_tx = pool.begin().await?;
+        let mut tx = pool.begin().await?;
+        Self::acquire(pool, &mut lock_tx, key, timeout).await?;
+
+        let lost = {
+            let mut closure = pin!(f(&mut tx));
+            let mut lose = pin!(async {
+                DefaultClock::sleep(lose_after).await;
+                Self::release(pool, &mut lock_tx, key).await
+            });
+            let mut lost = None;
+
+            // クロージャが終わるまでに時間が来たら、クロージャを待たずにロックを手放す
+            std::future::poll_fn(|cx| {
+                if lost.is_none() {
+                    if let Poll::Ready(r) = lose.as_mut().poll(cx) {
+                        lost = Some(r);
+                    }
+                }
+                closure.as_mut().poll(cx).map(|_| ())
+            })
+            .await;
+
+            lost
+        };
+
+        match lost {
+            Some(r) => r,
+            None => Self::release(pool, &mut lock_tx, key).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_matches;
+    use std::time::Duration;
+    use tokio::time::sleep;
+
+    use super::*;
+
+    use crate::{LockClient, StdCollectionLocker};
+    use sqlx::{Sqlite, SqlitePool};
+
+    type Chaos = ChaosLocker<StdCollectionLocker<Sqlite>>;
+
+    #[sqlx::test]
+    async fn dropped_release_leaves_the_lock_held(pool: SqlitePool) -> sqlx::Result<()> {
+        Chaos::inject(
+            "T7DZ8TADdysKYMvwkZHBn3Qja57gfLXK45nMAs1la6SCwA0gwPa45wMfdfqDsudQ",
+            Faults {
+                drop_release: true,
+                ..Default::default()
+            },
+        );
+
+        let r = Chaos::with_locking(
+            &pool,
+            "T7DZ8TADdysKYMvwkZHBn3Qja57gfLXK45nMAs1la6SCwA0gwPa45wMfdfqDsudQ",
+            None,
+            async |_| {},
+        )
+        .await;
+        assert_matches!(r, Ok(()));
+
+        let r = StdCollectionLocker::with_locking(
+            &pool,
+            "T7DZ8TADdysKYMvwkZHBn3Qja57gfLXK45nMAs1la6SCwA0gwPa45wMfdfqDsudQ",
+            None,
+            async |_| {},
+        )
+        .await;
+        assert_matches!(r, Err(Error::FailedToGetLock(_)));
+
+        Chaos::clear("T7DZ8TADdysKYMvwkZHBn3Qja57gfLXK45nMAs1la6SCwA0gwPa45wMfdfqDsudQ");
+        let mut tx = pool.begin().await?;
+        let r = Chaos::release(
+            &pool,
+            &mut tx,
+            "T7DZ8TADdysKYMvwkZHBn3Qja57gfLXK45nMAs1la6SCwA0gwPa45wMfdfqDsudQ",
+        )
+        .await;
+        assert_matches!(r, Ok(()));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn lock_is_lost_while_the_closure_runs(pool: SqlitePool) -> sqlx::Result<()> {
+        Chaos::inject(
+            "Ah7pZ0mJ1nDq9V8fXbWc3KsYtR5uLgE2oHiN4lMvB6aQwT0zCyUxSdFjGkPeIrO1",
+            Faults {
+                lose_after: Some(Duration::from_millis(100)),
+                ..Default::default()
+            },
+        );
+
+        let stolen = StdCollectionLocker::with_locking(
+            &pool,
+            "Ah7pZ0mJ1nDq9V8fXbWc3KsYtR5uLgE2oHiN4lMvB6aQwT0zCyUxSdFjGkPeIrO1",
+            Duration::from_secs(1).into(),
+            async |_| {},
+        );
+        let (r1, r2) = tokio::join!(
+            Chaos::with_locking(
+                &pool,
+                "Ah7pZ0mJ1nDq9V8fXbWc3KsYtR5uLgE2oHiN4lMvB6aQwT0zCyUxSdFjGkPeIrO1",
+                None,
+                async |_| {
+                    sleep(Duration::from_secs(2)).await;
+                },
+            ),
+            async {
+                sleep(Duration::from_millis(10)).await;
+                stolen.await
+            }
+        );
+
+        assert_matches!(r1, Ok(()));
+        assert_matches!(r2, Ok(()));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn acquisition_is_delayed(pool: SqlitePool) -> sqlx::Result<()> {
+        Chaos::inject(
+            "Qm3Rt8Wz1Xc5Vb9Nn2Lk7Jh4Gf6Dd0Ss3Aa8Pp1Oo5Ii9Uu2Yy7Tt4Rr6Ee0Ww3Qq",
+            Faults {
+                acquire_delay: Duration::from_millis(500),
+                ..Default::default()
+            },
+        );
+
+        let started = std::time::Instant::now();
+        let r = Chaos::with_locking(
+            &pool,
+            "Qm3Rt8Wz1Xc5Vb9Nn2Lk7Jh4Gf6Dd0Ss3Aa8Pp1Oo5Ii9Uu2Yy7Tt4Rr6Ee0Ww3Qq",
+            None,
+            async |_| {},
+        )
+        .await;
+
+        assert_matches!(r, Ok(()));
+        assert!(started.elapsed() >= Duration::from_millis(500));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn failed_releases_are_reported(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Rf3Sg5Th7Ui9Vj1Wk3Xl5Ym7Zn9Ao1Bp3Cq5Dr7Es9Ft1Gu3Hv5Iw7Jx9Ky1Lz3M";
+        Chaos::inject(
+            key,
+            Faults {
+                fail_release: true,
+                ..Default::default()
+            },
+        );
+        let reported = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let client = LockClient::<Chaos>::new(pool.clone()).on_release_error({
+            let reported = std::sync::Arc::clone(&reported);
+            move |key, _| reported.lock().unwrap().push(key.to_owned())
+        });
+
+        // クロージャが成功していれば解放の失敗を返す
+        assert_matches!(
+            client.with_locking(key, None, async |_| {}).await,
+            Err(Error::Sqlx(_))
+        );
+        // 両方失敗したら両方返す
+        let r = client
+            .clone()
+            .max_hold(Duration::from_millis(50))
+            .with_locking(key, None, async |_| {
+                sleep(Duration::from_millis(500)).await;
+            })
+            .await;
+        assert_matches!(
+            r,
+            Err(Error::ReleaseFailed { closure, release })
+                if matches!(*closure, Error::LeaseExpired(_)) && matches!(*release, Error::Sqlx(_))
+        );
+        pretty_assertions::assert_eq!(*reported.lock().unwrap(), [key, key]);
+
+        // 鍵自体は解放されている
+        Chaos::clear(key);
+        assert_matches!(client.with_locking(key, None, async |_| {}).await, Ok(()));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,2415 @@
+use std::{
+    borrow::Cow,
+    marker::PhantomData,
+    pin::{Pin, pin},
+    sync::Arc,
+    task::Poll,
+    time::Duration,
+    time::Instant,
+};
+
+use sqlx::Connection;
+
+use super::after_release;
+use super::audit::AuditLog;
+use super::introspect::{self, HolderLookup};
+use super::quota::QuotaSlot;
+use super::section::{self, Section};
+use super::shutdown::Registration;
+use super::slow::SlowLockWarnings;
+use super::trace;
+#[cfg(feature = "unicode-normalization")]
+use crate::KeyNormalization;
+use crate::{
+    AcquireStream, AuditDatabase, AuditOutcome, CancellationToken, Clock, DefaultClock, Error,
+    Introspect, KeyHmac, LockAttempt, LockFn, LockGroups, LockGuard, LockHooks, LockInfo,
+    LockMetrics, LockReport, Locker, NoopMetrics, OverLimit, Result, RetryPolicy, ShutdownRegistry,
+    SlowLock,
+};
+
+// 取得の前か後に、ロックのセッションで SQL を流す
+pub(crate) type SessionSql<DB> = Arc<
+    dyn for<'t> Fn(
+            &'t mut sqlx::Transaction<'static, DB>,
+        ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + 't>>
+        + Send
+        + Sync,
+>;
+
+type ReleaseErrorFn = Arc<dyn Fn(&str, &Error) + Send + Sync>;
+
+/// timeout of a call that fails right away if the key is held, even on a client with a
+/// [`LockClient::default_timeout`] or a [`RetryPolicy::attempt_timeout`]
+pub const NO_WAIT: Option<Duration> = Some(Duration::ZERO);
+
+/// Locker bound to a pool, carrying per-instance configuration.
+///
+/// the waits, retries, max hold and reported durations of the client run on the clock `C`, see
+/// [`LockClient::clock`].
+///
+/// ```ignore
+/// let locker = LockClient::<MySqlLocker>::new(pool)
+///     .lock_pool(lock_pool)
+///     .metrics(MyMetrics::default());
+///
+/// let r = locker
+///     .with_locking("key", Duration::from_secs(1).into(), async |_| {
+///         sleep(Duration::from_secs(1)).await;
+///     })
+///     .await;
+/// ```
+pub struct LockClient<L: Locker, C: Clock = DefaultClock> {
+    pool: sqlx::Pool<L::DB>,
+    lock_pool: Option<sqlx::Pool<L::DB>>,
+    metrics: Arc<dyn LockMetrics>,
+    audit: Option<AuditLog<L::DB>>,
+    slow: SlowLockWarnings,
+    release_error: Option<ReleaseErrorFn>,
+    shutdown: ShutdownRegistry,
+    shutdown_order: u32,
+    namespace: Option<String>,
+    timeout: Option<Duration>,
+    retry: RetryPolicy,
+    quota: Option<usize>,
+    min_free: Option<u32>,
+    #[cfg(feature = "unicode-normalization")]
+    normalization: Option<KeyNormalization>,
+    over_limit: Option<OverLimit>,
+    hmac: Option<KeyHmac>,
+    holder: Option<HolderLookup<L::DB>>,
+    session_check: Option<Duration>,
+    keepalive: Option<Duration>,
+    pub(crate) before_lock_sql: Vec<SessionSql<L::DB>>,
+    pub(crate) after_lock_sql: Vec<SessionSql<L::DB>>,
+    max_hold: Option<Duration>,
+    pub(crate) owner: Option<String>,
+    clock: PhantomData<fn() -> C>,
+}
+
+impl<L: Locker, C: Clock> Clone for LockClient<L, C> {
+    fn clone(&self) -> Self {
+        Self {
+            pool: self.pool.clone(),
+            lock_pool: self.lock_pool.clone(),
+            metrics: Arc::clone(&self.metrics),
+            audit: self.audit.clone(),
+            slow: self.slow.clone(),
+            release_error: self.release_error.clone(),
+            shutdown: self.shutdown.clone(),
+            shutdown_order: self.shutdown_order,
+            namespace: self.namespace.clone(),
+            timeout: self.timeout,
+            retry: self.retry,
+            quota: self.quota,
+            min_free: self.min_free,
+            #[cfg(feature = "unicode-normalization")]
+            normalization: self.normalization,
+            over_limit: self.over_limit,
+            hmac: self.hmac.clone(),
+            holder: self.holder,
+            session_check: self.session_check,
+            keepalive: self.keepalive,
+            before_lock_sql: self.before_lock_sql.clone(),
+            after_lock_sql: self.after_lock_sql.clone(),
+            max_hold: self.max_hold,
+            owner: self.owner.clone(),
+            clock: PhantomData,
+        }
+    }
+}
+
+// ロックを持っている間の状態。lock と unlock の間でクロージャを実行する
+pub(crate) struct Held<'a, DB: sqlx::Database, C: Clock = DefaultClock> {
+    key: &'a str,
+    lifecycle: trace::Lifecycle<'a>,
+    lock_pool: &'a sqlx::Pool<DB>,
+    registration: Registration,
+    hold_until: Option<Instant>,
+    // 取得のときに他のセッションが持っていたか
+    contended: bool,
+    _quota: Option<QuotaSlot>,
+    clock: PhantomData<fn() -> C>,
+}
+
+impl<DB: sqlx::Database, C: Clock> Held<'_, DB, C> {
+    // シャットダウンか Locker::MAX_HOLD で打ち切られることがある
+    pub(crate) async fn holding<F: Future>(&mut self, f: F) -> Result<F::Output> {
+        self.registration
+            .holding_until_with::<C, F>(self.hold_until, self.key, f)
+            .await
+    }
+
+    pub(crate) fn contended(&self) -> bool {
+        self.contended
+    }
+
+    // Locker::MAX_HOLD までの残り
+    pub(crate) fn remaining(&self) -> Option<Duration> {
+        self.hold_until
+            .map(|hold_until| hold_until.saturating_duration_since(C::now()))
+    }
+
+    pub(crate) fn extend(&mut self, by: Duration) {
+        if let Some(hold_until) = &mut self.hold_until {
+            *hold_until += by;
+        }
+    }
+}
+
+impl<L: Locker> LockClient<L> {
+    /// create a locker whose lock sessions and closures both use `pool`
+    pub fn new(pool: sqlx::Pool<L::DB>) -> Self {
+        Self {
+            pool,
+            lock_pool: None,
+            metrics: Arc::new(NoopMetrics),
+            audit: None,
+            slow: SlowLockWarnings::default(),
+            release_error: None,
+            shutdown: ShutdownRegistry::global().clone(),
+            shutdown_order: 0,
+            namespace: None,
+            timeout: None,
+            retry: RetryPolicy::default(),
+            quota: None,
+            min_free: None,
+            #[cfg(feature = "unicode-normalization")]
+            normalization: None,
+            over_limit: None,
+            hmac: None,
+            holder: None,
+            session_check: None,
+            keepalive: None,
+            before_lock_sql: Vec::new(),
+            after_lock_sql: Vec::new(),
+            max_hold: None,
+            owner: None,
+            clock: PhantomData,
+        }
+    }
+}
+
+impl<L: Locker, C: Clock> LockClient<L, C> {
+    /// time the waits, retries, max hold and reported durations of the client on `C2` instead of
+    /// [`DefaultClock`], e.g. a clock paused in tests
+    pub fn clock<C2: Clock>(self) -> LockClient<L, C2> {
+        LockClient {
+            pool: self.pool,
+            lock_pool: self.lock_pool,
+            metrics: self.metrics,
+            audit: self.audit,
+            slow: self.slow,
+            release_error: self.release_error,
+            shutdown: self.shutdown,
+            shutdown_order: self.shutdown_order,
+            namespace: self.namespace,
+            timeout: self.timeout,
+            retry: self.retry,
+            quota: self.quota,
+            min_free: self.min_free,
+            #[cfg(feature = "unicode-normalization")]
+            normalization: self.normalization,
+            over_limit: self.over_limit,
+            hmac: self.hmac,
+            holder: self.holder,
+            session_check: self.session_check,
+            keepalive: self.keepalive,
+            before_lock_sql: self.before_lock_sql,
+            after_lock_sql: self.after_lock_sql,
+            max_hold: self.max_hold,
+            owner: self.owner,
+            clock: PhantomData,
+        }
+    }
+
+    /// take lock sessions from `lock_pool` instead, see [`Locker::with_locking_on`]
+    pub fn lock_pool(mut self, lock_pool: sqlx::Pool<L::DB>) -> Self {
+        self.lock_pool = Some(lock_pool);
+        self
+    }
+
+    /// report the lock lifecycle to `metrics`
+    pub fn metrics(mut self, metrics: impl LockMetrics + 'static) -> Self {
+        self.metrics = Arc::new(metrics);
+        self
+    }
+
+    /// append every acquire and release to the `lock_audit` table of the pool, see [`AuditDatabase`]
+    ///
+    /// a call fails if its acquisition or release can't be recorded, the closure isn't executed
+    /// when the acquisition can't be.
+    ///
+    /// * `owner` - who holds the lock, e.g. the host name and the process id
+    pub fn audit(mut self, owner: impl Into<String>) -> Self
+    where
+        L::DB: AuditDatabase,
+    {
+        self.audit = Some(AuditLog::new(owner.into()));
+        self
+    }
+
+    /// label the locks of the client with `owner`, e.g. the host, the pid and the task name
+    ///
+    /// stored with the lock where the backend can, see [`Locker::set_owner`], and listed as
+    /// [`LockInfo::owner`] and in [`Error::HeldBy`]. clone the client to label a single call.
+    pub fn owner(mut self, owner: impl Into<String>) -> Self {
+        self.owner = Some(owner.into());
+        self
+    }
+
+    /// look the holder up when a key can't be acquired, failing with [`Error::HeldBy`] instead of
+    /// [`Error::FailedToGetLock`] if it is found
+    ///
+    /// when the retries of [`LockClient::retry`] are used up, the holder is set on
+    /// [`Error::RetriesExhausted`] instead.
+    ///
+    /// see [`Introspect::holder`]. the lookup takes a connection of the lock pool.
+    pub fn describe_holder(mut self) -> Self
+    where
+        L: Introspect + 'static,
+    {
+        self.holder = Some(introspect::holder_lookup::<L>());
+        self
+    }
+
+    /// warn when an acquisition waits longer than `threshold`
+    ///
+    /// warnings go to `tracing` when the feature is enabled, and to [`LockClient::on_slow`].
+    pub fn warn_wait_over(mut self, threshold: Duration) -> Self {
+        self.slow.wait = Some(threshold);
+        self
+    }
+
+    /// warn when the closure holds the lock longer than `threshold`, checked when it is released
+    pub fn warn_hold_over(mut self, threshold: Duration) -> Self {
+        self.slow.hold = Some(threshold);
+        self
+    }
+
+    /// abort a closure holding the lock longer than `max` with [`Error::LeaseExpired`]
+    ///
+    /// the closure's transaction is rolled back and the lock released, so a runaway job can't keep
+    /// a hot key. the earlier of this and [`Locker::MAX_HOLD`] applies. clone the client to set it
+    /// for one call:
+    ///
+    /// ```ignore
+    /// locker.clone().max_hold(Duration::from_secs(30)).with_locking("report", None, f).await?;
+    /// ```
+    pub fn max_hold(mut self, max: Duration) -> Self {
+        self.max_hold = Some(max);
+        self
+    }
+
+    /// call `callback` for every warning of [`LockClient::warn_wait_over`] and
+    /// [`LockClient::warn_hold_over`]
+    pub fn on_slow(mut self, callback: impl Fn(&SlowLock<'_>) + Send + Sync + 'static) -> Self {
+        self.slow.callback = Some(Arc::new(callback));
+        self
+    }
+
+    /// call `callback` with the key and the error of every release that fails
+    ///
+    /// the key may then stay held until its session ends. also called for the releases whose
+    /// error can't be returned: of a key whose owner or audit record couldn't be stored, and of
+    /// the locks released in the background once their guard was dropped. a call whose closure
+    /// failed too returns both as [`Error::ReleaseFailed`].
+    ///
+    /// ```ignore
+    /// let locker = LockClient::<MySqlLocker>::new(pool).on_release_error(|key, error| {
+    ///     tracing::error!(key, %error, "the lock may stay held until its session ends")
+    /// });
+    /// ```
+    pub fn on_release_error(
+        mut self,
+        callback: impl Fn(&str, &Error) + Send + Sync + 'static,
+    ) -> Self {
+        self.release_error = Some(Arc::new(callback));
+        self
+    }
+
+    /// ping the lock session every `interval` while a closure runs on the pool, aborting it with
+    /// [`Error::LockLost`] once the session is gone
+    ///
+    /// a backend like MySQL releases the key with the lost connection, while the closure would keep
+    /// running unaware. only takes effect with [`LockClient::lock_pool`], when the closure doesn't
+    /// run on the lock session.
+    pub fn check_session(mut self, interval: Duration) -> Self {
+        self.session_check = Some(interval);
+        self
+    }
+
+    /// ping the lock session every `interval` while it idles under the lock, so MySQL's
+    /// `wait_timeout` or Postgres' `idle_session_timeout` doesn't drop the connection and silently
+    /// release the key with it
+    ///
+    /// covers the lock sessions nothing else runs on: those of [`LockClient::lock_pool`] under
+    /// [`LockClient::with_locking`], of the jobs, axum, actix and tower integrations. a closure
+    /// handed the lock session keeps it busy itself, and a [`LockGuard`] only while its
+    /// transaction is used. the call fails with [`Error::LockLost`] once a ping fails.
+    pub fn keepalive(mut self, interval: Duration) -> Self {
+        self.keepalive = Some(interval);
+        self
+    }
+
+    /// register lock calls to `registry` instead of [`ShutdownRegistry::global`]
+    pub fn shutdown_registry(mut self, registry: ShutdownRegistry) -> Self {
+        self.shutdown = registry;
+        self
+    }
+
+    /// release the locks of the client after the ones of lower orders when the registry aborts,
+    /// see [`crate::ShutdownPolicy::Abort`]. 0 by default, as for the calls outside of a client
+    ///
+    /// e.g. a client locking whole tenants after the one locking their records, and a client
+    /// flushing buffers last of all, so other waiters never get a parent with a child still held.
+    ///
+    /// ```ignore
+    /// let records = LockClient::<MySqlLocker>::new(pool.clone());
+    /// let tenants = LockClient::<MySqlLocker>::new(pool.clone()).shutdown_order(1);
+    /// let flush = LockClient::<MySqlLocker>::new(pool).shutdown_order(2);
+    /// ```
+    pub fn shutdown_order(mut self, order: u32) -> Self {
+        self.shutdown_order = order;
+        self
+    }
+
+    /// lock `{namespace}:{key}` on the backend for every key
+    ///
+    /// metrics, events and audit records still see the key as it was given.
+    pub fn namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.namespace = Some(namespace.into());
+        self
+    }
+
+    /// normalize every key to `normalization` before it reaches the backend
+    ///
+    /// keys differing only in their Unicode normalization form then take the same lock.
+    #[cfg(feature = "unicode-normalization")]
+    pub fn normalize(mut self, normalization: KeyNormalization) -> Self {
+        self.normalization = Some(normalization);
+        self
+    }
+
+    /// lock the HMAC of every key on the backend, see [`KeyHmac`]
+    ///
+    /// applied after [`LockClient::namespace`], so the namespace is hidden too. metrics, events
+    /// and audit records still see the key as it was given, and [`LockInfo::original_key`] reads
+    /// the listed keys this process hashed back.
+    pub fn hash_keys(mut self, hmac: KeyHmac) -> Self {
+        self.hmac = Some(hmac);
+        self
+    }
+
+    /// shorten keys longer than [`Locker::MAX_KEY_LEN`] as `strategy` says
+    ///
+    /// without it, the backend shortens them its own way.
+    pub fn over_limit(mut self, strategy: OverLimit) -> Self {
+        self.over_limit = Some(strategy);
+        self
+    }
+
+    /// wait up to `timeout` when a call is given None, instead of failing immediately
+    ///
+    /// calls given [`NO_WAIT`] still fail immediately.
+    pub fn default_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// try again as `policy` says when the key could not be acquired
+    pub fn retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    /// policy set by [`LockClient::retry`], no retries by default
+    pub fn retry_policy(&self) -> RetryPolicy {
+        self.retry
+    }
+
+    /// fail with [`Error::QuotaExceeded`] instead of locking when `max` locks of the namespace are
+    /// held or waited for in the process
+    ///
+    /// every client with the same [`LockClient::namespace`], or without one, shares the count.
+    pub fn quota(mut self, max: usize) -> Self {
+        self.quota = Some(max);
+        self
+    }
+
+    /// fail with [`Error::PoolSaturated`] instead of locking when fewer than `min` connections of
+    /// the pool lock sessions are taken from are free
+    ///
+    /// idle connections and the ones the pool may still open count as free. every waiting lock
+    /// holds a connection, so under pressure the calls fail fast instead of leaving the
+    /// application's own queries nothing to run on.
+    ///
+    /// ```ignore
+    /// // keep 5 of the 20 connections for the queries
+    /// let locker = LockClient::<MySqlLocker>::new(pool).min_free_connections(5);
+    /// ```
+    pub fn min_free_connections(mut self, min: u32) -> Self {
+        self.min_free = Some(min);
+        self
+    }
+
+    /// connection pool the closures' transactions are started from
+    pub fn pool(&self) -> &sqlx::Pool<L::DB> {
+        &self.pool
+    }
+
+    // ロックのセッションを取るプール
+    pub(crate) fn lock_session_pool(&self) -> &sqlx::Pool<L::DB> {
+        self.lock_pool.as_ref().unwrap_or(&self.pool)
+    }
+
+    // 専用のロック用プールが無く、クロージャがロックを持つセッションで実行されるか
+    #[cfg(feature = "axum")]
+    pub(crate) fn shares_session(&self) -> bool {
+        self.lock_pool.is_none()
+    }
+
+    /// execute the given closure while the key is locked
+    ///
+    /// * `key` - key to get locked
+    /// * `timeout` - timeout duration. if None is given and a conflict occurs, it will fail immediately,
+    ///   unless [`LockClient::default_timeout`] is set.
+    /// * `f` - closure that executed while the key is locked
+    pub async fn with_locking<T, F>(&self, key: &str, timeout: Option<Duration>, f: F) -> Result<()>
+    where
+        F: LockFn<L::DB, T>,
+    {
+        self.with_locking_hooked(key, timeout, LockHooks::new(), f)
+            .await
+    }
+
+    /// execute the given closure while the key is locked, with the defaults of the client
+    ///
+    /// waits up to [`LockClient::default_timeout`] and retries as [`LockClient::retry`] says, so
+    /// the application code doesn't repeat them at every call. a call needing other options can
+    /// pass its own timeout to [`LockClient::with_locking`], or run on a clone of the client with
+    /// the builder methods applied.
+    ///
+    /// ```ignore
+    /// let locker = MySqlLocker::from_config(&LockConfig::from_env()?).await?;
+    /// locker.with_lock("settlement", async |tx| settle(tx).await).await?;
+    /// ```
+    pub async fn with_lock<T, F>(&self, key: &str, f: F) -> Result<()>
+    where
+        F: LockFn<L::DB, T>,
+    {
+        self.with_locking(key, None, f).await
+    }
+
+    /// [`LockClient::with_locking`] returning how the lock was got and how long it was held
+    ///
+    /// lets callers log and alert on contended or slow locking without instrumenting the client.
+    ///
+    /// ```ignore
+    /// let report = locker.with_locking_reported("key", None, async |_| {}).await?;
+    /// if report.attempts > 1 || report.wait > Duration::from_secs(1) {
+    ///     warn!(?report, "degraded locking");
+    /// }
+    /// ```
+    pub async fn with_locking_reported<T, F>(
+        &self,
+        key: &str,
+        timeout: Option<Duration>,
+        f: F,
+    ) -> Result<LockReport>
+    where
+        F: LockFn<L::DB, T>,
+    {
+        let started = C::now();
+        let mut contended = false;
+        let mut attempts = 1;
+        let mut acquired = None;
+
+        let hooks = LockHooks::new()
+            .on_contention(|_| contended = true)
+            .on_acquired(|attempt| attempts = attempt.attempt);
+        self.with_locking_hooked(key, timeout, hooks, async |tx| {
+            acquired = Some(C::now());
+            f(tx).await
+        })
+        .await?;
+
+        // 成功したならクロージャは実行されている
+        let acquired = acquired.unwrap_or(started);
+        Ok(LockReport {
+            contended,
+            wait: acquired - started,
+            held: C::now().saturating_duration_since(acquired),
+            attempts,
+        })
+    }
+
+    /// [`LockClient::with_locking`] calling `hooks` on the contention, the waits, the acquisition
+    /// and the timeout of this call
+    pub async fn with_locking_hooked<T, F>(
+        &self,
+        key: &str,
+        timeout: Option<Duration>,
+        mut hooks: LockHooks<'_>,
+        f: F,
+    ) -> Result<()>
+    where
+        F: LockFn<L::DB, T>,
+    {
+        let fut = async move {
+            let (mut held, mut lock_tx) = self.lock_hooked(key, timeout, &mut hooks).await?;
+
+            // クロージャ用のトランザクションが取れなくてもロックは必ず解放する
+            let r = match &self.lock_pool {
+                None => held.holding(f(&mut lock_tx)).await.map(|_| ()),
+                Some(_) => held
+                    .holding(async {
+                        let mut tx = self.pool.begin().await?;
+                        self.pinging(key, &mut lock_tx, f(&mut tx))
+                            .await
+                            .map(|_| ())
+                    })
+                    .await
+                    .and_then(|r| r),
+            };
+
+            let released = self.unlock(held, &mut lock_tx).await;
+            // 切れたセッションでは解放も失敗するが、失ったことの方を返す
+            if let Err(Error::LockLost(_)) = r {
+                return r;
+            }
+
+            after_release(released, r)
+        };
+
+        trace::instrument(fut, L::NAME, key).await
+    }
+
+    /// execute the given closure while the key is locked, giving it a token cancelled once it
+    /// should stop
+    ///
+    /// the token is cancelled when the shutdown of the registry starts, when [`Locker::lock_lost`]
+    /// completes, and when the call ends for any reason, e.g. the max hold elapsed, so a long job
+    /// and the tasks it spawned have one way to see that they may no longer hold the key. the
+    /// closure is cut off as by [`LockClient::with_locking`], and otherwise left to return: the
+    /// call fails with [`Error::LockLost`] if the lock was lost meanwhile.
+    ///
+    /// ```ignore
+    /// locker
+    ///     .with_locking_cancellable("reindex", None, async |tx, token| {
+    ///         for batch in batches {
+    ///             if token.is_cancelled() {
+    ///                 break;
+    ///             }
+    ///             reindex(tx, batch).await;
+    ///         }
+    ///     })
+    ///     .await?;
+    /// ```
+    pub async fn with_locking_cancellable<T, F>(
+        &self,
+        key: &str,
+        timeout: Option<Duration>,
+        f: F,
+    ) -> Result<T>
+    where
+        F: AsyncFnOnce(&mut sqlx::Transaction<'static, L::DB>, CancellationToken) -> T,
+    {
+        let backend_key = self.backend_key(key)?;
+        let token = CancellationToken::new();
+        // クロージャが生んだタスクも、呼び出しが終わったら止まれるようにする
+        let _cancel = token.clone().drop_guard();
+        let lost = std::cell::Cell::new(false);
+        let mut out = None;
+
+        let r = self
+            .with_locking_hooked(key, timeout, LockHooks::new(), async |tx| {
+                let mut run = pin!(f(tx, token.clone()));
+                let mut started = pin!(self.shutdown.started());
+                let mut lock_lost = pin!(L::lock_lost(&backend_key));
+                let mut shutting_down = false;
+                let watched = std::future::poll_fn(|cx| {
+                    if let Poll::Ready(out) = run.as_mut().poll(cx) {
+                        return Poll::Ready(out);
+                    }
+                    // 知らせるだけで、クロージャは打ち切らない
+                    if !shutting_down && started.as_mut().poll(cx).is_ready() {
+                        shutting_down = true;
+                        token.cancel();
+                    }
+                    if !lost.get() && lock_lost.as_mut().poll(cx).is_ready() {
+                        lost.set(true);
+                        token.cancel();
+                    }
+                    Poll::Pending
+                });
+                out = Some(watched.await);
+            })
+            .await;
+
+        // 失ったロックの解放は失敗してもよい
+        if lost.get() {
+            return Err(Error::LockLost(key.to_owned()));
+        }
+        r?;
+
+        // NOTE: 成功したならクロージャは実行されている
+        Ok(out.unwrap())
+    }
+
+    /// execute the CPU-bound closure on [`tokio::task::spawn_blocking`] while the key is locked,
+    /// pinging the lock session every `ping` until it returns
+    ///
+    /// the lock is taken and released asynchronously, so the work neither stalls the runtime nor
+    /// lets the connection holding the lock hit an idle timeout. fails with [`Error::LockLost`]
+    /// once a ping fails, and is cut off like [`LockClient::with_locking`]: the closure can't be
+    /// stopped and keeps running on its thread then. a panic of the closure is resumed once the
+    /// lock is released.
+    ///
+    /// ```ignore
+    /// let thumbnails = locker
+    ///     .with_locking_blocking_section("thumbnails:42", None, Duration::from_secs(10), move || {
+    ///         images.iter().map(render_thumbnail).collect::<Vec<_>>()
+    ///     })
+    ///     .await?;
+    /// ```
+    pub async fn with_locking_blocking_section<T, F>(
+        &self,
+        key: &str,
+        timeout: Option<Duration>,
+        ping: Duration,
+        f: F,
+    ) -> Result<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let fut = async move {
+            let (mut held, mut lock_tx) = self.lock(key, timeout).await?;
+
+            let r = held
+                .holding(async {
+                    let mut task = pin!(tokio::task::spawn_blocking(f));
+                    let mut lost = pin!(session_lost::<C, _>(&mut lock_tx, ping));
+                    std::future::poll_fn(|cx| {
+                        if let Poll::Ready(r) = task.as_mut().poll(cx) {
+                            return Poll::Ready(Ok(r));
+                        }
+                        lost.as_mut()
+                            .poll(cx)
+                            .map(|()| Err(Error::LockLost(key.to_owned())))
+                    })
+                    .await
+                })
+                .await
+                .and_then(|r| r);
+
+            let released = self.unlock(held, &mut lock_tx).await;
+            match r {
+                Ok(Ok(out)) => released.map(|()| out),
+                Ok(Err(e)) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+                // 始まる前にランタイムが止まった
+                Ok(Err(_)) => Err(Error::ShuttingDown),
+                // 切れたセッションでは解放も失敗するが、失ったことの方を返す
+                Err(e @ Error::LockLost(_)) => Err(e),
+                Err(e) => after_release(released, Err(e)),
+            }
+        };
+
+        trace::instrument(fut, L::NAME, key).await
+    }
+
+    /// execute the CPU-bound closure while the key is locked like
+    /// [`LockClient::with_locking_blocking_section`], letting it borrow from the caller
+    ///
+    /// the closure runs on a scoped thread and the call returns only once it did, so it may
+    /// update the caller's locals without `Arc<Mutex<_>>`. the worker thread of the call is handed
+    /// over with [`tokio::task::block_in_place`] to ping the lock session meanwhile, which needs a
+    /// multi-threaded runtime: on a current-thread one the closure runs inline, unpinged. losing
+    /// the lock can't cut the closure off, the call fails with [`Error::LockLost`] once it returns.
+    ///
+    /// ```ignore
+    /// let mut totals = HashMap::new();
+    /// locker
+    ///     .with_locking_blocking_scoped("report", None, Duration::from_secs(10), || {
+    ///         for row in &rows {
+    ///             *totals.entry(row.account).or_default() += row.amount;
+    ///         }
+    ///     })
+    ///     .await?;
+    /// ```
+    #[cfg(feature = "blocking")]
+    pub async fn with_locking_blocking_scoped<T, F>(
+        &self,
+        key: &str,
+        timeout: Option<Duration>,
+        ping: Duration,
+        f: F,
+    ) -> Result<T>
+    where
+        F: FnOnce() -> T + Send,
+        T: Send,
+    {
+        use std::panic::{AssertUnwindSafe, catch_unwind};
+        use tokio::runtime::{Handle, RuntimeFlavor};
+
+        let fut = async move {
+            let (mut held, mut lock_tx) = self.lock(key, timeout).await?;
+
+            let r = held
+                .holding(async {
+                    let handle = Handle::current();
+                    if handle.runtime_flavor() == RuntimeFlavor::CurrentThread {
+                        return (false, catch_unwind(AssertUnwindSafe(f)));
+                    }
+
+                    tokio::task::block_in_place(|| {
+                        std::thread::scope(|scope| {
+                            let (done_tx, done) = tokio::sync::oneshot::channel();
+                            let task = scope.spawn(move || {
+                                let out = catch_unwind(AssertUnwindSafe(f));
+                                let _ = done_tx.send(());
+                                out
+                            });
+
+                            let lost = handle.block_on(async {
+                                let mut done = pin!(done);
+                                let mut lost = pin!(session_lost::<C, _>(&mut lock_tx, ping));
+                                std::future::poll_fn(|cx| {
+                                    if done.as_mut().poll(cx).is_ready() {
+                                        return Poll::Ready(false);
+                                    }
+                                    lost.as_mut().poll(cx).map(|()| true)
+                                })
+                                .await
+                            });
+                            // NOTE: panic は中で捕まえているので join は失敗しない
+                            (lost, task.join().unwrap())
+                        })
+                    })
+                })
+                .await;
+
+            let released = self.unlock(held, &mut lock_tx).await;
+            match r {
+                Ok((_, Err(panic))) => std::panic::resume_unwind(panic),
+                // 切れたセッションでは解放も失敗するが、失ったことの方を返す
+                Ok((true, Ok(_))) => Err(Error::LockLost(key.to_owned())),
+                Ok((false, Ok(out))) => released.map(|()| out),
+                Err(e) => after_release(released, Err(e)),
+            }
+        };
+
+        trace::instrument(fut, L::NAME, key).await
+    }
+
+    /// lock the key until the returned guard is released
+    ///
+    /// * `timeout` - timeout duration, also used by [`LockGuard::yield_for`] to acquire it again
+    pub async fn guard<'c>(
+        &'c self,
+        key: &'c str,
+        timeout: Option<Duration>,
+    ) -> Result<LockGuard<'c, L, C>>
+    where
+        L: 'static,
+    {
+        LockGuard::acquire(self, key, timeout).await
+    }
+
+    /// acquire the key like [`LockClient::guard`], yielding the states of the acquisition
+    ///
+    /// for UIs and orchestrators showing where a call stands in line, see [`AcquireStream`].
+    pub fn acquire_stream<'c>(
+        &'c self,
+        key: &'c str,
+        timeout: Option<Duration>,
+    ) -> AcquireStream<'c, L, C>
+    where
+        L: 'static,
+    {
+        AcquireStream::new(self, key, timeout)
+    }
+
+    /// run `f` on each of `chunks` while the key is locked, releasing and acquiring it again after
+    /// every `every` chunks
+    ///
+    /// the waiters of the key get their turn between the chunks of a long batch. each chunk runs
+    /// on a transaction of its own from the pool of the client, committed once `f` returns Ok, so
+    /// the waiters see the chunks done before them. stops at the first chunk failing, which is
+    /// rolled back, and fails if the key can't be acquired again within `timeout`, with the chunks
+    /// until then committed.
+    ///
+    /// ```ignore
+    /// locker
+    ///     .with_locking_chunks("reindex", Duration::from_secs(5).into(), 10, batches, async |tx, batch| {
+    ///         reindex(tx, batch).await?;
+    ///         Ok(())
+    ///     })
+    ///     .await?;
+    /// ```
+    pub async fn with_locking_chunks<K, F>(
+        &self,
+        key: &str,
+        timeout: Option<Duration>,
+        every: usize,
+        chunks: impl IntoIterator<Item = K>,
+        mut f: F,
+    ) -> Result<()>
+    where
+        L: 'static,
+        F: AsyncFnMut(&mut sqlx::Transaction<'static, L::DB>, K) -> Result<()>,
+    {
+        let mut chunks = chunks.into_iter().peekable();
+        let mut guard = self.guard(key, timeout).await?;
+
+        let mut done = 0;
+        while let Some(chunk) = chunks.next() {
+            // ロックのトランザクションは解放で巻き戻されるので、塊ごとに別のトランザクションで確定させる
+            let ran = match self.pool.begin().await {
+                Ok(mut tx) => match f(&mut tx, chunk).await {
+                    Ok(()) => tx.commit().await.map_err(Error::from),
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e.into()),
+            };
+            if ran.is_err() {
+                return after_release(guard.release().await, ran);
+            }
+            done += 1;
+
+            // 最後の塊の後は取り直さずに解放する
+            if done % every.max(1) == 0 && chunks.peek().is_some() {
+                guard = guard.yield_for(Duration::ZERO).await?;
+            }
+        }
+
+        guard.release().await
+    }
+
+    /// guards released by group, see [`LockGroups`]
+    pub fn groups(&self) -> LockGroups<'_, L, C>
+    where
+        L: 'static,
+    {
+        LockGroups::new(self)
+    }
+
+    /// run `fut` while the key is locked, without a transaction
+    // NOTE: AsyncFnOnce を受け取る with_locking の Future は、今のコンパイラでは Send の判定が
+    //       "not general enough" で通らないので、Send が要る統合はこちらを使う
+    pub(crate) async fn run_locked<Fut: Future>(
+        &self,
+        key: &str,
+        timeout: Option<Duration>,
+        fut: Fut,
+    ) -> Result<Fut::Output> {
+        let locked = async move {
+            let (mut held, mut lock_tx) = self.lock(key, timeout).await?;
+            let out = held
+                .holding(self.pinging(key, &mut lock_tx, fut))
+                .await
+                .and_then(|r| r);
+            let released = self.unlock(held, &mut lock_tx).await;
+            // 切れたセッションでは解放も失敗するが、失ったことの方を返す
+            if let Err(Error::LockLost(_)) = out {
+                return out;
+            }
+            after_release(released, out)
+        };
+
+        trace::instrument(locked, L::NAME, key).await
+    }
+
+    // 遊んでいるロックのセッションに、fut の間 keepalive と check_session の短い方の間隔で ping
+    // を送る。失敗したらロックを失ったことにする
+    pub(crate) async fn pinging<F: Future>(
+        &self,
+        key: &str,
+        lock_tx: &mut sqlx::Transaction<'static, L::DB>,
+        fut: F,
+    ) -> Result<F::Output> {
+        let Some(interval) = self.keepalive.into_iter().chain(self.session_check).min() else {
+            return Ok(fut.await);
+        };
+
+        let mut run = pin!(fut);
+        let mut lost = pin!(session_lost::<C, _>(lock_tx, interval));
+        std::future::poll_fn(|cx| {
+            if let Poll::Ready(out) = run.as_mut().poll(cx) {
+                return Poll::Ready(Ok(out));
+            }
+            lost.as_mut()
+                .poll(cx)
+                .map(|()| Err(Error::LockLost(key.to_owned())))
+        })
+        .await
+    }
+
+    // ロックを取って、それを持っているセッションのトランザクションと一緒に返す
+    pub(crate) async fn lock<'a>(
+        &'a self,
+        key: &'a str,
+        timeout: Option<Duration>,
+    ) -> Result<(Held<'a, L::DB, C>, sqlx::Transaction<'static, L::DB>)> {
+        self.lock_hooked(key, timeout, &mut LockHooks::new()).await
+    }
+
+    pub(crate) async fn lock_hooked<'a>(
+        &'a self,
+        key: &'a str,
+        timeout: Option<Duration>,
+        hooks: &mut LockHooks<'_>,
+    ) -> Result<(Held<'a, L::DB, C>, sqlx::Transaction<'static, L::DB>)> {
+        let quota = self
+            .quota
+            .map(|max| QuotaSlot::take(self.namespace.as_deref(), max))
+            .transpose()?;
+        let lock_pool = self.lock_session_pool();
+        if let Some(min) = self.min_free {
+            let free = free_connections(lock_pool);
+            if free < min {
+                return Err(Error::PoolSaturated {
+                    key: key.to_owned(),
+                    free,
+                    required: min,
+                });
+            }
+        }
+        let mut section =
+            Section::start::<L, C>(self.shutdown.register_ordered(self.shutdown_order)?, key);
+        let mut lock_tx = lock_pool.begin().await?;
+        for sql in &self.before_lock_sql {
+            sql(&mut lock_tx).await?;
+        }
+
+        let r = section
+            .registration
+            .acquiring(self.acquire(lock_pool, &mut lock_tx, key, timeout, hooks))
+            .await;
+        let (r, wait) = section.acquired(lock_pool, &mut lock_tx, r).await;
+        let Section {
+            registration,
+            mut lifecycle,
+            hold_until,
+        } = section;
+        self.slow.check_wait(key, wait);
+        let contended = match r {
+            Ok(contended) => contended,
+            Err(e) => {
+                let outcome = match e {
+                    Error::FailedToGetLock(_) | Error::RetriesExhausted { .. } => {
+                        AuditOutcome::TimedOut
+                    }
+                    _ => AuditOutcome::Failed,
+                };
+                // 監査ログが書けなくても取得に失敗した理由の方を返す
+                let _ = self.record_audit(key, outcome, wait).await;
+                return Err(self.describe(lock_pool, key, e).await);
+            }
+        };
+
+        // 取得してから数える。Locker::MAX_HOLD の方が先に来ればそちら
+        let hold_until = self
+            .max_hold
+            .map(|max| C::now() + max)
+            .into_iter()
+            .chain(hold_until)
+            .min();
+
+        // 取得できたキーは変換にも成功している
+        let backend_key = self.backend_key(key)?;
+        let recorded = async {
+            if let Some(owner) = &self.owner {
+                L::set_owner(lock_pool, &mut lock_tx, &backend_key, owner).await?;
+            }
+            for sql in &self.after_lock_sql {
+                sql(&mut lock_tx).await?;
+            }
+            self.record_audit(key, AuditOutcome::Acquired, wait).await
+        };
+        if let Err(e) = recorded.await {
+            let released = L::release(lock_pool, &mut lock_tx, &backend_key).await;
+            lifecycle.release(&released);
+            report_release_error(self.release_error.as_ref(), key, &released);
+            return Err(e);
+        }
+
+        Ok((
+            Held {
+                key,
+                lifecycle,
+                lock_pool,
+                registration,
+                hold_until,
+                contended,
+                _quota: quota,
+                clock: PhantomData,
+            },
+            lock_tx,
+        ))
+    }
+
+    pub(crate) async fn unlock(
+        &self,
+        mut held: Held<'_, L::DB, C>,
+        lock_tx: &mut sqlx::Transaction<'static, L::DB>,
+    ) -> Result<()> {
+        let key = held.key;
+
+        let released = match self.backend_key(key) {
+            Ok(backend_key) => L::release(held.lock_pool, lock_tx, &backend_key).await,
+            Err(e) => Err(e),
+        };
+        let hold = held.lifecycle.release(&released);
+        report_release_error(self.release_error.as_ref(), key, &released);
+        released?;
+        self.metrics.on_released(key, hold);
+        self.slow.check_hold(key, hold);
+        self.record_audit(key, AuditOutcome::Released, hold).await
+    }
+
+    // L::release を流さずに、持っていた記録だけを閉じる。リースを別のプロセスに引き継ぐとき
+    #[cfg(all(
+        any(feature = "grpc", feature = "http"),
+        any(
+            feature = "sqlx-mysql",
+            feature = "sqlx-postgres",
+            feature = "sqlx-std-collection"
+        )
+    ))]
+    pub(crate) fn hand_over(&self, mut held: Held<'_, L::DB, C>) {
+        let hold = held.lifecycle.release(&Ok(()));
+        self.metrics.on_released(held.key, hold);
+    }
+
+    // 解放されずに捨てられたロックを、別のタスクで並べた順に1つずつ解放する。tokio のランタイムの
+    // 外では async-io で別のスレッドから解放する。どちらも無ければセッションを閉じる
+    pub(crate) fn unlock_detached<'h>(
+        &self,
+        locked: impl IntoIterator<Item = (Held<'h, L::DB, C>, sqlx::Transaction<'static, L::DB>)>,
+    ) where
+        L: 'static,
+    {
+        let mut releases = Vec::new();
+        for (held, lock_tx) in locked {
+            held.lifecycle.dropped();
+            let detached = Detached {
+                lock_pool: held.lock_pool.clone(),
+                lock_tx: Some(lock_tx),
+            };
+            // 変換できないキーは解放の文を流せないので、ここで捨ててセッションを閉じる
+            if let Ok(backend_key) = self.backend_key(held.key) {
+                releases.push((held.key.to_owned(), backend_key.into_owned(), detached));
+            }
+        }
+        if releases.is_empty() {
+            return;
+        }
+
+        let callback = self.release_error.clone();
+        let release = async move {
+            for (key, backend_key, mut detached) in releases {
+                let Detached { lock_pool, lock_tx } = &mut detached;
+                let mut lock_tx = lock_tx.take().unwrap();
+                let released = L::release(lock_pool, &mut lock_tx, &backend_key).await;
+                report_release_error(callback.as_ref(), &key, &released);
+            }
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn(release)),
+            #[cfg(feature = "async-io")]
+            Err(_) => drop(std::thread::spawn(|| async_io::block_on(release))),
+            #[cfg(not(feature = "async-io"))]
+            Err(_) => drop(release),
+        }
+    }
+
+    // 誰が持っているか分かれば Error::HeldBy にする。調べられなければ元のエラーのまま
+    async fn describe(&self, pool: &sqlx::Pool<L::DB>, key: &str, e: Error) -> Error {
+        let (Some(lookup), Error::FailedToGetLock(_) | Error::RetriesExhausted { .. }) =
+            (self.holder, &e)
+        else {
+            return e;
+        };
+        let Ok(backend_key) = self.backend_key(key) else {
+            return e;
+        };
+        let Ok(Some(LockInfo {
+            holder: Some(holder),
+            owner,
+            held_since,
+            ..
+        })) = lookup(pool, &backend_key).await
+        else {
+            return e;
+        };
+
+        match e {
+            // 何回試したかは残す
+            Error::RetriesExhausted {
+                key,
+                attempts,
+                elapsed,
+                ..
+            } => Error::RetriesExhausted {
+                key,
+                attempts,
+                elapsed,
+                holder: Some(holder),
+            },
+            _ => Error::HeldBy {
+                key: key.to_owned(),
+                holder,
+                owner,
+                since: held_since,
+            },
+        }
+    }
+
+    async fn record_audit(
+        &self,
+        key: &str,
+        outcome: AuditOutcome,
+        duration: Duration,
+    ) -> Result<()> {
+        match &self.audit {
+            Some(audit) => audit.append(&self.pool, key, outcome, duration).await,
+            None => Ok(()),
+        }
+    }
+
+    // 正規化して名前空間が付いた、バックエンドに渡すキー
+    pub(crate) fn backend_key<'k>(&self, key: &'k str) -> Result<Cow<'k, str>> {
+        let key = self.scoped_key(key);
+
+        match (self.over_limit, L::MAX_KEY_LEN) {
+            (Some(strategy), Some(max)) if key.len() > max => strategy
+                .apply(&key, max)
+                .map(|shortened| Cow::Owned(shortened.into_owned())),
+            _ => Ok(key),
+        }
+    }
+
+    // 長すぎるキーを縮める前の backend_key
+    pub(crate) fn scoped_key<'k>(&self, key: &'k str) -> Cow<'k, str> {
+        #[cfg(feature = "unicode-normalization")]
+        let key = match &self.normalization {
+            Some(normalization) => normalization.normalize(key),
+            None => Cow::Borrowed(key),
+        };
+        #[cfg(not(feature = "unicode-normalization"))]
+        let key = Cow::Borrowed(key);
+
+        let key = match &self.namespace {
+            Some(namespace) => Cow::Owned(format!("{namespace}:{key}")),
+            None => key,
+        };
+
+        match &self.hmac {
+            Some(hmac) => Cow::Owned(hmac.apply(&key)),
+            None => key,
+        }
+    }
+
+    async fn acquire(
+        &self,
+        pool: &sqlx::Pool<L::DB>,
+        tx: &mut sqlx::Transaction<'static, L::DB>,
+        key: &str,
+        timeout: Option<Duration>,
+        hooks: &mut LockHooks<'_>,
+    ) -> Result<bool> {
+        let started = C::now();
+        let elapsed = || C::now().saturating_duration_since(started);
+        let backend_key = self.backend_key(key)?;
+        // NO_WAIT は既定の待ち時間があっても待たない
+        let wait = timeout
+            .or(self.retry.attempt_timeout)
+            .or(self.timeout)
+            .filter(|wait| !wait.is_zero());
+        let deadline = self.retry.deadline.map(|deadline| started + deadline);
+        let mut retries = self.retry.attempts;
+        let mut attempt = 1;
+        let report = |attempt| LockAttempt {
+            key,
+            elapsed: elapsed(),
+            attempt,
+        };
+
+        let mut aborted = false;
+        let mut contended = false;
+        let r = loop {
+            // 最後の試行は期限までしか待たない。0 は「無期限」の意味になる DB があるので 1ms は待つ
+            let timeout = match deadline {
+                Some(deadline) => wait.map(|wait| {
+                    wait.min(deadline.saturating_duration_since(C::now()))
+                        .max(Duration::from_millis(1))
+                }),
+                None => wait,
+            };
+
+            // 競合したかどうかを知るために、まず待たずに取得を試みる。待つ取得の往復が増えるのは
+            // 他のセッションが持っていたときだけ
+            let r = match L::acquire(pool, tx, &backend_key, None).await {
+                Err(Error::FailedToGetLock(k)) => {
+                    contended = true;
+                    self.metrics.on_contended(key);
+                    hooks.contended(&report(attempt));
+                    match timeout {
+                        Some(_) => {
+                            let waited = L::acquire(pool, tx, &backend_key, timeout);
+                            match hooks.waiting::<C, _>(key, started, attempt, waited).await {
+                                Some(r) => r,
+                                None => {
+                                    aborted = true;
+                                    // 打ち切った待ちの文は DB で走り続けていて、あとでロックが取れてしまう
+                                    section::close_session(pool, tx).await;
+                                    Err(Error::FailedToGetLock(key.to_owned()))
+                                }
+                            }
+                        }
+                        None => Err(Error::FailedToGetLock(k)),
+                    }
+                }
+                r => r,
+            };
+            // 名前空間やハッシュの付いたバックエンドでのキーではなく、渡されたキーで返す
+            let r = r.map_err(|e| match e {
+                Error::FailedToGetLock(_) => Error::FailedToGetLock(key.to_owned()),
+                e => e,
+            });
+
+            match r {
+                Err(Error::FailedToGetLock(_))
+                    if retries > 0
+                        && !aborted
+                        && deadline
+                            .is_none_or(|deadline| C::now() + self.retry.backoff < deadline) =>
+                {
+                    retries -= 1;
+                    attempt += 1;
+                    C::sleep(self.retry.backoff).await;
+                }
+                r => break r,
+            }
+        };
+
+        match &r {
+            Ok(()) => {
+                self.metrics.on_acquired(key, elapsed());
+                hooks.acquired(&report(attempt));
+            }
+            Err(Error::FailedToGetLock(_)) => {
+                self.metrics.on_timeout(key, elapsed());
+                hooks.timed_out(&report(attempt));
+
+                // on_waiting で打ち切ったときは再試行を使い切っていない
+                if self.retry.attempts > 0 && !aborted {
+                    return Err(Error::RetriesExhausted {
+                        key: key.to_owned(),
+                        attempts: attempt,
+                        elapsed: elapsed(),
+                        holder: None,
+                    });
+                }
+            }
+            Err(_) => {}
+        }
+
+        r.map(|()| contended)
+    }
+}
+
+// 捨てられたガードのロックを持つセッション。解放の文を流す前に捨てられたら(終わりかけの
+// ランタイムに捨てられたタスクなど)、ロールバックだけではセッションのロックが残ったままプールに
+// 戻るので、セッションを閉じる
+struct Detached<DB: sqlx::Database> {
+    lock_pool: sqlx::Pool<DB>,
+    lock_tx: Option<sqlx::Transaction<'static, DB>>,
+}
+
+impl<DB: sqlx::Database> Drop for Detached<DB> {
+    fn drop(&mut self) {
+        if let Some(lock_tx) = &mut self.lock_tx {
+            section::discard_session(&self.lock_pool, lock_tx);
+        }
+    }
+}
+
+// ロックを持つセッションが切れたら完了する
+async fn session_lost<C: Clock, DB: sqlx::Database>(
+    lock_tx: &mut sqlx::Transaction<'static, DB>,
+    interval: Duration,
+) {
+    loop {
+        C::sleep(interval).await;
+        if lock_tx.ping().await.is_err() {
+            return;
+        }
+    }
+}
+
+// 遊んでいる接続と、まだ開ける分
+fn free_connections<DB: sqlx::Database>(pool: &sqlx::Pool<DB>) -> u32 {
+    let unopened = pool
+        .options()
+        .get_max_connections()
+        .saturating_sub(pool.size());
+    pool.num_idle() as u32 + unopened
+}
+
+fn report_release_error(callback: Option<&ReleaseErrorFn>, key: &str, released: &Result<()>) {
+    if let (Some(callback), Err(e)) = (callback, released) {
+        callback(key, e);
+    }
+}
+
+#[cfg(all(test, feature = "sqlx-std-collection"))]
+mod tests {
+    use pretty_assertions::{assert_eq, assert_matches};
+    use std::sync::Mutex;
+    use tokio::time::sleep;
+
+    use super::*;
+
+    use crate::{ShutdownPolicy, StdCollectionLocker};
+    use sqlx::{Sqlite, SqlitePool};
+
+    // 止めた時計は接続を待つ間にも進んで取得が時間切れになるので、接続を先に開いておき、進めた
+    // 時計で閉じられないようにしたプール
+    async fn paused_pool(pool: &SqlitePool) -> sqlx::Result<SqlitePool> {
+        let pool = sqlx::pool::PoolOptions::<Sqlite>::new()
+            .test_before_acquire(false)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect_with((*pool.connect_options()).clone())
+            .await?;
+        let mut opened = Vec::new();
+        for _ in 0..4 {
+            opened.push(pool.acquire().await?);
+        }
+        // 接続はあとからプールに戻る
+        drop(opened);
+        while pool.num_idle() < 4 {
+            sleep(Duration::from_millis(1)).await;
+        }
+        Ok(pool)
+    }
+
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<&'static str>>>);
+
+    impl LockMetrics for Recorder {
+        fn on_acquired(&self, _key: &str, _wait: Duration) {
+            self.0.lock().unwrap().push("acquired");
+        }
+
+        fn on_contended(&self, _key: &str) {
+            self.0.lock().unwrap().push("contended");
+        }
+
+        fn on_timeout(&self, _key: &str, _wait: Duration) {
+            self.0.lock().unwrap().push("timeout");
+        }
+
+        fn on_released(&self, _key: &str, _hold: Duration) {
+            self.0.lock().unwrap().push("released");
+        }
+    }
+
+    #[sqlx::test]
+    async fn metrics_see_contention(pool: SqlitePool) -> sqlx::Result<()> {
+        let recorder = Recorder::default();
+        let locker = LockClient::<StdCollectionLocker<Sqlite>>::new(pool).metrics(recorder.clone());
+
+        let (r1, r2) = tokio::join!(
+            locker.with_locking(
+                "mV2qkLp8RxTz4NwYs7GdHc1JbUf5Ea9Ko3Iu6Ml0Pn2Qr8St4Vw6Xy1Za3Bc5De7",
+                Duration::from_secs(2).into(),
+                async |_| {
+                    sleep(Duration::from_millis(500)).await;
+                },
+            ),
+            // 先の呼び出しが取得してから待ち始める
+            async {
+                sleep(Duration::from_millis(100)).await;
+                locker
+                    .with_locking(
+                        "mV2qkLp8RxTz4NwYs7GdHc1JbUf5Ea9Ko3Iu6Ml0Pn2Qr8St4Vw6Xy1Za3Bc5De7",
+                        Duration::from_secs(2).into(),
+                        async |_| {},
+                    )
+                    .await
+            }
+        );
+
+        assert_matches!(r1, Ok(()));
+        assert_matches!(r2, Ok(()));
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec!["acquired", "contended", "released", "acquired", "released"]
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn metrics_see_timeout(pool: SqlitePool) -> sqlx::Result<()> {
+        let recorder = Recorder::default();
+        let locker = LockClient::<StdCollectionLocker<Sqlite>>::new(pool).metrics(recorder.clone());
+
+        let (r1, r2) = tokio::join!(
+            locker.with_locking(
+                "Fg4Hj6Kl8Zx0Cv2Bn4Mq6Wr8Et0Yu2Io4Pa6Sd8Fg0Hj2Kl4Zx6Cv8Bn0Mq2Wr4",
+                Duration::from_secs(1).into(),
+                async |_| {
+                    sleep(Duration::from_secs(2)).await;
+                },
+            ),
+            // 先の呼び出しが取得してから待ち始める
+            async {
+                sleep(Duration::from_millis(100)).await;
+                locker
+                    .with_locking(
+                        "Fg4Hj6Kl8Zx0Cv2Bn4Mq6Wr8Et0Yu2Io4Pa6Sd8Fg0Hj2Kl4Zx6Cv8Bn0Mq2Wr4",
+                        Duration::from_secs(1).into(),
+                        async |_| {},
+                    )
+                    .await
+            }
+        );
+
+        assert_matches!(r1, Ok(()));
+        assert_matches!(r2, Err(Error::FailedToGetLock(_)));
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec!["acquired", "contended", "timeout", "released"]
+        );
+
+        Ok(())
+    }
+
+    #[cfg(feature = "sqlx-sqlite")]
+    #[sqlx::test]
+    async fn audit_records_acquire_and_release(pool: SqlitePool) -> sqlx::Result<()> {
+        use crate::AuditDatabase;
+
+        sqlx::raw_sql(Sqlite::AUDIT_TABLE).execute(&pool).await?;
+        let locker = LockClient::<StdCollectionLocker<Sqlite>>::new(pool.clone()).audit("worker-1");
+
+        let (r1, r2) = tokio::join!(
+            locker.with_locking(
+                "Ab3De5Gh7Jk9Mn1Pq3St5Vw7Yz9Bc1Ef3Hi5Kl7No9Qr1Tu3Wx5Za7Cd9Fg1Ij3L",
+                Duration::from_secs(1).into(),
+                async |_| {
+                    sleep(Duration::from_millis(500)).await;
+                },
+            ),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                locker
+                    .with_locking(
+                        "Ab3De5Gh7Jk9Mn1Pq3St5Vw7Yz9Bc1Ef3Hi5Kl7No9Qr1Tu3Wx5Za7Cd9Fg1Ij3L",
+                        None,
+                        async |_| {},
+                    )
+                    .await
+            }
+        );
+
+        assert_matches!(r1, Ok(()));
+        assert_matches!(r2, Err(Error::FailedToGetLock(_)));
+
+        let rows: Vec<(String, String)> =
+            sqlx::query_as("SELECT owner, outcome FROM lock_audit WHERE lock_key = ? ORDER BY id")
+                .bind("Ab3De5Gh7Jk9Mn1Pq3St5Vw7Yz9Bc1Ef3Hi5Kl7No9Qr1Tu3Wx5Za7Cd9Fg1Ij3L")
+                .fetch_all(&pool)
+                .await?;
+        assert_eq!(
+            rows,
+            vec![
+                ("worker-1".to_owned(), "acquired".to_owned()),
+                ("worker-1".to_owned(), "timeout".to_owned()),
+                ("worker-1".to_owned(), "released".to_owned()),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn slow_waits_and_holds_are_reported(pool: SqlitePool) -> sqlx::Result<()> {
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let locker = LockClient::<StdCollectionLocker<Sqlite>>::new(pool)
+            .warn_wait_over(Duration::from_millis(200))
+            .warn_hold_over(Duration::from_millis(300))
+            .on_slow({
+                let reported = Arc::clone(&reported);
+                move |slow| {
+                    reported.lock().unwrap().push(match slow {
+                        SlowLock::Wait { .. } => "wait",
+                        SlowLock::Hold { .. } => "hold",
+                    })
+                }
+            });
+
+        let (r1, r2) = tokio::join!(
+            locker.with_locking(
+                "Tg5Yh7Uj9Ik1Ol3Pz5Xc7Vb9Nm1Qa3Ws5Ed7Rf9Tg1Yh3Uj5Ik7Ol9Pz1Xc3Vb5N",
+                Duration::from_secs(2).into(),
+                async |_| {
+                    sleep(Duration::from_millis(500)).await;
+                },
+            ),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                locker
+                    .with_locking(
+                        "Tg5Yh7Uj9Ik1Ol3Pz5Xc7Vb9Nm1Qa3Ws5Ed7Rf9Tg1Yh3Uj5Ik7Ol9Pz1Xc3Vb5N",
+                        Duration::from_secs(2).into(),
+                        async |_| {},
+                    )
+                    .await
+            }
+        );
+
+        assert_matches!(r1, Ok(()));
+        assert_matches!(r2, Ok(()));
+        assert_eq!(*reported.lock().unwrap(), vec!["hold", "wait"]);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn quota_limits_the_locks_of_a_namespace(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Qx4Wc7Ev0Rb3Tn6Ym9Uq2Iw5Oe8Pr1At4Sy7Du0Fi3Go6Hp9Ja2Ks5Ld8Zf1Xg4C";
+        let tenant = LockClient::<StdCollectionLocker<Sqlite>>::new(pool.clone())
+            .namespace(format!("{key}:tenant"))
+            .quota(2);
+        let other = LockClient::<StdCollectionLocker<Sqlite>>::new(pool)
+            .namespace(format!("{key}:other"))
+            .quota(2);
+
+        // 待っているロックも枠を使う
+        let (r1, r2, r3, r4) = tokio::join!(
+            tenant.with_locking("a", None, async |_| {
+                sleep(Duration::from_millis(300)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(50)).await;
+                tenant
+                    .with_locking("a", Duration::from_secs(1).into(), async |_| {})
+                    .await
+            },
+            async {
+                sleep(Duration::from_millis(100)).await;
+                tenant.with_locking("b", None, async |_| {}).await
+            },
+            async {
+                sleep(Duration::from_millis(100)).await;
+                other.with_locking("b", None, async |_| {}).await
+            }
+        );
+
+        assert_matches!(r1, Ok(()));
+        assert_matches!(r2, Ok(()));
+        assert_matches!(r3, Err(Error::QuotaExceeded(_)));
+        assert_matches!(r4, Ok(()));
+        assert_matches!(tenant.with_locking("b", None, async |_| {}).await, Ok(()));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn saturated_pool_fails_fast(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Ps6Qt8Ru0Sv2Tw4Ux6Vy8Wz0Xa2Yb4Zc6Ad8Be0Cf2Dg4Eh6Fi8Gj0Hk2Il4Jm6N";
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(2)
+            .connect_with((*pool.connect_options()).clone())
+            .await?;
+        let locker =
+            LockClient::<StdCollectionLocker<Sqlite>>::new(pool.clone()).min_free_connections(2);
+
+        // 開いていない分も空きに数える
+        let guard = locker.guard(key, None).await.unwrap();
+
+        // 1 つ使われている間は待たずに失敗する
+        let other = format!("{key}:other");
+        assert_matches!(
+            locker
+                .with_locking(&other, Duration::from_secs(1).into(), async |_| {})
+                .await,
+            Err(Error::PoolSaturated {
+                free: 1,
+                required: 2,
+                ..
+            })
+        );
+
+        assert_matches!(guard.release().await, Ok(()));
+        // 接続がプールに戻るのを待つ
+        sleep(Duration::from_millis(100)).await;
+        assert_matches!(
+            locker.with_locking(&other, None, async |_| {}).await,
+            Ok(())
+        );
+
+        Ok(())
+    }
+
+    #[cfg(feature = "unicode-normalization")]
+    #[sqlx::test]
+    async fn normalized_keys_take_the_same_lock(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Tb8Nm1Qv4Wc7Er0Ty3Ui6Op9As2Df5Gh8Jk1Lz4Xc7Vb0Nm3Qw6Er9Ty2Ui5Op8A";
+        let client =
+            LockClient::<StdCollectionLocker<Sqlite>>::new(pool).normalize(KeyNormalization::Nfc);
+        // 合成済みの "é" と、"e" + 結合アクセント
+        let composed = format!("{key}:caf\u{e9}");
+        let decomposed = format!("{key}:cafe\u{301}");
+
+        let (r1, r2) = tokio::join!(
+            client.with_locking(&composed, None, async |_| {
+                sleep(Duration::from_millis(300)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                client.with_locking(&decomposed, None, async |_| {}).await
+            }
+        );
+
+        assert_matches!(r1, Ok(()));
+        assert_matches!(r2, Err(Error::FailedToGetLock(_)));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn failure_names_the_holder(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Hd3Lf6Nh9Pj2Rl5Tn8Vp1Xr4Zt7Bv0Dx3Fz6Hb9Jd2Lf5Nh8Pj1Rl4Tn7Vp0Xr3Z";
+        let client = LockClient::<StdCollectionLocker<Sqlite>>::new(pool).describe_holder();
+
+        let (r1, r2) = tokio::join!(
+            client.with_locking(key, None, async |_| {
+                sleep(Duration::from_millis(300)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                client.with_locking(key, None, async |_| {}).await
+            }
+        );
+
+        assert_matches!(r1, Ok(()));
+        let e = r2.unwrap_err();
+        assert_matches!(e, Error::HeldBy { since: Some(_), .. });
+        assert!(e.to_string().contains(" UTC"));
+        assert_matches!(client.with_locking(key, None, async |_| {}).await, Ok(()));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn exhausted_retries_name_the_holder(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Xr5Hn7Ld9Rt1Ex3Hs6Ta8Ud0Vh2Wi4Xj6Yk8Zl0Am2Bn4Co6Dp8Eq0Fr2Gs4Ht6I";
+        let client = LockClient::<StdCollectionLocker<Sqlite>>::new(pool)
+            .describe_holder()
+            .retry(RetryPolicy {
+                attempts: 1,
+                backoff: Duration::from_millis(10),
+                ..RetryPolicy::default()
+            });
+
+        let (r1, r2) = tokio::join!(
+            client.with_locking(key, None, async |_| {
+                sleep(Duration::from_millis(300)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                client.with_locking(key, None, async |_| {}).await
+            }
+        );
+
+        assert_matches!(r1, Ok(()));
+        let e = r2.unwrap_err();
+        assert_matches!(
+            e,
+            Error::RetriesExhausted {
+                attempts: 2,
+                holder: Some(_),
+                ..
+            }
+        );
+        assert!(e.to_string().contains(", held by "));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn owner_is_listed_and_named(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Ow4Nr6Lb8Ke0Lh2Mi4Nj6Ok8Pl0Qm2Rn4So6Tp8Uq0Vr2Ws4Xt6Yu8Zv0Aw2Bx4C";
+        type Collection = StdCollectionLocker<Sqlite>;
+        let client = LockClient::<Collection>::new(pool.clone()).describe_holder();
+        let labeled = client.clone().owner("worker-1 pid 42");
+
+        let (listed, r) = tokio::join!(
+            labeled.run_locked(key, None, async {
+                sleep(Duration::from_millis(300)).await;
+                Collection::list_locks(&pool).await.unwrap()
+            }),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                client.with_locking(key, None, async |_| {}).await
+            }
+        );
+
+        let listed = listed.unwrap();
+        let lock = listed.iter().find(|lock| lock.key == key).unwrap();
+        assert_eq!(lock.owner.as_deref(), Some("worker-1 pid 42"));
+        let e = r.unwrap_err();
+        assert_matches!(&e, Error::HeldBy { owner: Some(owner), .. } if owner == "worker-1 pid 42");
+        assert!(e.to_string().contains(" (worker-1 pid 42) since "));
+
+        // 解放するとラベルも外れる
+        let listed = client
+            .run_locked(key, None, Collection::list_locks(&pool))
+            .await
+            .unwrap()
+            .unwrap();
+        let lock = listed.iter().find(|lock| lock.key == key).unwrap();
+        assert_eq!(lock.owner, None);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn backend_sees_only_the_hash(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Hm7In9Jo1Kp3Lq5Mr7Ns9Ot1Pu3Qv5Rw7Sx9Ty1Uz3Va5Wb7Xc9Yd1Ze3Af5Bg7C";
+        type Collection = StdCollectionLocker<Sqlite>;
+        let client = LockClient::<Collection>::new(pool.clone());
+        let hashed = client.clone().hash_keys(KeyHmac::new("secret"));
+
+        let (listed, same, plain) = tokio::join!(
+            hashed.run_locked(key, None, async {
+                sleep(Duration::from_millis(200)).await;
+                Collection::list_locks(&pool).await.unwrap()
+            }),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                // 同じ秘密なら同じロック
+                let same = hashed.clone();
+                same.with_locking(key, None, async |_| {}).await
+            },
+            async {
+                sleep(Duration::from_millis(100)).await;
+                client.with_locking(key, None, async |_| {}).await
+            }
+        );
+
+        let listed = listed.unwrap();
+        assert!(listed.iter().all(|lock| lock.key != key));
+        let lock = listed
+            .iter()
+            .find(|lock| lock.original_key().as_deref() == Some(key))
+            .unwrap();
+        assert_eq!(lock.key, KeyHmac::new("secret").apply(key));
+        assert_matches!(same, Err(Error::FailedToGetLock(k)) if k == key);
+        assert_matches!(plain, Ok(()));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn hooks_see_the_attempts_of_the_call(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Rc5Tv8Yb1Un4Im7Ko0Lp3Aq6Sw9De2Fr5Gt8Hy1Ju4Ki7Lo0Pz3Xa6Sc9Dv2Fb5G";
+        let client = LockClient::<StdCollectionLocker<Sqlite>>::new(pool).retry(RetryPolicy {
+            attempts: 2,
+            backoff: Duration::from_millis(10),
+            ..RetryPolicy::default()
+        });
+        let contended = Mutex::new(Vec::new());
+        let mut timed_out = None;
+
+        let (r1, r2) = tokio::join!(
+            client.with_locking(key, None, async |_| {
+                sleep(Duration::from_millis(300)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                let hooks = LockHooks::new()
+                    .on_contention(|attempt| contended.lock().unwrap().push(attempt.attempt))
+                    .on_timeout(|attempt| timed_out = Some(attempt.attempt));
+                client
+                    .with_locking_hooked(key, None, hooks, async |_| {})
+                    .await
+            }
+        );
+
+        assert_matches!(r1, Ok(()));
+        assert_matches!(r2, Err(Error::RetriesExhausted { attempts: 3, .. }));
+        assert_eq!(*contended.lock().unwrap(), vec![1, 2, 3]);
+        assert_eq!(timed_out, Some(3));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn waiting_hook_can_stop_the_wait(pool: SqlitePool) -> sqlx::Result<()> {
+        use std::ops::ControlFlow;
+
+        let key = "Wt5Yu7Ai9Bo1Cp3Dq5Er7Fs9Gt1Hu3Iv5Jw7Kx9Ly1Mz3Na5Ob7Pc9Qd1Re3Sf5T";
+        let client = LockClient::<StdCollectionLocker<Sqlite>>::new(pool);
+        let ticks = Mutex::new(Vec::new());
+
+        let (r1, r2) = tokio::join!(
+            client.with_locking(key, None, async |_| {
+                sleep(Duration::from_millis(500)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(50)).await;
+                let hooks = LockHooks::new().on_waiting(Duration::from_millis(100), |attempt| {
+                    let mut ticks = ticks.lock().unwrap();
+                    ticks.push(attempt.attempt);
+                    if ticks.len() < 2 {
+                        ControlFlow::Continue(())
+                    } else {
+                        ControlFlow::Break(())
+                    }
+                });
+                let started = Instant::now();
+                let r = client
+                    .with_locking_hooked(key, Duration::from_secs(1).into(), hooks, async |_| {})
+                    .await;
+                (r, started.elapsed())
+            }
+        );
+
+        assert_matches!(r1, Ok(()));
+        let (r2, waited) = r2;
+        assert_matches!(r2, Err(Error::FailedToGetLock(_)));
+        // 2 回目の呼び出しで、保持者が解放する前にやめている
+        assert_eq!(*ticks.lock().unwrap(), vec![1, 1]);
+        assert!(waited < Duration::from_millis(400), "{waited:?}");
+
+        let ticks = Mutex::new(0);
+        let (r1, r2) = tokio::join!(
+            client.with_locking(key, None, async |_| {
+                sleep(Duration::from_millis(350)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(50)).await;
+                let hooks = LockHooks::new().on_waiting(Duration::from_millis(100), |_| {
+                    *ticks.lock().unwrap() += 1;
+                    ControlFlow::Continue(())
+                });
+                client
+                    .with_locking_hooked(key, Duration::from_secs(1).into(), hooks, async |_| {})
+                    .await
+            }
+        );
+        assert_matches!(r1, Ok(()));
+        assert_matches!(r2, Ok(()));
+        assert!(*ticks.lock().unwrap() >= 2);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn report_tells_how_the_lock_was_got(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Rp7St9Tu1Uv3Vw5Wx7Xy9Yz1Za3Ab5Bc7Cd9De1Ef3Fg5Gh7Hi9Ij1Jk3Kl5Lm7N";
+        let client = LockClient::<StdCollectionLocker<Sqlite>>::new(pool).retry(RetryPolicy {
+            attempts: 3,
+            backoff: Duration::from_millis(100),
+            ..RetryPolicy::default()
+        });
+
+        let report = client
+            .with_locking_reported(key, None, async |_| {
+                sleep(Duration::from_millis(100)).await;
+            })
+            .await
+            .unwrap();
+        assert!(!report.contended);
+        assert_eq!(report.attempts, 1);
+        assert!(report.held >= Duration::from_millis(100));
+
+        // 2 回目の再試行で取れる。50ms から 250ms まで待つ
+        let (r1, r2) = tokio::join!(
+            client.with_locking(key, None, async |_| {
+                sleep(Duration::from_millis(200)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(50)).await;
+                client.with_locking_reported(key, None, async |_| {}).await
+            }
+        );
+        assert_matches!(r1, Ok(()));
+        let report = r2.unwrap();
+        assert!(report.contended);
+        assert_eq!(report.attempts, 3);
+        assert!(report.wait >= Duration::from_millis(200), "{report:?}");
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn wrappers_can_name_the_closure_bound(pool: SqlitePool) -> sqlx::Result<()> {
+        use crate::LockFn;
+
+        // ライブラリの利用者が書くような、with_locking を包む関数
+        async fn twice<T, F>(client: &LockClient<StdCollectionLocker<Sqlite>>, f: F) -> Result<()>
+        where
+            F: LockFn<Sqlite, T> + Clone,
+        {
+            let key = "Lf8Mg0Nh2Oi4Pj6Qk8Rl0Sm2Tn4Uo6Vp8Wq0Xr2Ys4Zt6Au8Bv0Cw2Dx4Ey6Fz8G";
+            client.with_locking(key, None, f.clone()).await?;
+            client.with_locking(key, None, f).await
+        }
+
+        let client = LockClient::new(pool);
+        let runs = Mutex::new(0);
+        let r = twice(
+            &client,
+            async |tx: &mut sqlx::Transaction<'static, Sqlite>| {
+                sqlx::query("SELECT 1").execute(&mut **tx).await.unwrap();
+                *runs.lock().unwrap() += 1;
+            },
+        )
+        .await;
+
+        assert_matches!(r, Ok(()));
+        assert_eq!(*runs.lock().unwrap(), 2);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn runaway_closure_is_aborted(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Mh9Ni1Oj3Pk5Ql7Rm9Sn1To3Up5Vq7Wr9Xs1Yt3Zu5Av7Bw9Cx1Dy3Ez5Fa7Gb9H";
+        sqlx::query("CREATE TABLE jobs (id INTEGER)")
+            .execute(&pool)
+            .await?;
+        let client = LockClient::<StdCollectionLocker<Sqlite>>::new(pool.clone());
+        let bounded = client.clone().max_hold(Duration::from_millis(100));
+
+        let started = Instant::now();
+        let r = bounded
+            .with_locking(key, None, async |tx| {
+                sqlx::query("INSERT INTO jobs VALUES (1)")
+                    .execute(&mut **tx)
+                    .await
+                    .unwrap();
+                sleep(Duration::from_secs(5)).await;
+            })
+            .await;
+        assert_matches!(r, Err(Error::LeaseExpired(_)));
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // トランザクションは巻き戻され、キーは解放されている
+        let (jobs,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM jobs")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(jobs, 0);
+        assert_matches!(client.with_locking(key, None, async |_| {}).await, Ok(()));
+        assert_matches!(
+            bounded
+                .with_locking(key, None, async |_| {
+                    sleep(Duration::from_millis(10)).await;
+                })
+                .await,
+            Ok(())
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn chunks_let_waiters_in_between(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Ch1Di3Ej5Fk7Gl9Hm1In3Jo5Kp7Lq9Mr1Ns3Ot5Pu7Qv9Rw1Sx3Ty5Uz7Va9Wb1X";
+        sqlx::query("CREATE TABLE jobs (id INTEGER)")
+            .execute(&pool)
+            .await?;
+        let client = LockClient::<StdCollectionLocker<Sqlite>>::new(pool.clone());
+        let order = Mutex::new(Vec::new());
+        let (started, waiting) = (tokio::sync::Notify::new(), tokio::sync::Notify::new());
+        let mut seen = None;
+
+        let (r1, r2) = tokio::join!(
+            client.with_locking_chunks(
+                key,
+                Duration::from_secs(1).into(),
+                2,
+                1..=6,
+                async |tx, chunk| {
+                    order.lock().unwrap().push(chunk);
+                    sqlx::query("INSERT INTO jobs VALUES (?)")
+                        .bind(chunk)
+                        .execute(&mut **tx)
+                        .await?;
+                    match chunk {
+                        1 => started.notify_one(),
+                        // 待っている呼び出しが並んでから解放する
+                        2 => waiting.notified().await,
+                        _ => {}
+                    }
+                    Ok(())
+                }
+            ),
+            async {
+                started.notified().await;
+                let hooks = LockHooks::new().on_contention(|_| waiting.notify_one());
+                client
+                    .with_locking_hooked(key, Duration::from_secs(1).into(), hooks, async |_| {
+                        order.lock().unwrap().push(0);
+                        let (jobs,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM jobs")
+                            .fetch_one(&pool)
+                            .await
+                            .unwrap();
+                        seen = Some(jobs);
+                    })
+                    .await
+            }
+        );
+
+        assert_matches!(r1, Ok(()));
+        assert_matches!(r2, Ok(()));
+        // 2 つごとに解放するので、待っていた呼び出しは 2 つ目の後に入る
+        assert_eq!(*order.lock().unwrap(), vec![1, 2, 0, 3, 4, 5, 6]);
+        // 先に終わった塊は確定していて、待っていた呼び出しから見える
+        assert_eq!(seen, Some(2));
+        let (jobs,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM jobs")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(jobs, 6);
+        assert_matches!(client.with_locking(key, None, async |_| {}).await, Ok(()));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn calls_inherit_the_defaults_of_the_client(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Df5Eg7Fh9Gi1Hj3Ik5Jl7Km9Ln1Mo3Np5Oq7Pr9Qs1Rt3Su5Tv7Uw9Vx1Wy3Xz5A";
+        let client = LockClient::<StdCollectionLocker<Sqlite>>::new(pool)
+            .default_timeout(Duration::from_secs(1));
+        let impatient = client.clone().default_timeout(Duration::from_millis(50));
+
+        let (r1, r2, r3, r4) = tokio::join!(
+            client.with_lock(key, async |_| {
+                sleep(Duration::from_millis(300)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                client.with_lock(key, async |_| {}).await
+            },
+            async {
+                sleep(Duration::from_millis(100)).await;
+                impatient.with_lock(key, async |_| {}).await
+            },
+            async {
+                // 呼び出しごとの指定が優先される
+                sleep(Duration::from_millis(100)).await;
+                impatient
+                    .with_locking(key, Duration::from_secs(1).into(), async |_| {})
+                    .await
+            }
+        );
+
+        assert_matches!(r1, Ok(()));
+        assert_matches!(r2, Ok(()));
+        assert_matches!(r3, Err(Error::FailedToGetLock(_)));
+        assert_matches!(r4, Ok(()));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn token_is_cancelled_on_shutdown(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Ck3Dl5Em7Fn9Go1Hp3Iq5Jr7Ks9Lt1Mu3Nv5Ow7Px9Qy1Rz3Sa5Tb7Uc9Vd1We3X";
+        let registry = ShutdownRegistry::new();
+        let client = LockClient::<StdCollectionLocker<Sqlite>>::new(pool)
+            .shutdown_registry(registry.clone());
+
+        // Wait でもクロージャは止まるように知らされる
+        let (stopped, ()) = tokio::join!(
+            client.with_locking_cancellable(key, None, async |_, token| {
+                token.cancelled().await;
+                "stopped"
+            }),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                registry.shutdown(ShutdownPolicy::Wait).await;
+            }
+        );
+        assert_matches!(stopped, Ok("stopped"));
+
+        // 呼び出しが終われば、クロージャの外に渡したトークンも取り消される
+        let client = LockClient::<StdCollectionLocker<Sqlite>>::new(client.pool.clone());
+        let token = client
+            .with_locking_cancellable(key, None, async |_, token| {
+                assert!(!token.is_cancelled());
+                token
+            })
+            .await
+            .unwrap();
+        assert!(token.is_cancelled());
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn blocking_section_leaves_the_runtime_free(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Bs5Ct7Du9Ev1Fw3Gx5Hy7Iz9Ja1Kb3Lc5Md7Ne9Of1Pg3Qh5Ri7Sj9Tk1Ul3Vm5W";
+        let client = LockClient::<StdCollectionLocker<Sqlite>>::new(pool);
+        let ticks = Mutex::new(0);
+
+        let (sum, ()) = tokio::join!(
+            client.with_locking_blocking_section(key, None, Duration::from_millis(50), || {
+                std::thread::sleep(Duration::from_millis(300));
+                (1..=100u32).sum::<u32>()
+            }),
+            async {
+                // 重い処理の間もランタイムは他のタスクを進め、キーは持たれている
+                for _ in 0..5 {
+                    sleep(Duration::from_millis(40)).await;
+                    *ticks.lock().unwrap() += 1;
+                }
+                assert_matches!(
+                    client.with_locking(key, None, async |_| {}).await,
+                    Err(Error::FailedToGetLock(_))
+                );
+            }
+        );
+
+        assert_matches!(sum, Ok(5050));
+        assert_eq!(*ticks.lock().unwrap(), 5);
+        assert_matches!(client.with_locking(key, None, async |_| {}).await, Ok(()));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "blocking")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn scoped_section_updates_the_locals() {
+        let key = "Sb7Tc9Ud1Ve3Wf5Xg7Yh9Zi1Aj3Bk5Cl7Dm9En1Fo3Gp5Hq7Ir9Js1Kt3Lu5Mv7N";
+        let pool = SqlitePool::connect_with(
+            sqlx::sqlite::SqliteConnectOptions::new()
+                .filename(std::env::temp_dir().join("rusty_ad_lock_scoped.db"))
+                .create_if_missing(true),
+        )
+        .await
+        .unwrap();
+        let client = LockClient::<StdCollectionLocker<Sqlite>>::new(pool);
+        let mut totals = vec![0u32; 3];
+
+        // 呼び出したタスクは止まるので、確かめるのは別のタスクから
+        let other = client.clone();
+        let check = tokio::spawn(async move {
+            sleep(Duration::from_millis(100)).await;
+            other.run_locked(key, None, async {}).await
+        });
+        let r = client
+            .with_locking_blocking_scoped(key, None, Duration::from_millis(50), || {
+                std::thread::sleep(Duration::from_millis(300));
+                for (i, total) in totals.iter_mut().enumerate() {
+                    *total += i as u32 * 10;
+                }
+                "done"
+            })
+            .await;
+
+        assert_matches!(r, Ok("done"));
+        // クロージャが返るまでキーは持たれていた
+        assert_matches!(check.await.unwrap(), Err(Error::FailedToGetLock(_)));
+        assert_eq!(totals, [0, 10, 20]);
+        assert_matches!(client.with_locking(key, None, async |_| {}).await, Ok(()));
+    }
+
+    #[sqlx::test]
+    async fn call_timeout_wins_over_the_attempt_timeout(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Ct2Wn4Ov6At8Tt0Mp2Ti4Me6Ou8Tq0Rs2Tu4Vw6Xy8Za0Bc2De4Fg6Hi8Jk0Lm2N";
+        let client = LockClient::<StdCollectionLocker<Sqlite>>::new(pool).retry(RetryPolicy {
+            attempt_timeout: Some(Duration::from_secs(10)),
+            ..RetryPolicy::default()
+        });
+        let held = client.guard(key, None).await.unwrap();
+
+        let started = Instant::now();
+        let r = client
+            .with_locking(key, Duration::from_millis(50).into(), async |_| {})
+            .await;
+        assert_matches!(r, Err(Error::FailedToGetLock(_)));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        held.release().await.unwrap();
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn deadline_bounds_the_retries(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Dl4Fm6Gn8Ho0Ip2Jq4Kr6Ls8Mt0Nu2Ov4Pw6Qx8Ry0Sz2Ta4Ub6Vc8Wd0Xe2Yf4Z";
+        let client = LockClient::<StdCollectionLocker<Sqlite>>::new(pool).retry(RetryPolicy {
+            attempts: u32::MAX,
+            backoff: Duration::from_millis(50),
+            attempt_timeout: Some(Duration::from_millis(100)),
+            deadline: Some(Duration::from_millis(300)),
+        });
+
+        let (r1, r2) = tokio::join!(
+            client.with_locking(key, None, async |_| {
+                sleep(Duration::from_millis(800)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                client.with_locking(key, None, async |_| {}).await
+            }
+        );
+
+        assert_matches!(r1, Ok(()));
+        let Err(Error::RetriesExhausted {
+            attempts, elapsed, ..
+        }) = r2
+        else {
+            panic!("expected RetriesExhausted, got: {r2:?}");
+        };
+        // 100ms 待って 50ms 空けるのを、300ms の期限まで繰り返す
+        assert_eq!(attempts, 2);
+        assert!(elapsed < Duration::from_millis(500), "{elapsed:?}");
+
+        // 期限の内に解放されれば取れる
+        let (r1, r2) = tokio::join!(
+            client.with_locking(key, None, async |_| {
+                sleep(Duration::from_millis(200)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(50)).await;
+                client.with_locking(key, None, async |_| {}).await
+            }
+        );
+        assert_matches!(r1, Ok(()));
+        assert_matches!(r2, Ok(()));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn deadline_follows_paused_clock(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Pc8Dk2Lm4No6Pq8Rs0Tu2Vw4Xy6Za8Bc0De2Fg4Hi6Jk8Lm0No2Pq4Rs6Tu8Vw0X";
+        let pool = paused_pool(&pool).await?;
+        let client = LockClient::<StdCollectionLocker<Sqlite>>::new(pool).retry(RetryPolicy {
+            attempts: u32::MAX,
+            backoff: Duration::from_secs(60),
+            attempt_timeout: Some(Duration::from_secs(60)),
+            deadline: Some(Duration::from_secs(600)),
+        });
+        let held = client.guard(key, None).await.unwrap();
+
+        tokio::time::pause();
+        let started = Instant::now();
+        let r = client.with_locking(key, None, async |_| {}).await;
+        tokio::time::resume();
+
+        let Err(Error::RetriesExhausted {
+            attempts, elapsed, ..
+        }) = r
+        else {
+            panic!("expected RetriesExhausted, got: {r:?}");
+        };
+        // 1 分待って 1 分空けるのを、止めた時計で 10 分の期限まで繰り返す
+        assert_eq!(attempts, 5);
+        assert!(elapsed <= Duration::from_secs(600), "{elapsed:?}");
+        assert!(started.elapsed() < Duration::from_secs(5));
+        held.release().await.unwrap();
+
+        Ok(())
+    }
+
+    // sleep はすぐに終わり、その分だけ now を進める時計
+    struct SkippingClock;
+
+    static SKIPPED: Mutex<Duration> = Mutex::new(Duration::ZERO);
+
+    impl Clock for SkippingClock {
+        fn now() -> Instant {
+            Instant::now() + *SKIPPED.lock().unwrap()
+        }
+
+        async fn sleep(duration: Duration) {
+            *SKIPPED.lock().unwrap() += duration;
+        }
+    }
+
+    #[sqlx::test]
+    async fn client_runs_on_its_clock(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Ck4Sk6Ip8Pi0Ng2Cl4Oc6Kt8Im0Es2Th4Ec6Li8En0Tw2Ai4Ts6Re8Po0Rt2Sh4M";
+        let client = LockClient::<StdCollectionLocker<Sqlite>>::new(pool)
+            .retry(RetryPolicy {
+                attempts: 3,
+                backoff: Duration::from_secs(3600),
+                attempt_timeout: None,
+                deadline: None,
+            })
+            .clock::<SkippingClock>();
+        let started = Instant::now();
+
+        // 1 時間空けて 3 回試し直すのも、その時計で数える
+        let held = client.guard(key, None).await.unwrap();
+        let r = client.with_locking(key, None, async |_| {}).await;
+        let Err(Error::RetriesExhausted {
+            attempts, elapsed, ..
+        }) = r
+        else {
+            panic!("expected RetriesExhausted, got: {r:?}");
+        };
+        assert_eq!(attempts, 4);
+        assert!(elapsed >= Duration::from_secs(3 * 3600), "{elapsed:?}");
+        held.release().await.unwrap();
+
+        let report = client
+            .with_locking_reported(key, None, async |_| {
+                SkippingClock::sleep(Duration::from_secs(60)).await;
+            })
+            .await
+            .unwrap();
+        assert!(report.held >= Duration::from_secs(60), "{report:?}");
+
+        let r = client
+            .clone()
+            .max_hold(Duration::from_secs(3600))
+            .with_locking(key, None, async |_| std::future::pending::<()>().await)
+            .await;
+        assert_matches!(r, Err(Error::LeaseExpired(_)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn max_hold_follows_paused_clock(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Mx3Hp5Cl7Ok9Pa1Us2Ed4Fo6Lw8Ti0Me2Qr4St6Uv8Wx0Yz2Ab4Cd6Ef8Gh0Ij2K";
+        // 返した接続はあとから戻るので、呼び出しごとにプールを分ける
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            clients.push(
+                LockClient::<StdCollectionLocker<Sqlite>>::new(paused_pool(&pool).await?)
+                    .max_hold(Duration::from_secs(600)),
+            );
+        }
+
+        // 止めた時計が壁時計から離れていても、持てる時間はその時計で数える
+        tokio::time::pause();
+        tokio::time::advance(Duration::from_secs(3600)).await;
+        let r = clients[0]
+            .with_locking(key, None, async |_| {
+                sleep(Duration::from_secs(300)).await;
+            })
+            .await;
+        assert_matches!(r, Ok(()));
+        let r = clients[1]
+            .with_locking(key, None, async |_| {
+                sleep(Duration::from_secs(1200)).await;
+            })
+            .await;
+        assert_matches!(r, Err(Error::LeaseExpired(_)));
+        tokio::time::resume();
+
+        Ok(())
+    }
+}
@@ -0,0 +1,119 @@
+use std::{
+    future::Future,
+    pin::pin,
+    task::Poll,
+    time::{Duration, Instant},
+};
+
+/// Source of time used for lock timeouts and durations.
+///
+/// the waits, retries, max hold, lease expiry and reported durations of a
+/// [`LockClient`](crate::LockClient) run on the clock given to
+/// [`LockClient::clock`](crate::LockClient::clock), those of a
+/// [`StdCollectionLockerWith`](crate::StdCollectionLockerWith) on its `C`. everything else, like
+/// the calls of [`Locker`](crate::Locker) itself, runs on [`DefaultClock`].
+pub trait Clock {
+    /// current instant of this clock
+    fn now() -> Instant;
+
+    /// complete after `duration` has elapsed on this clock
+    fn sleep(duration: Duration) -> impl Future<Output = ()> + Send;
+
+    /// run `f` until it completes or `duration` has elapsed on this clock, whichever comes first
+    ///
+    /// returns None if `duration` has elapsed first.
+    fn timeout<F>(duration: Duration, f: F) -> impl Future<Output = Option<F::Output>> + Send
+    where
+        F: Future + Send,
+    {
+        async move {
+            let mut f = pin!(f);
+            let mut sleep = pin!(Self::sleep(duration));
+
+            std::future::poll_fn(|cx| {
+                if let Poll::Ready(v) = f.as_mut().poll(cx) {
+                    return Poll::Ready(Some(v));
+                }
+                sleep.as_mut().poll(cx).map(|()| None)
+            })
+            .await
+        }
+    }
+}
+
+/// Clock backed by `tokio::time`.
+///
+/// time stops while `tokio::time::pause` is in effect, so timeouts can be tested deterministically
+/// with the `test-util` feature (`tokio::time::advance` or auto-advance of a paused runtime).
+#[cfg(feature = "tokio")]
+pub struct TokioClock;
+
+#[cfg(feature = "tokio")]
+impl Clock for TokioClock {
+    fn now() -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+
+    fn sleep(duration: Duration) -> impl Future<Output = ()> + Send {
+        tokio::time::sleep(duration)
+    }
+}
+
+/// Clock backed by `async-io` timers, for async-std and smol applications.
+#[cfg(feature = "async-io")]
+pub struct AsyncIoClock;
+
+#[cfg(feature = "async-io")]
+impl Clock for AsyncIoClock {
+    fn now() -> Instant {
+        Instant::now()
+    }
+
+    async fn sleep(duration: Duration) {
+        async_io::Timer::after(duration).await;
+    }
+}
+
+/// Clock used by the lockers, [`TokioClock`] unless `runtime-async-std` is the only runtime.
+#[cfg(all(
+    feature = "tokio",
+    not(all(
+        feature = "runtime-async-std",
+        not(feature = "runtime-tokio"),
+        not(feature = "runtime-actix")
+    ))
+))]
+pub type DefaultClock = TokioClock;
+
+/// Clock used by the lockers, [`TokioClock`] unless `runtime-async-std` is the only runtime.
+#[cfg(all(
+    feature = "runtime-async-std",
+    not(feature = "runtime-tokio"),
+    not(feature = "runtime-actix")
+))]
+pub type DefaultClock = AsyncIoClock;
+
+#[cfg(all(test, feature = "async-io"))]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn async_io_clock_times_out_without_a_tokio_runtime() {
+        let started = Instant::now();
+        let r = async_io::block_on(AsyncIoClock::timeout(
+            Duration::from_millis(50),
+            AsyncIoClock::sleep(Duration::from_secs(10)),
+        ));
+
+        assert_eq!(r, None);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(
+            async_io::block_on(AsyncIoClock::timeout(Duration::from_secs(1), async { 1 })),
+            Some(1)
+        );
+    }
+}
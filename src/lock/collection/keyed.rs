@@ -0,0 +1,321 @@
+use std::{
+    borrow::Borrow,
+    collections::{HashMap, hash_map},
+    hash::{BuildHasher, BuildHasherDefault, DefaultHasher, Hash},
+    pin::pin,
+    sync::{
+        Mutex,
+        atomic::{AtomicU32, Ordering},
+    },
+    task::Poll,
+    time::{Duration, SystemTime},
+};
+
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{Clock, DefaultClock};
+
+const CHANNEL_BUFFER_SIZE: usize = 32;
+
+// is_probably_locked が見る、キーのハッシュごとの保持数の枠
+const SLOTS: usize = 1024;
+
+/// In-process mutex of keys, the one [`crate::StdCollectionLocker`] locks on.
+///
+/// each key is locked independently, keys that aren't locked take no memory.
+///
+/// ```ignore
+/// static RENDERS: LazyLock<KeyedMutex<String>> = LazyLock::new(KeyedMutex::new);
+///
+/// let _guard = RENDERS.lock(format!("thumbnail:{id}")).await;
+/// render_thumbnail(id).await;
+/// ```
+pub struct KeyedMutex<K> {
+    held: Mutex<HashMap<K, SystemTime>>,
+    // held の写し。ハッシュが同じキーは同じ枠に数える
+    slots: Box<[AtomicU32]>,
+    // 解放を待っている数
+    waiters: Mutex<HashMap<K, usize>>,
+    released: broadcast::Sender<K>,
+}
+
+/// Lock of a key of a [`KeyedMutex`], the key is unlocked when it is dropped.
+pub struct KeyedLock<'a, K: Eq + Hash + Clone> {
+    mutex: &'a KeyedMutex<K>,
+    key: K,
+}
+
+impl<K: Eq + Hash + Clone> Default for KeyedMutex<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Eq + Hash + Clone> KeyedMutex<K> {
+    /// mutex with no key locked
+    pub fn new() -> Self {
+        let (released, _) = broadcast::channel(CHANNEL_BUFFER_SIZE);
+        Self {
+            held: Mutex::default(),
+            slots: (0..SLOTS).map(|_| AtomicU32::new(0)).collect(),
+            waiters: Mutex::default(),
+            released,
+        }
+    }
+
+    /// lock `key` if no one holds it
+    pub fn try_lock(&self, key: K) -> Option<KeyedLock<'_, K>> {
+        self.insert(&key).then(|| self.guard(key))
+    }
+
+    /// lock `key`, waiting until it is unlocked
+    pub async fn lock(&self, key: K) -> KeyedLock<'_, K> {
+        self.wait(&key, std::future::pending()).await;
+        self.guard(key)
+    }
+
+    /// lock `key`, waiting for `timeout` at most
+    pub async fn lock_timeout(&self, key: K, timeout: Duration) -> Option<KeyedLock<'_, K>> {
+        self.lock_timeout_with::<DefaultClock>(key, timeout).await
+    }
+
+    /// lock `key`, waiting for `timeout` of the clock `C` at most
+    pub async fn lock_timeout_with<C: Clock>(
+        &self,
+        key: K,
+        timeout: Duration,
+    ) -> Option<KeyedLock<'_, K>> {
+        self.wait(&key, C::sleep(timeout))
+            .await
+            .then(|| self.guard(key))
+    }
+
+    /// whether `key` is locked at the moment
+    pub fn is_locked<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.held.lock().unwrap().contains_key(key)
+    }
+
+    /// whether `key` is probably locked, without taking the mutex
+    ///
+    /// wait-free, for hot paths skipping work a holder is already doing. a key hashing like a
+    /// locked one is reported locked too, about 1 in 1024 per locked key, and a lock or unlock
+    /// happening at the same time may not be seen yet.
+    pub fn is_probably_locked<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + ?Sized,
+    {
+        self.slot(key).load(Ordering::Relaxed) > 0
+    }
+
+    // Borrow の決まりで、借用した形でも同じハッシュになり同じ枠を指す
+    fn slot<Q: Hash + ?Sized>(&self, key: &Q) -> &AtomicU32 {
+        // NOTE: 固定の鍵のハッシュなので、同じキーはいつも同じ枠になる
+        let hash = BuildHasherDefault::<DefaultHasher>::default().hash_one(key);
+        &self.slots[hash as usize % SLOTS]
+    }
+
+    fn guard(&self, key: K) -> KeyedLock<'_, K> {
+        KeyedLock { mutex: self, key }
+    }
+
+    // 誰も持っていなければ持つ
+    pub(crate) fn insert(&self, key: &K) -> bool {
+        match self.held.lock().unwrap().entry(key.clone()) {
+            hash_map::Entry::Vacant(e) => {
+                e.insert(SystemTime::now());
+                self.slot(key).fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            hash_map::Entry::Occupied(_) => false,
+        }
+    }
+
+    pub(crate) fn remove<Q>(&self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let removed = self.held.lock().unwrap().remove_entry(key);
+        let Some((key, _)) = removed else {
+            return;
+        };
+        self.slot(&key).fetch_sub(1, Ordering::Relaxed);
+
+        // 待っている者が居なければ知らせない。購読は取り直す前なので、この後に購読した者は
+        // 空いたキーを自分で取れる
+        if self.released.receiver_count() > 0 {
+            // NOTE: エラーが来ても、それは受信者が0なことを表しているだけ
+            let _ = self.released.send(key);
+        }
+    }
+
+    // deadline が来るまで解放を待って持つ。持てたら true
+    pub(crate) async fn wait(&self, key: &K, deadline: impl Future<Output = ()>) -> bool {
+        // 空いていれば購読せずに持つ
+        if self.insert(key) {
+            return true;
+        }
+        // 取れなかった直後の解放を逃さないよう、購読してから取り直す
+        let mut rx = self.released.subscribe();
+        if self.insert(key) {
+            return true;
+        }
+
+        let _waiting = Waiting::new(self, key);
+        let acquired = async {
+            loop {
+                match rx.recv().await {
+                    Ok(k) if k == *key && self.insert(key) => break,
+                    // 取りこぼした解放の中に目的のキーがあったかもしれない
+                    Err(RecvError::Lagged(_)) if self.insert(key) => break,
+                    Ok(_) | Err(RecvError::Lagged(_)) => {}
+                    // 送信側は self が持っているので閉じない
+                    Err(RecvError::Closed) => std::future::pending().await,
+                }
+            }
+        };
+
+        let mut acquired = pin!(acquired);
+        let mut deadline = pin!(deadline);
+        std::future::poll_fn(|cx| {
+            if acquired.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(true);
+            }
+            deadline.as_mut().poll(cx).map(|()| false)
+        })
+        .await
+    }
+
+    /// the keys held, with when they were locked, and the keys waited for only
+    pub(crate) fn entries(&self) -> Vec<(K, Option<SystemTime>, usize)> {
+        let held = self.held.lock().unwrap();
+        let waiters = self.waiters.lock().unwrap();
+
+        let mut entries: Vec<_> = held
+            .iter()
+            .map(|(key, since)| {
+                let n = waiters.get(key).copied().unwrap_or(0);
+                (key.clone(), Some(*since), n)
+            })
+            .collect();
+
+        // 保持者が解放した直後で、まだ誰も取り直していないキー
+        entries.extend(
+            waiters
+                .iter()
+                .filter(|(key, _)| !held.contains_key(*key))
+                .map(|(key, n)| (key.clone(), None, *n)),
+        );
+
+        entries
+    }
+}
+
+impl<K: Eq + Hash + Clone> KeyedLock<'_, K> {
+    /// key this lock holds
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+}
+
+impl<K: Eq + Hash + Clone + std::fmt::Debug> std::fmt::Debug for KeyedLock<'_, K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyedLock").field("key", &self.key).finish()
+    }
+}
+
+impl<K: Eq + Hash + Clone> Drop for KeyedLock<'_, K> {
+    fn drop(&mut self) {
+        self.mutex.remove(&self.key);
+    }
+}
+
+// 待っている間だけ waiters に数えられる。キャンセルされても Drop で戻る
+struct Waiting<'a, K: Eq + Hash + Clone> {
+    mutex: &'a KeyedMutex<K>,
+    key: K,
+}
+
+impl<'a, K: Eq + Hash + Clone> Waiting<'a, K> {
+    fn new(mutex: &'a KeyedMutex<K>, key: &K) -> Self {
+        *mutex
+            .waiters
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default() += 1;
+        Self {
+            mutex,
+            key: key.clone(),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone> Drop for Waiting<'_, K> {
+    fn drop(&mut self) {
+        let mut waiters = self.mutex.waiters.lock().unwrap();
+        if let Some(n) = waiters.get_mut(&self.key) {
+            *n -= 1;
+            if *n == 0 {
+                waiters.remove(&self.key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_matches;
+    use tokio::time::sleep;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn keys_are_locked_independently() {
+        let mutex = KeyedMutex::<String>::new();
+        let key = "Zd3Qf6Wh9Ej2Rk5Tl8Yz1Ux4Ic7Ov0Pb3An6Sm9Dq2Fw5Ge8Hr1Jt4Ky7Lu0Zi3X".to_owned();
+
+        let guard = mutex.try_lock(key.clone()).unwrap();
+        assert!(mutex.is_locked(&key));
+        assert!(mutex.is_locked(key.as_str()));
+        assert_matches!(mutex.try_lock(key.clone()), None);
+        assert_matches!(mutex.try_lock(format!("{key}:other")), Some(_));
+        assert_matches!(
+            mutex
+                .lock_timeout(key.clone(), Duration::from_millis(50))
+                .await,
+            None
+        );
+
+        let (_, waited) = tokio::join!(
+            async {
+                sleep(Duration::from_millis(100)).await;
+                drop(guard);
+            },
+            mutex.lock(key.clone())
+        );
+        assert_matches!(waited.key(), k if *k == key);
+        drop(waited);
+        assert!(!mutex.is_locked(&key));
+    }
+
+    #[test]
+    fn probable_state_follows_the_locks() {
+        let mutex = KeyedMutex::<String>::new();
+        let key = "Pl4Qm6Rn8So0Tp2Uq4Vr6Ws8Xt0Yu2Zv4Aw6Bx8Cy0Dz2Ea4Fb6Gc8Hd0Ie2Jf4K".to_owned();
+        assert!(!mutex.is_probably_locked(&key));
+
+        let guard = mutex.try_lock(key.clone()).unwrap();
+        assert!(mutex.is_probably_locked(&key));
+        // 取れなかった分は数えない
+        assert_matches!(mutex.try_lock(key.clone()), None);
+
+        drop(guard);
+        assert!(!mutex.is_probably_locked(&key));
+    }
+}
@@ -1,152 +1,334 @@
+mod keyed;
+
+pub use keyed::*;
+
+mod scope;
+
+pub use scope::*;
+
+#[cfg(all(unix, feature = "unix-socket"))]
+mod socket;
+
+#[cfg(all(unix, feature = "unix-socket"))]
+pub use socket::*;
+
 use std::{
-    collections::{HashMap, HashSet},
+    any::Any,
+    borrow::Borrow,
+    collections::HashMap,
+    hash::{Hash, Hasher},
     marker::PhantomData,
-    sync::{Arc, LazyLock, Mutex},
+    sync::{
+        Arc, LazyLock, Mutex, Weak,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::SystemTime,
 };
 
 use sqlx::ConnectOptions;
-use tokio::sync::broadcast;
 
-use crate::{Error, Locker};
+use crate::{Capabilities, Clock, DefaultClock, Error, Introspect, LockClient, LockInfo, Locker};
 
 /// Advisory lock implementation using tokio::sync and std collections.
-pub struct StdCollectionLocker<D: sqlx::Database> {
-    _marker: PhantomData<D>,
+///
+/// ```ignore
+/// let (r1, r2) = tokio::join!(
+///         StdCollectionLocker::with_locking(
+///             &pool,
+///             "ivcK1ms0G8xoI5aA40BMkiI2aVlhyM025EGFv1nJxNIC50pJovn2Vn1i7IKlnqYB",
+///             Duration::from_secs(1).into(),
+///             async |_| {
+///                 sleep(Duration::from_secs(2)).await;
+///             },
+///         ),
+///         StdCollectionLocker::with_locking(
+///             &pool,
+///             "ivcK1ms0G8xoI5aA40BMkiI2aVlhyM025EGFv1nJxNIC50pJovn2Vn1i7IKlnqYB",
+///             Duration::from_secs(1).into(),
+///             async |_| {
+///                 sleep(Duration::from_secs(2)).await;
+///             },
+///         )
+///     );
+///
+///     match (&r1, &r2) {
+///         (Ok(()), Err(_)) | (Err(_), Ok(())) => (),
+///         other => panic!("expected one Ok and one FailedToGetLock, got: {other:?}"),
+///     }
+///
+///     let r = StdCollectionLocker::with_locking(
+///         &pool,
+///         "ivcK1ms0G8xoI5aA40BMkiI2aVlhyM025EGFv1nJxNIC50pJovn2Vn1i7IKlnqYB",
+///         Duration::from_secs(1).into(),
+///         async |_| {},
+///     )
+///     .await;
+///
+///     assert_matches!(r, Ok(()));
+/// ```
+pub type StdCollectionLocker<D> = StdCollectionLockerWith<D, DefaultClock>;
+
+/// [`StdCollectionLocker`] whose waits are driven by the clock `C` instead of [`DefaultClock`].
+///
+/// the keys are bucketed by the scope `S`, per pool URL by default.
+pub struct StdCollectionLockerWith<D: sqlx::Database, C: Clock, S: KeyScope = PerUrlScope> {
+    _marker: PhantomData<(D, C, S)>,
+}
+
+// (プール URL, プール) のうちスコープで区別するもの
+type Bucket = (Option<Arc<String>>, Option<usize>);
+
+// (バケット, キー)
+type KeyId = (Bucket, Arc<str>);
+
+// KeyId を作らずに KEYS と OWNERS を引くための形。キーを Arc にすると呼び出しごとに確保する
+trait KeyRef {
+    fn parts(&self) -> (&Bucket, &str);
+}
+
+impl KeyRef for KeyId {
+    fn parts(&self) -> (&Bucket, &str) {
+        (&self.0, &self.1)
+    }
+}
+
+impl KeyRef for (Bucket, &str) {
+    fn parts(&self) -> (&Bucket, &str) {
+        (&self.0, self.1)
+    }
+}
+
+// NOTE: Borrow の決まりどおり、KeyId と同じ順に同じ値を流すので同じハッシュになる
+impl Hash for dyn KeyRef + '_ {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.parts().hash(state);
+    }
+}
+
+impl PartialEq for dyn KeyRef + '_ {
+    fn eq(&self, other: &Self) -> bool {
+        self.parts() == other.parts()
+    }
+}
+
+impl Eq for dyn KeyRef + '_ {}
+
+impl<'a> Borrow<dyn KeyRef + 'a> for KeyId {
+    fn borrow(&self) -> &(dyn KeyRef + 'a) {
+        self
+    }
+}
+
+// キーごとのロック
+static KEYS: LazyLock<KeyedMutex<KeyId>> = LazyLock::new(KeyedMutex::new);
+
+// 持っているキーに付けられた所有者のラベル。解放で外す
+static OWNERS: LazyLock<Mutex<HashMap<KeyId, String>>> = LazyLock::new(Mutex::default);
+
+// OWNERS の件数の写し。所有者を付けていなければ解放で OWNERS を触らない
+static OWNED: AtomicUsize = AtomicUsize::new(0);
+
+// 接続設定の Arc のアドレスごとの URL。Weak が残っている間はアドレスが使い回されない
+type UrlCache = HashMap<usize, (Weak<dyn Any + Send + Sync>, Arc<String>)>;
+
+static URLS: LazyLock<Mutex<UrlCache>> = LazyLock::new(Mutex::default);
+
+/// Key held or waited for in the process, see [`StdCollectionLockerWith::snapshot`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct KeyStatus {
+    /// URL of the pool the key was locked through, empty for [`Scope::Global`]
+    pub url: String,
+    pub key: String,
+    /// when the key was acquired, None if it is only waited for
+    pub held_since: Option<SystemTime>,
+    /// number of sessions waiting for the key
+    pub waiters: usize,
 }
 
-#[derive(Clone, Debug)]
-enum Event {
-    Released { url: Arc<String>, key: Arc<String> },
+// to_url_lossy は URL を組み立て直すので、接続設定ごとに一度だけ呼ぶ
+fn pool_url<D: sqlx::Database>(pool: &sqlx::Pool<D>) -> Arc<String> {
+    let options = pool.connect_options();
+    let address = Arc::as_ptr(&options) as usize;
+    let mut urls = URLS.lock().unwrap();
+    if let Some((options, url)) = urls.get(&address)
+        && options.strong_count() > 0
+    {
+        return Arc::clone(url);
+    }
+
+    // 捨てられたプールの分を片付けてから覚える
+    urls.retain(|_, (options, _)| options.strong_count() > 0);
+    let url = Arc::new(options.to_url_lossy().to_string());
+    let weak: Weak<dyn Any + Send + Sync> = Arc::downgrade(&options) as _;
+    urls.insert(address, (weak, Arc::clone(&url)));
+    url
 }
 
-const CHANNEL_BUFFER_SIZE: usize = 32;
+fn key_id<D: sqlx::Database>(scope: Scope, pool: &sqlx::Pool<D>, key: &str) -> KeyId {
+    (bucket(scope, pool), Arc::from(key))
+}
 
-static STATE: LazyLock<Mutex<HashMap<Arc<String>, HashSet<Arc<String>>>>> =
-    LazyLock::new(|| Mutex::default());
+// 引くだけなら key_id と違ってキーを確保しない
+fn key_ref<'k, D: sqlx::Database>(
+    scope: Scope,
+    pool: &sqlx::Pool<D>,
+    key: &'k str,
+) -> (Bucket, &'k str) {
+    (bucket(scope, pool), key)
+}
 
-static BROADCAST: LazyLock<broadcast::Sender<Event>> = LazyLock::new(|| {
-    let (sx, _rx) = broadcast::channel(CHANNEL_BUFFER_SIZE);
-    sx
-});
+fn bucket<D: sqlx::Database>(scope: Scope, pool: &sqlx::Pool<D>) -> Bucket {
+    match scope {
+        Scope::Global => (None, None),
+        Scope::PerUrl => (Some(pool_url(pool)), None),
+        // プールの複製は接続設定の Arc を共有している
+        Scope::PerPool => (
+            Some(pool_url(pool)),
+            Some(Arc::as_ptr(&pool.connect_options()) as usize),
+        ),
+    }
+}
 
-impl<D: sqlx::Database> Locker for StdCollectionLocker<D> {
+impl<D: sqlx::Database, C: Clock, S: KeyScope> Locker for StdCollectionLockerWith<D, C, S> {
     type DB = D;
 
-    /// execute the given closure while the key is locked
-    ///
-    /// * `pool` - connection pool
-    /// * `key` - key to get locked
-    /// * `timeout` - timeout duration. if it can't get lock in 1 sec, with_locking will return Err. if None is given and a conflict occurs, it will fail immediately.
-    /// * `f` - closure that executed while the key is locked
-    ///
-    /// ```ignore
-    /// let (r1, r2) = tokio::join!(
-    ///         StdCollectionLocker::with_locking(
-    ///             &pool,
-    ///             "ivcK1ms0G8xoI5aA40BMkiI2aVlhyM025EGFv1nJxNIC50pJovn2Vn1i7IKlnqYB",
-    ///             Duration::from_secs(1).into(),
-    ///             async |_| {
-    ///                 sleep(Duration::from_secs(2)).await;
-    ///             },
-    ///         ),
-    ///         StdCollectionLocker::with_locking(
-    ///             &pool,
-    ///             "ivcK1ms0G8xoI5aA40BMkiI2aVlhyM025EGFv1nJxNIC50pJovn2Vn1i7IKlnqYB",
-    ///             Duration::from_secs(1).into(),
-    ///             async |_| {
-    ///                 sleep(Duration::from_secs(2)).await;
-    ///             },
-    ///         )
-    ///     );
-    ///
-    ///     match (&r1, &r2) {
-    ///         (Ok(()), Err(_)) | (Err(_), Ok(())) => (),
-    ///         other => panic!("expected one Ok and one FailedToGetLock, got: {:?}", other),
-    ///     }
-    ///
-    ///     let r = StdCollectionLocker::with_locking(
-    ///         &pool,
-    ///         "ivcK1ms0G8xoI5aA40BMkiI2aVlhyM025EGFv1nJxNIC50pJovn2Vn1i7IKlnqYB",
-    ///         Duration::from_secs(1).into(),
-    ///         async |_| {},
-    ///     )
-    ///     .await;
-    ///
-    ///     assert_matches!(r, Ok(()));
-    /// ```
-    async fn with_locking<T, F>(
+    const NAME: &'static str = "std-collection";
+
+    fn capabilities() -> Capabilities {
+        Capabilities {
+            sub_second_timeouts: true,
+            holder_lookup: true,
+            ..Capabilities::default()
+        }
+    }
+
+    async fn acquire(
         pool: &sqlx::Pool<Self::DB>,
+        _tx: &mut sqlx::Transaction<'static, Self::DB>,
         key: &str,
         timeout: Option<std::time::Duration>,
-        f: F,
-    ) -> super::Result<()>
-    where
-        F: AsyncFnOnce(&mut sqlx::Transaction<'static, Self::DB>) -> T,
-    {
-        let url = Arc::new(pool.connect_options().to_url_lossy().to_string());
-        let key = Arc::new(key.to_owned());
+    ) -> super::Result<()> {
+        let id = key_id(S::SCOPE, pool, key);
 
-        let mut tx = pool.begin().await?;
+        // 待たない設定なら即失敗
+        let acquired = match timeout {
+            None => KEYS.insert(&id),
+            Some(dur) => KEYS.wait(&id, C::sleep(dur)).await,
+        };
 
-        fn try_lock(url: &Arc<String>, key: &Arc<String>) -> bool {
-            let mut state = STATE.lock().unwrap();
-            state
-                .entry(Arc::clone(url))
-                .or_default()
-                .insert(Arc::clone(key))
+        if !acquired {
+            return Err(Error::FailedToGetLock(key.to_string()));
         }
 
-        // まず即時取得を試みる
-        if !try_lock(&url, &key) {
-            // 待たない設定なら即失敗
-            let Some(dur) = timeout else {
-                return Err(Error::FailedToGetLock((*key).to_string()));
-            };
-
-            // 指定期間待って、目的の (url, key) が解放されたら再取得を試みる
-            let mut rx = BROADCAST.subscribe();
-            let acquired = tokio::time::timeout(dur, async {
-                loop {
-                    match rx.recv().await {
-                        Ok(Event::Released { url: u, key: k })
-                            if u.eq(&url) && k.eq(&key) && try_lock(&url, &key) =>
-                        {
-                            break true;
-                        }
-                        Ok(_) => { /* 別のロック解放: 無視 */ }
-                        Err(_) => break false, // チャネルが閉じた等
-                    }
-                }
-            })
-            .await
-            .ok()
-            .unwrap_or(false);
+        Ok(())
+    }
 
-            if !acquired {
-                return Err(Error::FailedToGetLock((*key).to_string()));
+    async fn release(
+        pool: &sqlx::Pool<Self::DB>,
+        _tx: &mut sqlx::Transaction<'static, Self::DB>,
+        key: &str,
+    ) -> super::Result<()> {
+        let id = key_ref(S::SCOPE, pool, key);
+        let id: &dyn KeyRef = &id;
+        // NOTE: set_owner は同じセッションで解放より前に済んでいる
+        if OWNED.load(Ordering::Relaxed) > 0 {
+            let mut owners = OWNERS.lock().unwrap();
+            if owners.remove(id).is_some() {
+                OWNED.store(owners.len(), Ordering::Relaxed);
             }
         }
+        KEYS.remove(id);
 
-        f(&mut tx).await;
-
-        // ロックを解除する
-        let mut state = STATE.lock().unwrap();
-        state.get_mut(&url).map(|set| set.remove(&key));
-        drop(state);
-        // ロックを開放したことを送信
-        // NOTE: エラーが来ても、それは受診者が0なことを表しているだけ
-        let _ = BROADCAST.send(Event::Released {
-            url: Arc::clone(&url),
-            key,
-        });
+        Ok(())
+    }
+
+    async fn set_owner(
+        pool: &sqlx::Pool<Self::DB>,
+        _tx: &mut sqlx::Transaction<'static, Self::DB>,
+        key: &str,
+        owner: &str,
+    ) -> super::Result<()> {
+        let mut owners = OWNERS.lock().unwrap();
+        owners.insert(key_id(S::SCOPE, pool, key), owner.to_owned());
+        OWNED.store(owners.len(), Ordering::Relaxed);
 
         Ok(())
     }
 }
 
+impl<D: sqlx::Database, C: Clock, S: KeyScope> StdCollectionLockerWith<D, C, S> {
+    /// list the keys held or waited for in the process, for every pool URL
+    pub fn snapshot() -> Vec<KeyStatus> {
+        KEYS.entries()
+            .into_iter()
+            .map(|(((url, _), key), held_since, waiters)| KeyStatus {
+                url: url.as_deref().cloned().unwrap_or_default(),
+                key: key.to_string(),
+                held_since,
+                waiters,
+            })
+            .collect()
+    }
+
+    /// whether `key` is probably held through `pool`, without taking a mutex or a connection
+    ///
+    /// see [`KeyedMutex::is_probably_locked`] for how probable.
+    pub fn is_probably_locked(pool: &sqlx::Pool<D>, key: &str) -> bool {
+        KEYS.is_probably_locked::<dyn KeyRef>(&key_ref(S::SCOPE, pool, key))
+    }
+
+    /// [`StdCollectionLockerWith::snapshot`] as a JSON array, for admin endpoints
+    #[cfg(feature = "serde")]
+    pub fn snapshot_json() -> String {
+        // NOTE: KeyStatus の直列化は失敗しない
+        serde_json::to_string(&Self::snapshot()).unwrap()
+    }
+}
+
+impl<D: sqlx::Database, C: Clock, S: KeyScope, K: Clock>
+    LockClient<StdCollectionLockerWith<D, C, S>, K>
+{
+    /// whether `key` is probably held through the client, see
+    /// [`StdCollectionLockerWith::is_probably_locked`]
+    ///
+    /// ```ignore
+    /// if locker.is_probably_locked("cache:refresh") {
+    ///     // another task is refreshing it
+    ///     return stale;
+    /// }
+    /// ```
+    pub fn is_probably_locked(&self, key: &str) -> bool {
+        self.backend_key(key).is_ok_and(|key| {
+            StdCollectionLockerWith::<D, C, S>::is_probably_locked(self.lock_session_pool(), &key)
+        })
+    }
+}
+
+impl<D: sqlx::Database, C: Clock, S: KeyScope> Introspect for StdCollectionLockerWith<D, C, S> {
+    async fn list_locks(pool: &sqlx::Pool<Self::DB>) -> super::Result<Vec<LockInfo>> {
+        let bucket = bucket(S::SCOPE, pool);
+        let url = pool_url(pool);
+        let owners = OWNERS.lock().unwrap();
+
+        Ok(KEYS
+            .entries()
+            .into_iter()
+            .filter(|((b, _), _, _)| *b == bucket)
+            .map(|(id, held_since, waiters)| LockInfo {
+                holder: held_since.map(|_| url.to_string()),
+                owner: owners.get(&id).cloned(),
+                key: id.1.to_string(),
+                waiters,
+                held_since,
+            })
+            .collect())
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use pretty_assertions::assert_matches;
+    use pretty_assertions::{assert_eq, assert_matches};
     use std::time::Duration;
     use tokio::time::sleep;
 
@@ -177,7 +359,7 @@ mod tests {
 
         match (&r1, &r2) {
             (Ok(()), Err(_)) | (Err(_), Ok(())) => (),
-            other => panic!("expected one Ok and one FailedToGetLock, got: {:?}", other),
+            other => panic!("expected one Ok and one FailedToGetLock, got: {other:?}"),
         }
 
         let r = StdCollectionLocker::with_locking(
@@ -243,7 +425,7 @@ mod tests {
 
         match (&r1, &r2) {
             (Ok(()), Err(_)) | (Err(_), Ok(())) => (),
-            other => panic!("expected one Ok and one FailedToGetLock, got: {:?}", other),
+            other => panic!("expected one Ok and one FailedToGetLock, got: {other:?}"),
         }
 
         let r = StdCollectionLocker::with_locking(
@@ -303,4 +485,422 @@ mod tests {
 
         Ok(())
     }
+
+    #[sqlx::test]
+    async fn lock_on_dedicated_pool(pool: SqlitePool) -> sqlx::Result<()> {
+        let lock_pool = SqlitePool::connect_with((*pool.connect_options()).clone()).await?;
+
+        let (r1, r2) = tokio::join!(
+            StdCollectionLocker::with_locking_on(
+                &lock_pool,
+                &pool,
+                "5Mytn8ui9N7TttU9NjzXFy7wknx3HpghrZTC7zIaJu7SEVpft9ev6spUdzZGKVi4",
+                Duration::from_secs(1).into(),
+                async |_| {
+                    sleep(Duration::from_secs(2)).await;
+                },
+            ),
+            StdCollectionLocker::with_locking_on(
+                &lock_pool,
+                &pool,
+                "5Mytn8ui9N7TttU9NjzXFy7wknx3HpghrZTC7zIaJu7SEVpft9ev6spUdzZGKVi4",
+                Duration::from_secs(1).into(),
+                async |_| {
+                    sleep(Duration::from_secs(2)).await;
+                },
+            )
+        );
+
+        match (&r1, &r2) {
+            (Ok(()), Err(_)) | (Err(_), Ok(())) => (),
+            other => panic!("expected one Ok and one FailedToGetLock, got: {other:?}"),
+        }
+
+        let r = StdCollectionLocker::with_locking(
+            &lock_pool,
+            "5Mytn8ui9N7TttU9NjzXFy7wknx3HpghrZTC7zIaJu7SEVpft9ev6spUdzZGKVi4",
+            Duration::from_secs(1).into(),
+            async |_| {},
+        )
+        .await;
+
+        assert_matches!(r, Ok(()));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn timeout_follows_paused_clock(pool: SqlitePool) -> sqlx::Result<()> {
+        let mut tx1 = pool.begin().await?;
+        let mut tx2 = pool.begin().await?;
+
+        tokio::time::pause();
+        let started = std::time::Instant::now();
+
+        let r = StdCollectionLocker::acquire(
+            &pool,
+            &mut tx1,
+            "6hGZIv4Fy5B0xJCqf08CfbLFwWK5HyFJUt1DbRXZtPFlU4ifiYb83blj8fUA2q8X",
+            None,
+        )
+        .await;
+        assert_matches!(r, Ok(()));
+
+        let r = StdCollectionLocker::acquire(
+            &pool,
+            &mut tx2,
+            "6hGZIv4Fy5B0xJCqf08CfbLFwWK5HyFJUt1DbRXZtPFlU4ifiYb83blj8fUA2q8X",
+            Duration::from_secs(3600).into(),
+        )
+        .await;
+        assert_matches!(r, Err(Error::FailedToGetLock(_)));
+        assert!(started.elapsed() < Duration::from_secs(3600));
+
+        let r = StdCollectionLocker::release(
+            &pool,
+            &mut tx1,
+            "6hGZIv4Fy5B0xJCqf08CfbLFwWK5HyFJUt1DbRXZtPFlU4ifiYb83blj8fUA2q8X",
+        )
+        .await;
+        assert_matches!(r, Ok(()));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn timeout_follows_custom_clock(pool: SqlitePool) -> sqlx::Result<()> {
+        struct ImmediateClock;
+
+        impl Clock for ImmediateClock {
+            fn now() -> std::time::Instant {
+                std::time::Instant::now()
+            }
+
+            fn sleep(_duration: Duration) -> impl Future<Output = ()> + Send {
+                std::future::ready(())
+            }
+        }
+
+        type ImmediateLocker = StdCollectionLockerWith<sqlx::Sqlite, ImmediateClock>;
+
+        let key = "JK0Er2BYdobmTbEuOiEP8ScEC5Xr2sLh0VhDorzZwC2E0UgERAVSGu4zjz0X7zJ9";
+        let mut tx1 = pool.begin().await?;
+        let mut tx2 = pool.begin().await?;
+        assert_matches!(
+            ImmediateLocker::acquire(&pool, &mut tx1, key, None).await,
+            Ok(())
+        );
+
+        // 1 時間の待ちを、すぐ終わる時計がすぐに打ち切る
+        let started = std::time::Instant::now();
+        let r =
+            ImmediateLocker::acquire(&pool, &mut tx2, key, Duration::from_secs(3600).into()).await;
+        assert_matches!(r, Err(Error::FailedToGetLock(_)));
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        assert_matches!(ImmediateLocker::release(&pool, &mut tx1, key).await, Ok(()));
+
+        Ok(())
+    }
+
+    async fn next_event_for(events: &mut crate::LockEvents, key: &str) -> crate::LockEvent {
+        loop {
+            let event = events.recv().await.unwrap();
+            if event.key() == key {
+                return event;
+            }
+        }
+    }
+
+    #[sqlx::test]
+    async fn events_follow_the_lock_lifecycle(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "sJ0mVZr3Un8qXb2oQfDhW7yLkT4eCgA9iPzRtu5N6wMdE1xKjYHvBsa2FlOcGhTy";
+        let mut events = StdCollectionLocker::<sqlx::Sqlite>::subscribe_events();
+
+        let r = StdCollectionLocker::with_locking(&pool, key, None, async |_| {}).await;
+        assert_matches!(r, Ok(()));
+
+        assert_matches!(
+            next_event_for(&mut events, key).await,
+            crate::LockEvent::Requested {
+                backend: "std-collection",
+                ..
+            }
+        );
+        assert_matches!(
+            next_event_for(&mut events, key).await,
+            crate::LockEvent::Acquired { .. }
+        );
+        assert_matches!(
+            next_event_for(&mut events, key).await,
+            crate::LockEvent::Released { .. }
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn panic_while_locked_is_reported_as_poisoned(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Pq7LxR2vNc9tYb4mKe1sWa6uHd3oJg8fZi5nCr0yTl2kVxMb7qEwAz4jUh9sDpGo";
+        let mut events = StdCollectionLocker::<sqlx::Sqlite>::subscribe_events();
+
+        // panic をタスクの境界で受け止める
+        let r = tokio::task::LocalSet::new()
+            .run_until(async {
+                tokio::task::spawn_local(async move {
+                    let _ = StdCollectionLocker::with_locking(&pool, key, None, async |_| {
+                        panic!("boom");
+                    })
+                    .await;
+                })
+                .await
+            })
+            .await;
+        assert!(r.unwrap_err().is_panic());
+
+        assert_matches!(
+            next_event_for(&mut events, key).await,
+            crate::LockEvent::Requested { .. }
+        );
+        assert_matches!(
+            next_event_for(&mut events, key).await,
+            crate::LockEvent::Acquired { .. }
+        );
+        assert_matches!(
+            next_event_for(&mut events, key).await,
+            crate::LockEvent::Poisoned { .. }
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn snapshot_lists_holders_and_waiters(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Wn4Rt6Yu8Io0Pa2Sd4Fg6Hj8Kl0Zx2Cv4Bn6Mq8We0Rt2Yu4Io6Pa8Sd0Fg2Hj4K";
+
+        let (r1, r2, locks) = tokio::join!(
+            StdCollectionLocker::with_locking(&pool, key, None, async |_| {
+                sleep(Duration::from_millis(500)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                StdCollectionLocker::with_locking(
+                    &pool,
+                    key,
+                    Duration::from_secs(1).into(),
+                    async |_| {},
+                )
+                .await
+            },
+            async {
+                sleep(Duration::from_millis(300)).await;
+                StdCollectionLocker::<sqlx::Sqlite>::list_locks(&pool).await
+            }
+        );
+
+        assert_matches!(r1, Ok(()));
+        assert_matches!(r2, Ok(()));
+        let locks = locks.unwrap();
+        let lock = locks.iter().find(|lock| lock.key == key).unwrap();
+        assert_eq!(lock.waiters, 1);
+        assert_matches!(lock.holder, Some(_));
+        assert_matches!(lock.held_since, Some(_));
+
+        assert!(
+            StdCollectionLocker::<sqlx::Sqlite>::snapshot()
+                .iter()
+                .all(|status| status.key != key)
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn probably_locked_through_the_client(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Ph3Qi5Rj7Sk9Tl1Um3Vn5Wo7Xp9Yq1Zr3As5Bt7Cu9Dv1Ew3Fx5Gy7Hz9Ia1Jb3K";
+        let client =
+            LockClient::<StdCollectionLocker<sqlx::Sqlite>>::new(pool.clone()).namespace("tenant");
+
+        let mut seen = None;
+        client
+            .with_locking(key, None, async |_| {
+                seen = Some((
+                    client.is_probably_locked(key),
+                    StdCollectionLocker::is_probably_locked(&pool, &format!("tenant:{key}")),
+                    StdCollectionLocker::is_probably_locked(&pool, key),
+                ));
+            })
+            .await
+            .unwrap();
+        // 名前空間を付けたキーだけが取られている
+        assert_eq!(seen, Some((true, true, false)));
+        assert!(!client.is_probably_locked(key));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    #[sqlx::test]
+    async fn snapshot_json_lists_held_keys(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Jx8Kc2Lv4Mb6Nn8Bq0Wv2Ec4Rx6Tz8Ya0Us2Id4Of6Pg8Ah0Sj2Dk4Fl6Gz8Hx0J";
+
+        let (r, json) = tokio::join!(
+            StdCollectionLocker::with_locking(&pool, key, None, async |_| {
+                sleep(Duration::from_millis(300)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                StdCollectionLocker::<sqlx::Sqlite>::snapshot_json()
+            }
+        );
+
+        assert_matches!(r, Ok(()));
+        let statuses: Vec<serde_json::Value> = serde_json::from_str(&json).unwrap();
+        let status = statuses.iter().find(|status| status["key"] == key).unwrap();
+        assert_eq!(status["waiters"], 0);
+        assert!(status["held_since"].is_object());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "macros")]
+    #[sqlx::test]
+    async fn locked_fn_runs_under_the_lock(pool: SqlitePool) -> sqlx::Result<()> {
+        #[crate::locked(
+            locker = StdCollectionLocker<sqlx::Sqlite>,
+            key = "Vb1Nm3Qa5Ws7Ed9Rf1Tg3Yh5Uj7Ik9Ol1Pz3Xc5Vb7Nm9Qa1Ws3Ed5Rf7Tg9Yh{id}",
+            timeout = "2s",
+            pool = pool,
+            tx = tx,
+        )]
+        async fn double(pool: &SqlitePool, id: u8, n: i64) -> crate::Result<i64> {
+            let doubled: i64 = sqlx::query_scalar("SELECT ? * 2")
+                .bind(n)
+                .fetch_one(&mut **tx)
+                .await?;
+            sleep(Duration::from_millis(300)).await;
+            Ok(doubled)
+        }
+
+        let started = std::time::Instant::now();
+        let (r1, r2) = tokio::join!(double(&pool, 1, 2), double(&pool, 1, 3));
+
+        assert_matches!(r1, Ok(4));
+        assert_matches!(r2, Ok(6));
+        assert!(started.elapsed() >= Duration::from_millis(600));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "async-io")]
+    #[test]
+    fn waits_on_async_io_clock_without_a_tokio_runtime() {
+        type Locker = StdCollectionLockerWith<sqlx::Sqlite, crate::AsyncIoClock>;
+        let key = "Hq2Wn4Em6Rb8Tv0Yc2Ux4Iz6Oa8Ps0Ad2Sf4Dg6Fh8Gj0Hk2Jl4Kq6Lw8Ze0Xr2C";
+
+        async_io::block_on(async {
+            let pool = SqlitePool::connect_with(
+                sqlx::sqlite::SqliteConnectOptions::new()
+                    .filename(std::env::temp_dir().join("rusty_ad_lock_async_io.db"))
+                    .create_if_missing(true),
+            )
+            .await
+            .unwrap();
+
+            let started = std::time::Instant::now();
+            let r = Locker::with_locking(&pool, key, None, async |_| {
+                let nested = Locker::with_locking(
+                    &pool,
+                    key,
+                    Duration::from_millis(100).into(),
+                    async |_| {},
+                )
+                .await;
+                assert_matches!(nested, Err(Error::FailedToGetLock(_)));
+            })
+            .await;
+
+            assert_matches!(r, Ok(()));
+            assert!(started.elapsed() >= Duration::from_millis(100));
+        });
+    }
+
+    #[sqlx::test]
+    async fn scope_buckets_the_keys(pool: SqlitePool) -> sqlx::Result<()> {
+        type PerPool = StdCollectionLockerWith<sqlx::Sqlite, DefaultClock, PerPoolScope>;
+        type Global = StdCollectionLockerWith<sqlx::Sqlite, DefaultClock, GlobalScope>;
+        let key = "Xe5Rc8Tv1Yb4Un7Im0Ko3Lp6Aq9Sw2De5Fr8Gt1Hy4Ju7Ki0Lo3Pz6Xa9Sc2Dv5F";
+        let same_url = SqlitePool::connect_with((*pool.connect_options()).clone()).await?;
+        let other_url = SqlitePool::connect("sqlite::memory:").await?;
+
+        let (per_pool, clone, global, across_urls) = tokio::join!(
+            PerPool::with_locking(&pool, key, None, async |_| {
+                sleep(Duration::from_millis(300)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                let same_pool = pool.clone();
+                (
+                    PerPool::with_locking(&same_pool, key, None, async |_| {}).await,
+                    PerPool::with_locking(&same_url, key, None, async |_| {}).await,
+                )
+            },
+            async {
+                sleep(Duration::from_millis(100)).await;
+                Global::with_locking(&other_url, key, None, async |_| {
+                    sleep(Duration::from_millis(300)).await;
+                })
+                .await
+            },
+            async {
+                sleep(Duration::from_millis(200)).await;
+                Global::with_locking(&pool, key, None, async |_| {}).await
+            }
+        );
+
+        assert_matches!(per_pool, Ok(()));
+        assert_matches!(clone, (Err(Error::FailedToGetLock(_)), Ok(())));
+        assert_matches!(global, Ok(()));
+        assert_matches!(across_urls, Err(Error::FailedToGetLock(_)));
+        assert_matches!(
+            Global::with_locking(&pool, key, None, async |_| {}).await,
+            Ok(())
+        );
+        assert_eq!(
+            Global::snapshot()
+                .into_iter()
+                .filter(|status| status.key == key)
+                .count(),
+            0
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn pool_urls_are_built_once_per_pool(pool: SqlitePool) -> sqlx::Result<()> {
+        // 複製は同じ URL を使い回す
+        let url = pool_url(&pool);
+        assert!(Arc::ptr_eq(&url, &pool_url(&pool.clone())));
+
+        let other = SqlitePool::connect_lazy_with((*pool.connect_options()).clone());
+        let other_url = pool_url(&other);
+        assert_eq!(other_url, url);
+        assert!(!Arc::ptr_eq(&other_url, &url));
+
+        // 捨てたプールの分は次に覚えるときに片付く
+        let address = Arc::as_ptr(&other.connect_options()) as usize;
+        drop(other);
+        pool_url(&SqlitePool::connect_lazy_with(
+            (*pool.connect_options()).clone(),
+        ));
+        assert!(
+            URLS.lock()
+                .unwrap()
+                .get(&address)
+                .is_none_or(|(options, _)| options.strong_count() > 0)
+        );
+
+        Ok(())
+    }
 }
@@ -0,0 +1,37 @@
+/// How [`crate::StdCollectionLockerWith`] buckets its keys, only pools of the same bucket
+/// exclude each other.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scope {
+    /// every pool of the process shares the keys
+    Global,
+    /// pools connected to the same URL share the keys
+    PerUrl,
+    /// each pool and its clones have keys of their own, e.g. to isolate in-memory tests
+    PerPool,
+}
+
+/// Type-level [`Scope`] of a [`crate::StdCollectionLockerWith`].
+pub trait KeyScope {
+    const SCOPE: Scope;
+}
+
+/// [`Scope::Global`]
+pub struct GlobalScope;
+
+/// [`Scope::PerUrl`], the default
+pub struct PerUrlScope;
+
+/// [`Scope::PerPool`]
+pub struct PerPoolScope;
+
+impl KeyScope for GlobalScope {
+    const SCOPE: Scope = Scope::Global;
+}
+
+impl KeyScope for PerUrlScope {
+    const SCOPE: Scope = Scope::PerUrl;
+}
+
+impl KeyScope for PerPoolScope {
+    const SCOPE: Scope = Scope::PerPool;
+}
@@ -0,0 +1,260 @@
+use std::{
+    collections::HashMap,
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+
+use super::KeyedMutex;
+use crate::{Capabilities, Error, Locker, Result};
+
+/// Coordinator holding the keys of the [`SocketLocker`]s of one host, served on a Unix socket.
+///
+/// each lock is held on a connection of its own: `LOCK <timeout_ms> <key>` answered `OK` or
+/// `BUSY`, then `UNLOCK` answered `OK`. a key is released when its connection closes too, so the
+/// keys of a crashed process don't stay held.
+///
+/// ```ignore
+/// // in the supervisor of a prefork server
+/// tokio::spawn(SocketCoordinator::bind("/run/app/locks.sock")?.serve());
+/// ```
+pub struct SocketCoordinator {
+    listener: UnixListener,
+    keys: Arc<KeyedMutex<String>>,
+}
+
+impl SocketCoordinator {
+    /// listen on `path`, replacing the socket file a stopped coordinator left there
+    ///
+    /// fails if a coordinator still answers on it.
+    pub fn bind(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        if path.exists() {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(std::io::Error::from(std::io::ErrorKind::AddrInUse).into());
+            }
+            std::fs::remove_file(path)?;
+        }
+
+        Ok(Self {
+            listener: UnixListener::bind(path)?,
+            keys: Arc::new(KeyedMutex::new()),
+        })
+    }
+
+    /// accept connections until the future is dropped
+    pub async fn serve(self) -> Result<()> {
+        loop {
+            let (stream, _) = self.listener.accept().await?;
+            let keys = Arc::clone(&self.keys);
+            tokio::spawn(async move {
+                // 接続が切れたら持っていたキーは解放される
+                let _ = serve_connection(stream, &keys).await;
+            });
+        }
+    }
+}
+
+async fn serve_connection(stream: UnixStream, keys: &KeyedMutex<String>) -> std::io::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    let mut held = None;
+
+    while let Some(line) = lines.next_line().await? {
+        let reply = match line.split_once(' ') {
+            Some(("LOCK", rest)) if held.is_none() => {
+                let Some((timeout_ms, key)) = rest.split_once(' ') else {
+                    break;
+                };
+                let Ok(timeout_ms) = timeout_ms.parse() else {
+                    break;
+                };
+
+                held = match timeout_ms {
+                    0 => keys.try_lock(key.to_owned()),
+                    ms => {
+                        keys.lock_timeout(key.to_owned(), Duration::from_millis(ms))
+                            .await
+                    }
+                };
+                if held.is_some() { "OK\n" } else { "BUSY\n" }
+            }
+            None if line == "UNLOCK" => {
+                held = None;
+                "OK\n"
+            }
+            // 知らない命令が来たら切って解放する
+            _ => break,
+        };
+        write.write_all(reply.as_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+/// [`SocketCoordinator`] that a [`SocketLocker`] holds its keys on.
+pub trait SocketEndpoint: Send + Sync + 'static {
+    /// path of the socket of the coordinator
+    fn path() -> PathBuf;
+}
+
+/// Locker holding its keys on the [`SocketCoordinator`] of `E`, excluding the other processes of
+/// the host without a round-trip to the database.
+///
+/// the pool is only used for the closures' transactions. if the coordinator stops, the keys it
+/// held are lost without the closures noticing, run it in a process that outlives the workers.
+///
+/// ```ignore
+/// struct Locks;
+///
+/// impl SocketEndpoint for Locks {
+///     fn path() -> PathBuf {
+///         "/run/app/locks.sock".into()
+///     }
+/// }
+///
+/// let r = SocketLocker::<Postgres, Locks>::with_locking(&pool, "key", None, async |_| {}).await;
+/// ```
+pub struct SocketLocker<D: sqlx::Database, E: SocketEndpoint> {
+    _marker: PhantomData<(D, E)>,
+}
+
+// 持っているロックの接続。(エンドポイント, キー) ごとに、持てるのはどれか 1 つ
+static HELD: LazyLock<Mutex<HashMap<(&'static str, String), UnixStream>>> =
+    LazyLock::new(Mutex::default);
+
+// 改行で命令が切れないように、% と改行を逃がす
+fn escape(key: &str) -> String {
+    key.replace('%', "%25").replace('\n', "%0A")
+}
+
+async fn request(stream: &mut UnixStream, line: &str) -> std::io::Result<String> {
+    stream.write_all(line.as_bytes()).await?;
+
+    // 応答は 1 行だけなので、読みすぎないように 1 バイトずつ読む
+    let mut reply = Vec::new();
+    let mut reader = BufReader::with_capacity(1, stream);
+    reader.read_until(b'\n', &mut reply).await?;
+    Ok(String::from_utf8_lossy(&reply).into_owned())
+}
+
+impl<D: sqlx::Database, E: SocketEndpoint> Locker for SocketLocker<D, E> {
+    type DB = D;
+
+    const NAME: &'static str = "unix-socket";
+
+    fn capabilities() -> Capabilities {
+        Capabilities {
+            sub_second_timeouts: true,
+            ..Capabilities::default()
+        }
+    }
+
+    async fn acquire(
+        _pool: &sqlx::Pool<Self::DB>,
+        _tx: &mut sqlx::Transaction<'static, Self::DB>,
+        key: &str,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        let mut stream = UnixStream::connect(E::path()).await?;
+        let timeout_ms = timeout.map_or(0, |timeout| timeout.as_millis().max(1) as u64);
+
+        let reply = request(&mut stream, &format!("LOCK {timeout_ms} {}\n", escape(key))).await?;
+        match reply.as_str() {
+            "OK\n" => {}
+            "BUSY\n" => return Err(Error::FailedToGetLock(key.to_owned())),
+            _ => return Err(std::io::Error::from(std::io::ErrorKind::InvalidData).into()),
+        }
+
+        HELD.lock()
+            .unwrap()
+            .insert((std::any::type_name::<E>(), key.to_owned()), stream);
+
+        Ok(())
+    }
+
+    async fn release(
+        _pool: &sqlx::Pool<Self::DB>,
+        _tx: &mut sqlx::Transaction<'static, Self::DB>,
+        key: &str,
+    ) -> Result<()> {
+        let held = HELD
+            .lock()
+            .unwrap()
+            .remove(&(std::any::type_name::<E>(), key.to_owned()));
+        let Some(mut stream) = held else {
+            return Ok(());
+        };
+
+        // 応答を待たずに切っても解放されるが、次の取得と競らないように待つ
+        request(&mut stream, "UNLOCK\n").await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_matches;
+    use tokio::time::sleep;
+
+    use super::*;
+
+    use sqlx::{Sqlite, SqlitePool};
+
+    const SOCKET: &str = "/tmp/rusty_ad_lock-socket-locker-test.sock";
+
+    struct Coordinator;
+
+    impl SocketEndpoint for Coordinator {
+        fn path() -> PathBuf {
+            SOCKET.into()
+        }
+    }
+
+    type Socket = SocketLocker<Sqlite, Coordinator>;
+
+    #[sqlx::test]
+    async fn keys_exclude_each_other_across_connections(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Us7Nx9Ck1Dl3Em5Fn7Go9Hp1Iq3Jr5Ks7Lt9Mu1Nv3Ow5Px7Qy9Rz1Sa3Tb5Uc7V\nline";
+        let coordinator = tokio::spawn(SocketCoordinator::bind(SOCKET).unwrap().serve());
+        assert_matches!(SocketCoordinator::bind(SOCKET).map(drop), Err(Error::Io(_)));
+
+        let (r1, r2, r3) = tokio::join!(
+            Socket::with_locking(&pool, key, None, async |_| {
+                sleep(Duration::from_millis(300)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                Socket::with_locking(&pool, key, None, async |_| {}).await
+            },
+            async {
+                sleep(Duration::from_millis(100)).await;
+                Socket::with_locking(&pool, key, Duration::from_secs(1).into(), async |_| {}).await
+            }
+        );
+        assert_matches!(r1, Ok(()));
+        assert_matches!(r2, Err(Error::FailedToGetLock(_)));
+        assert_matches!(r3, Ok(()));
+
+        // 持っている接続が切れると解放される
+        let mut stream = UnixStream::connect(SOCKET).await.unwrap();
+        let reply = request(&mut stream, &format!("LOCK 0 {}\n", escape(key))).await;
+        assert_matches!(reply.as_deref(), Ok("OK\n"));
+        drop(stream);
+        assert_matches!(
+            Socket::with_locking(&pool, key, Duration::from_secs(1).into(), async |_| {}).await,
+            Ok(())
+        );
+
+        coordinator.abort();
+
+        Ok(())
+    }
+}
@@ -0,0 +1,305 @@
+use std::{str::FromStr, time::Duration};
+
+use sqlx::pool::PoolOptions;
+
+use crate::{Error, LockClient, Locker, Result};
+
+/// Retries of an acquisition that failed because the key was held.
+///
+/// when they are all used up, the call fails with [`Error::RetriesExhausted`].
+///
+/// ```ignore
+/// // wait up to 2s each attempt, give up after 30s total
+/// let policy = RetryPolicy {
+///     attempts: u32::MAX,
+///     backoff: Duration::from_millis(100),
+///     attempt_timeout: Some(Duration::from_secs(2)),
+///     deadline: Some(Duration::from_secs(30)),
+/// };
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// how many times to try again, no retries by default
+    pub attempts: u32,
+    /// how long to wait before each retry
+    pub backoff: Duration,
+    /// how long each attempt waits for the key when the call is given no timeout, the
+    /// [`LockClient::default_timeout`] by default
+    pub attempt_timeout: Option<Duration>,
+    /// give up once this long has passed since the first attempt, even with attempts left
+    ///
+    /// the wait of the last attempt is cut short to end by then.
+    pub deadline: Option<Duration>,
+}
+
+impl RetryPolicy {
+    /// how long a caller refused under the policy should wait before calling again, e.g. for the
+    /// `Retry-After` header of a conflict response
+    ///
+    /// the backoff rounded up to whole seconds, a second without one.
+    pub fn retry_after(&self) -> Duration {
+        Duration::from_secs(self.backoff.as_millis().div_ceil(1000).max(1) as u64)
+    }
+}
+
+/// Locking configuration of a deployment, see [`Locker::from_config`].
+///
+/// ```ignore
+/// // RUSTY_AD_LOCK_DATABASE_URL=postgres://localhost/app RUSTY_AD_LOCK_TIMEOUT_MS=500
+/// let locker = PostgresLocker::from_config(&LockConfig::from_env()?).await?;
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LockConfig {
+    /// [`Locker::NAME`] of the backend to use, e.g. `mysql` or `postgres`
+    pub backend: Option<String>,
+    /// URL of the database the pool connects to
+    pub database_url: Option<String>,
+    /// URL of the primary the lock sessions connect to, see [`LockClient::lock_pool`]
+    ///
+    /// for a `database_url` load-balanced across read replicas, where the locks would mean nothing.
+    pub lock_database_url: Option<String>,
+    /// see [`LockClient::default_timeout`]
+    pub timeout: Option<Duration>,
+    /// see [`LockClient::retry`]
+    pub retry: RetryPolicy,
+    /// see [`LockClient::namespace`]
+    pub namespace: Option<String>,
+    /// see [`LockClient::quota`]
+    pub quota: Option<usize>,
+    /// maximum connections of the pool
+    pub max_connections: Option<u32>,
+    /// connections the pool keeps open
+    pub min_connections: Option<u32>,
+    /// how long to wait for a connection of the pool
+    pub acquire_timeout: Option<Duration>,
+}
+
+impl LockConfig {
+    /// read the configuration from the `RUSTY_AD_LOCK_` variables
+    ///
+    /// `BACKEND`, `DATABASE_URL`, `LOCK_DATABASE_URL`, `TIMEOUT_MS`, `RETRY_ATTEMPTS`,
+    /// `RETRY_BACKOFF_MS`, `RETRY_ATTEMPT_TIMEOUT_MS`, `RETRY_DEADLINE_MS`, `NAMESPACE`, `QUOTA`,
+    /// `MAX_CONNECTIONS`, `MIN_CONNECTIONS` and `ACQUIRE_TIMEOUT_MS`, all optional.
+    pub fn from_env() -> Result<Self> {
+        Self::from_env_with_prefix("RUSTY_AD_LOCK_")
+    }
+
+    /// read the configuration from the variables of [`LockConfig::from_env`] prefixed with `prefix`
+    pub fn from_env_with_prefix(prefix: &str) -> Result<Self> {
+        Self::from_vars(prefix, |name| std::env::var(name).ok())
+    }
+
+    fn from_vars(prefix: &str, var: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        // 空の値は設定されていないものとして扱う
+        let get = |name: &str| {
+            let name = format!("{prefix}{name}");
+            var(&name)
+                .filter(|value| !value.is_empty())
+                .map(|value| (name, value))
+        };
+        let millis = |name: &str| -> Result<Option<Duration>> {
+            Ok(parse::<u64>(get(name))?.map(Duration::from_millis))
+        };
+
+        Ok(Self {
+            backend: get("BACKEND").map(|(_, value)| value),
+            database_url: get("DATABASE_URL").map(|(_, value)| value),
+            lock_database_url: get("LOCK_DATABASE_URL").map(|(_, value)| value),
+            timeout: millis("TIMEOUT_MS")?,
+            retry: RetryPolicy {
+                attempts: parse(get("RETRY_ATTEMPTS"))?.unwrap_or_default(),
+                backoff: millis("RETRY_BACKOFF_MS")?.unwrap_or_default(),
+                attempt_timeout: millis("RETRY_ATTEMPT_TIMEOUT_MS")?,
+                deadline: millis("RETRY_DEADLINE_MS")?,
+            },
+            namespace: get("NAMESPACE").map(|(_, value)| value),
+            quota: parse(get("QUOTA"))?,
+            max_connections: parse(get("MAX_CONNECTIONS"))?,
+            min_connections: parse(get("MIN_CONNECTIONS"))?,
+            acquire_timeout: millis("ACQUIRE_TIMEOUT_MS")?,
+        })
+    }
+
+    /// options of a pool with the limits of the configuration
+    pub fn pool_options<DB: sqlx::Database>(&self) -> PoolOptions<DB> {
+        let mut options = PoolOptions::new();
+        if let Some(max) = self.max_connections {
+            options = options.max_connections(max);
+        }
+        if let Some(min) = self.min_connections {
+            options = options.min_connections(min);
+        }
+        if let Some(timeout) = self.acquire_timeout {
+            options = options.acquire_timeout(timeout);
+        }
+        options
+    }
+
+    /// apply the timeout, the retries, the namespace and the quota to `client`
+    pub fn apply<L: Locker>(&self, mut client: LockClient<L>) -> LockClient<L> {
+        if let Some(timeout) = self.timeout {
+            client = client.default_timeout(timeout);
+        }
+        if let Some(namespace) = &self.namespace {
+            client = client.namespace(namespace.clone());
+        }
+        if let Some(quota) = self.quota {
+            client = client.quota(quota);
+        }
+        client.retry(self.retry)
+    }
+}
+
+// (変数名, 値) を整数として読む
+fn parse<T: FromStr>(var: Option<(String, String)>) -> Result<Option<T>> {
+    var.map(|(name, value)| {
+        value.parse().map_err(|_| {
+            Error::InvalidConfig(format!("{name} must be a non-negative integer: {value}"))
+        })
+    })
+    .transpose()
+}
+
+// Locker::from_config の本体
+pub(crate) async fn connect<L: Locker>(config: &LockConfig) -> Result<LockClient<L>> {
+    if let Some(backend) = config
+        .backend
+        .as_deref()
+        .filter(|backend| *backend != L::NAME)
+    {
+        return Err(Error::InvalidConfig(format!(
+            "the backend is {backend}, not {}",
+            L::NAME
+        )));
+    }
+    let Some(url) = &config.database_url else {
+        return Err(Error::InvalidConfig(
+            "the database URL is not set".to_owned(),
+        ));
+    };
+
+    let pool = config.pool_options::<L::DB>().connect(url).await?;
+    let mut client = LockClient::new(pool);
+    // ロックの SQL だけをプライマリに送る
+    if let Some(lock_url) = &config.lock_database_url {
+        let lock_pool = config.pool_options::<L::DB>().connect(lock_url).await?;
+        client = client.lock_pool(lock_pool);
+    }
+
+    Ok(config.apply(client))
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::{assert_eq, assert_matches};
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn from_vars(vars: &[(&str, &str)]) -> Result<LockConfig> {
+        let vars: HashMap<_, _> = vars.iter().copied().collect();
+        LockConfig::from_vars("APP_LOCK_", |name| vars.get(name).map(|v| v.to_string()))
+    }
+
+    #[test]
+    fn variables_are_read_with_the_prefix() {
+        let config = from_vars(&[
+            ("APP_LOCK_BACKEND", "postgres"),
+            ("APP_LOCK_DATABASE_URL", "postgres://localhost/app"),
+            ("APP_LOCK_LOCK_DATABASE_URL", "postgres://primary/app"),
+            ("APP_LOCK_TIMEOUT_MS", "500"),
+            ("APP_LOCK_RETRY_ATTEMPTS", "3"),
+            ("APP_LOCK_RETRY_BACKOFF_MS", "100"),
+            ("APP_LOCK_RETRY_DEADLINE_MS", "30000"),
+            ("APP_LOCK_NAMESPACE", "billing"),
+            ("APP_LOCK_QUOTA", "10"),
+            ("APP_LOCK_MAX_CONNECTIONS", "8"),
+            ("APP_LOCK_MIN_CONNECTIONS", ""),
+            ("RUSTY_AD_LOCK_ACQUIRE_TIMEOUT_MS", "1000"),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            config,
+            LockConfig {
+                backend: Some("postgres".to_owned()),
+                database_url: Some("postgres://localhost/app".to_owned()),
+                lock_database_url: Some("postgres://primary/app".to_owned()),
+                timeout: Some(Duration::from_millis(500)),
+                retry: RetryPolicy {
+                    attempts: 3,
+                    backoff: Duration::from_millis(100),
+                    attempt_timeout: None,
+                    deadline: Some(Duration::from_secs(30)),
+                },
+                namespace: Some("billing".to_owned()),
+                quota: Some(10),
+                max_connections: Some(8),
+                min_connections: None,
+                acquire_timeout: None,
+            }
+        );
+        assert_matches!(
+            from_vars(&[("APP_LOCK_TIMEOUT_MS", "1s")]),
+            Err(Error::InvalidConfig(_))
+        );
+    }
+
+    #[cfg(feature = "sqlx-std-collection")]
+    #[tokio::test]
+    async fn configured_client_namespaces_and_retries() {
+        use tokio::time::sleep;
+
+        use crate::StdCollectionLocker;
+        use sqlx::Sqlite;
+
+        type Locker = StdCollectionLocker<Sqlite>;
+        let key = "Vm3Lq5Kw7Jx9Hc1Gv3Fb5Dn7Sm9Aq1Zw3Xe5Cr7Vt9By1Nu3Mi5Ko7Lp9Qa1Ws3E";
+        let config = LockConfig {
+            backend: Some("std-collection".to_owned()),
+            database_url: Some(format!(
+                "sqlite://{}?mode=rwc",
+                std::env::temp_dir()
+                    .join("rusty_ad_lock_config.db")
+                    .display()
+            )),
+            retry: RetryPolicy {
+                attempts: 5,
+                backoff: Duration::from_millis(100),
+                ..RetryPolicy::default()
+            },
+            namespace: Some("billing".to_owned()),
+            ..LockConfig::default()
+        };
+        let client = Locker::from_config(&config).await.unwrap();
+        let namespaced = format!("billing:{key}");
+
+        let without_retry = client.clone().retry(RetryPolicy::default());
+
+        let (r1, r2, r3) = tokio::join!(
+            Locker::with_locking(client.pool(), &namespaced, None, async |_| {
+                sleep(Duration::from_millis(250)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(50)).await;
+                without_retry.with_locking(key, None, async |_| {}).await
+            },
+            async {
+                sleep(Duration::from_millis(50)).await;
+                client.with_locking(key, None, async |_| {}).await
+            }
+        );
+
+        assert_matches!(r1, Ok(()));
+        assert_matches!(r2, Err(Error::FailedToGetLock(_)));
+        assert_matches!(r3, Ok(()));
+        let mysql = LockConfig {
+            backend: Some("mysql".to_owned()),
+            ..config
+        };
+        assert_matches!(
+            Locker::from_config(&mysql).await.err(),
+            Some(Error::InvalidConfig(_))
+        );
+    }
+}
@@ -0,0 +1,202 @@
+//! Behavior every [`Locker`](crate::Locker) is expected to share, runnable against any backend.
+//!
+//! each check panics with what the backend did wrong, so third-party backends can run the suite
+//! from their own tests. the pool needs room for two connections, and keys of the form
+//! `rusty_ad_lock:conformance:*` are locked on it.
+//!
+//! ```ignore
+//! #[sqlx::test]
+//! async fn conforms(pool: PgPool) {
+//!     rusty_ad_lock::conformance::run::<MyLocker>(&pool).await;
+//! }
+//! ```
+
+use std::{
+    pin::pin,
+    task::Poll,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{Clock, DefaultClock, Error, Locker};
+
+// トランザクションの終わりで解放するバックエンドは、接続がプールに戻ってから解放する
+const RELEASE_DELAY: Duration = Duration::from_secs(1);
+
+/// run every check of the suite against `L`
+pub async fn run<L: Locker>(pool: &sqlx::Pool<L::DB>) {
+    mutual_exclusion::<L>(pool).await;
+    timeout::<L>(pool).await;
+    release_on_error::<L>(pool).await;
+    long_keys::<L>(pool).await;
+}
+
+/// a held key can't be acquired by another session, and can once it is released
+///
+/// the key may take up to a second to be free again, for backends releasing on the end of the
+/// transaction when the connection is back in the pool.
+pub async fn mutual_exclusion<L: Locker>(pool: &sqlx::Pool<L::DB>) {
+    let key = unique_key("mutual-exclusion");
+
+    let mut inner = None;
+    let outer = L::with_locking(pool, &key, None, async |_| {
+        inner = Some(L::with_locking(pool, &key, None, async |_| {}).await);
+    })
+    .await;
+    check::<L>(outer.is_ok(), "a free key can be acquired", &outer);
+    check::<L>(
+        matches!(inner, Some(Err(Error::FailedToGetLock(_)))),
+        "a held key fails with FailedToGetLock",
+        &inner,
+    );
+
+    let after = L::with_locking(pool, &key, RELEASE_DELAY.into(), async |_| {}).await;
+    check::<L>(
+        after.is_ok(),
+        "a released key can be acquired again",
+        &after,
+    );
+}
+
+/// an acquisition waits for its timeout before failing, and gets the key released meanwhile
+///
+/// the timeouts are whole seconds unless [`crate::Capabilities::sub_second_timeouts`].
+pub async fn timeout<L: Locker>(pool: &sqlx::Pool<L::DB>) {
+    let key = unique_key("timeout");
+    let timeout = if L::capabilities().sub_second_timeouts {
+        Duration::from_millis(300)
+    } else {
+        Duration::from_secs(1)
+    };
+
+    let mut inner = None;
+    let outer = L::with_locking(pool, &key, None, async |_| {
+        let start = Instant::now();
+        let r = L::with_locking(pool, &key, Some(timeout), async |_| {}).await;
+        inner = Some((r, start.elapsed()));
+    })
+    .await;
+    check::<L>(outer.is_ok(), "a free key can be acquired", &outer);
+    check::<L>(
+        matches!(
+            inner,
+            Some((Err(Error::FailedToGetLock(_)), waited)) if waited >= timeout.mul_f64(0.9)
+        ),
+        "a held key fails with FailedToGetLock once the timeout elapsed",
+        &inner,
+    );
+
+    let key = unique_key("timeout-release");
+    let (released, waiter) = join(
+        L::with_locking(pool, &key, None, async |_| {
+            DefaultClock::sleep(timeout / 3).await;
+        }),
+        async {
+            DefaultClock::sleep(timeout / 6).await;
+            L::with_locking(pool, &key, Some(timeout * 3), async |_| {}).await
+        },
+    )
+    .await;
+    check::<L>(released.is_ok(), "a free key can be acquired", &released);
+    check::<L>(
+        waiter.is_ok(),
+        "a key released within the timeout is acquired",
+        &waiter,
+    );
+}
+
+/// a closure failing leaves the key free
+pub async fn release_on_error<L: Locker>(pool: &sqlx::Pool<L::DB>) {
+    let key = unique_key("release-on-error");
+
+    let failed = L::with_locking(pool, &key, None, async |_| {
+        Err::<(), _>("the closure failed")
+    })
+    .await;
+    check::<L>(failed.is_ok(), "a free key can be acquired", &failed);
+
+    let after = L::with_locking(pool, &key, RELEASE_DELAY.into(), async |_| {}).await;
+    check::<L>(
+        after.is_ok(),
+        "a key whose closure failed can be acquired again",
+        &after,
+    );
+}
+
+/// keys over [`Locker::MAX_KEY_LEN`] are locked, without colliding with one another
+pub async fn long_keys<L: Locker>(pool: &sqlx::Pool<L::DB>) {
+    let base = unique_key("long-keys");
+    let len = L::MAX_KEY_LEN.unwrap_or(64).max(256);
+    let first = format!("{base:a<len$}1");
+    let second = format!("{base:a<len$}2");
+
+    let mut inner = None;
+    let outer = L::with_locking(pool, &first, None, async |_| {
+        inner = Some((
+            L::with_locking(pool, &first, None, async |_| {}).await,
+            L::with_locking(pool, &second, None, async |_| {}).await,
+        ));
+    })
+    .await;
+    check::<L>(outer.is_ok(), "a long key can be acquired", &outer);
+    check::<L>(
+        matches!(inner, Some((Err(Error::FailedToGetLock(_)), _))),
+        "a held long key fails with FailedToGetLock",
+        &inner,
+    );
+    check::<L>(
+        matches!(inner, Some((_, Ok(())))),
+        "long keys differing in their last byte don't collide",
+        &inner,
+    );
+}
+
+fn unique_key(check: &str) -> String {
+    // 同じデータベースで並んで走っても重ならないように
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!("rusty_ad_lock:conformance:{check}:{nanos}")
+}
+
+#[track_caller]
+fn check<L: Locker>(ok: bool, expected: &str, got: &impl std::fmt::Debug) {
+    assert!(ok, "{}: {expected}, got {got:?}", L::NAME);
+}
+
+async fn join<A: Future, B: Future>(a: A, b: B) -> (A::Output, B::Output) {
+    let (mut a, mut b) = (pin!(a), pin!(b));
+    let (mut a_out, mut b_out) = (None, None);
+    std::future::poll_fn(|cx| {
+        if a_out.is_none()
+            && let Poll::Ready(out) = a.as_mut().poll(cx)
+        {
+            a_out = Some(out);
+        }
+        if b_out.is_none()
+            && let Poll::Ready(out) = b.as_mut().poll(cx)
+        {
+            b_out = Some(out);
+        }
+        if a_out.is_some() && b_out.is_some() {
+            return Poll::Ready((a_out.take().unwrap(), b_out.take().unwrap()));
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+#[cfg(all(test, feature = "sqlx-std-collection", feature = "sqlx-sqlite"))]
+mod tests {
+    use super::*;
+
+    use crate::StdCollectionLocker;
+    use sqlx::{Sqlite, SqlitePool};
+
+    #[sqlx::test]
+    async fn collection_conforms(pool: SqlitePool) -> sqlx::Result<()> {
+        run::<StdCollectionLocker<Sqlite>>(&pool).await;
+
+        Ok(())
+    }
+}
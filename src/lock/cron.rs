@@ -0,0 +1,100 @@
+use tokio_cron_scheduler::Job;
+
+use crate::{JobRun, Locker, Result, ScheduledJobGuard};
+
+impl<L: Locker + 'static> ScheduledJobGuard<L>
+where
+    L::DB: Send,
+{
+    /// tokio-cron-scheduler job running `job` on the cron `schedule` under the guard
+    ///
+    /// the ticks finding the lock held follow the [`crate::ContentionPolicy`] of the guard and are
+    /// counted by [`ScheduledJobGuard::skipped`]. the errors of the lock are logged with
+    /// `tracing` when the feature is enabled, the tick is lost then.
+    ///
+    /// ```ignore
+    /// let settlement = ScheduledJobGuard::new(LockClient::<MySqlLocker>::new(pool), "daily-settlement")
+    ///     .policy(ContentionPolicy::Queue(Duration::from_secs(60)));
+    ///
+    /// scheduler.add(settlement.cron_job("0 0 2 * * *", settle)?).await?;
+    /// ```
+    pub fn cron_job<F, Fut>(self, schedule: &str, mut job: F) -> Result<Job>
+    where
+        F: FnMut() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        Ok(Job::new_async(schedule, move |_, _| {
+            let guard = self.clone();
+            let job = job();
+            Box::pin(async move {
+                match guard.run(job).await {
+                    Ok(JobRun::Ran(())) => {}
+                    Ok(JobRun::Skipped) => {
+                        #[cfg(feature = "tracing")]
+                        tracing::info!(job = &*guard.key, "another replica is running the job");
+                    }
+                    Err(_e) => {
+                        #[cfg(feature = "tracing")]
+                        tracing::error!(job = &*guard.key, error = %_e, "failed to lock the job");
+                    }
+                }
+            })
+        })?)
+    }
+}
+
+#[cfg(all(test, feature = "sqlx-std-collection"))]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+    use tokio::time::sleep;
+    use tokio_cron_scheduler::JobScheduler;
+
+    use super::*;
+
+    use crate::{ContentionPolicy, LockClient, StdCollectionLocker};
+    use sqlx::{Sqlite, SqlitePool};
+
+    #[sqlx::test]
+    async fn replicas_share_the_ticks(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Cr8Ds0Et2Fu4Gv6Hw8Ix0Jy2Kz4La6Mb8Nc0Od2Pe4Qf6Rg8Sh0Ti2Uj4Vk6Wl8X";
+        let client = LockClient::<StdCollectionLocker<Sqlite>>::new(pool);
+        let runs = Arc::new(Mutex::new(Vec::new()));
+        let mut scheduler = JobScheduler::new().await.unwrap();
+
+        // 同じ秒に 2 つのレプリカが起きるが、走るのはどちらか一方だけ
+        let guard = ScheduledJobGuard::new(client, key).policy(ContentionPolicy::Skip);
+        for replica in 0..2 {
+            let runs = Arc::clone(&runs);
+            let job = guard.clone().cron_job("* * * * * *", move || {
+                let runs = Arc::clone(&runs);
+                async move {
+                    runs.lock().unwrap().push(replica);
+                    sleep(Duration::from_millis(300)).await;
+                }
+            });
+            scheduler.add(job.unwrap()).await.unwrap();
+        }
+
+        scheduler.start().await.unwrap();
+        // 秒の境目で起きるので、止めるのは境目の間にする
+        let subsec = std::time::SystemTime::UNIX_EPOCH
+            .elapsed()
+            .unwrap()
+            .subsec_millis();
+        sleep(Duration::from_millis(
+            2000 + u64::from((1500 - subsec) % 1000),
+        ))
+        .await;
+        scheduler.shutdown().await.unwrap();
+
+        let ticks = runs.lock().unwrap().len() as u64;
+        assert!(ticks >= 2);
+        assert_eq!(guard.skipped(), ticks);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+use std::{sync::LazyLock, time::Duration};
+
+use tokio::sync::broadcast;
+
+/// Stage of a lock call, published to every [`LockEvents`] subscriber of the process.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize),
+    serde(tag = "event", rename_all = "snake_case")
+)]
+pub enum LockEvent {
+    /// an acquisition of `key` started
+    Requested { backend: &'static str, key: String },
+    /// `key` was acquired after waiting `wait`
+    Acquired {
+        backend: &'static str,
+        key: String,
+        wait: Duration,
+    },
+    /// the acquisition of `key` gave up after waiting `wait`
+    TimedOut {
+        backend: &'static str,
+        key: String,
+        wait: Duration,
+    },
+    /// `key` was released after being held for `hold`
+    Released {
+        backend: &'static str,
+        key: String,
+        hold: Duration,
+    },
+    /// the closure panicked while holding `key`
+    Poisoned { backend: &'static str, key: String },
+    /// the guard of `key` was dropped without being released, `key` is released in the background
+    DroppedUnreleased { backend: &'static str, key: String },
+}
+
+impl LockEvent {
+    /// key the event is about
+    pub fn key(&self) -> &str {
+        match self {
+            LockEvent::Requested { key, .. }
+            | LockEvent::Acquired { key, .. }
+            | LockEvent::TimedOut { key, .. }
+            | LockEvent::Released { key, .. }
+            | LockEvent::Poisoned { key, .. }
+            | LockEvent::DroppedUnreleased { key, .. } => key,
+        }
+    }
+}
+
+const EVENT_BUFFER_SIZE: usize = 256;
+
+static EVENTS: LazyLock<broadcast::Sender<LockEvent>> = LazyLock::new(|| {
+    let (sx, _rx) = broadcast::channel(EVENT_BUFFER_SIZE);
+    sx
+});
+
+/// Subscription to the lock events of the process, see [`crate::Locker::subscribe_events`].
+pub struct LockEvents {
+    rx: broadcast::Receiver<LockEvent>,
+}
+
+impl LockEvents {
+    pub(crate) fn subscribe() -> Self {
+        Self {
+            rx: EVENTS.subscribe(),
+        }
+    }
+
+    /// wait for the next event
+    ///
+    /// events dropped because this subscriber fell more than 256 events behind are skipped.
+    pub async fn recv(&mut self) -> Option<LockEvent> {
+        loop {
+            match self.rx.recv().await {
+                Ok(event) => return Some(event),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+// 購読者がいなければイベントを組み立てない
+pub(crate) fn emit(event: impl FnOnce() -> LockEvent) {
+    if EVENTS.receiver_count() > 0 {
+        // NOTE: エラーは受信者が0なことを表しているだけ
+        let _ = EVENTS.send(event());
+    }
+}
@@ -0,0 +1,303 @@
+//! C API over the lockers, declared in `include/rusty_ad_lock.h`.
+//!
+//! the calls block the calling thread and drive the lockers on [`super::blocking::runtime`].
+//! build the shared library with the backends it should connect to, e.g.
+//! `cargo rustc --release --features ffi,sqlx-mysql,sqlx-postgres,runtime-tokio-rustls --crate-type cdylib`.
+
+use std::{
+    cell::RefCell,
+    ffi::{CStr, CString, c_char, c_void},
+    ptr,
+    time::Duration,
+};
+
+use super::{
+    bindings::{HeldLock, Pool},
+    blocking::runtime,
+};
+use crate::{Error, Locker, Result};
+
+#[cfg(feature = "sqlx-mysql")]
+use crate::MySqlLocker;
+#[cfg(feature = "sqlx-postgres")]
+use crate::PostgresLocker;
+
+/// the call succeeded
+pub const RAL_OK: i32 = 0;
+/// the key could not be acquired within the timeout
+pub const RAL_FAILED_TO_GET_LOCK: i32 = 1;
+/// the call failed, see [`ral_last_error`]
+pub const RAL_ERROR: i32 = 2;
+/// a pointer was null or a string was not UTF-8
+pub const RAL_INVALID_ARGUMENT: i32 = 3;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: impl ToString) {
+    let message = message.to_string().replace('\0', "");
+    LAST_ERROR.set(CString::new(message).ok());
+}
+
+fn code(r: Result<()>) -> i32 {
+    match r {
+        Ok(()) => RAL_OK,
+        Err(e) => {
+            let code = match e {
+                Error::FailedToGetLock(_) | Error::RetriesExhausted { .. } => {
+                    RAL_FAILED_TO_GET_LOCK
+                }
+                _ => RAL_ERROR,
+            };
+            set_last_error(e);
+            code
+        }
+    }
+}
+
+fn invalid_argument(message: &str) -> i32 {
+    set_last_error(message);
+    RAL_INVALID_ARGUMENT
+}
+
+unsafe fn str_arg<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        return None;
+    }
+    unsafe { CStr::from_ptr(s) }.to_str().ok()
+}
+
+// 0 以下は待たずに失敗する None にする。PostgreSQL では lock_timeout = 0 が無制限になってしまう
+fn timeout(timeout_ms: i64) -> Option<Duration> {
+    u64::try_from(timeout_ms)
+        .ok()
+        .filter(|&ms| ms > 0)
+        .map(Duration::from_millis)
+}
+
+/// Connection pool of a MySQL or PostgreSQL database, opaque to C.
+pub struct RalPool(Pool);
+
+/// Key acquired by [`ral_acquire`], held until [`ral_release`], opaque to C.
+pub struct RalLock(HeldLock);
+
+/// connect to the mysql://, mariadb:// or postgres:// URL, returning NULL on failure
+///
+/// only the backends the library was built with are supported.
+///
+/// # Safety
+///
+/// `url` must be a NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ral_connect(url: *const c_char) -> *mut RalPool {
+    let Some(url) = (unsafe { str_arg(url) }) else {
+        set_last_error("url must be a UTF-8 string");
+        return ptr::null_mut();
+    };
+
+    let pool = runtime().block_on(Pool::connect(url));
+
+    match pool {
+        Ok(pool) => Box::into_raw(Box::new(RalPool(pool))),
+        Err(e) => {
+            set_last_error(e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// close the pool returned by [`ral_connect`]
+///
+/// # Safety
+///
+/// `pool` must be returned by [`ral_connect`] and not used afterwards.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ral_disconnect(pool: *mut RalPool) {
+    if pool.is_null() {
+        return;
+    }
+    let _enter = runtime().enter();
+    drop(unsafe { Box::from_raw(pool) });
+}
+
+/// acquire the key, storing the held lock to `*out`
+///
+/// a `timeout_ms` of 0 or less fails immediately if the key is held. returns [`RAL_OK`],
+/// [`RAL_FAILED_TO_GET_LOCK`], [`RAL_ERROR`] or [`RAL_INVALID_ARGUMENT`].
+///
+/// # Safety
+///
+/// `pool` must be returned by [`ral_connect`], `key` must be a NUL-terminated string and `out`
+/// must be valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ral_acquire(
+    pool: *const RalPool,
+    key: *const c_char,
+    timeout_ms: i64,
+    out: *mut *mut RalLock,
+) -> i32 {
+    let (Some(pool), Some(key)) = (unsafe { pool.as_ref() }, unsafe { str_arg(key) }) else {
+        return invalid_argument("pool and key must not be null, and key must be UTF-8");
+    };
+    if out.is_null() {
+        return invalid_argument("out must not be null");
+    }
+
+    let lock = runtime().block_on(pool.0.acquire(key, timeout(timeout_ms)));
+
+    code(lock.map(|lock| unsafe { *out = Box::into_raw(Box::new(RalLock(lock))) }))
+}
+
+/// release the lock returned by [`ral_acquire`]
+///
+/// # Safety
+///
+/// `lock` must be returned by [`ral_acquire`] and not used afterwards.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ral_release(lock: *mut RalLock) -> i32 {
+    if lock.is_null() {
+        return invalid_argument("lock must not be null");
+    }
+    let lock = unsafe { Box::from_raw(lock) };
+
+    code(runtime().block_on(lock.0.release()))
+}
+
+/// call `f(user_data)` on the calling thread while the key is locked
+///
+/// a `timeout_ms` of 0 or less fails immediately if the key is held.
+///
+/// # Safety
+///
+/// `pool` must be returned by [`ral_connect`], `key` must be a NUL-terminated string and `f`
+/// must be safe to call with `user_data`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ral_with_locking(
+    pool: *const RalPool,
+    key: *const c_char,
+    timeout_ms: i64,
+    f: Option<unsafe extern "C" fn(*mut c_void)>,
+    user_data: *mut c_void,
+) -> i32 {
+    let (Some(pool), Some(key), Some(f)) = (unsafe { pool.as_ref() }, unsafe { str_arg(key) }, f)
+    else {
+        return invalid_argument("pool, key and f must not be null, and key must be UTF-8");
+    };
+
+    let call = || unsafe { f(user_data) };
+    code(match &pool.0 {
+        #[cfg(feature = "sqlx-mysql")]
+        Pool::MySql(pool) => {
+            MySqlLocker::with_locking_blocking(pool, key, timeout(timeout_ms), call)
+        }
+        #[cfg(feature = "sqlx-postgres")]
+        Pool::Postgres(pool) => {
+            PostgresLocker::with_locking_blocking(pool, key, timeout(timeout_ms), call)
+        }
+    })
+}
+
+/// message of the last failure on this thread, NULL if nothing failed yet
+///
+/// the string is valid until the next failing call on the thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn ral_last_error() -> *const c_char {
+    LAST_ERROR.with_borrow(|message| message.as_ref().map_or(ptr::null(), |m| m.as_ptr()))
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn connect() -> *mut RalPool {
+        let url = CString::new(std::env::var("DATABASE_URL").unwrap()).unwrap();
+        let pool = unsafe { ral_connect(url.as_ptr()) };
+        assert!(!pool.is_null());
+        pool
+    }
+
+    #[test]
+    fn acquired_key_conflicts_until_released() {
+        let key = c"Uf3Yd5Ts7Ra9Qe1Pw3Oq5Nz7Mx9Lc1Kv3Jb5Hn7Gm9Fa1Ds3Sx5Ac7Zq9Ww1Ee3R";
+        let pool = connect();
+
+        unsafe {
+            let mut lock = ptr::null_mut();
+            assert_eq!(ral_acquire(pool, key.as_ptr(), -1, &mut lock), RAL_OK);
+
+            let mut other = ptr::null_mut();
+            assert_eq!(
+                ral_acquire(pool, key.as_ptr(), 100, &mut other),
+                RAL_FAILED_TO_GET_LOCK
+            );
+            assert!(other.is_null());
+            assert!(!ral_last_error().is_null());
+
+            assert_eq!(ral_release(lock), RAL_OK);
+            assert_eq!(ral_acquire(pool, key.as_ptr(), -1, &mut other), RAL_OK);
+            assert_eq!(ral_release(other), RAL_OK);
+
+            ral_disconnect(pool);
+        }
+    }
+
+    #[test]
+    fn callback_runs_while_the_key_is_locked() {
+        let key = c"Gk8Jh0Hg2Ff4Dd6Ss8Aa0Pp2Oo4Ii6Uu8Yy0Tt2Rr4Ee6Ww8Qq0Zz2Xx4Cc6Vv8B";
+        let pool = connect();
+
+        unsafe extern "C" fn count(user_data: *mut c_void) {
+            unsafe { *user_data.cast::<i32>() += 1 };
+        }
+
+        unsafe {
+            let mut n = 0;
+            let user_data = (&raw mut n).cast();
+            assert_eq!(
+                ral_with_locking(pool, key.as_ptr(), -1, Some(count), user_data),
+                RAL_OK
+            );
+            assert_eq!(n, 1);
+            assert_eq!(
+                ral_with_locking(pool, ptr::null(), -1, Some(count), user_data),
+                RAL_INVALID_ARGUMENT
+            );
+            assert_eq!(n, 1);
+
+            ral_disconnect(pool);
+        }
+    }
+
+    #[test]
+    fn zero_timeout_fails_immediately() {
+        let key = c"Zt4Mo6Fa8Il0Sn2Ow4Wa6It8Zr0Tb3Cd5Ef7Gh9Ij1Kl3Mn5Op7Qr9St1Uv3Wx5Y";
+        let pool = connect();
+
+        unsafe extern "C" fn unreachable(_: *mut c_void) {
+            panic!("the callback ran without the key");
+        }
+
+        unsafe {
+            let mut lock = ptr::null_mut();
+            assert_eq!(ral_acquire(pool, key.as_ptr(), -1, &mut lock), RAL_OK);
+
+            let started = std::time::Instant::now();
+            let mut other = ptr::null_mut();
+            assert_eq!(
+                ral_acquire(pool, key.as_ptr(), 0, &mut other),
+                RAL_FAILED_TO_GET_LOCK
+            );
+            assert_eq!(
+                ral_with_locking(pool, key.as_ptr(), 0, Some(unreachable), ptr::null_mut()),
+                RAL_FAILED_TO_GET_LOCK
+            );
+            assert!(started.elapsed() < Duration::from_secs(1));
+
+            assert_eq!(ral_release(lock), RAL_OK);
+            ral_disconnect(pool);
+        }
+    }
+}
@@ -0,0 +1,691 @@
+use std::{collections::HashMap, time::Duration};
+
+use super::client::Held;
+use crate::{Clock, DefaultClock, LockClient, LockHooks, Locker, Result};
+
+// ロックと、それを持っているセッションのトランザクション
+type Locked<'c, DB, C> = (Held<'c, DB, C>, sqlx::Transaction<'static, DB>);
+
+/// Lock held until [`LockGuard::release`], returned by [`LockClient::guard`].
+///
+/// unlike the closures of [`LockClient::with_locking`], the guard isn't cut off by a shutdown, it
+/// is waited for like a closure under [`crate::ShutdownPolicy::Wait`] whatever the policy: even
+/// under [`crate::ShutdownPolicy::Abort`], [`crate::ShutdownRegistry::shutdown`] doesn't return
+/// until the guard is released or dropped. release the guards of long jobs on the shutdown signal
+/// of the application.
+///
+/// ```ignore
+/// let mut guard = locker.guard("reindex", None).await?;
+/// for batch in batches {
+///     // the transaction of the guard is rolled back by yield_for, commit the work on its own
+///     let mut tx = pool.begin().await?;
+///     reindex(&mut tx, batch).await?;
+///     tx.commit().await?;
+///     guard = guard.yield_for(Duration::from_millis(100)).await?;
+/// }
+/// guard.release().await?;
+/// ```
+///
+/// the transaction only lives as long as the lock: it is borrowed from the guard, and
+/// [`LockGuard::release`] and [`LockGuard::yield_for`] consume the guard, so a query on the
+/// transaction of a released lock doesn't compile.
+///
+/// ```compile_fail
+/// # use rusty_ad_lock::{LockClient, StdCollectionLocker};
+/// # async fn f(client: LockClient<StdCollectionLocker<sqlx::Sqlite>>) -> rusty_ad_lock::Result<()> {
+/// let mut guard = client.guard("reindex", None).await?;
+/// let tx = guard.tx();
+/// guard.release().await?;
+/// sqlx::query("DELETE FROM jobs").execute(&mut **tx).await?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`LockGuard::lock_child`] takes finer-grained locks tied to the guard, released with it.
+///
+/// a guard dropped without [`LockGuard::release`] is released by a background task, with a
+/// [`crate::LockEvent::DroppedUnreleased`] warning. the error of that release can't be seen.
+/// outside a tokio runtime it runs on a thread of its own with the `async-io` feature, otherwise
+/// the session is closed instead.
+pub struct LockGuard<'c, L: Locker + 'static, C: Clock = DefaultClock> {
+    client: &'c LockClient<L, C>,
+    key: &'c str,
+    timeout: Option<Duration>,
+    // release と yield_for で取り出す。残っていれば Drop で解放する
+    locked: Option<Locked<'c, L::DB, C>>,
+    // lock_child で取った子。親より先に、後から取ったものから解放する
+    children: Vec<LockGuard<'c, L, C>>,
+}
+
+impl<'c, L: Locker + 'static, C: Clock> LockGuard<'c, L, C> {
+    pub(crate) async fn acquire(
+        client: &'c LockClient<L, C>,
+        key: &'c str,
+        timeout: Option<Duration>,
+    ) -> Result<Self> {
+        Self::acquire_hooked(client, key, timeout, LockHooks::new()).await
+    }
+
+    pub(crate) async fn acquire_hooked(
+        client: &'c LockClient<L, C>,
+        key: &'c str,
+        timeout: Option<Duration>,
+        mut hooks: LockHooks<'_>,
+    ) -> Result<Self> {
+        let locked = client.lock_hooked(key, timeout, &mut hooks).await?;
+
+        Ok(Self {
+            client,
+            key,
+            timeout,
+            locked: Some(locked),
+            children: Vec::new(),
+        })
+    }
+
+    fn locked(&mut self) -> &mut Locked<'c, L::DB, C> {
+        // 取り出すのは self を消費するメソッドだけ
+        self.locked.as_mut().unwrap()
+    }
+
+    /// key held by the guard
+    pub fn key(&self) -> &str {
+        self.key
+    }
+
+    /// whether another session held the key when it was acquired, so the guard had to wait or retry
+    ///
+    /// the client tries the key without waiting first, and only waits for it when that fails, so an
+    /// uncontended acquisition costs no extra round-trip. the rate of contended acquisitions is the
+    /// main signal of how close a lock is to its capacity.
+    pub fn contended(&self) -> bool {
+        self.locked
+            .as_ref()
+            .is_some_and(|(held, _)| held.contended())
+    }
+
+    /// time left until [`Locker::MAX_HOLD`] elapses, None if the backend has no max hold
+    pub fn remaining(&self) -> Option<Duration> {
+        self.locked.as_ref().and_then(|(held, _)| held.remaining())
+    }
+
+    /// push the max hold later by `by`, see [`Locker::extend_hold`]
+    ///
+    /// lets a long job decide to go on instead of checkpointing before the lease expires. fails
+    /// with [`crate::Error::LockLost`] if the server no longer holds the lease.
+    pub async fn extend(&mut self, by: Duration) -> Result<()> {
+        let backend_key = self.client.backend_key(self.key)?;
+        L::extend_hold(&backend_key, by).await?;
+        self.locked().0.extend(by);
+
+        Ok(())
+    }
+
+    /// transaction of the session holding the lock
+    ///
+    /// like that of [`LockClient::with_locking`], it isn't committed: it is rolled back when the
+    /// lock is released, by [`LockGuard::release`], [`LockGuard::yield_for`] or the drop.
+    pub fn tx(&mut self) -> &mut sqlx::Transaction<'static, L::DB> {
+        &mut self.locked().1
+    }
+
+    /// lock `key` as a child of the guard, waiting for it with the timeout the guard was acquired
+    /// with
+    ///
+    /// the children are released before the guard, the last acquired first, by
+    /// [`LockGuard::release`], [`LockGuard::yield_for`] and the drop of the guard, so a multi-step
+    /// job taking finer-grained locks as it goes can't leave one behind. `key` is locked as given,
+    /// prefix it with the key of the guard to keep the children of two jobs apart.
+    ///
+    /// ```ignore
+    /// let mut job = locker.guard("import:42", None).await?;
+    /// job.lock_child("import:42/step-1").await?;
+    /// load(job.tx()).await?;
+    /// job.release_child("import:42/step-1").await?;
+    /// job.lock_child("import:42/step-2").await?;
+    /// index(job.tx()).await?;
+    /// job.release().await?; // releases step-2 too
+    /// ```
+    pub async fn lock_child(&mut self, key: &'c str) -> Result<&mut LockGuard<'c, L, C>> {
+        let child = Self::acquire(self.client, key, self.timeout).await?;
+        self.children.push(child);
+        Ok(self.children.last_mut().unwrap())
+    }
+
+    /// release the last acquired child on `key` before the guard, if any
+    pub async fn release_child(&mut self, key: &str) -> Result<()> {
+        match self.children.iter().rposition(|child| child.key == key) {
+            Some(i) => Box::pin(self.children.remove(i).release()).await,
+            None => Ok(()),
+        }
+    }
+
+    // 子を後から取ったものから解放する。途中で失敗しても残りは必ず解放する
+    async fn release_children(&mut self) -> Result<()> {
+        let mut r = Ok(());
+        for child in std::mem::take(&mut self.children).into_iter().rev() {
+            // 子も子を持てるので、再帰する Future は箱に入れる
+            r = r.and(Box::pin(child.release()).await);
+        }
+        r
+    }
+
+    /// release the lock, sleep for `duration`, and acquire it again with the timeout it was first
+    /// acquired with
+    ///
+    /// lets the waiters of the key in at a safe checkpoint. the transaction of [`LockGuard::tx`] is
+    /// rolled back with the release and a new one begun with the lock, so the work to keep should
+    /// be committed on a transaction of its own before. fails if it can't be acquired again. the
+    /// children are released first, and not acquired again.
+    pub async fn yield_for(mut self, duration: Duration) -> Result<Self> {
+        self.release_children().await?;
+        let (client, key, timeout) = (self.client, self.key, self.timeout);
+        let (held, mut tx) = self.locked.take().unwrap();
+
+        client.unlock(held, &mut tx).await?;
+        drop(tx);
+        C::sleep(duration).await;
+
+        Self::acquire(client, key, timeout).await
+    }
+
+    /// release the children, then the lock, failing with the first error of the backend's
+    /// release statement
+    pub async fn release(mut self) -> Result<()> {
+        let children = self.release_children().await;
+        let (held, mut tx) = self.locked.take().unwrap();
+        children.and(self.client.unlock(held, &mut tx).await)
+    }
+}
+
+impl<'c, L: Locker + 'static, C: Clock> LockGuard<'c, L, C> {
+    // 子を解放して、自分のロックは解放せずに取り出す
+    #[cfg(all(
+        any(feature = "grpc", feature = "http"),
+        any(
+            feature = "sqlx-mysql",
+            feature = "sqlx-postgres",
+            feature = "sqlx-std-collection"
+        )
+    ))]
+    pub(crate) async fn into_locked(
+        mut self,
+    ) -> Result<(&'c LockClient<L, C>, &'c str, Locked<'c, L::DB, C>)> {
+        self.release_children().await?;
+        let locked = self.locked.take().unwrap();
+        Ok((self.client, self.key, locked))
+    }
+
+    // release_children と同じ順、子の子から後から取ったものへ、最後に自分のロックを並べる
+    fn take_locked(&mut self, locked: &mut Vec<Locked<'c, L::DB, C>>) {
+        for mut child in std::mem::take(&mut self.children).into_iter().rev() {
+            child.take_locked(locked);
+        }
+        locked.extend(self.locked.take());
+    }
+}
+
+impl<L: Locker + 'static, C: Clock> Drop for LockGuard<'_, L, C> {
+    fn drop(&mut self) {
+        // 子を先に、1つのタスクで順に手放す
+        let mut locked = Vec::new();
+        self.take_locked(&mut locked);
+        self.client.unlock_detached(locked);
+    }
+}
+
+/// Same key held on two databases at once, returned by [`GuardPair::acquire`].
+///
+/// ```ignore
+/// let mut pair = GuardPair::acquire(&mysql, &postgres, "sync-orders", None).await?;
+/// copy_orders(pair.first().tx(), pair.second().tx()).await?;
+/// pair.release().await?;
+/// ```
+pub struct GuardPair<'c, A: Locker + 'static, B: Locker + 'static> {
+    first: LockGuard<'c, A>,
+    second: LockGuard<'c, B>,
+}
+
+impl<'c, A: Locker + 'static, B: Locker + 'static> GuardPair<'c, A, B> {
+    /// lock the key through `first`, then through `second`
+    ///
+    /// either both are held, or neither: the first is released when the second can't be acquired.
+    pub async fn acquire(
+        first: &'c LockClient<A>,
+        second: &'c LockClient<B>,
+        key: &'c str,
+        timeout: Option<Duration>,
+    ) -> Result<Self> {
+        let held = first.guard(key, timeout).await?;
+
+        match second.guard(key, timeout).await {
+            Ok(second) => Ok(Self {
+                first: held,
+                second,
+            }),
+            Err(e) => {
+                // 取れなかった方のエラーを返す
+                let _ = held.release().await;
+                Err(e)
+            }
+        }
+    }
+
+    /// guard on the first database
+    pub fn first(&mut self) -> &mut LockGuard<'c, A> {
+        &mut self.first
+    }
+
+    /// guard on the second database
+    pub fn second(&mut self) -> &mut LockGuard<'c, B> {
+        &mut self.second
+    }
+
+    /// release the second lock, then the first, failing with the first error
+    pub async fn release(self) -> Result<()> {
+        // 片方が失敗してももう片方は必ず解放する
+        let second = self.second.release().await;
+        let first = self.first.release().await;
+        second.and(first)
+    }
+}
+
+/// Guards tagged with the group of the step that took them, returned by [`LockClient::groups`].
+///
+/// a saga locks keys over several steps and releases those of a step at once, in the reverse
+/// order they were acquired in.
+///
+/// ```ignore
+/// let mut groups = locker.groups();
+/// groups.lock("reserve", "stock:42", None).await?;
+/// groups.lock("reserve", "stock:43", None).await?;
+/// groups.lock("charge", "account:7", None).await?;
+/// groups.release_group("reserve").await?;
+/// ```
+pub struct LockGroups<'c, L: Locker + 'static, C: Clock = DefaultClock> {
+    client: &'c LockClient<L, C>,
+    groups: HashMap<String, Vec<LockGuard<'c, L, C>>>,
+}
+
+impl<'c, L: Locker + 'static, C: Clock> LockGroups<'c, L, C> {
+    pub(crate) fn new(client: &'c LockClient<L, C>) -> Self {
+        Self {
+            client,
+            groups: HashMap::new(),
+        }
+    }
+
+    /// lock the key and add its guard to `group`
+    pub async fn lock(
+        &mut self,
+        group: &str,
+        key: &'c str,
+        timeout: Option<Duration>,
+    ) -> Result<&mut LockGuard<'c, L, C>> {
+        let guard = self.client.guard(key, timeout).await?;
+        let guards = self.groups.entry(group.to_owned()).or_default();
+        guards.push(guard);
+        Ok(guards.last_mut().unwrap())
+    }
+
+    /// guards of `group`, in the order they were acquired in
+    pub fn group(&mut self, group: &str) -> &mut [LockGuard<'c, L, C>] {
+        self.groups
+            .get_mut(group)
+            .map_or(&mut [], Vec::as_mut_slice)
+    }
+
+    /// release the guards of `group`, the last acquired first, failing with the first error
+    pub async fn release_group(&mut self, group: &str) -> Result<()> {
+        let Some(guards) = self.groups.remove(group) else {
+            return Ok(());
+        };
+
+        let mut r = Ok(());
+        // 途中で失敗しても残りは必ず解放する
+        for guard in guards.into_iter().rev() {
+            r = r.and(guard.release().await);
+        }
+        r
+    }
+}
+
+#[cfg(all(test, feature = "sqlx-std-collection"))]
+mod tests {
+    use pretty_assertions::{assert_eq, assert_matches};
+    use std::sync::{Arc, Mutex};
+    use tokio::time::sleep;
+
+    use super::*;
+
+    use crate::{Error, LockEvent, LockMetrics, StdCollectionLocker};
+    use sqlx::{Sqlite, SqlitePool};
+
+    type Collection = StdCollectionLocker<Sqlite>;
+
+    #[sqlx::test]
+    async fn yield_lets_a_waiter_in(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Qm3Xv8Kd1Np6Zs4Lw9Hc2Tf7Rb0Jy5Ue8Ga3Io6Sk1Wn4Ez9Pl2Cx7Dh0Vt5Mr8B";
+        let client = LockClient::<Collection>::new(pool);
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        let (yielded, waited) = tokio::join!(
+            async {
+                let guard = client
+                    .guard(key, Duration::from_secs(1).into())
+                    .await
+                    .unwrap();
+                order.lock().unwrap().push("guard");
+                sleep(Duration::from_millis(200)).await;
+                let guard = guard.yield_for(Duration::from_millis(50)).await?;
+                order.lock().unwrap().push("guard again");
+                guard.release().await
+            },
+            async {
+                sleep(Duration::from_millis(100)).await;
+                client
+                    .with_locking(key, Duration::from_secs(1).into(), async |_| {
+                        order.lock().unwrap().push("waiter");
+                        sleep(Duration::from_millis(100)).await;
+                    })
+                    .await
+            }
+        );
+
+        assert_matches!(yielded, Ok(()));
+        assert_matches!(waited, Ok(()));
+        assert_eq!(*order.lock().unwrap(), ["guard", "waiter", "guard again"]);
+
+        // 譲っている間に取られたままだと取り直せない
+        let (yielded, _) = tokio::join!(
+            async {
+                let guard = client.guard(key, None).await.unwrap();
+                sleep(Duration::from_millis(100)).await;
+                guard.yield_for(Duration::from_millis(50)).await.map(drop)
+            },
+            async {
+                sleep(Duration::from_millis(50)).await;
+                client
+                    .with_locking(key, Duration::from_secs(1).into(), async |_| {
+                        sleep(Duration::from_millis(200)).await;
+                    })
+                    .await
+            }
+        );
+        assert_matches!(yielded, Err(Error::FailedToGetLock(_)));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn yield_rolls_the_transaction_back(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Yr4Bk6Tx8Rl0Ob2Ac4Kd6Ef8Gh0Ij2Kl4Mn6Op8Qr0St2Uv4Wx6Yz8Ab0Cd2Ef4G";
+        sqlx::query("CREATE TABLE jobs (id INTEGER)")
+            .execute(&pool)
+            .await?;
+        let client = LockClient::<Collection>::new(pool.clone());
+
+        let mut guard = client.guard(key, None).await.unwrap();
+        sqlx::query("INSERT INTO jobs VALUES (1)")
+            .execute(&mut **guard.tx())
+            .await?;
+        let guard = guard.yield_for(Duration::ZERO).await.unwrap();
+        guard.release().await.unwrap();
+
+        let (jobs,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM jobs")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(jobs, 0);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn pair_holds_both_or_neither(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Lt6Wq1Dv8Ky3Xp0Bn5Rc2Gz7Mh4Js9Fa6Uo1Ie8Tk3Cw0Ny5Pb2Sx7Vm4Hd9Zr6Q";
+        // 名前空間で別のデータベースに見立てる
+        let primary = LockClient::<Collection>::new(pool.clone()).namespace("primary");
+        let analytics = LockClient::<Collection>::new(pool).namespace("analytics");
+
+        let pair = GuardPair::acquire(&primary, &analytics, key, None)
+            .await
+            .unwrap();
+        assert_matches!(
+            primary.with_locking(key, None, async |_| {}).await,
+            Err(Error::FailedToGetLock(_))
+        );
+        assert_matches!(
+            analytics.with_locking(key, None, async |_| {}).await,
+            Err(Error::FailedToGetLock(_))
+        );
+        assert_matches!(pair.release().await, Ok(()));
+
+        let held = analytics.guard(key, None).await.unwrap();
+        assert_matches!(
+            GuardPair::acquire(&primary, &analytics, key, None)
+                .await
+                .map(drop),
+            Err(Error::FailedToGetLock(_))
+        );
+        assert_matches!(primary.with_locking(key, None, async |_| {}).await, Ok(()));
+        assert_matches!(held.release().await, Ok(()));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn dropped_guard_is_released_in_the_background(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Dg6Eh8Fi0Gj2Hk4Il6Jm8Kn0Lo2Mp4Nq6Or8Ps0Qt2Ru4Sv6Tw8Ux0Vy2Wz4Xa6Y";
+        let client = LockClient::<Collection>::new(pool);
+        let mut events = Collection::subscribe_events();
+
+        let guard = client.guard(key, None).await.unwrap();
+        drop(guard);
+
+        let dropped = loop {
+            match events.recv().await.unwrap() {
+                event @ LockEvent::DroppedUnreleased { .. } if event.key() == key => break event,
+                _ => {}
+            }
+        };
+        assert_eq!(
+            dropped,
+            LockEvent::DroppedUnreleased {
+                backend: Collection::NAME,
+                key: key.to_owned(),
+            }
+        );
+        assert_matches!(
+            client
+                .with_locking(key, Duration::from_secs(1).into(), async |_| {})
+                .await,
+            Ok(())
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn dropped_children_are_released_before_the_parent(pool: SqlitePool) -> sqlx::Result<()> {
+        let keys = [
+            "Dc1Ed3Fe5Gf7Hg9Ih1Ji3Kj5Lk7Ml9Nm1On3Po5Qp7Rq9Sr1Ts3Ut5Vu7Wv9Xw1Y",
+            "Dc2Ed4Fe6Gf8Hg0Ih2Ji4Kj6Lk8Ml0Nm2On4Po6Qp8Rq0Sr2Ts4Ut6Vu8Wv0Xw2Z",
+            "Dc3Ed5Fe7Gf9Hg1Ih3Ji5Kj7Lk9Ml1Nm3On5Po7Qp9Rq1Sr3Ts5Ut7Vu9Wv1Xw3A",
+            "Dc4Ed6Fe8Gf0Hg2Ih4Ji6Kj8Lk0Ml2Nm4On6Po8Qp0Rq2Sr4Ts6Ut8Vu0Wv2Xw4B",
+        ];
+        let client = LockClient::<Collection>::new(pool);
+        let mut events = Collection::subscribe_events();
+
+        let mut parent = client.guard(keys[0], None).await.unwrap();
+        parent.lock_child(keys[1]).await.unwrap();
+        parent
+            .lock_child(keys[2])
+            .await
+            .unwrap()
+            .lock_child(keys[3])
+            .await
+            .unwrap();
+        drop(parent);
+
+        let mut dropped = Vec::new();
+        while dropped.len() < keys.len() {
+            if let LockEvent::DroppedUnreleased { key, .. } = events.recv().await.unwrap() {
+                if keys.contains(&key.as_str()) {
+                    dropped.push(key);
+                }
+            }
+        }
+        // 子の子から、後から取ったものから順に手放し、親は最後
+        assert_eq!(dropped, [keys[3], keys[2], keys[1], keys[0]]);
+        for key in keys {
+            assert_matches!(
+                client
+                    .with_locking(key, Duration::from_secs(1).into(), async |_| {})
+                    .await,
+                Ok(())
+            );
+        }
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn guard_tells_whether_it_waited(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Ct4Du6Ev8Fw0Gx2Hy4Iz6Ja8Kb0Lc2Md4Ne6Of8Pg0Qh2Ri4Sj6Tk8Ul0Vm2Wn4X";
+        let client = LockClient::<Collection>::new(pool);
+
+        let guard = client.guard(key, None).await.unwrap();
+        assert!(!guard.contended());
+
+        let (released, waited) = tokio::join!(
+            async {
+                sleep(Duration::from_millis(100)).await;
+                guard.release().await
+            },
+            client.guard(key, Duration::from_secs(1).into())
+        );
+        assert_matches!(released, Ok(()));
+        let waited = waited.unwrap();
+        assert!(waited.contended());
+        assert_matches!(waited.release().await, Ok(()));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "async-io")]
+    #[test]
+    fn dropped_guard_is_released_without_a_tokio_runtime() {
+        type Locker = crate::StdCollectionLockerWith<Sqlite, crate::AsyncIoClock>;
+        let key = "Dr3Op5Pe7Dw9It1Ho3Ut5Ru7Nt9Im1Ea3Gu5Ar7Dx9Yz1Ab3Cd5Ef7Gh9Ij1Kl3M";
+
+        async_io::block_on(async {
+            let pool = SqlitePool::connect_with(
+                sqlx::sqlite::SqliteConnectOptions::new()
+                    .filename(std::env::temp_dir().join("rusty_ad_lock_dropped_guard.db"))
+                    .create_if_missing(true),
+            )
+            .await
+            .unwrap();
+            let client = LockClient::<Locker>::new(pool);
+
+            drop(client.guard(key, None).await.unwrap());
+
+            let again =
+                crate::AsyncIoClock::timeout(Duration::from_secs(1), client.guard(key, None))
+                    .await
+                    .expect("the dropped guard still holds the key");
+            assert_matches!(again.unwrap().release().await, Ok(()));
+        });
+    }
+
+    struct Released(Arc<Mutex<Vec<String>>>);
+
+    impl LockMetrics for Released {
+        fn on_released(&self, key: &str, _hold: Duration) {
+            self.0.lock().unwrap().push(key.to_owned());
+        }
+    }
+
+    #[sqlx::test]
+    async fn groups_release_in_reverse_order(pool: SqlitePool) -> sqlx::Result<()> {
+        let keys = [
+            "Gr1Hs3It5Ju7Kv9Lw1Mx3Ny5Oz7Pa9Qb1Rc3Sd5Te7Uf9Vg1Wh3Xi5Yj7Zk9Al1B",
+            "Gr2Hs4It6Ju8Kv0Lw2Mx4Ny6Oz8Pa0Qb2Rc4Sd6Te8Uf0Vg2Wh4Xi6Yj8Zk0Al2C",
+            "Gr3Hs5It7Ju9Kv1Lw3Mx5Ny7Oz9Pa1Qb3Rc5Sd7Te9Uf1Vg3Wh5Xi7Yj9Zk1Al3D",
+        ];
+        let released = Arc::new(Mutex::new(Vec::new()));
+        let client = LockClient::<Collection>::new(pool).metrics(Released(Arc::clone(&released)));
+        let mut groups = client.groups();
+
+        groups.lock("reserve", keys[0], None).await.unwrap();
+        groups.lock("charge", keys[1], None).await.unwrap();
+        groups.lock("reserve", keys[2], None).await.unwrap();
+        assert_eq!(groups.group("reserve").len(), 2);
+        assert_matches!(
+            client.with_locking(keys[2], None, async |_| {}).await,
+            Err(Error::FailedToGetLock(_))
+        );
+
+        // 後から取ったキーから解放され、別のグループのキーは持ったまま
+        assert_matches!(groups.release_group("reserve").await, Ok(()));
+        assert_eq!(*released.lock().unwrap(), [keys[2], keys[0]]);
+        assert_eq!(groups.group("reserve").len(), 0);
+        assert_matches!(
+            client.with_locking(keys[1], None, async |_| {}).await,
+            Err(Error::FailedToGetLock(_))
+        );
+
+        assert_matches!(groups.release_group("reserve").await, Ok(()));
+        assert_matches!(groups.release_group("charge").await, Ok(()));
+        assert_matches!(
+            client.with_locking(keys[1], None, async |_| {}).await,
+            Ok(())
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn children_are_released_with_the_parent(pool: SqlitePool) -> sqlx::Result<()> {
+        let keys = [
+            "Pa4Qb6Rc8Sd0Te2Uf4Vg6Wh8Xi0Yj2Zk4Al6Bm8Cn0Do2Ep4Fq6Gr8Hs0It2Ju4K",
+            "Pa5Qb7Rc9Sd1Te3Uf5Vg7Wh9Xi1Yj3Zk5Al7Bm9Cn1Do3Ep5Fq7Gr9Hs1It3Ju5L",
+            "Pa6Qb8Rc0Sd2Te4Uf6Vg8Wh0Xi2Yj4Zk6Al8Bm0Cn2Do4Ep6Fq8Gr0Hs2It4Ju6M",
+            "Pa7Qb9Rc1Sd3Te5Uf7Vg9Wh1Xi3Yj5Zk7Al9Bm1Cn3Do5Ep7Fq9Gr1Hs3It5Ju7N",
+        ];
+        let released = Arc::new(Mutex::new(Vec::new()));
+        let client = LockClient::<Collection>::new(pool).metrics(Released(Arc::clone(&released)));
+
+        let mut parent = client.guard(keys[0], None).await.unwrap();
+        parent.lock_child(keys[1]).await.unwrap();
+        // 子の子
+        parent
+            .lock_child(keys[2])
+            .await
+            .unwrap()
+            .lock_child(keys[3])
+            .await
+            .unwrap();
+        assert_matches!(
+            client.with_locking(keys[1], None, async |_| {}).await,
+            Err(Error::FailedToGetLock(_))
+        );
+        assert_matches!(
+            parent.lock_child(keys[1]).await.map(drop),
+            Err(Error::FailedToGetLock(_))
+        );
+
+        assert_matches!(parent.release_child(keys[1]).await, Ok(()));
+        assert_matches!(
+            client.with_locking(keys[1], None, async |_| {}).await,
+            Ok(())
+        );
+
+        // 子は親より先に、後から取ったものから解放される
+        released.lock().unwrap().clear();
+        assert_matches!(parent.release().await, Ok(()));
+        assert_eq!(*released.lock().unwrap(), [keys[3], keys[2], keys[0]]);
+
+        Ok(())
+    }
+}
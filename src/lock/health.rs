@@ -0,0 +1,104 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{Error, Locker, Result};
+
+static NEXT_PROBE: AtomicU64 = AtomicU64::new(1);
+
+// 他のプロセスのヘルスチェックと競合しないキー
+fn probe_key<L: Locker>() -> String {
+    format!(
+        "rusty_ad_lock:health:{}:{}:{}",
+        L::NAME,
+        std::process::id(),
+        NEXT_PROBE.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+// Locker::health_check の本体
+pub(crate) async fn check<L: Locker>(pool: &sqlx::Pool<L::DB>) -> Result<()> {
+    let key = probe_key::<L>();
+    let mut holder = pool.begin().await?;
+    let mut other = pool.begin().await?;
+
+    L::acquire(pool, &mut holder, &key, None).await?;
+
+    // 持っている間は別のセッションから取れてはいけない
+    match L::acquire(pool, &mut other, &key, None).await {
+        Err(Error::FailedToGetLock(_)) => {}
+        Ok(()) => {
+            L::release(pool, &mut other, &key).await?;
+            L::release(pool, &mut holder, &key).await?;
+            return Err(Error::Unhealthy(
+                "another session acquired the held probe key, the sessions may be pooled by \
+                 transaction"
+                    .to_owned(),
+            ));
+        }
+        Err(e) => {
+            let _ = L::release(pool, &mut holder, &key).await;
+            return Err(e);
+        }
+    }
+
+    L::release(pool, &mut holder, &key).await?;
+
+    // 解放したら取れなければならない
+    match L::acquire(pool, &mut other, &key, None).await {
+        Ok(()) => L::release(pool, &mut other, &key).await,
+        Err(Error::FailedToGetLock(_)) => Err(Error::Unhealthy(
+            "the probe key was still held after it was released".to_owned(),
+        )),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(all(test, feature = "sqlx-std-collection"))]
+mod tests {
+    use pretty_assertions::assert_matches;
+    use std::time::Duration;
+
+    use super::*;
+
+    use crate::StdCollectionLocker;
+    use sqlx::{Sqlite, SqlitePool};
+
+    // どのセッションからでも取れてしまう壊れたロッカー
+    struct SharedLocker;
+
+    impl Locker for SharedLocker {
+        type DB = Sqlite;
+
+        const NAME: &'static str = "shared";
+
+        async fn acquire(
+            _pool: &SqlitePool,
+            _tx: &mut sqlx::Transaction<'static, Sqlite>,
+            _key: &str,
+            _timeout: Option<Duration>,
+        ) -> Result<()> {
+            Ok(())
+        }
+
+        async fn release(
+            _pool: &SqlitePool,
+            _tx: &mut sqlx::Transaction<'static, Sqlite>,
+            _key: &str,
+        ) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[sqlx::test]
+    async fn sessions_must_exclude_each_other(pool: SqlitePool) -> sqlx::Result<()> {
+        assert_matches!(
+            StdCollectionLocker::<Sqlite>::health_check(&pool).await,
+            Ok(())
+        );
+        assert_matches!(
+            SharedLocker::health_check(&pool).await,
+            Err(Error::Unhealthy(_))
+        );
+
+        Ok(())
+    }
+}
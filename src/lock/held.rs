@@ -0,0 +1,162 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+};
+
+use tokio::task;
+
+/// Key held by the current task, returned by [`crate::Locker::held_keys`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeldKey {
+    pub key: String,
+    /// number of locks the task holds on the key at once, more than 1 when it locked the key
+    /// again while holding it
+    pub depth: usize,
+}
+
+// タスクごとに、バックエンドとキーの組を何重に持っているか
+type Held = HashMap<task::Id, HashMap<(&'static str, String), usize>>;
+
+static HELD: LazyLock<Mutex<Held>> = LazyLock::new(Mutex::default);
+
+// 持っている間だけ HELD に数えられる。取得したタスクの分として、解放か Drop で外す
+pub(crate) struct HeldEntry {
+    task: task::Id,
+    backend: &'static str,
+    key: String,
+}
+
+impl HeldEntry {
+    // tokio のタスクの外では数えない
+    pub(crate) fn enter(backend: &'static str, key: &str) -> Option<Self> {
+        let task = task::try_id()?;
+
+        *HELD
+            .lock()
+            .unwrap()
+            .entry(task)
+            .or_default()
+            .entry((backend, key.to_owned()))
+            .or_default() += 1;
+
+        Some(Self {
+            task,
+            backend,
+            key: key.to_owned(),
+        })
+    }
+}
+
+impl Drop for HeldEntry {
+    fn drop(&mut self) {
+        let mut held = HELD.lock().unwrap();
+        let Some(keys) = held.get_mut(&self.task) else {
+            return;
+        };
+
+        let entry = (self.backend, std::mem::take(&mut self.key));
+        if let Some(depth) = keys.get_mut(&entry) {
+            *depth -= 1;
+            if *depth == 0 {
+                keys.remove(&entry);
+            }
+        }
+        if keys.is_empty() {
+            held.remove(&self.task);
+        }
+    }
+}
+
+pub(crate) fn held_keys(backend: &'static str) -> Vec<HeldKey> {
+    let Some(task) = task::try_id() else {
+        return Vec::new();
+    };
+
+    let held = HELD.lock().unwrap();
+    let mut keys: Vec<_> = held
+        .get(&task)
+        .into_iter()
+        .flatten()
+        .filter(|((b, _), _)| *b == backend)
+        .map(|((_, key), depth)| HeldKey {
+            key: key.clone(),
+            depth: *depth,
+        })
+        .collect();
+    keys.sort_unstable_by(|a, b| a.key.cmp(&b.key));
+    keys
+}
+
+#[cfg(all(test, feature = "sqlx-std-collection"))]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    use crate::{LockClient, Locker, StdCollectionLocker};
+    use sqlx::{Sqlite, SqlitePool};
+
+    type Collection = StdCollectionLocker<Sqlite>;
+
+    #[sqlx::test]
+    async fn held_keys_count_the_locks_of_the_task(pool: SqlitePool) -> sqlx::Result<()> {
+        let keys = [
+            "Hk1Ld3Mf5Ng7Oh9Pi1Qj3Rk5Sl7Tm9Un1Vo3Wp5Xq7Yr9Zs1At3Bu5Cv7Dw9Ex1F",
+            "Hk2Ld4Mf6Ng8Oh0Pi2Qj4Rk6Sl8Tm0Un2Vo4Wp6Xq8Yr0Zs2At4Bu6Cv8Dw0Ex2G",
+        ];
+        // 名前空間が違えば同じキーをもう一度取れる
+        let outer = LockClient::<Collection>::new(pool.clone()).namespace("outer");
+        let inner = LockClient::<Collection>::new(pool).namespace("inner");
+
+        // block_on の中はタスクではないので数えられない。with_locking の Future は Send にならない
+        let local = tokio::task::LocalSet::new();
+        let task = local.spawn_local(async move {
+            let mut held = Vec::new();
+            outer
+                .with_locking(keys[0], None, async |_| {
+                    inner
+                        .with_locking(keys[0], None, async |_| {
+                            held.push(Collection::held_keys());
+                        })
+                        .await
+                        .unwrap();
+                    inner
+                        .with_locking(keys[1], None, async |_| {
+                            held.push(Collection::held_keys());
+                        })
+                        .await
+                        .unwrap();
+                    held.push(Collection::held_keys());
+                })
+                .await
+                .unwrap();
+
+            let held_key = |key: &str, depth| HeldKey {
+                key: key.to_owned(),
+                depth,
+            };
+            assert_eq!(
+                held,
+                [
+                    vec![held_key(keys[0], 2)],
+                    vec![held_key(keys[0], 1), held_key(keys[1], 1)],
+                    vec![held_key(keys[0], 1)],
+                ]
+            );
+            assert_eq!(Collection::held_keys(), []);
+
+            // 別のタスクの分は見えない
+            let guard = outer.guard(keys[1], None).await.unwrap();
+            let elsewhere = tokio::spawn(async { Collection::held_keys() })
+                .await
+                .unwrap();
+            assert_eq!(elsewhere, []);
+            assert_eq!(Collection::held_keys(), [held_key(keys[1], 1)]);
+            guard.release().await.unwrap();
+            assert_eq!(Collection::held_keys(), []);
+        });
+        local.run_until(task).await.unwrap();
+
+        Ok(())
+    }
+}
@@ -0,0 +1,143 @@
+use std::{ops::ControlFlow, pin::pin, task::Poll, time::Duration, time::Instant};
+
+use crate::{Clock, Result};
+
+/// Acquisition attempt reported to [`LockHooks`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LockAttempt<'a> {
+    pub key: &'a str,
+    /// time since the call started to acquire the key
+    pub elapsed: Duration,
+    /// attempt of [`crate::RetryPolicy`], 1 at first
+    pub attempt: u32,
+}
+
+/// How a call of [`crate::LockClient::with_locking_reported`] got its lock.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LockReport {
+    /// whether an attempt found the key held by another session
+    pub contended: bool,
+    /// time from the start of the call until the closure started
+    pub wait: Duration,
+    /// time from the start of the closure until the key was released
+    pub held: Duration,
+    /// attempts of [`crate::RetryPolicy`] made, 1 without retries
+    pub attempts: u32,
+}
+
+type Hook<'h> = Box<dyn FnMut(&LockAttempt<'_>) + Send + 'h>;
+type WaitHook<'h> = Box<dyn FnMut(&LockAttempt<'_>) -> ControlFlow<()> + Send + 'h>;
+
+/// Callbacks of a single call of [`crate::LockClient::with_locking_hooked`].
+///
+/// ```ignore
+/// let hooks = LockHooks::new()
+///     .on_contention(|attempt| contended.push(attempt.attempt))
+///     .on_timeout(|attempt| fall_back_to_queue(attempt.key))
+///     .on_waiting(Duration::from_millis(100), |attempt| {
+///         spinner.set_message(format!("waiting for {} ({:?})", attempt.key, attempt.elapsed));
+///         ControlFlow::Continue(())
+///     });
+/// ```
+#[derive(Default)]
+pub struct LockHooks<'h> {
+    contention: Option<Hook<'h>>,
+    acquired: Option<Hook<'h>>,
+    timeout: Option<Hook<'h>>,
+    waiting: Option<(Duration, WaitHook<'h>)>,
+}
+
+impl<'h> LockHooks<'h> {
+    /// hooks doing nothing
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// call `hook` whenever an attempt finds the key held by another session
+    pub fn on_contention(mut self, hook: impl FnMut(&LockAttempt<'_>) + Send + 'h) -> Self {
+        self.contention = Some(Box::new(hook));
+        self
+    }
+
+    /// call `hook` once the key is acquired
+    pub fn on_acquired(mut self, hook: impl FnMut(&LockAttempt<'_>) + Send + 'h) -> Self {
+        self.acquired = Some(Box::new(hook));
+        self
+    }
+
+    /// call `hook` once the call gives up with [`crate::Error::FailedToGetLock`] or
+    /// [`crate::Error::RetriesExhausted`]
+    pub fn on_timeout(mut self, hook: impl FnMut(&LockAttempt<'_>) + Send + 'h) -> Self {
+        self.timeout = Some(Box::new(hook));
+        self
+    }
+
+    /// call `hook` every `interval` while an attempt waits for the key
+    ///
+    /// [`ControlFlow::Break`] stops waiting, the call fails with [`crate::Error::FailedToGetLock`]
+    /// without further retries. the session that waited is closed rather than returned to the
+    /// pool, as the backend may still grant it the key.
+    pub fn on_waiting(
+        mut self,
+        interval: Duration,
+        hook: impl FnMut(&LockAttempt<'_>) -> ControlFlow<()> + Send + 'h,
+    ) -> Self {
+        self.waiting = Some((interval, Box::new(hook)));
+        self
+    }
+
+    // 待っている間 on_waiting を呼び続ける。Break で待つのをやめたら None
+    pub(crate) async fn waiting<C: Clock, F: Future<Output = Result<()>>>(
+        &mut self,
+        key: &str,
+        started: Instant,
+        attempt: u32,
+        f: F,
+    ) -> Option<Result<()>> {
+        let Some((interval, hook)) = &mut self.waiting else {
+            return Some(f.await);
+        };
+        let mut f = pin!(f);
+
+        loop {
+            let mut tick = pin!(C::sleep(*interval));
+            let done = std::future::poll_fn(|cx| {
+                if let Poll::Ready(r) = f.as_mut().poll(cx) {
+                    return Poll::Ready(Some(r));
+                }
+                tick.as_mut().poll(cx).map(|()| None)
+            })
+            .await;
+            if done.is_some() {
+                return done;
+            }
+
+            let report = LockAttempt {
+                key,
+                elapsed: C::now().saturating_duration_since(started),
+                attempt,
+            };
+            if hook(&report).is_break() {
+                return None;
+            }
+        }
+    }
+
+    pub(crate) fn contended(&mut self, attempt: &LockAttempt<'_>) {
+        if let Some(hook) = &mut self.contention {
+            hook(attempt);
+        }
+    }
+
+    pub(crate) fn acquired(&mut self, attempt: &LockAttempt<'_>) {
+        if let Some(hook) = &mut self.acquired {
+            hook(attempt);
+        }
+    }
+
+    pub(crate) fn timed_out(&mut self, attempt: &LockAttempt<'_>) {
+        if let Some(hook) = &mut self.timeout {
+            hook(attempt);
+        }
+    }
+}
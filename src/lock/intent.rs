@@ -0,0 +1,308 @@
+use std::{
+    future::Future,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{Clock, LockClient, Locker, Result};
+
+/// Database the intent markers of [`LockClient::announce_intent`] are kept in.
+///
+/// the `lock_intents` table has to exist beforehand, add [`IntentDatabase::INTENTS_TABLE`] to the
+/// migrations of the application.
+pub trait IntentDatabase: sqlx::Database {
+    /// DDL of the `lock_intents` table
+    const INTENTS_TABLE: &'static str;
+
+    /// insert the marker, or move the one of the same key and owner to `intent.at`
+    fn announce_intent(
+        pool: &sqlx::Pool<Self>,
+        intent: &LockIntent,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// delete the marker of `owner` on `key`, if any
+    fn withdraw_intent(
+        pool: &sqlx::Pool<Self>,
+        key: &str,
+        owner: &str,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// markers on `key`, or on every key if None, the earliest first
+    fn list_intents(
+        pool: &sqlx::Pool<Self>,
+        key: Option<&str>,
+    ) -> impl Future<Output = Result<Vec<LockIntent>>> + Send;
+}
+
+/// Row of the `lock_intents` table: `owner` intends to lock `key` at `at`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct LockIntent {
+    pub key: String,
+    /// label of the client that announced it, see [`LockClient::owner`]. empty without one
+    pub owner: String,
+    /// when the key is to be locked, to the millisecond
+    pub at: SystemTime,
+}
+
+impl LockIntent {
+    fn at_ms(&self) -> i64 {
+        self.at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64
+    }
+
+    fn from_row((key, owner, at_ms): (String, String, i64)) -> Self {
+        Self {
+            key,
+            owner,
+            at: UNIX_EPOCH + Duration::from_millis(at_ms.max(0) as u64),
+        }
+    }
+}
+
+impl<L: Locker, C: Clock> LockClient<L, C>
+where
+    L::DB: IntentDatabase,
+{
+    /// record in the `lock_intents` table that the client intends to lock `key` at `at`
+    ///
+    /// the marker doesn't block anything: other processes look it up with
+    /// [`LockClient::intents`], e.g. to drain the traffic of a key before a maintenance job takes
+    /// the real lock. it stays until [`LockClient::withdraw_intent`], one per key and
+    /// [`LockClient::owner`].
+    ///
+    /// ```ignore
+    /// let maintenance = locker.clone().owner("reindex-job");
+    /// maintenance.announce_intent("tenant:42", SystemTime::now() + Duration::from_secs(600)).await?;
+    ///
+    /// // in the request handlers
+    /// if !locker.intents("tenant:42").await?.is_empty() {
+    ///     return Err(StatusCode::SERVICE_UNAVAILABLE);
+    /// }
+    /// ```
+    pub async fn announce_intent(&self, key: &str, at: SystemTime) -> Result<()> {
+        let intent = LockIntent {
+            key: key.to_owned(),
+            owner: self.owner.clone().unwrap_or_default(),
+            at,
+        };
+
+        L::DB::announce_intent(self.pool(), &intent).await
+    }
+
+    /// delete the marker [`LockClient::announce_intent`] recorded on `key` for this owner
+    pub async fn withdraw_intent(&self, key: &str) -> Result<()> {
+        let owner = self.owner.as_deref().unwrap_or_default();
+        L::DB::withdraw_intent(self.pool(), key, owner).await
+    }
+
+    /// markers announced on `key` by any process, the earliest first
+    pub async fn intents(&self, key: &str) -> Result<Vec<LockIntent>> {
+        L::DB::list_intents(self.pool(), Some(key)).await
+    }
+
+    /// markers announced on every key, the earliest first
+    pub async fn all_intents(&self) -> Result<Vec<LockIntent>> {
+        L::DB::list_intents(self.pool(), None).await
+    }
+}
+
+#[cfg(feature = "sqlx-mysql")]
+impl IntentDatabase for sqlx::MySql {
+    const INTENTS_TABLE: &'static str = "CREATE TABLE IF NOT EXISTS lock_intents (
+    lock_key VARCHAR(255) NOT NULL,
+    owner VARCHAR(255) NOT NULL,
+    at_ms BIGINT NOT NULL,
+    announced_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3) ON UPDATE CURRENT_TIMESTAMP(3),
+    PRIMARY KEY (lock_key, owner),
+    INDEX lock_intents_at (at_ms)
+)";
+
+    async fn announce_intent(pool: &sqlx::Pool<Self>, intent: &LockIntent) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO lock_intents (lock_key, owner, at_ms) VALUES (?, ?, ?) \
+             ON DUPLICATE KEY UPDATE at_ms = VALUES(at_ms)",
+        )
+        .bind(&intent.key)
+        .bind(&intent.owner)
+        .bind(intent.at_ms())
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn withdraw_intent(pool: &sqlx::Pool<Self>, key: &str, owner: &str) -> Result<()> {
+        sqlx::query("DELETE FROM lock_intents WHERE lock_key = ? AND owner = ?")
+            .bind(key)
+            .bind(owner)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn list_intents(pool: &sqlx::Pool<Self>, key: Option<&str>) -> Result<Vec<LockIntent>> {
+        let rows = sqlx::query_as(
+            "SELECT lock_key, owner, at_ms FROM lock_intents \
+             WHERE ? IS NULL OR lock_key = ? ORDER BY at_ms, lock_key, owner",
+        )
+        .bind(key)
+        .bind(key)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows.into_iter().map(LockIntent::from_row).collect())
+    }
+}
+
+#[cfg(feature = "sqlx-postgres")]
+impl IntentDatabase for sqlx::Postgres {
+    const INTENTS_TABLE: &'static str = "CREATE TABLE IF NOT EXISTS lock_intents (
+    lock_key TEXT NOT NULL,
+    owner TEXT NOT NULL,
+    at_ms BIGINT NOT NULL,
+    announced_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (lock_key, owner)
+);
+CREATE INDEX IF NOT EXISTS lock_intents_at ON lock_intents (at_ms);";
+
+    async fn announce_intent(pool: &sqlx::Pool<Self>, intent: &LockIntent) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO lock_intents (lock_key, owner, at_ms) VALUES ($1, $2, $3) \
+             ON CONFLICT (lock_key, owner) DO UPDATE SET at_ms = EXCLUDED.at_ms, announced_at = now()",
+        )
+        .bind(&intent.key)
+        .bind(&intent.owner)
+        .bind(intent.at_ms())
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn withdraw_intent(pool: &sqlx::Pool<Self>, key: &str, owner: &str) -> Result<()> {
+        sqlx::query("DELETE FROM lock_intents WHERE lock_key = $1 AND owner = $2")
+            .bind(key)
+            .bind(owner)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn list_intents(pool: &sqlx::Pool<Self>, key: Option<&str>) -> Result<Vec<LockIntent>> {
+        let rows = sqlx::query_as(
+            "SELECT lock_key, owner, at_ms FROM lock_intents \
+             WHERE $1::text IS NULL OR lock_key = $1 ORDER BY at_ms, lock_key, owner",
+        )
+        .bind(key)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows.into_iter().map(LockIntent::from_row).collect())
+    }
+}
+
+#[cfg(feature = "sqlx-sqlite")]
+impl IntentDatabase for sqlx::Sqlite {
+    const INTENTS_TABLE: &'static str = "CREATE TABLE IF NOT EXISTS lock_intents (
+    lock_key TEXT NOT NULL,
+    owner TEXT NOT NULL,
+    at_ms INTEGER NOT NULL,
+    announced_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+    PRIMARY KEY (lock_key, owner)
+);
+CREATE INDEX IF NOT EXISTS lock_intents_at ON lock_intents (at_ms);";
+
+    async fn announce_intent(pool: &sqlx::Pool<Self>, intent: &LockIntent) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO lock_intents (lock_key, owner, at_ms) VALUES (?, ?, ?) \
+             ON CONFLICT (lock_key, owner) DO UPDATE SET at_ms = excluded.at_ms, \
+               announced_at = strftime('%Y-%m-%d %H:%M:%f', 'now')",
+        )
+        .bind(&intent.key)
+        .bind(&intent.owner)
+        .bind(intent.at_ms())
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn withdraw_intent(pool: &sqlx::Pool<Self>, key: &str, owner: &str) -> Result<()> {
+        sqlx::query("DELETE FROM lock_intents WHERE lock_key = ? AND owner = ?")
+            .bind(key)
+            .bind(owner)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn list_intents(pool: &sqlx::Pool<Self>, key: Option<&str>) -> Result<Vec<LockIntent>> {
+        let rows = sqlx::query_as(
+            "SELECT lock_key, owner, at_ms FROM lock_intents \
+             WHERE ?1 IS NULL OR lock_key = ?1 ORDER BY at_ms, lock_key, owner",
+        )
+        .bind(key)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows.into_iter().map(LockIntent::from_row).collect())
+    }
+}
+
+#[cfg(all(test, feature = "sqlx-std-collection", feature = "sqlx-sqlite"))]
+mod tests {
+    use pretty_assertions::{assert_eq, assert_matches};
+
+    use super::*;
+
+    use crate::StdCollectionLocker;
+    use sqlx::{Sqlite, SqlitePool};
+
+    #[sqlx::test]
+    async fn intents_are_listed_without_blocking(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "In2Jo4Kp6Lq8Mr0Ns2Ot4Pu6Qv8Rw0Sx2Ty4Uz6Va8Wb0Xc2Yd4Ze6Af8Bg0Ch2D";
+        sqlx::raw_sql(Sqlite::INTENTS_TABLE).execute(&pool).await?;
+        let client = LockClient::<StdCollectionLocker<Sqlite>>::new(pool);
+        let maintenance = client.clone().owner("reindex");
+        let at = UNIX_EPOCH + Duration::from_secs(1_800_000_000);
+
+        maintenance.announce_intent(key, at).await.unwrap();
+        // 同じ持ち主の印は動かすだけ
+        maintenance
+            .announce_intent(key, at + Duration::from_secs(60))
+            .await
+            .unwrap();
+        client.announce_intent("other", at).await.unwrap();
+
+        // 印があってもロックは取れる
+        assert_matches!(client.with_locking(key, None, async |_| {}).await, Ok(()));
+        assert_eq!(
+            client.intents(key).await.unwrap(),
+            [LockIntent {
+                key: key.to_owned(),
+                owner: "reindex".to_owned(),
+                at: at + Duration::from_secs(60),
+            }]
+        );
+        assert_eq!(
+            client
+                .all_intents()
+                .await
+                .unwrap()
+                .iter()
+                .map(|intent| intent.key.as_str())
+                .collect::<Vec<_>>(),
+            ["other", key]
+        );
+
+        maintenance.withdraw_intent(key).await.unwrap();
+        assert_eq!(client.intents(key).await.unwrap(), []);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,172 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    time::{Duration, SystemTime},
+};
+
+use crate::{Locker, Result};
+
+/// Key held or waited for at the time it was listed, see [`Introspect::list_locks`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct LockInfo {
+    /// key as the backend stores it. keys hashed or shortened by the backend are listed in that
+    /// form, [`LockInfo::original_key`] reads them back.
+    pub key: String,
+    /// session holding the key, None if it is only waited for
+    pub holder: Option<String>,
+    /// label the holder locked the key with, see [`crate::LockClient::owner`]. None if it gave
+    /// none or the backend can't store it
+    pub owner: Option<String>,
+    /// number of sessions waiting for the key
+    pub waiters: usize,
+    /// when the key was acquired, if the backend knows
+    pub held_since: Option<SystemTime>,
+}
+
+impl LockInfo {
+    /// the key [`LockInfo::key`] was shortened from, see [`crate::original_key`]
+    pub fn original_key(&self) -> Option<String> {
+        crate::original_key(&self.key)
+    }
+}
+
+/// What a backend supports, see [`Locker::capabilities`].
+///
+/// generic code checks it at runtime instead of trying and failing, e.g. rounding its timeouts up
+/// to whole seconds, or only offering a holder lookup when the backend has one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Capabilities {
+    /// whether timeouts are waited to the millisecond, instead of truncated to whole seconds
+    pub sub_second_timeouts: bool,
+    /// whether several sessions can hold a key together in shared mode
+    pub shared_mode: bool,
+    /// whether each acquisition is handed an increasing token the protected resource can check
+    pub fencing_tokens: bool,
+    /// longest a key may be held before the backend expires it, see [`Locker::MAX_HOLD`]
+    pub ttl: Option<Duration>,
+    /// whether the backend implements [`Introspect`], listing the locks and their holders
+    pub holder_lookup: bool,
+}
+
+/// Locker that can list the locks currently held on its backend.
+pub trait Introspect: Locker {
+    /// list the keys held or waited for on the database `pool` is connected to
+    fn list_locks(
+        pool: &sqlx::Pool<Self::DB>,
+    ) -> impl Future<Output = Result<Vec<LockInfo>>> + Send;
+
+    /// `key` in the form [`LockInfo::key`] lists it, normalized exactly as the locker does
+    fn listed_key(
+        pool: &sqlx::Pool<Self::DB>,
+        key: &str,
+    ) -> impl Future<Output = Result<String>> + Send {
+        let _ = pool;
+        std::future::ready(Ok(key.to_owned()))
+    }
+
+    /// the lock of `key` if someone holds it
+    ///
+    /// looks `key` up in [`Introspect::list_locks`] by default, backends override it with a
+    /// cheaper lookup of the one key.
+    fn holder(
+        pool: &sqlx::Pool<Self::DB>,
+        key: &str,
+    ) -> impl Future<Output = Result<Option<LockInfo>>> + Send {
+        async move {
+            let key = Self::listed_key(pool, key).await?;
+
+            Ok(Self::list_locks(pool)
+                .await?
+                .into_iter()
+                .find(|lock| lock.key == key && lock.holder.is_some()))
+        }
+    }
+}
+
+type HolderFuture<'a> = Pin<Box<dyn Future<Output = Result<Option<LockInfo>>> + Send + 'a>>;
+
+// LockClient は L: Introspect を要求しないので、Introspect::holder を関数ポインタで持っておく
+pub(crate) type HolderLookup<DB> = for<'a> fn(&'a sqlx::Pool<DB>, &'a str) -> HolderFuture<'a>;
+
+pub(crate) fn holder_lookup<L: Introspect + 'static>() -> HolderLookup<L::DB> {
+    fn lookup<'a, L: Introspect + 'static>(
+        pool: &'a sqlx::Pool<L::DB>,
+        key: &'a str,
+    ) -> HolderFuture<'a> {
+        Box::pin(L::holder(pool, key))
+    }
+
+    lookup::<L>
+}
+
+// ", held by 4242"、分からなければ空
+pub(crate) fn held_by(holder: &Option<String>) -> String {
+    holder
+        .as_ref()
+        .map(|holder| format!(", held by {holder}"))
+        .unwrap_or_default()
+}
+
+// " (worker-1)"、ラベルがなければ空
+pub(crate) fn owned_by(owner: &Option<String>) -> String {
+    owner
+        .as_ref()
+        .map(|owner| format!(" ({owner})"))
+        .unwrap_or_default()
+}
+
+// " since 12:03:05 UTC"、時刻が分からなければ空
+pub(crate) fn since(since: &Option<SystemTime>) -> String {
+    let Some(secs) = since
+        .and_then(|since| since.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map(|since| since.as_secs() % 86400)
+    else {
+        return String::new();
+    };
+
+    format!(
+        " since {:02}:{:02}:{:02} UTC",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Locker whose held keys can be broken from another session.
+pub trait ForceRelease: Introspect {
+    /// terminate the session holding `key`, returning it as [`LockInfo::holder`] lists it
+    ///
+    /// returns None if nobody holds the key. the holder loses every lock and transaction of
+    /// that session.
+    fn force_release(
+        pool: &sqlx::Pool<Self::DB>,
+        key: &str,
+    ) -> impl Future<Output = Result<Option<String>>> + Send;
+}
+
+#[cfg(all(test, feature = "sqlx-std-collection"))]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    use crate::{Polling, StdCollectionLocker, WaitingLocker};
+    use sqlx::Sqlite;
+
+    #[test]
+    fn capabilities_follow_the_wrapped_backend() {
+        let collection = Capabilities {
+            sub_second_timeouts: true,
+            holder_lookup: true,
+            ..Capabilities::default()
+        };
+
+        assert_eq!(StdCollectionLocker::<Sqlite>::capabilities(), collection);
+        assert_eq!(
+            WaitingLocker::<StdCollectionLocker<Sqlite>, Polling>::capabilities(),
+            collection
+        );
+    }
+}
@@ -0,0 +1,326 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        Arc, LazyLock, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use tokio::sync::watch;
+
+use crate::{CancellationToken, Error, LockClient, Locker, NO_WAIT, Result};
+
+/// Guard running a scheduled job on only one replica per tick.
+///
+/// by default each tick tries the lock without waiting, and skips the run instead of queueing it
+/// when another replica holds it, see [`ScheduledJobGuard::policy`].
+///
+/// ```ignore
+/// let guard = ScheduledJobGuard::new(LockClient::<MySqlLocker>::new(pool), "daily-settlement");
+///
+/// scheduler.add(Job::new_async("0 0 2 * * *", move |_, _| {
+///     let guard = guard.clone();
+///     Box::pin(async move {
+///         match guard.run(settle()).await {
+///             Ok(JobRun::Ran(_)) => {}
+///             Ok(JobRun::Skipped) => tracing::info!("another replica is settling"),
+///             Err(e) => tracing::error!(error = %e, "settlement failed"),
+///         }
+///     })
+/// })?)
+/// ```
+pub struct ScheduledJobGuard<L: Locker> {
+    client: LockClient<L>,
+    pub(crate) key: Arc<str>,
+    policy: ContentionPolicy,
+    skipped: Arc<AtomicU64>,
+}
+
+impl<L: Locker> Clone for ScheduledJobGuard<L> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            key: Arc::clone(&self.key),
+            policy: self.policy,
+            skipped: Arc::clone(&self.skipped),
+        }
+    }
+}
+
+/// What a tick of a [`ScheduledJobGuard`] does when another run holds the lock.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ContentionPolicy {
+    /// skip the run of the tick
+    #[default]
+    Skip,
+    /// wait up to the duration for the other run to finish, and skip the run if it doesn't
+    Queue(Duration),
+    /// cancel the token of the other run and take over once it returns
+    ///
+    /// only a run of the same process, started by [`ScheduledJobGuard::run_cancellable`], can be
+    /// cancelled: it is waited for until it returns, however long it ignores the token. the run
+    /// is skipped when a run of another process holds the lock.
+    Replace,
+}
+
+/// Former name of [`ContentionPolicy`].
+pub type TickPolicy = ContentionPolicy;
+
+// (バックエンド, キー) ごとに、このプロセスで動いているジョブを止めるトークンと、ロックを放したら閉じる受信側
+type Runs = HashMap<(&'static str, String), (CancellationToken, watch::Receiver<()>)>;
+
+static RUNS: LazyLock<Mutex<Runs>> = LazyLock::new(Mutex::default);
+
+// ロックを持っている間だけ RUNS に載る。Drop で外れ、入れ替えを待つ方を起こす
+struct Running {
+    entry: (&'static str, String),
+    released: watch::Sender<()>,
+}
+
+impl Running {
+    fn enter(&self, token: CancellationToken) {
+        RUNS.lock()
+            .unwrap()
+            .insert(self.entry.clone(), (token, self.released.subscribe()));
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let mut runs = RUNS.lock().unwrap();
+        // 後から入れ替えた方の印は残す
+        if runs
+            .get(&self.entry)
+            .is_some_and(|(_, released)| released.same_channel(&self.released.subscribe()))
+        {
+            runs.remove(&self.entry);
+        }
+    }
+}
+
+/// Outcome of a tick of [`ScheduledJobGuard::run`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JobRun<T> {
+    /// this replica ran the job
+    Ran(T),
+    /// another replica held the lock, the job wasn't run
+    Skipped,
+}
+
+impl<L: Locker> ScheduledJobGuard<L> {
+    /// guard the job named `job`, which is used as the lock key
+    pub fn new(client: LockClient<L>, job: &str) -> Self {
+        Self {
+            client,
+            key: job.into(),
+            policy: ContentionPolicy::Skip,
+            skipped: Arc::default(),
+        }
+    }
+
+    /// handle the ticks finding the lock held with `policy`
+    pub fn policy(mut self, policy: ContentionPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// run `job` if no other replica is running it, or once it is done under
+    /// [`ContentionPolicy::Queue`]
+    ///
+    /// the job can't be cancelled by [`ContentionPolicy::Replace`], see
+    /// [`ScheduledJobGuard::run_cancellable`].
+    pub async fn run<Fut: Future>(&self, job: Fut) -> Result<JobRun<Fut::Output>> {
+        self.run_cancellable(async |_| job.await).await
+    }
+
+    /// [`ScheduledJobGuard::run`] handing the job a token cancelled when a later run takes over
+    /// under [`ContentionPolicy::Replace`]
+    ///
+    /// the job should stop at a safe checkpoint once the token is cancelled, the run taking over
+    /// waits for it to return.
+    ///
+    /// ```ignore
+    /// let guard = ScheduledJobGuard::new(locker, "rebuild-index").policy(ContentionPolicy::Replace);
+    /// guard
+    ///     .run_cancellable(async |token| {
+    ///         for batch in batches {
+    ///             if token.is_cancelled() {
+    ///                 break;
+    ///             }
+    ///             index(batch).await;
+    ///         }
+    ///     })
+    ///     .await?;
+    /// ```
+    pub async fn run_cancellable<T>(
+        &self,
+        job: impl AsyncFnOnce(CancellationToken) -> T,
+    ) -> Result<JobRun<T>> {
+        let running = Running {
+            entry: (L::NAME, self.client.backend_key(&self.key)?.into_owned()),
+            released: watch::channel(()).0,
+        };
+        let timeout = match self.policy {
+            ContentionPolicy::Skip => NO_WAIT,
+            ContentionPolicy::Queue(timeout) => Some(timeout),
+            ContentionPolicy::Replace => {
+                let run = RUNS.lock().unwrap().get(&running.entry).cloned();
+                if let Some((token, mut released)) = run {
+                    token.cancel();
+                    // 送られることはなく、ロックを放して送信側が落ちると返る
+                    let _ = released.changed().await;
+                }
+                NO_WAIT
+            }
+        };
+
+        let token = CancellationToken::new();
+        let r = self
+            .client
+            .run_locked(&self.key, timeout, async {
+                running.enter(token.clone());
+                job(token.clone()).await
+            })
+            .await;
+        // ロックを放してから外れる
+        drop(running);
+
+        match r {
+            Ok(out) => Ok(JobRun::Ran(out)),
+            Err(Error::FailedToGetLock(_) | Error::RetriesExhausted { .. }) => {
+                self.skipped.fetch_add(1, Ordering::Relaxed);
+                Ok(JobRun::Skipped)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// number of ticks skipped by this guard and its clones
+    pub fn skipped(&self) -> u64 {
+        self.skipped.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(all(test, feature = "sqlx-std-collection"))]
+mod tests {
+    use pretty_assertions::{assert_eq, assert_matches};
+    use std::time::Duration;
+    use tokio::time::sleep;
+
+    use super::*;
+
+    use crate::StdCollectionLocker;
+    use sqlx::{Sqlite, SqlitePool};
+
+    #[sqlx::test]
+    async fn only_one_replica_runs_a_tick(pool: SqlitePool) -> sqlx::Result<()> {
+        let guard = ScheduledJobGuard::new(
+            LockClient::<StdCollectionLocker<Sqlite>>::new(pool),
+            "Rd6Fs8Gt0Hy2Ju4Ki6Lo8Mp0Nq2Br4Cs6Dt8Eu0Fv2Gw4Hx6Iy8Jz0Ka2Lb4Mc6N",
+        );
+        let replica = guard.clone();
+
+        let (r1, r2) = tokio::join!(
+            guard.run(async {
+                sleep(Duration::from_millis(300)).await;
+                1
+            }),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                replica.run(async { 2 }).await
+            }
+        );
+
+        assert_matches!(r1, Ok(JobRun::Ran(1)));
+        assert_matches!(r2, Ok(JobRun::Skipped));
+        assert_eq!(guard.skipped(), 1);
+
+        assert_matches!(replica.run(async { 3 }).await, Ok(JobRun::Ran(3)));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn skipping_ignores_the_default_timeout(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Sk3Ip5De7Fa9Ul1Tt3Im5Eo7Ut9Aa1Bb3Cc5Dd7Ee9Ff1Gg3Hh5Ii7Jj9Kk1Ll3M";
+        let client = LockClient::<StdCollectionLocker<Sqlite>>::new(pool)
+            .default_timeout(Duration::from_secs(10));
+        let guard = ScheduledJobGuard::new(client.clone(), key);
+
+        let held = client.guard(key, None).await.unwrap();
+        let started = std::time::Instant::now();
+        assert_matches!(guard.run(async {}).await, Ok(JobRun::Skipped));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        held.release().await.unwrap();
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn queued_tick_runs_after_the_other_replica(pool: SqlitePool) -> sqlx::Result<()> {
+        let guard = ScheduledJobGuard::new(
+            LockClient::<StdCollectionLocker<Sqlite>>::new(pool),
+            "Qt7Ru9Sv1Tw3Ux5Vy7Wz9Xa1Yb3Zc5Ad7Be9Cf1Dg3Eh5Fi7Gj9Hk1Il3Jm5Kn7L",
+        )
+        .policy(ContentionPolicy::Queue(Duration::from_secs(1)));
+        let replica = guard.clone();
+        let late = guard
+            .clone()
+            .policy(ContentionPolicy::Queue(Duration::from_millis(50)));
+
+        let (r1, r2, r3) = tokio::join!(
+            guard.run(async {
+                sleep(Duration::from_millis(300)).await;
+                1
+            }),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                replica.run(async { 2 }).await
+            },
+            async {
+                sleep(Duration::from_millis(100)).await;
+                late.run(async { 3 }).await
+            }
+        );
+
+        assert_matches!(r1, Ok(JobRun::Ran(1)));
+        assert_matches!(r2, Ok(JobRun::Ran(2)));
+        assert_matches!(r3, Ok(JobRun::Skipped));
+        assert_eq!(guard.skipped(), 1);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn replacing_run_cancels_the_current_one(pool: SqlitePool) -> sqlx::Result<()> {
+        let client = LockClient::<StdCollectionLocker<Sqlite>>::new(pool);
+        let key = "Rp8Sq0Tr2Us4Vt6Wu8Xv0Yw2Zx4Ay6Bz8Ca0Db2Ec4Fd6Ge8Hf0Ig2Jh4Ki6Lj8M";
+        let guard = ScheduledJobGuard::new(client.clone(), key).policy(ContentionPolicy::Replace);
+        let replica = guard.clone();
+
+        let (r1, r2) = tokio::join!(
+            guard.run_cancellable(async |token| {
+                token.cancelled().await;
+                // 止められてからも後片付けが終わるまでは持っている
+                sleep(Duration::from_millis(100)).await;
+                "cancelled"
+            }),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                replica.run_cancellable(async |_| "took over").await
+            }
+        );
+
+        assert_matches!(r1, Ok(JobRun::Ran("cancelled")));
+        assert_matches!(r2, Ok(JobRun::Ran("took over")));
+
+        // 別のプロセスの持ち主は止められないので飛ばす
+        let other = client.guard(key, None).await.unwrap();
+        assert_matches!(guard.run(async {}).await, Ok(JobRun::Skipped));
+        other.release().await.unwrap();
+
+        Ok(())
+    }
+}
@@ -0,0 +1,909 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Arc, LazyLock, Mutex},
+};
+
+use sha1::{Digest, Sha1};
+
+use crate::{Clock, Error, LockClient, Locker, Result};
+
+/// Value a lock can be taken on by [`crate::Locker::with_key_lock`].
+pub trait LockKey {
+    /// representation of the value handed to the backend
+    fn lock_key(&self) -> KeyRepr<'_>;
+}
+
+/// Key handed to [`crate::Locker::acquire_key`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeyRepr<'a> {
+    /// key locked like the `&str` of [`crate::Locker::acquire`]
+    Text(Cow<'a, str>),
+    /// numeric key, locked on `pg_advisory_lock(bigint)` as is by `PostgresLocker`
+    Int(i64),
+    /// key of another framework, locked on `pg_advisory_lock(int, int)` by `PostgresLocker` and
+    /// on `text` by the backends keyed by strings, see [`KeyCodec`]
+    Pair { ids: (i32, i32), text: Cow<'a, str> },
+}
+
+impl KeyRepr<'_> {
+    /// string key of the value for the backends without numeric keys
+    pub fn text(&self) -> Cow<'_, str> {
+        match self {
+            Self::Text(key) => Cow::Borrowed(key),
+            Self::Int(id) => Cow::Owned(format!("int:{id}")),
+            Self::Pair { text, .. } => Cow::Borrowed(text),
+        }
+    }
+}
+
+impl LockKey for KeyRepr<'_> {
+    fn lock_key(&self) -> KeyRepr<'_> {
+        match self {
+            Self::Text(key) => KeyRepr::Text(Cow::Borrowed(key)),
+            Self::Int(id) => KeyRepr::Int(*id),
+            Self::Pair { ids, text } => KeyRepr::Pair {
+                ids: *ids,
+                text: Cow::Borrowed(text),
+            },
+        }
+    }
+}
+
+/// Key format of another framework, to share its locks with a service migrated from it.
+///
+/// ```ignore
+/// let rails = KeyCodec::Rails { prefix: None };
+/// PostgresLocker::with_key_lock(&pool, rails.encode("orders"), None, async |_| {}).await?;
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeyCodec {
+    /// `with_advisory_lock` of Rails, `prefix` being the number in its `WITH_ADVISORY_LOCK_PREFIX`
+    ///
+    /// Postgres locks `pg_advisory_lock(crc32(key) & 0x7fffffff, prefix & 0x7fffffff)`, the other
+    /// backends `"{prefix}{key}"` like `GET_LOCK` on MySQL.
+    Rails { prefix: Option<i32> },
+}
+
+impl KeyCodec {
+    /// the key the framework locks for `key`
+    pub fn encode<'k>(&self, key: &'k str) -> KeyRepr<'k> {
+        match self {
+            Self::Rails { prefix } => KeyRepr::Pair {
+                ids: (
+                    (crc32(key.as_bytes()) & 0x7fff_ffff) as i32,
+                    prefix.unwrap_or(0) & 0x7fff_ffff,
+                ),
+                text: match prefix {
+                    Some(prefix) => Cow::Owned(format!("{prefix}{key}")),
+                    None => Cow::Borrowed(key),
+                },
+            },
+        }
+    }
+}
+
+// Ruby の Zlib.crc32 と同じ CRC-32/ISO-HDLC
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in bytes {
+        crc ^= u32::from(*b);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                crc >> 1 ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Unicode normalization applied to keys by [`crate::LockClient::normalize`].
+#[cfg(feature = "unicode-normalization")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyNormalization {
+    /// canonical composition, visually identical keys collide
+    Nfc,
+    /// compatibility composition, also folds e.g. full-width letters and ligatures
+    Nfkc,
+}
+
+#[cfg(feature = "unicode-normalization")]
+impl KeyNormalization {
+    /// the key in the normalization form, borrowed if it already is
+    pub fn normalize<'k>(&self, key: &'k str) -> Cow<'k, str> {
+        use unicode_normalization::UnicodeNormalization;
+
+        match self {
+            Self::Nfc if unicode_normalization::is_nfc(key) => Cow::Borrowed(key),
+            Self::Nfc => Cow::Owned(key.nfc().collect()),
+            Self::Nfkc if unicode_normalization::is_nfkc(key) => Cow::Borrowed(key),
+            Self::Nfkc => Cow::Owned(key.nfkc().collect()),
+        }
+    }
+}
+
+/// How keys longer than [`crate::Locker::MAX_KEY_LEN`] are shortened, see
+/// [`crate::LockClient::over_limit`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverLimit {
+    /// keep the longest prefix that fits and append the SHA-1 of the key in hex, MySQL's default
+    TruncateHash,
+    /// keep up to `prefix` bytes of the key and append the first `hash` hex digits of its SHA-1
+    ///
+    /// a longer prefix keeps long keys readable in errors and introspection, a shorter hash
+    /// makes two keys sharing the prefix more likely to collide. the prefix is cut to fit.
+    Truncate { prefix: usize, hash: usize },
+    /// the SHA-1 of the key in hex, 40 characters
+    HashHex,
+    /// the SHA-1 of the key in unpadded URL-safe base64, 27 characters
+    HashBase64,
+    /// fail with [`Error::KeyTooLong`]
+    Reject,
+}
+
+impl OverLimit {
+    /// the key if it is at most `max` bytes long, shortened otherwise
+    pub fn apply<'k>(&self, key: &'k str, max: usize) -> Result<Cow<'k, str>> {
+        if key.len() <= max {
+            return Ok(Cow::Borrowed(key));
+        }
+
+        let digest = Sha1::digest(key.as_bytes());
+        let hex = || {
+            digest
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect::<String>()
+        };
+        let shortened = match *self {
+            Self::TruncateHash => truncate(key, max, &hex()),
+            Self::Truncate { prefix, hash } => {
+                let mut hex = hex();
+                hex.truncate(hash.min(max));
+                truncate(key, prefix.min(max - hex.len()) + hex.len(), &hex)
+            }
+            Self::HashHex => hex(),
+            Self::HashBase64 => base64_url(&digest),
+            Self::Reject => return Err(Error::KeyTooLong(key.to_owned())),
+        };
+        RECENT.lock().unwrap().insert(&shortened, key);
+
+        Ok(Cow::Owned(shortened))
+    }
+}
+
+// max バイトに収まる最長の接頭辞に hash を付ける
+fn truncate(key: &str, max: usize, hash: &str) -> String {
+    // 文字の途中で切らない
+    let mut end = max.saturating_sub(hash.len());
+    while !key.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{hash}", &key[..end])
+}
+
+/// Keyed hash of the keys, so the backend, and whoever can read its lock tables, only sees an
+/// HMAC of them, see [`crate::LockClient::hash_keys`].
+///
+/// for keys embedding customer ids or other names a DBA shouldn't read in
+/// `performance_schema` or `pg_locks`. every service taking the same locks needs the same secret.
+#[derive(Clone)]
+pub struct KeyHmac {
+    secret: Arc<[u8]>,
+}
+
+impl std::fmt::Debug for KeyHmac {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // 秘密はログに出さない
+        f.write_str("KeyHmac(..)")
+    }
+}
+
+impl KeyHmac {
+    const BLOCK: usize = 64;
+
+    /// hash keys with `secret`
+    pub fn new(secret: impl AsRef<[u8]>) -> Self {
+        let secret = secret.as_ref();
+        // ブロックより長い秘密は、そのハッシュを秘密にする (RFC 2104)
+        let secret = match secret.len() > Self::BLOCK {
+            true => Sha1::digest(secret).to_vec(),
+            false => secret.to_vec(),
+        };
+
+        Self {
+            secret: secret.into(),
+        }
+    }
+
+    /// the HMAC-SHA1 of `key` in hex, 40 characters
+    ///
+    /// the pair is remembered like the keys shortened by [`OverLimit`], so [`original_key`] reads
+    /// the keys hashed by this process back.
+    pub fn apply(&self, key: &str) -> String {
+        let mut pad = [0u8; Self::BLOCK];
+        pad[..self.secret.len()].copy_from_slice(&self.secret);
+
+        let inner = Sha1::new()
+            .chain_update(pad.map(|b| b ^ 0x36))
+            .chain_update(key.as_bytes())
+            .finalize();
+        let digest = Sha1::new()
+            .chain_update(pad.map(|b| b ^ 0x5c))
+            .chain_update(inner)
+            .finalize();
+
+        let hashed = digest
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>();
+        RECENT.lock().unwrap().insert(&hashed, key);
+
+        hashed
+    }
+}
+
+const RECENT_KEYS: usize = 1024;
+
+// 短くしたキーから元のキーへの対応。最近使った RECENT_KEYS 個だけ覚えておく
+#[derive(Default)]
+struct RecentKeys {
+    keys: HashMap<String, (String, u64)>,
+    order: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+static RECENT: LazyLock<Mutex<RecentKeys>> = LazyLock::new(Mutex::default);
+
+impl RecentKeys {
+    fn insert(&mut self, shortened: &str, key: &str) {
+        self.tick += 1;
+        if let Some((_, used)) = self.keys.get_mut(shortened) {
+            self.order.remove(used);
+            *used = self.tick;
+        } else {
+            if self.keys.len() >= RECENT_KEYS {
+                // keys と order は同じ数だけある
+                let (_, oldest) = self.order.pop_first().unwrap();
+                self.keys.remove(&oldest);
+            }
+            self.keys
+                .insert(shortened.to_owned(), (key.to_owned(), self.tick));
+        }
+        self.order.insert(self.tick, shortened.to_owned());
+    }
+}
+
+/// the key `shortened` was made of by [`OverLimit`] or [`KeyHmac`], if this process shortened it
+/// recently
+///
+/// the last 1024 shortened keys are remembered, so diagnostics can show the key a caller used
+/// instead of its hash.
+pub fn original_key(shortened: &str) -> Option<String> {
+    let recent = RECENT.lock().unwrap();
+    recent.keys.get(shortened).map(|(key, _)| key.clone())
+}
+
+fn base64_url(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | u32::from(*b) << (16 - 8 * i));
+        // 3 バイトに満たない最後の塊は、パディングを付けずに必要な文字数だけ出す
+        for i in 0..=chunk.len() {
+            out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+        }
+    }
+    out
+}
+
+/// append `:` and the value to a key, escaping `%` and `:` so the parts stay apart
+///
+/// used by `#[derive(LockKey)]`.
+pub fn push_key_part(key: &mut String, part: &impl std::fmt::Display) {
+    key.push(':');
+    for c in part.to_string().chars() {
+        match c {
+            '%' => key.push_str("%25"),
+            ':' => key.push_str("%3A"),
+            c => key.push(c),
+        }
+    }
+}
+
+// テンプレートの部品。固定の文字列か、名前で値を埋める場所
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum TemplatePart {
+    Literal(String),
+    Placeholder(String),
+}
+
+/// Key format with named placeholders, checked when it is created.
+///
+/// the values rendered into the placeholders get `%` and the separators of the fixed parts, every
+/// character but letters and digits, percent-encoded, so a value containing the separator can't make two keys collide.
+/// `{{` and `}}` stand for literal braces.
+///
+/// ```ignore
+/// static INVOICE: LazyLock<KeyTemplate> =
+///     LazyLock::new(|| KeyTemplate::new("invoice:{tenant}:{id}").unwrap());
+///
+/// let key = INVOICE.render(&[("tenant", &tenant), ("id", &invoice.id)])?;
+/// locker.with_locking(&key, None, async |tx| settle(tx, &invoice).await).await?;
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyTemplate {
+    parts: Vec<TemplatePart>,
+    // 値の中で逃がす文字。固定の部分に出てくる英数字以外の文字すべて
+    reserved: Vec<char>,
+}
+
+impl KeyTemplate {
+    /// parse `template`
+    ///
+    /// fails with [`Error::InvalidKeyTemplate`] on an unbalanced brace, and on a placeholder that
+    /// is empty, isn't an identifier or comes twice.
+    pub fn new(template: &str) -> Result<Self> {
+        let invalid = |reason: &str| Error::InvalidKeyTemplate(format!("{template:?}: {reason}"));
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) if c == '_' || c.is_alphanumeric() => name.push(c),
+                            Some(_) => return Err(invalid("placeholders are identifiers")),
+                            None => return Err(invalid("unclosed placeholder")),
+                        }
+                    }
+                    if name.is_empty() {
+                        return Err(invalid("empty placeholder"));
+                    }
+                    if parts.contains(&TemplatePart::Placeholder(name.clone())) {
+                        return Err(invalid(&format!("{{{name}}} comes twice")));
+                    }
+                    if !literal.is_empty() {
+                        parts.push(TemplatePart::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(TemplatePart::Placeholder(name));
+                }
+                '}' => return Err(invalid("unmatched `}`")),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(TemplatePart::Literal(literal));
+        }
+
+        let mut reserved: Vec<char> = parts
+            .iter()
+            .filter_map(|part| match part {
+                TemplatePart::Literal(literal) => Some(literal.chars()),
+                TemplatePart::Placeholder(_) => None,
+            })
+            .flatten()
+            .filter(|c| !c.is_alphanumeric())
+            .chain(['%'])
+            .collect();
+        reserved.sort_unstable();
+        reserved.dedup();
+
+        Ok(Self { parts, reserved })
+    }
+
+    /// names of the placeholders, in the order of the template
+    pub fn placeholders(&self) -> impl Iterator<Item = &str> {
+        self.parts.iter().filter_map(|part| match part {
+            TemplatePart::Placeholder(name) => Some(name.as_str()),
+            TemplatePart::Literal(_) => None,
+        })
+    }
+
+    /// render the key with the `(placeholder, value)` pairs of `values`
+    ///
+    /// fails with [`Error::InvalidKeyTemplate`] if a placeholder has no value, or a value names no
+    /// placeholder.
+    pub fn render(&self, values: &[(&str, &dyn std::fmt::Display)]) -> Result<String> {
+        if let Some((name, _)) = values
+            .iter()
+            .find(|(name, _)| self.placeholders().all(|p| p != *name))
+        {
+            return Err(Error::InvalidKeyTemplate(format!(
+                "no placeholder {{{name}}}"
+            )));
+        }
+
+        let mut key = String::new();
+        for part in &self.parts {
+            match part {
+                TemplatePart::Literal(literal) => key.push_str(literal),
+                TemplatePart::Placeholder(name) => {
+                    let Some((_, value)) = values.iter().find(|(n, _)| n == name) else {
+                        return Err(Error::InvalidKeyTemplate(format!(
+                            "no value for {{{name}}}"
+                        )));
+                    };
+                    for c in value.to_string().chars() {
+                        if self.reserved.binary_search(&c).is_err() {
+                            key.push(c);
+                            continue;
+                        }
+                        for byte in c.encode_utf8(&mut [0; 4]).bytes() {
+                            key.push_str(&format!("%{byte:02X}"));
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(key)
+    }
+}
+
+/// Backend keys of a string key under the scheme of [`normalize_key`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NormalizedKey {
+    /// key of `GET_LOCK`, at most 64 bytes
+    pub text: String,
+    /// bigint key of `pg_advisory_lock`
+    pub int: i64,
+}
+
+impl LockKey for NormalizedKey {
+    /// the bigint key, hand [`NormalizedKey::text`] to the backends keyed by strings
+    fn lock_key(&self) -> KeyRepr<'_> {
+        KeyRepr::Int(self.int)
+    }
+}
+
+/// map a key to backend keys a service in any language can compute the same way
+///
+/// the scheme is part of the stable API and won't change between versions, unlike the
+/// `hashtext` of Postgres:
+///
+/// - `text` is the key if its UTF-8 is at most 64 bytes. a longer key keeps its longest prefix of
+///   at most 24 bytes ending on a character boundary, followed by the lowercase hex SHA-1 of the
+///   whole key, like [`OverLimit::TruncateHash`]
+/// - `int` is the first 8 bytes of the SHA-1 of the UTF-8 of the key, read as a big-endian
+///   two's complement integer
+///
+/// ```ignore
+/// let key = normalize_key("orders:42");
+/// PostgresLocker::with_key_lock(&pool, &key, None, async |_| {}).await?;
+/// MySqlLocker::with_locking(&pool, &key.text, None, async |_| {}).await?;
+/// ```
+pub fn normalize_key(key: &str) -> NormalizedKey {
+    let digest = Sha1::digest(key.as_bytes());
+    let mut head = [0; 8];
+    head.copy_from_slice(&digest[..8]);
+
+    NormalizedKey {
+        text: OverLimit::TruncateHash
+            .apply(key, 64)
+            .map(Cow::into_owned)
+            .unwrap_or_else(|_| unreachable!("TruncateHash never fails")),
+        int: i64::from_be_bytes(head),
+    }
+}
+
+/// Audit of a sample of keys under the key scheme of a client, returned by
+/// [`LockClient::key_report`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeyReport {
+    /// backend keys shared by distinct keys of the sample, those keys in the order of the sample
+    ///
+    /// the keys of an entry take the same lock, e.g. because they only differ in their Unicode
+    /// normalization form or were shortened to the same key.
+    pub collisions: BTreeMap<String, Vec<String>>,
+    /// number of distinct keys of the sample per prefix, the part of the key before the first
+    /// `:`, or the empty string for keys without one
+    pub prefixes: BTreeMap<String, usize>,
+    /// keys longer than [`Locker::MAX_KEY_LEN`] on the backend, shortened by
+    /// [`LockClient::over_limit`] or by the backend without one
+    pub too_long: Vec<String>,
+    /// keys failing with [`Error::KeyTooLong`] under [`OverLimit::Reject`]
+    pub rejected: Vec<String>,
+}
+
+impl KeyReport {
+    /// whether no key of the sample collides or is longer than the backend takes
+    pub fn is_clean(&self) -> bool {
+        self.collisions.is_empty() && self.too_long.is_empty()
+    }
+}
+
+impl<L: Locker, C: Clock> LockClient<L, C> {
+    /// map a sample of application keys to the backend like the lock calls do, and report the
+    /// keys sharing a lock, the cardinality of each prefix and the keys over the length limit
+    ///
+    /// nothing is locked. meant for a test or a one-off job auditing the key scheme, before two
+    /// keys silently share a lock in production.
+    ///
+    /// ```ignore
+    /// let report = locker.key_report(orders.iter().map(|o| o.lock_key()));
+    /// assert!(report.is_clean(), "{report:#?}");
+    /// ```
+    pub fn key_report<K: AsRef<str>>(&self, keys: impl IntoIterator<Item = K>) -> KeyReport {
+        let mut report = KeyReport::default();
+        let mut seen = HashSet::new();
+        let mut by_backend = BTreeMap::<String, Vec<String>>::new();
+
+        for key in keys {
+            let key = key.as_ref();
+            if !seen.insert(key.to_owned()) {
+                continue;
+            }
+
+            let prefix = key.split_once(':').map_or("", |(prefix, _)| prefix);
+            *report.prefixes.entry(prefix.to_owned()).or_default() += 1;
+
+            if L::MAX_KEY_LEN.is_some_and(|max| self.scoped_key(key).len() > max) {
+                report.too_long.push(key.to_owned());
+            }
+            match self.backend_key(key) {
+                Ok(backend_key) => by_backend
+                    .entry(backend_key.into_owned())
+                    .or_default()
+                    .push(key.to_owned()),
+                Err(_) => report.rejected.push(key.to_owned()),
+            }
+        }
+
+        report.collisions = by_backend
+            .into_iter()
+            .filter(|(_, keys)| keys.len() > 1)
+            .collect();
+
+        report
+    }
+}
+
+impl LockKey for str {
+    fn lock_key(&self) -> KeyRepr<'_> {
+        KeyRepr::Text(Cow::Borrowed(self))
+    }
+}
+
+impl LockKey for String {
+    fn lock_key(&self) -> KeyRepr<'_> {
+        KeyRepr::Text(Cow::Borrowed(self))
+    }
+}
+
+impl LockKey for i64 {
+    fn lock_key(&self) -> KeyRepr<'_> {
+        KeyRepr::Int(*self)
+    }
+}
+
+// bigint と同じビット列として扱う
+impl LockKey for u64 {
+    fn lock_key(&self) -> KeyRepr<'_> {
+        KeyRepr::Int(*self as i64)
+    }
+}
+
+impl<K: LockKey + ?Sized> LockKey for &K {
+    fn lock_key(&self) -> KeyRepr<'_> {
+        (**self).lock_key()
+    }
+}
+
+#[cfg(all(test, feature = "sqlx-std-collection"))]
+mod tests {
+    use pretty_assertions::{assert_eq, assert_matches};
+    use std::time::Duration;
+    use tokio::time::sleep;
+
+    use super::*;
+
+    use crate::{Locker, StdCollectionLocker};
+    use sqlx::{Sqlite, SqlitePool};
+
+    type Collection = StdCollectionLocker<Sqlite>;
+
+    #[test]
+    fn hmac_follows_rfc_2202() {
+        assert_eq!(
+            KeyHmac::new([0x0b; 20]).apply("Hi There"),
+            "b617318655057264e28bc0b6fb378c8ef146be00"
+        );
+        assert_eq!(
+            KeyHmac::new("Jefe").apply("what do ya want for nothing?"),
+            "effcdf6ae5eb2fa2d27416d5f184df9c259a7c79"
+        );
+        // ブロックより長い秘密
+        let hashed = KeyHmac::new([0xaa; 80])
+            .apply("Test Using Larger Than Block-Size Key - Hash Key First");
+        assert_eq!(hashed, "aa4ae5e15272d00e95705637ce8a3b55ed402112");
+        assert_eq!(
+            original_key(&hashed).as_deref(),
+            Some("Test Using Larger Than Block-Size Key - Hash Key First")
+        );
+        assert_eq!(format!("{:?}", KeyHmac::new("Jefe")), "KeyHmac(..)");
+    }
+
+    #[test]
+    fn templates_escape_the_separators() {
+        let template = KeyTemplate::new("invoice:{tenant}/{id} {{v1}}").unwrap();
+        assert_eq!(
+            template.placeholders().collect::<Vec<_>>(),
+            ["tenant", "id"]
+        );
+
+        // 区切りを含む値でも別のキーになる
+        let a = template
+            .render(&[("tenant", &"a:b"), ("id", &"c")])
+            .unwrap();
+        let b = template
+            .render(&[("tenant", &"a"), ("id", &"b:c")])
+            .unwrap();
+        assert_eq!(a, "invoice:a%3Ab/c {v1}");
+        assert_eq!(b, "invoice:a/b%3Ac {v1}");
+        assert_eq!(
+            template
+                .render(&[("id", &"50%/x"), ("tenant", &42)])
+                .unwrap(),
+            "invoice:42/50%25%2Fx {v1}"
+        );
+
+        assert_matches!(
+            template.render(&[("tenant", &1)]),
+            Err(Error::InvalidKeyTemplate(_))
+        );
+        assert_matches!(
+            template.render(&[("tenant", &1), ("id", &2), ("user", &3)]),
+            Err(Error::InvalidKeyTemplate(_))
+        );
+        for invalid in ["a:{id", "a:id}", "a:{}", "a:{id}:{id}", "a:{the id}"] {
+            assert_matches!(KeyTemplate::new(invalid), Err(Error::InvalidKeyTemplate(_)));
+        }
+    }
+
+    #[test]
+    fn u64_keys_share_the_bits_of_i64() {
+        assert_eq!(u64::MAX.lock_key(), (-1i64).lock_key());
+        assert_eq!(42i64.lock_key().text(), "int:42");
+    }
+
+    #[cfg(feature = "unicode-normalization")]
+    #[sqlx::test]
+    async fn report_lists_the_keys_sharing_a_lock(pool: SqlitePool) -> sqlx::Result<()> {
+        let client = LockClient::<Collection>::new(pool)
+            .namespace("billing")
+            .normalize(KeyNormalization::Nfkc);
+
+        let report = client.key_report([
+            "user:caf\u{e9}",
+            "user:cafe\u{301}",
+            "user:bob",
+            "user:bob",
+            "order:\u{ff21}",
+            "order:A",
+            "order:B",
+            "health",
+        ]);
+
+        // 名前空間が付いた後のキーでまとめ、サンプルの順に並べる
+        assert_eq!(
+            report.collisions,
+            BTreeMap::from([
+                (
+                    "billing:order:A".to_owned(),
+                    vec!["order:\u{ff21}".to_owned(), "order:A".to_owned()]
+                ),
+                (
+                    "billing:user:caf\u{e9}".to_owned(),
+                    vec!["user:caf\u{e9}".to_owned(), "user:cafe\u{301}".to_owned()]
+                ),
+            ])
+        );
+        assert_eq!(
+            report.prefixes,
+            BTreeMap::from([
+                (String::new(), 1),
+                ("order".to_owned(), 3),
+                ("user".to_owned(), 3)
+            ])
+        );
+        // 長さの上限が無いバックエンド
+        assert_eq!(report.too_long, Vec::<String>::new());
+        assert!(!report.is_clean());
+        assert!(client.key_report(["user:bob", "order:A"]).is_clean());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "unicode-normalization")]
+    #[test]
+    fn normalization_folds_equivalent_keys() {
+        // 合成済みの "é" と、"e" + 結合アクセント
+        assert_eq!(KeyNormalization::Nfc.normalize("caf\u{e9}"), "caf\u{e9}");
+        assert_eq!(KeyNormalization::Nfc.normalize("cafe\u{301}"), "caf\u{e9}");
+        assert_eq!(KeyNormalization::Nfc.normalize("\u{ff21}"), "\u{ff21}");
+        assert_eq!(KeyNormalization::Nfkc.normalize("\u{ff21}"), "A");
+    }
+
+    #[test]
+    fn over_limit_keys_are_shortened() {
+        let long = "k".repeat(65);
+
+        assert_eq!(OverLimit::Reject.apply("short", 64).unwrap(), "short");
+        assert_eq!(
+            OverLimit::TruncateHash.apply(&long, 64).unwrap(),
+            format!("{}e5f412114bfbd9c0a54966b9cbe0b4bd73568281", "k".repeat(24))
+        );
+        assert_eq!(
+            OverLimit::HashHex.apply(&long, 64).unwrap(),
+            "e5f412114bfbd9c0a54966b9cbe0b4bd73568281"
+        );
+        assert_eq!(
+            OverLimit::HashBase64.apply(&long, 64).unwrap(),
+            "5fQSEUv72cClSWa5y-C0vXNWgoE"
+        );
+        assert_matches!(
+            OverLimit::Reject.apply(&long, 64),
+            Err(Error::KeyTooLong(_))
+        );
+        // 切り詰めても文字は壊さない
+        assert_eq!(
+            OverLimit::TruncateHash
+                .apply(&format!("k{}", "\u{e9}".repeat(40)), 64)
+                .unwrap()
+                .len(),
+            63
+        );
+    }
+
+    #[test]
+    fn shortened_keys_can_be_read_back() {
+        let key = format!("invoices:{}", "2026-10-14:".repeat(8));
+        let readable = OverLimit::Truncate {
+            prefix: 48,
+            hash: 12,
+        };
+
+        let shortened = readable.apply(&key, 64).unwrap();
+        assert_eq!(shortened, format!("{}{}", &key[..48], "2da303556f47"));
+        assert_eq!(original_key(&shortened), Some(key.clone()));
+        // 長すぎる接頭辞は収まるように切る
+        assert_eq!(
+            OverLimit::Truncate {
+                prefix: 64,
+                hash: 12
+            }
+            .apply(&key, 64)
+            .unwrap()
+            .len(),
+            64
+        );
+        assert_eq!(original_key("invoices:unknown"), None);
+
+        // 古いものから忘れる
+        for i in 0..RECENT_KEYS {
+            readable
+                .apply(&format!("{i}:{}", "k".repeat(64)), 64)
+                .unwrap();
+        }
+        assert_eq!(original_key(&shortened), None);
+    }
+
+    #[test]
+    fn normalized_keys_follow_the_documented_scheme() {
+        // 他の言語の実装と突き合わせる値
+        assert_eq!(
+            normalize_key("abc"),
+            NormalizedKey {
+                text: "abc".to_owned(),
+                int: -6225876607022235286,
+            }
+        );
+        assert_eq!(normalize_key("orders:42").int, 1127154695574559308);
+        assert_eq!(
+            normalize_key(&"x".repeat(70)).text,
+            format!("{}bbaad84b42630a80b935ff83a4804512d8ef59f3", "x".repeat(24))
+        );
+        assert_eq!(
+            normalize_key("abc").lock_key(),
+            KeyRepr::Int(-6225876607022235286)
+        );
+    }
+
+    #[test]
+    fn compat_keys_match_the_frameworks() {
+        assert_eq!(crc32(b"abc"), 891568578);
+        assert_eq!(
+            KeyCodec::Rails { prefix: None }.encode("orders"),
+            KeyRepr::Pair {
+                ids: (1697644014, 0),
+                text: Cow::Borrowed("orders"),
+            }
+        );
+        assert_eq!(
+            KeyCodec::Rails { prefix: Some(-3) }.encode("orders").text(),
+            "-3orders"
+        );
+        // with_advisory_lock は Zlib.crc32 の上のビットを落とす
+        assert_eq!(crc32(b"invoice:42"), 3934793710);
+        assert_eq!(
+            KeyCodec::Rails { prefix: Some(42) }.encode("invoice:42"),
+            KeyRepr::Pair {
+                ids: (1787310062, 42),
+                text: Cow::Borrowed("42invoice:42"),
+            }
+        );
+    }
+
+    #[cfg(feature = "macros")]
+    #[test]
+    fn derived_keys_escape_their_parts() {
+        #[derive(crate::LockKey)]
+        #[lock_key(prefix = "order")]
+        struct OrderKey {
+            tenant: String,
+            id: i64,
+            #[lock_key(skip)]
+            _note: String,
+        }
+
+        #[derive(crate::LockKey)]
+        struct Shard(u32, &'static str);
+
+        let key = |tenant: &str, id| {
+            OrderKey {
+                tenant: tenant.to_owned(),
+                id,
+                _note: "ignored".to_owned(),
+            }
+            .lock_key()
+            .text()
+            .into_owned()
+        };
+        assert_eq!(key("acme", 42), "order:acme:42");
+        assert_eq!(key("a:b", 1), "order:a%3Ab:1");
+        assert_eq!(key("100%", 1), "order:100%25:1");
+        assert_eq!(Shard(3, "eu").lock_key().text(), "Shard:3:eu");
+    }
+
+    #[sqlx::test]
+    async fn numeric_keys_conflict(pool: SqlitePool) -> sqlx::Result<()> {
+        let (r1, r2, r3) = tokio::join!(
+            Collection::with_key_lock(&pool, 7081i64, None, async |_| {
+                sleep(Duration::from_millis(300)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                Collection::with_key_lock(&pool, 7081u64, None, async |_| {}).await
+            },
+            async {
+                sleep(Duration::from_millis(100)).await;
+                Collection::with_key_lock(&pool, "7081", None, async |_| {}).await
+            }
+        );
+
+        assert_matches!(r1, Ok(()));
+        assert_matches!(r2, Err(Error::FailedToGetLock(_)));
+        assert_matches!(r3, Ok(()));
+
+        Ok(())
+    }
+}
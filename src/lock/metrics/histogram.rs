@@ -0,0 +1,345 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use super::LockMetrics;
+
+/// upper bounds of the buckets of [`Histogram`], the last one catches everything above
+const BUCKETS: [Duration; 16] = [
+    Duration::from_millis(1),
+    Duration::from_millis(2),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(20),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(200),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_secs(2),
+    Duration::from_secs(5),
+    Duration::from_secs(10),
+    Duration::from_secs(30),
+    Duration::from_secs(60),
+    Duration::MAX,
+];
+
+/// key that aggregates every key over the cap of [`KeyHistograms`]
+pub const OTHER_KEYS: &str = "(other)";
+
+/// [`LockMetrics`] keeping wait and hold time histograms per key in process memory.
+///
+/// once `max_keys` keys are tracked, new keys are aggregated under [`OTHER_KEYS`], or take the
+/// place of the least recently used key with [`KeyHistograms::evict_least_recent`], so the memory
+/// stays bounded for keys like `order:42`. [`KeyHistograms::half_life`] lets old observations fade.
+/// clones share the same histograms.
+///
+/// ```ignore
+/// let histograms = KeyHistograms::new(1000);
+/// let locker = LockClient::<MySqlLocker>::new(pool).metrics(histograms.clone());
+///
+/// // later, e.g. from an admin endpoint
+/// for key in histograms.snapshot().iter().take(10) {
+///     println!("{}: p99 wait {:?}", key.key, key.wait.quantile(0.99));
+/// }
+/// ```
+#[derive(Clone)]
+pub struct KeyHistograms {
+    store: Arc<Mutex<Store>>,
+    max_keys: usize,
+    evict: bool,
+    half_life: Option<Duration>,
+}
+
+// キーごとのヒストグラムと、最後に使った順
+struct Store {
+    keys: HashMap<String, (KeyHistogram, u64)>,
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    decayed_at: Instant,
+}
+
+/// Histograms of a key, see [`KeyHistograms::snapshot`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct KeyHistogram {
+    pub key: String,
+    /// wait until acquisition, successful acquisitions only
+    pub wait: Histogram,
+    /// time the lock was held
+    pub hold: Histogram,
+    /// acquisitions that found the key held
+    pub contended: u64,
+    /// acquisitions that gave up
+    pub timeouts: u64,
+}
+
+/// Duration histogram with fixed buckets from 1ms to 60s.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Histogram {
+    pub count: u64,
+    pub sum: Duration,
+    /// upper bound and number of observations of each bucket, not cumulative
+    pub buckets: Vec<(Duration, u64)>,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            sum: Duration::ZERO,
+            buckets: BUCKETS.iter().map(|bound| (*bound, 0)).collect(),
+        }
+    }
+}
+
+impl Histogram {
+    fn observe(&mut self, d: Duration) {
+        self.count += 1;
+        self.sum = self.sum.saturating_add(d);
+        if let Some((_, n)) = self.buckets.iter_mut().find(|(bound, _)| d <= *bound) {
+            *n += 1;
+        }
+    }
+
+    // 回数を 2^halvings で割る。count は端数を切り捨てた各バケットの合計に合わせる
+    fn halve(&mut self, halvings: u32) {
+        for (_, n) in &mut self.buckets {
+            *n = n.checked_shr(halvings).unwrap_or(0);
+        }
+        self.count = self.buckets.iter().map(|(_, n)| n).sum();
+        self.sum = match self.count {
+            0 => Duration::ZERO,
+            _ => self.sum / 2u32.saturating_pow(halvings),
+        };
+    }
+
+    /// upper bound of the bucket the `q` quantile falls in, None if nothing was observed
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let rank = (q.clamp(0.0, 1.0) * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        self.buckets.iter().find_map(|(bound, n)| {
+            seen += n;
+            (seen >= rank).then_some(*bound)
+        })
+    }
+}
+
+impl KeyHistograms {
+    /// keep histograms of up to `max_keys` keys
+    pub fn new(max_keys: usize) -> Self {
+        Self {
+            store: Arc::new(Mutex::new(Store {
+                keys: HashMap::new(),
+                order: BTreeMap::new(),
+                tick: 0,
+                decayed_at: Instant::now(),
+            })),
+            max_keys,
+            evict: false,
+            half_life: None,
+        }
+    }
+
+    /// drop the least recently used key for a new one once `max_keys` keys are tracked, instead
+    /// of aggregating the new keys under [`OTHER_KEYS`]
+    ///
+    /// for keys like per-user locks, where the recent keys matter and most never come back.
+    pub fn evict_least_recent(mut self) -> Self {
+        self.evict = true;
+        self
+    }
+
+    /// halve every count and sum each `half_life`, dropping the keys left with nothing
+    ///
+    /// old bursts fade from the quantiles, and idle keys free their place under the cap.
+    pub fn half_life(mut self, half_life: Duration) -> Self {
+        self.half_life = Some(half_life);
+        self
+    }
+
+    /// histograms of every tracked key, the longest total wait first
+    pub fn snapshot(&self) -> Vec<KeyHistogram> {
+        let mut store = self.store.lock().unwrap();
+        self.decay(&mut store, Instant::now());
+
+        let mut keys: Vec<KeyHistogram> = store.keys.values().map(|(h, _)| h.clone()).collect();
+        keys.sort_by(|a, b| b.wait.sum.cmp(&a.wait.sum).then_with(|| a.key.cmp(&b.key)));
+        keys
+    }
+
+    /// forget every key
+    pub fn reset(&self) {
+        let mut store = self.store.lock().unwrap();
+        store.keys.clear();
+        store.order.clear();
+    }
+
+    fn with_key(&self, key: &str, f: impl FnOnce(&mut KeyHistogram)) {
+        let mut store = self.store.lock().unwrap();
+        self.decay(&mut store, Instant::now());
+
+        let key = if store.keys.contains_key(key) || store.keys.len() < self.max_keys {
+            key
+        } else if self.evict {
+            // keys と order は同じ数だけある
+            if let Some((_, oldest)) = store.order.pop_first() {
+                store.keys.remove(&oldest);
+            }
+            key
+        } else {
+            // 上限に達したら新しいキーはまとめて数える
+            OTHER_KEYS
+        };
+
+        store.tick += 1;
+        let tick = store.tick;
+        let (histogram, used) = store.keys.entry(key.to_owned()).or_insert_with(|| {
+            let histogram = KeyHistogram {
+                key: key.to_owned(),
+                wait: Histogram::default(),
+                hold: Histogram::default(),
+                contended: 0,
+                timeouts: 0,
+            };
+            (histogram, tick)
+        });
+        f(histogram);
+        let last = std::mem::replace(used, tick);
+        store.order.remove(&last);
+        store.order.insert(tick, key.to_owned());
+    }
+
+    // 前に減らしてから過ぎた半減期の数だけ減らす
+    fn decay(&self, store: &mut Store, now: Instant) {
+        let Some(half_life) = self.half_life.filter(|half_life| !half_life.is_zero()) else {
+            return;
+        };
+        let elapsed = now.saturating_duration_since(store.decayed_at);
+        let halvings = (elapsed.as_nanos() / half_life.as_nanos()).min(u128::from(u32::MAX)) as u32;
+        if halvings == 0 {
+            return;
+        }
+        store.decayed_at += half_life * halvings;
+
+        let Store { keys, order, .. } = store;
+        keys.retain(|_, (h, used)| {
+            h.wait.halve(halvings);
+            h.hold.halve(halvings);
+            h.contended = h.contended.checked_shr(halvings).unwrap_or(0);
+            h.timeouts = h.timeouts.checked_shr(halvings).unwrap_or(0);
+
+            let kept = h.wait.count > 0 || h.hold.count > 0 || h.contended > 0 || h.timeouts > 0;
+            if !kept {
+                order.remove(used);
+            }
+            kept
+        });
+    }
+}
+
+impl LockMetrics for KeyHistograms {
+    fn on_acquired(&self, key: &str, wait: Duration) {
+        self.with_key(key, |h| h.wait.observe(wait));
+    }
+
+    fn on_contended(&self, key: &str) {
+        self.with_key(key, |h| h.contended += 1);
+    }
+
+    fn on_timeout(&self, key: &str, _wait: Duration) {
+        self.with_key(key, |h| h.timeouts += 1);
+    }
+
+    fn on_released(&self, key: &str, hold: Duration) {
+        self.with_key(key, |h| h.hold.observe(hold));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn keys_over_the_cap_are_aggregated() {
+        let histograms = KeyHistograms::new(2);
+
+        histograms.on_acquired("order:1", Duration::from_millis(300));
+        histograms.on_acquired("order:1", Duration::from_millis(3));
+        histograms.on_contended("order:1");
+        histograms.on_acquired("order:2", Duration::from_millis(1));
+        histograms.on_acquired("order:3", Duration::from_millis(1));
+        histograms.on_timeout("order:4", Duration::from_secs(1));
+
+        let snapshot = histograms.snapshot();
+        assert_eq!(
+            snapshot
+                .iter()
+                .map(|h| (h.key.as_str(), h.wait.count, h.contended, h.timeouts))
+                .collect::<Vec<_>>(),
+            vec![
+                ("order:1", 2, 1, 0),
+                ("(other)", 1, 0, 1),
+                ("order:2", 1, 0, 0)
+            ]
+        );
+        assert_eq!(
+            snapshot[0].wait.quantile(0.5),
+            Some(Duration::from_millis(5))
+        );
+        assert_eq!(
+            snapshot[0].wait.quantile(0.99),
+            Some(Duration::from_millis(500))
+        );
+
+        histograms.reset();
+        assert_eq!(histograms.snapshot(), []);
+    }
+
+    #[test]
+    fn least_recent_keys_are_evicted_and_counts_decay() {
+        let half_life = Duration::from_secs(3600);
+        let histograms = KeyHistograms::new(2)
+            .evict_least_recent()
+            .half_life(half_life);
+
+        for _ in 0..4 {
+            histograms.on_acquired("user:1", Duration::from_millis(10));
+        }
+        histograms.on_acquired("user:2", Duration::from_millis(10));
+        histograms.on_contended("user:1");
+        // user:2 の方が前に使われている
+        histograms.on_acquired("user:3", Duration::from_millis(10));
+
+        let keys = |histograms: &KeyHistograms| {
+            let mut keys = histograms
+                .snapshot()
+                .into_iter()
+                .map(|h| (h.key, h.wait.count, h.contended))
+                .collect::<Vec<_>>();
+            keys.sort();
+            keys
+        };
+        assert_eq!(
+            keys(&histograms),
+            [("user:1".to_owned(), 4, 1), ("user:3".to_owned(), 1, 0)]
+        );
+
+        // 2 回半減すると、1 回しか見ていないキーは消える
+        let mut store = histograms.store.lock().unwrap();
+        let now = store.decayed_at + half_life * 2;
+        histograms.decay(&mut store, now);
+        drop(store);
+        assert_eq!(keys(&histograms), [("user:1".to_owned(), 1, 0)]);
+        assert_eq!(histograms.snapshot()[0].wait.sum, Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,41 @@
+use std::time::Duration;
+
+mod histogram;
+
+pub use histogram::*;
+
+#[cfg(feature = "prometheus")]
+mod prometheus;
+
+#[cfg(feature = "prometheus")]
+pub use prometheus::*;
+
+/// Hooks called by `LockClient` at each stage of the lock lifecycle.
+///
+/// every method defaults to doing nothing, so implementations only override what they record.
+pub trait LockMetrics: Send + Sync {
+    /// `key` was acquired after waiting `wait`
+    fn on_acquired(&self, key: &str, wait: Duration) {
+        let _ = (key, wait);
+    }
+
+    /// `key` was held by another session when the acquisition started
+    fn on_contended(&self, key: &str) {
+        let _ = key;
+    }
+
+    /// the acquisition of `key` gave up after waiting `wait`
+    fn on_timeout(&self, key: &str, wait: Duration) {
+        let _ = (key, wait);
+    }
+
+    /// `key` was released after being held for `hold`
+    fn on_released(&self, key: &str, hold: Duration) {
+        let _ = (key, hold);
+    }
+}
+
+/// [`LockMetrics`] that records nothing.
+pub struct NoopMetrics;
+
+impl LockMetrics for NoopMetrics {}
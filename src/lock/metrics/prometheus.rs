@@ -0,0 +1,145 @@
+use std::time::Duration;
+
+use ::prometheus::{HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry};
+
+use super::LockMetrics;
+
+/// [`LockMetrics`] exporting Prometheus counters and histograms labeled by key prefix.
+///
+/// the prefix of a key is the part before the first `:` (the whole key if there is none) unless
+/// configured otherwise with [`PrometheusMetrics::key_prefix`], keeping the label cardinality
+/// bounded for keys like `order:42`.
+///
+/// | metric | type |
+/// | --- | --- |
+/// | `rusty_ad_lock_acquire_seconds` | histogram of the wait until acquisition |
+/// | `rusty_ad_lock_hold_seconds` | histogram of the time the lock was held |
+/// | `rusty_ad_lock_contended_total` | acquisitions that found the key held |
+/// | `rusty_ad_lock_timeouts_total` | acquisitions that gave up |
+#[derive(Clone)]
+pub struct PrometheusMetrics {
+    acquire_seconds: HistogramVec,
+    hold_seconds: HistogramVec,
+    contended_total: IntCounterVec,
+    timeouts_total: IntCounterVec,
+    prefix: fn(&str) -> &str,
+}
+
+fn default_prefix(key: &str) -> &str {
+    key.split(':').next().unwrap_or(key)
+}
+
+impl PrometheusMetrics {
+    /// create the metrics and register them to `registry`
+    pub fn new(registry: &Registry) -> ::prometheus::Result<Self> {
+        let acquire_seconds = HistogramVec::new(
+            HistogramOpts::new(
+                "rusty_ad_lock_acquire_seconds",
+                "time spent waiting for a lock",
+            ),
+            &["prefix"],
+        )?;
+        let hold_seconds = HistogramVec::new(
+            HistogramOpts::new("rusty_ad_lock_hold_seconds", "time a lock was held"),
+            &["prefix"],
+        )?;
+        let contended_total = IntCounterVec::new(
+            Opts::new(
+                "rusty_ad_lock_contended_total",
+                "acquisitions that found the key held by another session",
+            ),
+            &["prefix"],
+        )?;
+        let timeouts_total = IntCounterVec::new(
+            Opts::new("rusty_ad_lock_timeouts_total", "acquisitions that gave up"),
+            &["prefix"],
+        )?;
+
+        registry.register(Box::new(acquire_seconds.clone()))?;
+        registry.register(Box::new(hold_seconds.clone()))?;
+        registry.register(Box::new(contended_total.clone()))?;
+        registry.register(Box::new(timeouts_total.clone()))?;
+
+        Ok(Self {
+            acquire_seconds,
+            hold_seconds,
+            contended_total,
+            timeouts_total,
+            prefix: default_prefix,
+        })
+    }
+
+    /// derive the `prefix` label of a key with `prefix` instead
+    pub fn key_prefix(mut self, prefix: fn(&str) -> &str) -> Self {
+        self.prefix = prefix;
+        self
+    }
+}
+
+impl LockMetrics for PrometheusMetrics {
+    fn on_acquired(&self, key: &str, wait: Duration) {
+        self.acquire_seconds
+            .with_label_values(&[(self.prefix)(key)])
+            .observe(wait.as_secs_f64());
+    }
+
+    fn on_contended(&self, key: &str) {
+        self.contended_total
+            .with_label_values(&[(self.prefix)(key)])
+            .inc();
+    }
+
+    fn on_timeout(&self, key: &str, _wait: Duration) {
+        self.timeouts_total
+            .with_label_values(&[(self.prefix)(key)])
+            .inc();
+    }
+
+    fn on_released(&self, key: &str, hold: Duration) {
+        self.hold_seconds
+            .with_label_values(&[(self.prefix)(key)])
+            .observe(hold.as_secs_f64());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn metrics_are_labeled_by_key_prefix() {
+        let registry = Registry::new();
+        let metrics = PrometheusMetrics::new(&registry).unwrap();
+
+        metrics.on_contended("order:1");
+        metrics.on_timeout("order:2", Duration::from_secs(1));
+        metrics.on_acquired("order:3", Duration::from_millis(10));
+        metrics.on_released("report", Duration::from_secs(2));
+
+        assert_eq!(
+            metrics.contended_total.with_label_values(&["order"]).get(),
+            1
+        );
+        assert_eq!(
+            metrics.timeouts_total.with_label_values(&["order"]).get(),
+            1
+        );
+        assert_eq!(
+            metrics
+                .acquire_seconds
+                .with_label_values(&["order"])
+                .get_sample_count(),
+            1
+        );
+        assert_eq!(
+            metrics
+                .hold_seconds
+                .with_label_values(&["report"])
+                .get_sample_count(),
+            1
+        );
+        assert_eq!(registry.gather().len(), 4);
+    }
+}
@@ -0,0 +1,101 @@
+use std::time::Duration;
+
+use sqlx::migrate::{Migrate, Migrator};
+
+use crate::{LockClient, Locker, Result};
+
+// 他のレプリカのマイグレーションが終わるのを待つ時間。大きなテーブルの変更でも収まるように長めに取る
+const MIGRATION_WAIT: Duration = Duration::from_secs(600);
+
+/// run the migrations of `migrator` on `pool` while `key` is locked
+///
+/// replicas booting together run the migrations one after the other: the first applies them, and
+/// the others wait up to 10 minutes for it and find nothing left to apply. the migrations run on a
+/// connection of their own, not in the transaction of the lock, so `no_tx` migrations work too.
+///
+/// ```ignore
+/// migrate_under_lock::<PostgresLocker>(&pool, "migrations", &sqlx::migrate!()).await?;
+/// ```
+pub async fn migrate_under_lock<L>(
+    pool: &sqlx::Pool<L::DB>,
+    key: &str,
+    migrator: &Migrator,
+) -> Result<()>
+where
+    L: Locker,
+    <L::DB as sqlx::Database>::Connection: Migrate,
+{
+    LockClient::<L>::new(pool.clone())
+        .run_locked(key, Some(MIGRATION_WAIT), migrator.run(pool))
+        .await??;
+
+    Ok(())
+}
+
+#[cfg(all(test, feature = "sqlx-std-collection"))]
+mod tests {
+    use pretty_assertions::{assert_eq, assert_matches};
+    use std::borrow::Cow;
+    use tokio::time::sleep;
+
+    use super::*;
+
+    use crate::StdCollectionLocker;
+    use sqlx::{
+        Sqlite, SqlitePool,
+        migrate::{Migration, MigrationType},
+    };
+
+    type Collection = StdCollectionLocker<Sqlite>;
+
+    async fn has_invoices(pool: &SqlitePool) -> bool {
+        sqlx::query("SELECT name FROM sqlite_master WHERE name = 'invoices'")
+            .fetch_optional(pool)
+            .await
+            .unwrap()
+            .is_some()
+    }
+
+    #[sqlx::test]
+    async fn migrations_wait_for_the_other_replica(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Mg9Nh1Oi3Pj5Qk7Rl9Sm1Tn3Uo5Vp7Wq9Xr1Ys3Zt5Au7Bv9Cw1Dx3Ey5Fz7Ga9H";
+        let migrator = Migrator {
+            migrations: Cow::Owned(vec![Migration::new(
+                1,
+                "create invoices".into(),
+                MigrationType::Simple,
+                "CREATE TABLE invoices (id INTEGER PRIMARY KEY)".into(),
+                false,
+            )]),
+            ..Migrator::DEFAULT
+        };
+        let client = LockClient::<Collection>::new(pool.clone());
+
+        // 別のレプリカがマイグレーション中の間は待つ
+        let (held, migrated) = tokio::join!(
+            client.run_locked(key, None, async {
+                sleep(Duration::from_millis(300)).await;
+                has_invoices(&pool).await
+            }),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                migrate_under_lock::<Collection>(&pool, key, &migrator).await
+            }
+        );
+        assert_matches!(held, Ok(false));
+        assert_matches!(migrated, Ok(()));
+        assert!(has_invoices(&pool).await);
+
+        // 後から起きたレプリカには適用するものが残っていない
+        assert_matches!(
+            migrate_under_lock::<Collection>(&pool, key, &migrator).await,
+            Ok(())
+        );
+        let applied: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM _sqlx_migrations")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(applied, 1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,232 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use crate::{Error, LockClient, Locker, NO_WAIT, Result};
+
+/// Disagreement of the shadow backend of a [`MigrationLocker`] with the authoritative one.
+#[derive(Debug)]
+pub enum Divergence<'a> {
+    /// the authoritative backend granted `key` while the shadow one found it held
+    ShadowContended { key: &'a str },
+    /// the authoritative backend found `key` held while the shadow one granted it
+    ShadowAdmitted { key: &'a str },
+    /// the shadow backend failed to acquire or release `key`
+    ShadowFailed { key: &'a str, error: &'a Error },
+}
+
+type Callback = Arc<dyn Fn(&Divergence<'_>) + Send + Sync>;
+
+/// Locker acquiring the keys on the backend being migrated from and, in the shadow, on the one
+/// being migrated to.
+///
+/// only the authoritative `Old` backend decides who runs, the `New` one is tried without waiting
+/// and its disagreements are reported as [`Divergence`]s. once no divergence shows up for a while,
+/// [`MigrationLocker::flip`] makes `New` authoritative, keeping `Old` for the processes not
+/// migrated yet, before dropping it.
+///
+/// ```ignore
+/// let locker = MigrationLocker::new(
+///     LockClient::<MySqlLocker>::new(mysql),
+///     LockClient::<PostgresLocker>::new(postgres),
+/// )
+/// .on_divergence(|divergence| tracing::warn!(?divergence, "lock backends disagree"));
+///
+/// locker.with_locking("settlement", None, async || settle().await).await?;
+/// ```
+pub struct MigrationLocker<Old: Locker, New: Locker> {
+    old: LockClient<Old>,
+    new: LockClient<New>,
+    divergences: Arc<AtomicU64>,
+    callback: Option<Callback>,
+}
+
+impl<Old: Locker, New: Locker> Clone for MigrationLocker<Old, New> {
+    fn clone(&self) -> Self {
+        Self {
+            old: self.old.clone(),
+            new: self.new.clone(),
+            divergences: Arc::clone(&self.divergences),
+            callback: self.callback.clone(),
+        }
+    }
+}
+
+impl<Old: Locker, New: Locker> MigrationLocker<Old, New> {
+    /// create a locker with `old` authoritative and `new` in the shadow
+    pub fn new(old: LockClient<Old>, new: LockClient<New>) -> Self {
+        Self {
+            old,
+            new,
+            divergences: Arc::default(),
+            callback: None,
+        }
+    }
+
+    /// call `f` on every divergence, after logging it with `tracing` when the feature is enabled
+    pub fn on_divergence(mut self, f: impl Fn(&Divergence<'_>) + Send + Sync + 'static) -> Self {
+        self.callback = Some(Arc::new(f));
+        self
+    }
+
+    /// number of divergences seen by this locker and its clones
+    pub fn divergences(&self) -> u64 {
+        self.divergences.load(Ordering::Relaxed)
+    }
+
+    /// make `new` authoritative and `old` the shadow, keeping the callback and the count
+    pub fn flip(self) -> MigrationLocker<New, Old> {
+        MigrationLocker {
+            old: self.new,
+            new: self.old,
+            divergences: self.divergences,
+            callback: self.callback,
+        }
+    }
+
+    /// execute the given closure while the key is held on the authoritative backend
+    ///
+    /// the key is acquired there within `timeout`, then tried once on the shadow backend. the
+    /// shadow is released first, so a process that gets the key from the authoritative backend
+    /// finds it free on the shadow one unless they really disagree. the errors of the shadow
+    /// backend are only reported, the call fails with the ones of the authoritative backend.
+    pub async fn with_locking<T, F>(&self, key: &str, timeout: Option<Duration>, f: F) -> Result<T>
+    where
+        Old: 'static,
+        New: 'static,
+        F: AsyncFnOnce() -> T,
+    {
+        let guard = match self.old.guard(key, timeout).await {
+            Ok(guard) => guard,
+            Err(e) => {
+                if matches!(
+                    e,
+                    Error::FailedToGetLock(_) | Error::RetriesExhausted { .. }
+                ) {
+                    // 影の方でも取られているか確かめ、取れてしまったらすぐ返す
+                    match self.new.guard(key, NO_WAIT).await {
+                        Ok(shadow) => {
+                            self.diverged(&Divergence::ShadowAdmitted { key });
+                            if let Err(error) = shadow.release().await {
+                                self.diverged(&Divergence::ShadowFailed { key, error: &error });
+                            }
+                        }
+                        Err(Error::FailedToGetLock(_) | Error::RetriesExhausted { .. }) => {}
+                        Err(error) => {
+                            self.diverged(&Divergence::ShadowFailed { key, error: &error })
+                        }
+                    }
+                }
+                return Err(e);
+            }
+        };
+
+        let shadow = match self.new.guard(key, NO_WAIT).await {
+            Ok(shadow) => Some(shadow),
+            Err(Error::FailedToGetLock(_) | Error::RetriesExhausted { .. }) => {
+                self.diverged(&Divergence::ShadowContended { key });
+                None
+            }
+            Err(error) => {
+                self.diverged(&Divergence::ShadowFailed { key, error: &error });
+                None
+            }
+        };
+
+        let out = f().await;
+
+        if let Some(shadow) = shadow
+            && let Err(error) = shadow.release().await
+        {
+            self.diverged(&Divergence::ShadowFailed { key, error: &error });
+        }
+        guard.release().await.map(|()| out)
+    }
+
+    fn diverged(&self, divergence: &Divergence<'_>) {
+        self.divergences.fetch_add(1, Ordering::Relaxed);
+
+        #[cfg(feature = "tracing")]
+        match divergence {
+            Divergence::ShadowContended { key } => {
+                tracing::warn!(key, "the shadow lock backend found the key held")
+            }
+            Divergence::ShadowAdmitted { key } => {
+                tracing::warn!(key, "the shadow lock backend granted a held key")
+            }
+            Divergence::ShadowFailed { key, error } => {
+                tracing::warn!(key, %error, "the shadow lock backend failed")
+            }
+        }
+
+        if let Some(callback) = &self.callback {
+            callback(divergence);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "sqlx-std-collection"))]
+mod tests {
+    use pretty_assertions::{assert_eq, assert_matches};
+    use std::sync::Mutex;
+
+    use super::*;
+
+    use crate::StdCollectionLocker;
+    use sqlx::{Sqlite, SqlitePool};
+
+    type Collection = StdCollectionLocker<Sqlite>;
+
+    #[sqlx::test]
+    async fn divergences_are_reported(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Mg5Nh7Oi9Pj1Qk3Rl5Sm7Tn9Uo1Vp3Wq5Xr7Ys9Zt1Au3Bv5Cw7Dx9Ey1Fz3Ga5H";
+        // 名前空間で別のバックエンドに見立てる
+        let old = LockClient::<Collection>::new(pool.clone()).namespace("old");
+        let new = LockClient::<Collection>::new(pool).namespace("new");
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let locker = MigrationLocker::new(old.clone(), new.clone()).on_divergence({
+            let seen = Arc::clone(&seen);
+            move |divergence| {
+                seen.lock().unwrap().push(match divergence {
+                    Divergence::ShadowContended { .. } => "contended",
+                    Divergence::ShadowAdmitted { .. } => "admitted",
+                    Divergence::ShadowFailed { .. } => "failed",
+                })
+            }
+        });
+
+        // 両方が揃っていれば何も報告しない
+        assert_matches!(locker.with_locking(key, None, async || 42).await, Ok(42));
+        assert_eq!(locker.divergences(), 0);
+
+        // 影の方だけで取られていても、古い方の判断で走る
+        let shadow = new.guard(key, None).await.unwrap();
+        assert_matches!(locker.with_locking(key, None, async || 42).await, Ok(42));
+        assert_matches!(shadow.release().await, Ok(()));
+
+        // 古い方だけで取られていれば走らない
+        let held = old.guard(key, None).await.unwrap();
+        assert_matches!(
+            locker.with_locking(key, None, async || 42).await,
+            Err(Error::FailedToGetLock(_))
+        );
+        assert_matches!(held.release().await, Ok(()));
+        assert_eq!(*seen.lock().unwrap(), ["contended", "admitted"]);
+
+        // 入れ替えると新しい方の判断になる
+        let locker = locker.flip();
+        let held = old.guard(key, None).await.unwrap();
+        assert_matches!(locker.with_locking(key, None, async || 42).await, Ok(42));
+        assert_matches!(held.release().await, Ok(()));
+        assert_eq!(locker.divergences(), 3);
+        // どちらにも残していない
+        assert_matches!(old.with_locking(key, None, async |_| {}).await, Ok(()));
+        assert_matches!(new.with_locking(key, None, async |_| {}).await, Ok(()));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,346 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    marker::PhantomData,
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+use tokio::sync::watch;
+
+use crate::{Capabilities, Clock, DefaultClock, Error, Locker};
+
+/// Locker test double whose acquisition outcomes are scripted per key.
+///
+/// lock calls never reach the database, the pool is only used to start the closure's transaction,
+/// so a cheap pool such as `sqlite::memory:` is enough. keys without a script are always acquired,
+/// unless the test holds them with [`MockLocker::hold`].
+///
+/// ```ignore
+/// MockLocker::<Sqlite>::script("key", [MockOutcome::Contend, MockOutcome::Acquire]);
+///
+/// let r = MockLocker::with_locking(&pool, "key", None, async |_| {}).await;
+/// assert_matches!(r, Err(Error::FailedToGetLock(_)));
+///
+/// let r = MockLocker::with_locking(&pool, "key", None, async |_| {}).await;
+/// assert_matches!(r, Ok(()));
+///
+/// assert_eq!(
+///     MockLocker::<Sqlite>::calls("key"),
+///     vec![
+///         MockCall::Acquire { timeout: None },
+///         MockCall::Acquire { timeout: None },
+///         MockCall::Release,
+///     ]
+/// );
+/// ```
+pub struct MockLocker<D: sqlx::Database> {
+    _marker: PhantomData<D>,
+}
+
+/// Result of a scripted acquisition.
+#[derive(Clone, Debug)]
+pub enum MockOutcome {
+    /// the lock is acquired
+    Acquire,
+    /// acquisition fails as if another session held the key
+    Contend,
+    /// acquisition fails with the error built by the function
+    Fail(fn() -> Error),
+}
+
+impl MockOutcome {
+    /// make the outcome happen after `delay` has elapsed
+    pub fn after(self, delay: Duration) -> MockStep {
+        MockStep {
+            delay,
+            outcome: self,
+        }
+    }
+}
+
+/// One scripted acquisition: the outcome and how long the acquisition takes to reach it.
+#[derive(Clone, Debug)]
+pub struct MockStep {
+    pub delay: Duration,
+    pub outcome: MockOutcome,
+}
+
+impl From<MockOutcome> for MockStep {
+    fn from(outcome: MockOutcome) -> Self {
+        outcome.after(Duration::ZERO)
+    }
+}
+
+/// Call recorded by [`MockLocker`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MockCall {
+    Acquire { timeout: Option<Duration> },
+    Release,
+}
+
+#[derive(Default)]
+struct KeyState {
+    steps: VecDeque<MockStep>,
+    calls: Vec<MockCall>,
+    // TestLockHandle が持っている間は true
+    held: Option<watch::Receiver<bool>>,
+}
+
+/// Key held by a test until [`TestLockHandle::release`] or until dropped, see
+/// [`MockLocker::hold`].
+pub struct TestLockHandle {
+    held: watch::Sender<bool>,
+}
+
+impl TestLockHandle {
+    /// let the acquisitions waiting for the key through
+    pub fn release(self) {
+        self.held.send_replace(false);
+    }
+}
+
+static STATE: LazyLock<Mutex<HashMap<String, KeyState>>> = LazyLock::new(Mutex::default);
+
+impl<D: sqlx::Database> MockLocker<D> {
+    /// queue outcomes for the next acquisitions of `key`, in order
+    pub fn script<S: Into<MockStep>>(key: &str, steps: impl IntoIterator<Item = S>) {
+        let mut state = STATE.lock().unwrap();
+        state
+            .entry(key.to_owned())
+            .or_default()
+            .steps
+            .extend(steps.into_iter().map(Into::into));
+    }
+
+    /// acquire/release calls recorded for `key`, oldest first
+    pub fn calls(key: &str) -> Vec<MockCall> {
+        let state = STATE.lock().unwrap();
+        state.get(key).map(|s| s.calls.clone()).unwrap_or_default()
+    }
+
+    /// hold `key` as another session would, until the returned handle is released
+    ///
+    /// acquisitions of the key without a scripted outcome fail right away without a timeout, and
+    /// wait up to their timeout for the release otherwise.
+    pub fn hold(key: &str) -> TestLockHandle {
+        let (held, rx) = watch::channel(true);
+        STATE
+            .lock()
+            .unwrap()
+            .entry(key.to_owned())
+            .or_default()
+            .held = Some(rx);
+
+        TestLockHandle { held }
+    }
+
+    /// forget the script and the recorded calls of `key`
+    pub fn reset(key: &str) {
+        STATE.lock().unwrap().remove(key);
+    }
+}
+
+impl<D: sqlx::Database> Locker for MockLocker<D> {
+    type DB = D;
+
+    const NAME: &'static str = "mock";
+
+    fn capabilities() -> Capabilities {
+        Capabilities {
+            sub_second_timeouts: true,
+            ..Capabilities::default()
+        }
+    }
+
+    async fn acquire(
+        _pool: &sqlx::Pool<Self::DB>,
+        _tx: &mut sqlx::Transaction<'static, Self::DB>,
+        key: &str,
+        timeout: Option<Duration>,
+    ) -> crate::Result<()> {
+        let (step, held) = {
+            let mut state = STATE.lock().unwrap();
+            let state = state.entry(key.to_owned()).or_default();
+            state.calls.push(MockCall::Acquire { timeout });
+            (state.steps.pop_front(), state.held.clone())
+        };
+        let Some(step) = step else {
+            return wait_for_release(key, held, timeout).await;
+        };
+
+        if !step.delay.is_zero() {
+            DefaultClock::sleep(step.delay).await;
+        }
+
+        match step.outcome {
+            MockOutcome::Acquire => Ok(()),
+            MockOutcome::Contend => Err(Error::FailedToGetLock(key.to_string())),
+            MockOutcome::Fail(f) => Err(f()),
+        }
+    }
+
+    async fn release(
+        _pool: &sqlx::Pool<Self::DB>,
+        _tx: &mut sqlx::Transaction<'static, Self::DB>,
+        key: &str,
+    ) -> crate::Result<()> {
+        let mut state = STATE.lock().unwrap();
+        state
+            .entry(key.to_owned())
+            .or_default()
+            .calls
+            .push(MockCall::Release);
+
+        Ok(())
+    }
+}
+
+// TestLockHandle で持たれていれば、解放されるか timeout が過ぎるまで待つ
+async fn wait_for_release(
+    key: &str,
+    held: Option<watch::Receiver<bool>>,
+    timeout: Option<Duration>,
+) -> crate::Result<()> {
+    let Some(mut held) = held.filter(|held| *held.borrow()) else {
+        return Ok(());
+    };
+    let Some(timeout) = timeout else {
+        return Err(Error::FailedToGetLock(key.to_string()));
+    };
+
+    // ハンドルが捨てられても解放されたものとする
+    match DefaultClock::timeout(timeout, held.wait_for(|held| !held)).await {
+        Some(_) => Ok(()),
+        None => Err(Error::FailedToGetLock(key.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::{assert_eq, assert_matches};
+    use std::time::Duration;
+
+    use super::*;
+
+    use sqlx::{Sqlite, SqlitePool};
+
+    #[sqlx::test]
+    async fn scripted_outcomes_are_consumed_in_order(pool: SqlitePool) -> sqlx::Result<()> {
+        MockLocker::<Sqlite>::script(
+            "CedCDSaXaj9xm4kp3krFnisp1GhegeDBruQt26gu5kZKal10kDnPQRDFRE3Xlyie",
+            [
+                MockOutcome::Contend,
+                MockOutcome::Fail(|| Error::Sqlx(sqlx::Error::PoolTimedOut)),
+            ],
+        );
+
+        let r = MockLocker::with_locking(
+            &pool,
+            "CedCDSaXaj9xm4kp3krFnisp1GhegeDBruQt26gu5kZKal10kDnPQRDFRE3Xlyie",
+            None,
+            async |_| {},
+        )
+        .await;
+        assert_matches!(r, Err(Error::FailedToGetLock(_)));
+
+        let r = MockLocker::with_locking(
+            &pool,
+            "CedCDSaXaj9xm4kp3krFnisp1GhegeDBruQt26gu5kZKal10kDnPQRDFRE3Xlyie",
+            Duration::from_secs(1).into(),
+            async |_| {},
+        )
+        .await;
+        assert_matches!(r, Err(Error::Sqlx(sqlx::Error::PoolTimedOut)));
+
+        let r = MockLocker::with_locking(
+            &pool,
+            "CedCDSaXaj9xm4kp3krFnisp1GhegeDBruQt26gu5kZKal10kDnPQRDFRE3Xlyie",
+            None,
+            async |_| {},
+        )
+        .await;
+        assert_matches!(r, Ok(()));
+
+        assert_eq!(
+            MockLocker::<Sqlite>::calls(
+                "CedCDSaXaj9xm4kp3krFnisp1GhegeDBruQt26gu5kZKal10kDnPQRDFRE3Xlyie"
+            ),
+            vec![
+                MockCall::Acquire { timeout: None },
+                MockCall::Acquire {
+                    timeout: Some(Duration::from_secs(1))
+                },
+                MockCall::Acquire { timeout: None },
+                MockCall::Release,
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn scripted_delay_is_applied(pool: SqlitePool) -> sqlx::Result<()> {
+        MockLocker::<Sqlite>::script(
+            "UUVZCeLwXay7jUbmJWhNREe6Oqgbk4MpUPgCUABL9tnWO1dOMgHJLFM8W7ebAsH1",
+            [MockOutcome::Acquire.after(Duration::from_millis(500))],
+        );
+
+        let started = std::time::Instant::now();
+        let r = MockLocker::with_locking(
+            &pool,
+            "UUVZCeLwXay7jUbmJWhNREe6Oqgbk4MpUPgCUABL9tnWO1dOMgHJLFM8W7ebAsH1",
+            None,
+            async |_| {},
+        )
+        .await;
+
+        assert_matches!(r, Ok(()));
+        assert!(started.elapsed() >= Duration::from_millis(500));
+
+        MockLocker::<Sqlite>::reset(
+            "UUVZCeLwXay7jUbmJWhNREe6Oqgbk4MpUPgCUABL9tnWO1dOMgHJLFM8W7ebAsH1",
+        );
+        assert_eq!(
+            MockLocker::<Sqlite>::calls(
+                "UUVZCeLwXay7jUbmJWhNREe6Oqgbk4MpUPgCUABL9tnWO1dOMgHJLFM8W7ebAsH1"
+            ),
+            vec![]
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn held_key_is_released_at_a_chosen_moment(pool: SqlitePool) -> sqlx::Result<()> {
+        use crate::{LockClient, RetryPolicy};
+
+        type Mock = MockLocker<Sqlite>;
+        let key = "Gw5Yc8Lv1Nx4Qb7Zt0Hm3Rk6Jd9Fs2Ap5Ue8Io1Sx4Ck7Wn0Tb3Vh6Dl9Mf2Zr5K";
+        let handle = Mock::hold(key);
+
+        assert_matches!(
+            Mock::with_locking(&pool, key, None, async |_| {}).await,
+            Err(Error::FailedToGetLock(_))
+        );
+        assert_matches!(
+            Mock::with_locking(&pool, key, Duration::from_millis(100).into(), async |_| {}).await,
+            Err(Error::FailedToGetLock(_))
+        );
+
+        // 2 回目の再試行の間に解放すると取れる
+        let client = LockClient::<Mock>::new(pool).retry(RetryPolicy {
+            attempts: 3,
+            backoff: Duration::from_millis(100),
+            ..RetryPolicy::default()
+        });
+        let (r, ()) = tokio::join!(client.with_locking(key, None, async |_| {}), async {
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            handle.release();
+        });
+        assert_matches!(r, Ok(()));
+
+        Mock::reset(key);
+
+        Ok(())
+    }
+}
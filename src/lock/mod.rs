@@ -1,14 +1,658 @@
+mod clock;
+
+pub use clock::*;
+
+mod metrics;
+
+pub use metrics::*;
+
 #[cfg(any(feature = "sqlx-mysql", feature = "sqlx-postgres"))]
 mod sqlx;
 
-#[cfg(any(feature = "sqlx-mysql", feature = "sqlx-postgres"))]
-pub use sqlx::*;
+#[cfg(any(feature = "sqlx-mysql", feature = "sqlx-postgres"))]
+pub use sqlx::*;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+mod trace;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+mod events;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+pub use events::*;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+mod introspect;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+pub use introspect::*;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+mod audit;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+pub use audit::*;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+mod intent;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+pub use intent::*;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+mod session_sql;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+pub use session_sql::*;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+mod stats;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+pub use stats::*;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+mod once;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+pub use once::*;
+
+#[cfg(all(
+    feature = "serde",
+    any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    )
+))]
+mod cache;
+
+#[cfg(all(
+    feature = "serde",
+    any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    )
+))]
+pub use cache::*;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+mod hooks;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+pub use hooks::*;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+mod slow;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+pub use slow::*;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+mod shutdown;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+pub use shutdown::*;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+mod section;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+mod client;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+pub use client::*;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+pub use tokio_util::sync::CancellationToken;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+mod guard;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+pub use guard::*;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+mod quorum;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+pub use quorum::*;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+mod migration;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+pub use migration::*;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+mod config;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+pub use config::*;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+mod health;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+mod job;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+mod quota;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+mod semaphore;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+mod outbox;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+pub use outbox::*;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+mod single;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+pub use single::*;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+mod scope;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+pub use scope::*;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+mod shard;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+pub use shard::*;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+mod wait;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+pub use wait::*;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+mod stream;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+pub use stream::*;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+mod migrate;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+pub use migrate::*;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+mod held;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+pub use held::*;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+mod key;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+pub use key::*;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+mod row;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+pub use row::*;
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+pub use job::*;
+
+#[cfg(all(
+    feature = "cron",
+    any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    )
+))]
+mod cron;
+
+#[cfg(feature = "sqlx-std-collection")]
+mod collection;
+
+#[cfg(feature = "sqlx-std-collection")]
+pub use collection::*;
+
+#[cfg(all(
+    feature = "test-util",
+    any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    )
+))]
+mod mock;
+
+#[cfg(all(
+    feature = "test-util",
+    any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    )
+))]
+pub use mock::*;
+
+#[cfg(all(
+    feature = "test-util",
+    any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    )
+))]
+mod chaos;
+
+#[cfg(all(
+    feature = "test-util",
+    any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    )
+))]
+pub use chaos::*;
+
+#[cfg(all(
+    feature = "tower",
+    any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    )
+))]
+mod tower;
+
+#[cfg(all(
+    feature = "tower",
+    any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    )
+))]
+pub use tower::*;
+
+#[cfg(all(
+    feature = "grpc",
+    feature = "tower",
+    any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    )
+))]
+mod tonic;
+
+#[cfg(all(
+    feature = "grpc",
+    feature = "tower",
+    any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    )
+))]
+pub use tonic::*;
+
+#[cfg(all(
+    feature = "axum",
+    any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    )
+))]
+mod axum;
+
+#[cfg(all(
+    feature = "axum",
+    any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    )
+))]
+pub use axum::*;
+
+#[cfg(all(
+    feature = "actix-web",
+    any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    )
+))]
+mod actix;
+
+#[cfg(all(
+    feature = "actix-web",
+    any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    )
+))]
+pub use actix::*;
+
+#[cfg(all(
+    feature = "blocking",
+    any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    )
+))]
+pub mod blocking;
 
-#[cfg(feature = "sqlx-std-collection")]
-mod collection;
+#[cfg(all(
+    feature = "conformance",
+    any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    )
+))]
+pub mod conformance;
 
-#[cfg(feature = "sqlx-std-collection")]
-pub use collection::*;
+#[cfg(all(
+    any(feature = "grpc", feature = "http"),
+    any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    )
+))]
+pub mod server;
+
+#[cfg(all(
+    any(feature = "grpc", feature = "http"),
+    any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    )
+))]
+pub use server::{LeaseLocker, LeaseToken};
+
+#[cfg(all(
+    feature = "grpc",
+    any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    )
+))]
+pub use server::grpc::{GrpcLocker, LockEndpoint};
+
+#[cfg(all(
+    feature = "http",
+    any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    )
+))]
+pub use server::http::{HttpEndpoint, HttpLocker};
+
+#[cfg(all(
+    any(feature = "ffi", feature = "python"),
+    any(feature = "sqlx-mysql", feature = "sqlx-postgres")
+))]
+mod bindings;
+
+#[cfg(all(
+    feature = "ffi",
+    any(feature = "sqlx-mysql", feature = "sqlx-postgres")
+))]
+pub mod ffi;
+
+#[cfg(all(
+    feature = "python",
+    any(feature = "sqlx-mysql", feature = "sqlx-postgres")
+))]
+pub mod python;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -29,10 +673,269 @@ pub enum Error {
 
     #[error("failed to get lock: {0}")]
     FailedToGetLock(String),
+
+    /// the key could not be acquired because `holder` holds it, see [`LockClient::describe_holder`]
+    #[cfg(any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    ))]
+    #[error(
+        "failed to get lock: {key} is held by {holder}{}{}",
+        introspect::owned_by(.owner),
+        introspect::since(.since)
+    )]
+    HeldBy {
+        key: String,
+        /// [`LockInfo::holder`] of the lock
+        holder: String,
+        /// [`LockInfo::owner`] of the lock
+        owner: Option<String>,
+        /// [`LockInfo::held_since`] of the lock
+        since: Option<std::time::SystemTime>,
+    },
+
+    /// the key was still held after every attempt of the [`RetryPolicy`] of the client
+    #[cfg(any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    ))]
+    #[error(
+        "failed to get lock: {key} after {attempts} attempts in {elapsed:?}{}",
+        introspect::held_by(.holder)
+    )]
+    RetriesExhausted {
+        key: String,
+        /// attempts made, the first one included
+        attempts: u32,
+        /// time since the first attempt
+        elapsed: std::time::Duration,
+        /// [`LockInfo::holder`] of the lock after the last attempt, looked up by
+        /// [`LockClient::describe_holder`]
+        holder: Option<String>,
+    },
+
+    /// the acquisition was refused or the closure was aborted by [`ShutdownRegistry::shutdown`]
+    #[cfg(any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    ))]
+    #[error("locks are being released for shutdown")]
+    ShuttingDown,
+
+    /// a variable read by [`LockConfig::from_env`] or the configuration itself is invalid
+    #[cfg(any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    ))]
+    #[error("invalid lock configuration: {0}")]
+    InvalidConfig(String),
+
+    /// [`Locker::health_check`] found that the locks don't exclude other sessions
+    #[cfg(any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    ))]
+    #[error("lock backend is unhealthy: {0}")]
+    Unhealthy(String),
+
+    /// the namespace already holds or waits for as many locks as [`LockClient::quota`] allows
+    #[cfg(any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    ))]
+    #[error("lock quota of the namespace is exceeded: {0}")]
+    QuotaExceeded(String),
+
+    /// fewer connections of the lock pool were free than [`LockClient::min_free_connections`]
+    #[cfg(any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    ))]
+    #[error("failed to get lock: {key}, the pool has {free} free connections, {required} required")]
+    PoolSaturated {
+        key: String,
+        free: u32,
+        required: u32,
+    },
+
+    /// the backend lost the key while the closure held it, see [`Locker::lock_lost`]
+    #[cfg(any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    ))]
+    #[error("lock was lost while it was held: {0}")]
+    LockLost(String),
+
+    /// the key couldn't be released after the closure failed, see [`LockClient::on_release_error`]
+    ///
+    /// a release failing after the closure succeeded is returned as is.
+    #[cfg(any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    ))]
+    #[error("failed to release lock: {release}, after the closure failed: {closure}")]
+    ReleaseFailed {
+        /// error of the closure, or of the call while the key was held
+        closure: Box<Error>,
+        /// error of the release
+        release: Box<Error>,
+    },
+
+    /// the key was held on fewer databases than [`QuorumLocker::quorum`]
+    #[cfg(any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    ))]
+    #[error("failed to get lock: {key} was acquired on {acquired} databases, {required} required")]
+    NoQuorum {
+        key: String,
+        acquired: usize,
+        required: usize,
+    },
+
+    /// the key is longer than [`Locker::MAX_KEY_LEN`] and [`OverLimit::Reject`] was chosen
+    #[cfg(any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    ))]
+    #[error("lock key is longer than the backend accepts: {0}")]
+    KeyTooLong(String),
+
+    /// the template of a [`KeyTemplate`] is malformed, or its values don't match its placeholders
+    #[cfg(any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    ))]
+    #[error("invalid lock key template: {0}")]
+    InvalidKeyTemplate(String),
+
+    /// the closure held the key longer than [`Locker::MAX_HOLD`] or [`LockClient::max_hold`] and
+    /// was aborted
+    #[cfg(any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    ))]
+    #[error("lock was held longer than its maximum hold: {0}")]
+    LeaseExpired(String),
+
+    /// a proxy ran the lock statements of [`MySqlPinnedLocker`] on a session other than the
+    /// holder of the key
+    #[cfg(feature = "sqlx-mysql")]
+    #[error(
+        "lock statements of {key} ran on session {session} while it is held by {holder:?}: the proxy doesn't pin the connection"
+    )]
+    AffinityLost {
+        key: String,
+        holder: Option<u64>,
+        session: u64,
+    },
+
+    /// the connections of the pool are multiplexed by a transaction pooler, see
+    /// [`PostgresLocker::check_session_pooling`]
+    #[cfg(feature = "sqlx-postgres")]
+    #[error(
+        "session locks are unsafe behind a transaction pooler, the backend pid changed from {0} to {1}: use PostgresXactLocker or a direct lock pool"
+    )]
+    TransactionPooling(i32, i32),
+
+    /// the lock server of [`GrpcLocker`] returned an error
+    #[cfg(feature = "grpc")]
+    #[error(transparent)]
+    Grpc(Box<::tonic::Status>),
+
+    /// the socket of the [`SocketCoordinator`] failed
+    #[cfg(all(unix, feature = "unix-socket"))]
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    /// the request to the lock server of [`HttpLocker`] failed
+    #[cfg(feature = "http")]
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+
+    /// the migrations of [`migrate_under_lock`] failed
+    #[cfg(any(
+        feature = "sqlx-mysql",
+        feature = "sqlx-postgres",
+        feature = "sqlx-std-collection"
+    ))]
+    #[error(transparent)]
+    Migrate(#[from] ::sqlx::migrate::MigrateError),
+
+    /// the job of [`ScheduledJobGuard::cron_job`] couldn't be created, e.g. from an invalid
+    /// schedule
+    #[cfg(feature = "cron")]
+    #[error(transparent)]
+    Cron(#[from] tokio_cron_scheduler::JobSchedulerError),
+
+    /// the result of [`Locker::with_locking_cached`] couldn't be stored or read back as JSON
+    #[cfg(feature = "serde")]
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+// 解放の失敗でクロージャの失敗を隠さない。両方失敗したら両方返す
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+pub(crate) fn after_release<T>(released: Result<()>, ran: Result<T>) -> Result<T> {
+    match (released, ran) {
+        (Ok(()), ran) => ran,
+        (Err(e), Ok(_)) => Err(e),
+        (Err(release), Err(closure)) => Err(Error::ReleaseFailed {
+            closure: Box::new(closure),
+            release: Box::new(release),
+        }),
+    }
+}
+
+/// Closure run while a key of `DB` is locked, returning `T`.
+///
+/// names the bound of [`Locker::with_locking`] and the like in wrappers around them, and is
+/// implemented by every such closure.
+///
+/// ```ignore
+/// async fn audited<T, F: LockFn<Postgres, T>>(client: &LockClient<PostgresLocker>, f: F) -> Result<()> {
+///     client.with_locking("audited", None, f).await
+/// }
+/// ```
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+pub trait LockFn<DB: ::sqlx::Database, T>:
+    AsyncFnOnce(&mut ::sqlx::Transaction<'static, DB>) -> T
+{
+}
+
+#[cfg(any(
+    feature = "sqlx-mysql",
+    feature = "sqlx-postgres",
+    feature = "sqlx-std-collection"
+))]
+impl<DB: ::sqlx::Database, T, F> LockFn<DB, T> for F where
+    F: AsyncFnOnce(&mut ::sqlx::Transaction<'static, DB>) -> T
+{
+}
+
 #[cfg(any(
     feature = "sqlx-mysql",
     feature = "sqlx-postgres",
@@ -41,6 +944,167 @@ pub type Result<T> = std::result::Result<T, Error>;
 pub trait Locker {
     type DB: ::sqlx::Database;
 
+    /// short name of the backend, used in traces and diagnostics
+    const NAME: &'static str;
+
+    /// longest a key may be held, counted from the start of its acquisition
+    ///
+    /// the closure is aborted with [`Error::LeaseExpired`] once it elapses. lease backends also
+    /// have the server stop renewing the lease then.
+    const MAX_HOLD: Option<std::time::Duration> = None;
+
+    /// longest key in bytes the backend locks as written, None if any key is
+    ///
+    /// longer keys are shortened by the backend, or as [`LockClient::over_limit`] says.
+    const MAX_KEY_LEN: Option<usize> = None;
+
+    /// what the backend supports
+    ///
+    /// nothing but the [`Locker::MAX_HOLD`] TTL by default, backends override it.
+    fn capabilities() -> Capabilities {
+        Capabilities {
+            ttl: Self::MAX_HOLD,
+            ..Capabilities::default()
+        }
+    }
+
+    /// acquire the key on the session that `tx` is bound to
+    ///
+    /// * `pool` - connection pool that `tx` was started from
+    /// * `tx` - transaction whose session holds the lock
+    /// * `key` - key to get locked
+    /// * `timeout` - timeout duration. if None is given and a conflict occurs, it will fail immediately.
+    fn acquire(
+        pool: &::sqlx::Pool<Self::DB>,
+        tx: &mut ::sqlx::Transaction<'static, Self::DB>,
+        key: &str,
+        timeout: Option<std::time::Duration>,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// release the key acquired by [`Locker::acquire`] on the same session
+    fn release(
+        pool: &::sqlx::Pool<Self::DB>,
+        tx: &mut ::sqlx::Transaction<'static, Self::DB>,
+        key: &str,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// store `owner` with the lock of `key` just acquired on the session of `tx`, see
+    /// [`LockClient::owner`]
+    ///
+    /// backends with nowhere to store it ignore it, their locks are listed without an owner.
+    fn set_owner(
+        pool: &::sqlx::Pool<Self::DB>,
+        tx: &mut ::sqlx::Transaction<'static, Self::DB>,
+        key: &str,
+        owner: &str,
+    ) -> impl Future<Output = Result<()>> + Send {
+        let _ = (pool, tx, key, owner);
+        std::future::ready(Ok(()))
+    }
+
+    /// keys the current tokio task holds through this backend, sorted by key
+    ///
+    /// e.g. for a middleware asserting that a handler holds no lock when it returns. the keys are
+    /// the ones given to the client, before the namespace, and stay counted for the task that
+    /// acquired them until they are released.
+    fn held_keys() -> Vec<HeldKey> {
+        held::held_keys(Self::NAME)
+    }
+
+    /// complete once the backend detects that the key held by this process was lost
+    ///
+    /// lease backends complete it when the lease can't be renewed, others never do.
+    fn lock_lost(key: &str) -> impl Future<Output = ()> + Send {
+        let _ = key;
+        std::future::pending()
+    }
+
+    /// push the [`Locker::MAX_HOLD`] of the key held by this process later by `by`
+    ///
+    /// lease backends extend the lease on the server, the others have nothing to extend.
+    fn extend_hold(key: &str, by: std::time::Duration) -> impl Future<Output = Result<()>> + Send {
+        let _ = (key, by);
+        async { Ok(()) }
+    }
+
+    /// acquire the row `id` of `table` on the session that `tx` is bound to
+    ///
+    /// locks the key [`RowId::key`] by default.
+    fn acquire_row(
+        pool: &::sqlx::Pool<Self::DB>,
+        tx: &mut ::sqlx::Transaction<'static, Self::DB>,
+        table: &str,
+        id: &RowId,
+        timeout: Option<std::time::Duration>,
+    ) -> impl Future<Output = Result<()>> + Send {
+        async move { Self::acquire(pool, tx, &id.key(table), timeout).await }
+    }
+
+    /// release the row acquired by [`Locker::acquire_row`] on the same session
+    fn release_row(
+        pool: &::sqlx::Pool<Self::DB>,
+        tx: &mut ::sqlx::Transaction<'static, Self::DB>,
+        table: &str,
+        id: &RowId,
+    ) -> impl Future<Output = Result<()>> + Send {
+        async move { Self::release(pool, tx, &id.key(table)).await }
+    }
+
+    /// acquire the key on the session that `tx` is bound to
+    ///
+    /// locks [`KeyRepr::text`] by default.
+    fn acquire_key(
+        pool: &::sqlx::Pool<Self::DB>,
+        tx: &mut ::sqlx::Transaction<'static, Self::DB>,
+        key: &KeyRepr<'_>,
+        timeout: Option<std::time::Duration>,
+    ) -> impl Future<Output = Result<()>> + Send {
+        async move { Self::acquire(pool, tx, &key.text(), timeout).await }
+    }
+
+    /// release the key acquired by [`Locker::acquire_key`] on the same session
+    fn release_key(
+        pool: &::sqlx::Pool<Self::DB>,
+        tx: &mut ::sqlx::Transaction<'static, Self::DB>,
+        key: &KeyRepr<'_>,
+    ) -> impl Future<Output = Result<()>> + Send {
+        async move { Self::release(pool, tx, &key.text()).await }
+    }
+
+    /// connect to the database of `config` and bind the locker to it with its settings
+    ///
+    /// fails with [`Error::InvalidConfig`] if the configuration names another backend or has no
+    /// database URL.
+    fn from_config(config: &LockConfig) -> impl Future<Output = Result<LockClient<Self>>> + Send
+    where
+        Self: Sized,
+    {
+        config::connect::<Self>(config)
+    }
+
+    /// acquire and release a probe key, checking that another session is excluded meanwhile
+    ///
+    /// fails with [`Error::Unhealthy`] when the locks don't hold across sessions, e.g. behind a
+    /// pooler that hands the session to other clients, and with the backend's error when the user
+    /// may not take locks. takes two connections of `pool`, suitable for readiness probes.
+    fn health_check(pool: &::sqlx::Pool<Self::DB>) -> impl Future<Output = Result<()>> + Send
+    where
+        Self: Sized,
+    {
+        health::check::<Self>(pool)
+    }
+
+    /// subscribe to the lock events of every locker in the process
+    fn subscribe_events() -> LockEvents {
+        LockEvents::subscribe()
+    }
+
+    /// execute the given closure while the key is locked
+    ///
+    /// * `pool` - connection pool
+    /// * `key` - key to get locked
+    /// * `timeout` - timeout duration. if it can't get lock in 1 sec, with_locking will return Err. if None is given and a conflict occurs, it will fail immediately.
+    /// * `f` - closure that executed while the key is locked
     fn with_locking<T, F>(
         pool: &::sqlx::Pool<Self::DB>,
         key: &str,
@@ -48,6 +1112,399 @@ pub trait Locker {
         f: F,
     ) -> impl Future<Output = Result<()>>
     where
-        // FIXME: 長過ぎるわけだけど、トレイトエイリアスパターンを使ってみても微妙だったのでこれでいく
-        F: AsyncFnOnce(&mut ::sqlx::Transaction<'static, Self::DB>) -> T;
+        F: LockFn<Self::DB, T>,
+    {
+        let fut = async move {
+            let (ran, r) = section::section::<Self, DefaultClock, _, _>(
+                pool,
+                key,
+                async |tx| Self::acquire(pool, tx, key, timeout).await,
+                // シャットダウンで打ち切られてもロックは解放する
+                async |registration, hold_until, tx| {
+                    registration.holding_until(hold_until, key, f(tx)).await
+                },
+                async |tx, ()| Self::release(pool, tx, key).await,
+            )
+            .await?;
+
+            after_release(r, ran.map(|_| ()))
+        };
+
+        trace::instrument(fut, Self::NAME, key)
+    }
+
+    /// execute the given closure while the row `id` of `table` is locked
+    ///
+    /// the key can't collide with the keys of other tables or with keys built by hand. numeric
+    /// ids are locked on a bigint key of their own by `PostgresLocker`.
+    ///
+    /// * `pool` - connection pool
+    /// * `table` - name of the table of the row
+    /// * `id` - primary key of the row
+    /// * `timeout` - timeout duration. if None is given and a conflict occurs, it will fail immediately.
+    /// * `f` - closure that executed while the row is locked
+    fn with_row_lock<T, F>(
+        pool: &::sqlx::Pool<Self::DB>,
+        table: &str,
+        id: impl Into<RowId>,
+        timeout: Option<std::time::Duration>,
+        f: F,
+    ) -> impl Future<Output = Result<()>>
+    where
+        F: LockFn<Self::DB, T>,
+    {
+        let id = id.into();
+        async move {
+            let key = id.key(table);
+            let fut = async {
+                let (ran, r) = section::section::<Self, DefaultClock, _, _>(
+                    pool,
+                    &key,
+                    async |tx| Self::acquire_row(pool, tx, table, &id, timeout).await,
+                    async |registration, hold_until, tx| {
+                        registration.holding_until(hold_until, &key, f(tx)).await
+                    },
+                    async |tx, ()| Self::release_row(pool, tx, table, &id).await,
+                )
+                .await?;
+
+                after_release(r, ran.map(|_| ()))
+            };
+
+            trace::instrument(fut, Self::NAME, &key).await
+        }
+    }
+
+    /// execute the given closure while the [`LockKey`] is locked
+    ///
+    /// numeric keys are locked on `pg_advisory_lock(bigint)` as is by `PostgresLocker`, skipping
+    /// the hashing of string keys.
+    ///
+    /// * `pool` - connection pool
+    /// * `key` - key to get locked, e.g. a `&str` or an `i64` id
+    /// * `timeout` - timeout duration. if None is given and a conflict occurs, it will fail immediately.
+    /// * `f` - closure that executed while the key is locked
+    fn with_key_lock<T, F>(
+        pool: &::sqlx::Pool<Self::DB>,
+        key: impl LockKey,
+        timeout: Option<std::time::Duration>,
+        f: F,
+    ) -> impl Future<Output = Result<()>>
+    where
+        F: LockFn<Self::DB, T>,
+    {
+        async move {
+            let key = key.lock_key();
+            let name = key.text();
+            let fut = async {
+                let (ran, r) = section::section::<Self, DefaultClock, _, _>(
+                    pool,
+                    &name,
+                    async |tx| Self::acquire_key(pool, tx, &key, timeout).await,
+                    async |registration, hold_until, tx| {
+                        registration.holding_until(hold_until, &name, f(tx)).await
+                    },
+                    async |tx, ()| Self::release_key(pool, tx, &key).await,
+                )
+                .await?;
+
+                after_release(r, ran.map(|_| ()))
+            };
+
+            trace::instrument(fut, Self::NAME, &name).await
+        }
+    }
+
+    /// execute the given closure while the key is locked, running it again if the lock is lost
+    ///
+    /// when [`Locker::lock_lost`] completes, the closure is aborted and run again once the key is
+    /// acquired again, up to `retries` times, then the call fails with [`Error::LockLost`]. the
+    /// closure is given the number of the attempt, 0 at first, so a re-run can check or undo what
+    /// the aborted one did. the closure's result is returned.
+    ///
+    /// * `pool` - connection pool
+    /// * `key` - key to get locked
+    /// * `timeout` - timeout duration of each acquisition. if None is given and a conflict occurs, it will fail immediately.
+    /// * `retries` - how many times the closure may be run again
+    /// * `f` - closure that executed while the key is locked
+    fn with_locking_retrying<T, F>(
+        pool: &::sqlx::Pool<Self::DB>,
+        key: &str,
+        timeout: Option<std::time::Duration>,
+        retries: u32,
+        mut f: F,
+    ) -> impl Future<Output = Result<T>>
+    where
+        Self: Sized,
+        F: AsyncFnMut(&mut ::sqlx::Transaction<'static, Self::DB>, u32) -> T,
+    {
+        let fut = async move {
+            for attempt in 0.. {
+                let (ran, r) = section::section::<Self, DefaultClock, _, _>(
+                    pool,
+                    key,
+                    async |tx| Self::acquire(pool, tx, key, timeout).await,
+                    // ロックが失われたらクロージャを打ち切る
+                    async |registration, hold_until, tx| {
+                        let mut ran = std::pin::pin!(f(tx, attempt));
+                        let mut lost = std::pin::pin!(Self::lock_lost(key));
+                        let within = std::future::poll_fn(|cx| {
+                            if let std::task::Poll::Ready(out) = ran.as_mut().poll(cx) {
+                                return std::task::Poll::Ready(Some(out));
+                            }
+                            lost.as_mut().poll(cx).map(|()| None)
+                        });
+                        registration.holding_until(hold_until, key, within).await
+                    },
+                    async |tx, ()| Self::release(pool, tx, key).await,
+                )
+                .await?;
+
+                match ran {
+                    Ok(Some(out)) => return r.map(|()| out),
+                    // 失われたロックの解放は失敗してもよい
+                    Ok(None) if attempt < retries => {}
+                    Ok(None) => return Err(Error::LockLost(key.to_owned())),
+                    Err(e) => return after_release(r, Err(e)),
+                }
+            }
+
+            unreachable!("the attempts ran out of u32")
+        };
+
+        trace::instrument(fut, Self::NAME, key)
+    }
+
+    /// execute the given closure while the key is locked, unless it already completed for the token
+    ///
+    /// the completion is recorded in the `lock_executions` table before the key is released, so a
+    /// retried call with the same token returns None without running the closure. see
+    /// [`ExecutionDatabase`].
+    ///
+    /// * `pool` - connection pool
+    /// * `key` - key to get locked
+    /// * `token` - idempotency token of the execution, e.g. a request or message id
+    /// * `timeout` - timeout duration. if None is given and a conflict occurs, it will fail immediately.
+    /// * `f` - closure that executed while the key is locked
+    fn with_locking_once<T, F>(
+        pool: &::sqlx::Pool<Self::DB>,
+        key: &str,
+        token: &str,
+        timeout: Option<std::time::Duration>,
+        f: F,
+    ) -> impl Future<Output = Result<Option<T>>>
+    where
+        Self: Sized,
+        Self::DB: ExecutionDatabase,
+        F: LockFn<Self::DB, T>,
+    {
+        let fut = async move {
+            let (ran, r) = section::section::<Self, DefaultClock, _, _>(
+                pool,
+                key,
+                async |tx| Self::acquire(pool, tx, key, timeout).await,
+                // 記録の確認も書き込みもロックを持っている間に行う
+                async |registration, hold_until, tx| {
+                    if Self::DB::execution_recorded(pool, key, token).await? {
+                        return Ok(None);
+                    }
+                    let out = registration.holding_until(hold_until, key, f(tx)).await?;
+                    Self::DB::record_execution(pool, key, token)
+                        .await
+                        .map(|()| Some(out))
+                },
+                async |tx, ()| Self::release(pool, tx, key).await,
+            )
+            .await?;
+
+            after_release(r, ran)
+        };
+
+        trace::instrument(fut, Self::NAME, key)
+    }
+
+    /// execute the given closure while the key is locked, unless a call on the key stored its
+    /// result less than `ttl` ago
+    ///
+    /// the first call runs the closure and stores its result as JSON in the `lock_results` table
+    /// before releasing the key. the calls that waited for it, in this process or another, read
+    /// that result instead of computing it again, collapsing a thundering herd on an expensive
+    /// computation into a single run. see [`CacheDatabase`].
+    ///
+    /// the result is read and stored on the transaction of the lock, which is committed with the
+    /// result, so the closure's work is committed too, unlike that of [`Locker::with_locking`].
+    ///
+    /// * `pool` - connection pool
+    /// * `key` - key to get locked, and to store the result under
+    /// * `ttl` - how long the stored result is returned
+    /// * `timeout` - timeout duration. the calls waiting for the first one need it: if None is given
+    ///   and a conflict occurs, it will fail immediately instead of reading the result.
+    /// * `f` - closure that executed while the key is locked
+    ///
+    /// ```ignore
+    /// let report = MySqlLocker::with_locking_cached(&pool, "report:daily", Duration::from_secs(60), Duration::from_secs(30).into(), async |tx| {
+    ///     build_report(tx).await
+    /// })
+    /// .await?;
+    /// ```
+    #[cfg(feature = "serde")]
+    fn with_locking_cached<T, F>(
+        pool: &::sqlx::Pool<Self::DB>,
+        key: &str,
+        ttl: std::time::Duration,
+        timeout: Option<std::time::Duration>,
+        f: F,
+    ) -> impl Future<Output = Result<T>>
+    where
+        Self: Sized,
+        Self::DB: CacheDatabase,
+        T: serde::Serialize + serde::de::DeserializeOwned,
+        F: LockFn<Self::DB, T>,
+    {
+        let fut = async move {
+            // 新しい結果があればロックを待たない
+            if let Some(out) =
+                cache::cached::<Self::DB, _>(&mut *pool.acquire().await?, key).await?
+            {
+                return Ok(out);
+            }
+
+            let (ran, r) = section::section::<Self, DefaultClock, _, _>(
+                pool,
+                key,
+                async |tx| Self::acquire(pool, tx, key, timeout).await,
+                async |registration, hold_until, tx| {
+                    registration
+                        .holding_until(hold_until, key, async {
+                            // 待っていた間に勝った呼び出しが書いた結果を、ロックの接続で読む
+                            if let Some(cached) =
+                                cache::cached::<Self::DB, _>(&mut **tx, key).await?
+                            {
+                                return Ok(cached);
+                            }
+                            let computed = f(tx).await;
+                            cache::store(tx, key, &computed, ttl).await?;
+                            Ok(computed)
+                        })
+                        .await
+                        .and_then(|r| r)
+                },
+                async |tx, ()| Self::release(pool, tx, key).await,
+            )
+            .await?;
+
+            after_release(r, ran)
+        };
+
+        trace::instrument(fut, Self::NAME, key)
+    }
+
+    /// execute the given closure while `weight` of the `capacity` permits of the key are held
+    ///
+    /// the permits are locked as keys of their own, so every caller of the key must use the same
+    /// capacity. a caller that can't get all its permits holds none of them while it waits. fails
+    /// with [`Error::InvalidConfig`] if `weight` exceeds `capacity`, since it could never be held.
+    ///
+    /// * `pool` - connection pool
+    /// * `key` - key of the shared budget
+    /// * `weight` - permits this call needs
+    /// * `capacity` - permits of the key
+    /// * `timeout` - timeout duration. if None is given and a conflict occurs, it will fail immediately.
+    /// * `f` - closure that executed while the permits are held
+    fn with_permits<T, F>(
+        pool: &::sqlx::Pool<Self::DB>,
+        key: &str,
+        weight: u32,
+        capacity: u32,
+        timeout: Option<std::time::Duration>,
+        f: F,
+    ) -> impl Future<Output = Result<()>>
+    where
+        Self: Sized,
+        F: LockFn<Self::DB, T>,
+    {
+        let fut = async move {
+            let (ran, r) = section::section::<Self, DefaultClock, _, _>(
+                pool,
+                key,
+                async |tx| {
+                    semaphore::acquire::<Self>(pool, tx, key, weight, capacity, timeout).await
+                },
+                async |registration, hold_until, tx| {
+                    registration.holding_until(hold_until, key, f(tx)).await
+                },
+                async |tx, permits| semaphore::release::<Self>(pool, tx, key, &permits).await,
+            )
+            .await?;
+
+            after_release(r, ran.map(|_| ()))
+        };
+
+        trace::instrument(fut, Self::NAME, key)
+    }
+
+    /// execute the given closure on the calling thread while the key is locked
+    ///
+    /// the locker is driven on [`blocking::runtime`], so this must not be called from an async
+    /// context. the closure's result is returned.
+    ///
+    /// * `pool` - connection pool, created on [`blocking::runtime`]
+    /// * `key` - key to get locked
+    /// * `timeout` - timeout duration. if None is given and a conflict occurs, it will fail immediately.
+    /// * `f` - closure that executed while the key is locked
+    #[cfg(feature = "blocking")]
+    fn with_locking_blocking<T>(
+        pool: &::sqlx::Pool<Self::DB>,
+        key: &str,
+        timeout: Option<std::time::Duration>,
+        f: impl FnOnce() -> T,
+    ) -> Result<T> {
+        blocking::with_locking::<Self, T>(pool, key, timeout, f)
+    }
+
+    /// execute the given closure while the key is locked, holding the lock on a dedicated pool
+    ///
+    /// the lock session is taken from `lock_pool`, so long lock waits never occupy connections of
+    /// `pool`. the closure still receives a transaction started from `pool`.
+    ///
+    /// * `lock_pool` - connection pool used only for lock sessions
+    /// * `pool` - connection pool the closure's transaction is started from
+    /// * `key` - key to get locked
+    /// * `timeout` - timeout duration. if None is given and a conflict occurs, it will fail immediately.
+    /// * `f` - closure that executed while the key is locked
+    fn with_locking_on<T, F>(
+        lock_pool: &::sqlx::Pool<Self::DB>,
+        pool: &::sqlx::Pool<Self::DB>,
+        key: &str,
+        timeout: Option<std::time::Duration>,
+        f: F,
+    ) -> impl Future<Output = Result<()>>
+    where
+        F: LockFn<Self::DB, T>,
+    {
+        let fut = async move {
+            let (ran, released) = section::section::<Self, DefaultClock, _, _>(
+                lock_pool,
+                key,
+                async |lock_tx| Self::acquire(lock_pool, lock_tx, key, timeout).await,
+                // クロージャ用のトランザクションが取れなくてもロックは必ず解放する
+                async |registration, hold_until, _| {
+                    registration
+                        .holding_until(hold_until, key, async {
+                            let mut tx = pool.begin().await?;
+                            f(&mut tx).await;
+                            Ok(())
+                        })
+                        .await
+                        .and_then(|r| r)
+                },
+                async |lock_tx, ()| Self::release(lock_pool, lock_tx, key).await,
+            )
+            .await?;
+
+            after_release(released, ran)
+        };
+
+        trace::instrument(fut, Self::NAME, key)
+    }
 }
@@ -0,0 +1,165 @@
+use std::future::Future;
+
+use crate::Result;
+
+/// Database the execution records of [`crate::Locker::with_locking_once`] are kept in.
+///
+/// the `lock_executions` table has to exist beforehand, add
+/// [`ExecutionDatabase::EXECUTION_TABLE`] to the migrations of the application.
+pub trait ExecutionDatabase: sqlx::Database {
+    /// DDL of the `lock_executions` table
+    const EXECUTION_TABLE: &'static str;
+
+    /// whether an execution of the token under the key was recorded
+    fn execution_recorded(
+        pool: &sqlx::Pool<Self>,
+        key: &str,
+        token: &str,
+    ) -> impl Future<Output = Result<bool>> + Send;
+
+    /// record that the token completed under the key
+    fn record_execution(
+        pool: &sqlx::Pool<Self>,
+        key: &str,
+        token: &str,
+    ) -> impl Future<Output = Result<()>> + Send;
+}
+
+#[cfg(feature = "sqlx-mysql")]
+impl ExecutionDatabase for sqlx::MySql {
+    const EXECUTION_TABLE: &'static str = "CREATE TABLE IF NOT EXISTS lock_executions (
+    lock_key VARCHAR(255) NOT NULL,
+    token VARCHAR(255) NOT NULL,
+    completed_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3),
+    PRIMARY KEY (lock_key, token)
+)";
+
+    async fn execution_recorded(pool: &sqlx::Pool<Self>, key: &str, token: &str) -> Result<bool> {
+        let recorded =
+            sqlx::query("SELECT 1 FROM lock_executions WHERE lock_key = ? AND token = ?")
+                .bind(key)
+                .bind(token)
+                .fetch_optional(pool)
+                .await?;
+
+        Ok(recorded.is_some())
+    }
+
+    async fn record_execution(pool: &sqlx::Pool<Self>, key: &str, token: &str) -> Result<()> {
+        sqlx::query("INSERT IGNORE INTO lock_executions (lock_key, token) VALUES (?, ?)")
+            .bind(key)
+            .bind(token)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sqlx-postgres")]
+impl ExecutionDatabase for sqlx::Postgres {
+    const EXECUTION_TABLE: &'static str = "CREATE TABLE IF NOT EXISTS lock_executions (
+    lock_key TEXT NOT NULL,
+    token TEXT NOT NULL,
+    completed_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (lock_key, token)
+)";
+
+    async fn execution_recorded(pool: &sqlx::Pool<Self>, key: &str, token: &str) -> Result<bool> {
+        let recorded =
+            sqlx::query("SELECT 1 FROM lock_executions WHERE lock_key = $1 AND token = $2")
+                .bind(key)
+                .bind(token)
+                .fetch_optional(pool)
+                .await?;
+
+        Ok(recorded.is_some())
+    }
+
+    async fn record_execution(pool: &sqlx::Pool<Self>, key: &str, token: &str) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO lock_executions (lock_key, token) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        )
+        .bind(key)
+        .bind(token)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sqlx-sqlite")]
+impl ExecutionDatabase for sqlx::Sqlite {
+    const EXECUTION_TABLE: &'static str = "CREATE TABLE IF NOT EXISTS lock_executions (
+    lock_key TEXT NOT NULL,
+    token TEXT NOT NULL,
+    completed_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+    PRIMARY KEY (lock_key, token)
+)";
+
+    async fn execution_recorded(pool: &sqlx::Pool<Self>, key: &str, token: &str) -> Result<bool> {
+        let recorded =
+            sqlx::query("SELECT 1 FROM lock_executions WHERE lock_key = ? AND token = ?")
+                .bind(key)
+                .bind(token)
+                .fetch_optional(pool)
+                .await?;
+
+        Ok(recorded.is_some())
+    }
+
+    async fn record_execution(pool: &sqlx::Pool<Self>, key: &str, token: &str) -> Result<()> {
+        sqlx::query("INSERT OR IGNORE INTO lock_executions (lock_key, token) VALUES (?, ?)")
+            .bind(key)
+            .bind(token)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "sqlx-std-collection", feature = "sqlx-sqlite"))]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    use crate::{Locker, StdCollectionLocker};
+    use sqlx::{Sqlite, SqlitePool};
+
+    type Collection = StdCollectionLocker<Sqlite>;
+
+    #[sqlx::test]
+    async fn completed_token_is_not_run_again(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Ow3Pe6Qr9St2Uv5Wx8Yz1Ab4Cd7Ef0Gh3Ij6Kl9Mn2Op5Qr8St1Uv4Wx7Yz0Ab3C";
+        sqlx::raw_sql(Sqlite::EXECUTION_TABLE)
+            .execute(&pool)
+            .await?;
+        let mut runs = 0;
+
+        let first = Collection::with_locking_once(&pool, key, "job-1", None, async |_| {
+            runs += 1;
+            "done"
+        })
+        .await;
+        let retried = Collection::with_locking_once(&pool, key, "job-1", None, async |_| {
+            runs += 1;
+            "done"
+        })
+        .await;
+        let other = Collection::with_locking_once(&pool, key, "job-2", None, async |_| {
+            runs += 1;
+            "done"
+        })
+        .await;
+
+        assert_eq!(first.unwrap(), Some("done"));
+        assert_eq!(retried.unwrap(), None);
+        assert_eq!(other.unwrap(), Some("done"));
+        assert_eq!(runs, 2);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,241 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::Duration,
+};
+
+use crate::{Clock, DefaultClock, Error, LockClient, Locker, NO_WAIT, Result};
+
+/// Partition of an outbox table claimed by an [`OutboxWorker`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Partition {
+    /// index of this partition, below `count`
+    pub index: u32,
+    /// number of partitions the table is split into
+    pub count: u32,
+}
+
+/// Claims the rows of a partition of an outbox table and delivers them.
+pub trait OutboxHandler<DB: sqlx::Database>: Send + Sync {
+    /// deliver up to `batch_size` undelivered rows of `partition`, returning how many it delivered
+    ///
+    /// `tx` is committed when this returns Ok, so the rows should be marked as delivered on it.
+    /// a row belongs to a partition by e.g. `id % partition.count = partition.index`.
+    fn deliver(
+        &self,
+        tx: &mut sqlx::Transaction<'static, DB>,
+        partition: Partition,
+        batch_size: usize,
+    ) -> impl Future<Output = Result<usize>> + Send;
+}
+
+/// Worker polling an outbox table, several of which deliver each row once.
+///
+/// the table is split into partitions, each locked by the worker delivering it. partitions locked
+/// by another worker are skipped instead of waited for.
+///
+/// ```ignore
+/// struct Publish;
+///
+/// impl OutboxHandler<Postgres> for Publish {
+///     async fn deliver(&self, tx: &mut Transaction<'static, Postgres>, partition: Partition, batch_size: usize) -> Result<usize> {
+///         let rows: Vec<(i64, String)> = sqlx::query_as(
+///             "UPDATE outbox SET delivered = TRUE WHERE id IN (
+///                  SELECT id FROM outbox WHERE NOT delivered AND id % $1 = $2 ORDER BY id LIMIT $3
+///              ) RETURNING id, payload",
+///         )
+///         // ...
+///         publish(&rows).await?;
+///         Ok(rows.len())
+///     }
+/// }
+///
+/// OutboxWorker::new(LockClient::<PostgresLocker>::new(pool), "orders", Publish)
+///     .partitions(8)
+///     .run(Duration::from_secs(1))
+///     .await?;
+/// ```
+pub struct OutboxWorker<L: Locker, H> {
+    client: LockClient<L>,
+    name: Arc<str>,
+    handler: Arc<H>,
+    partitions: u32,
+    batch_size: usize,
+    next: Arc<AtomicU32>,
+}
+
+impl<L: Locker, H> Clone for OutboxWorker<L, H> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            name: Arc::clone(&self.name),
+            handler: Arc::clone(&self.handler),
+            partitions: self.partitions,
+            batch_size: self.batch_size,
+            next: Arc::clone(&self.next),
+        }
+    }
+}
+
+impl<L: Locker, H: OutboxHandler<L::DB>> OutboxWorker<L, H> {
+    /// deliver the outbox named `name` with `handler`, in 16 partitions of batches of 100 rows
+    pub fn new(client: LockClient<L>, name: &str, handler: H) -> Self {
+        Self {
+            client,
+            name: name.into(),
+            handler: Arc::new(handler),
+            partitions: 16,
+            batch_size: 100,
+            next: Arc::default(),
+        }
+    }
+
+    /// split the table into `partitions`, at most that many workers deliver at once
+    ///
+    /// every worker of the outbox must use the same number.
+    pub fn partitions(mut self, partitions: u32) -> Self {
+        self.partitions = partitions.max(1);
+        self
+    }
+
+    /// rows handed to [`OutboxHandler::deliver`] at most at once
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// deliver a batch of every partition not locked by another worker, returning the rows delivered
+    pub async fn poll(&self) -> Result<usize> {
+        // 毎回違うパーティションから始めて、ワーカー同士がぶつかり続けないようにする
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut delivered = 0;
+
+        for i in 0..self.partitions {
+            let partition = Partition {
+                index: start.wrapping_add(i) % self.partitions,
+                count: self.partitions,
+            };
+            let key = format!("outbox:{}:{}", self.name, partition.index);
+
+            let r = self
+                .client
+                .run_locked(&key, NO_WAIT, async {
+                    let mut tx = self.client.pool().begin().await?;
+                    let n = self
+                        .handler
+                        .deliver(&mut tx, partition, self.batch_size)
+                        .await?;
+                    tx.commit().await?;
+                    Ok(n)
+                })
+                .await;
+
+            match r {
+                Ok(Ok(n)) => delivered += n,
+                Ok(Err(e)) => return Err(e),
+                Err(Error::FailedToGetLock(_) | Error::RetriesExhausted { .. }) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(delivered)
+    }
+
+    /// poll until it fails, waiting `interval` after a poll that delivered nothing
+    pub async fn run(&self, interval: Duration) -> Result<()> {
+        loop {
+            if self.poll().await? == 0 {
+                DefaultClock::sleep(interval).await;
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "sqlx-std-collection"))]
+mod tests {
+    use pretty_assertions::{assert_eq, assert_matches};
+    use std::sync::Mutex;
+    use tokio::time::sleep;
+
+    use super::*;
+
+    use crate::StdCollectionLocker;
+    use sqlx::{Sqlite, SqlitePool};
+
+    type Collection = StdCollectionLocker<Sqlite>;
+
+    #[derive(Default)]
+    struct Record(Mutex<Vec<i64>>);
+
+    impl OutboxHandler<Sqlite> for Record {
+        async fn deliver(
+            &self,
+            tx: &mut sqlx::Transaction<'static, Sqlite>,
+            partition: Partition,
+            batch_size: usize,
+        ) -> Result<usize> {
+            let ids: Vec<i64> = sqlx::query_scalar(
+                "UPDATE outbox SET delivered = TRUE WHERE id IN (
+                     SELECT id FROM outbox WHERE NOT delivered AND id % ? = ? ORDER BY id LIMIT ?
+                 ) RETURNING id",
+            )
+            .bind(partition.count)
+            .bind(partition.index)
+            .bind(batch_size as i64)
+            .fetch_all(&mut **tx)
+            .await?;
+
+            self.0.lock().unwrap().extend(&ids);
+
+            Ok(ids.len())
+        }
+    }
+
+    #[sqlx::test]
+    async fn workers_deliver_each_row_once(pool: SqlitePool) -> sqlx::Result<()> {
+        let name = "Kt7Lz9Xa1Cs3Vd5Bf7Ng9Mh1Qj3Wk5El7Rz9Tx1Yc3Uv5Ib7On9Pm1Aq3Sw5De7F";
+        sqlx::raw_sql(
+            "CREATE TABLE outbox (id INTEGER PRIMARY KEY, delivered BOOLEAN NOT NULL DEFAULT FALSE);
+             WITH RECURSIVE n(id) AS (SELECT 1 UNION ALL SELECT id + 1 FROM n WHERE id < 20)
+             INSERT INTO outbox (id) SELECT id FROM n;",
+        )
+        .execute(&pool)
+        .await?;
+
+        let worker = OutboxWorker::new(
+            LockClient::<Collection>::new(pool.clone()),
+            name,
+            Record::default(),
+        )
+        .partitions(2)
+        .batch_size(3);
+
+        // 他のワーカーが持っているパーティションは飛ばす
+        let held = format!("outbox:{name}:0");
+        let (_, r) = tokio::join!(
+            Collection::with_locking(&pool, &held, None, async |_| {
+                sleep(Duration::from_millis(300)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                worker.poll().await
+            }
+        );
+        assert_matches!(r, Ok(3));
+        assert_eq!(*worker.handler.0.lock().unwrap(), vec![1, 3, 5]);
+
+        let replica = worker.clone();
+        let drain = async |worker: &OutboxWorker<Collection, Record>| {
+            while worker.poll().await.unwrap() > 0 {}
+        };
+        tokio::join!(drain(&worker), drain(&replica));
+
+        let mut delivered = worker.handler.0.lock().unwrap().clone();
+        delivered.sort();
+        assert_eq!(delivered, (1..=20).collect::<Vec<_>>());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,204 @@
+//! Python module `rusty_ad_lock` over the MySQL and PostgreSQL lockers.
+//!
+//! keys are normalized exactly as on the Rust side, so both languages contend for the same locks.
+//! build the extension module with the backends it should connect to, e.g.
+//! `cargo rustc --release --features python-extension,sqlx-mysql,sqlx-postgres,runtime-tokio-rustls --crate-type cdylib`
+//! and install `librusty_ad_lock.so` as `rusty_ad_lock.so`.
+//!
+//! ```python
+//! pool = await rusty_ad_lock.connect("postgres://localhost/app")
+//! async with pool.with_locking("report:42", timeout=5.0):
+//!     ...
+//! ```
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use pyo3::{
+    create_exception,
+    exceptions::{PyRuntimeError, PyValueError},
+    prelude::*,
+    types::PyCFunction,
+};
+
+use super::{
+    bindings::{HeldLock, Pool},
+    blocking::runtime,
+};
+use crate::{Error, Result};
+
+create_exception!(
+    rusty_ad_lock,
+    LockError,
+    PyRuntimeError,
+    "the lock could not be acquired or released"
+);
+create_exception!(
+    rusty_ad_lock,
+    FailedToGetLock,
+    LockError,
+    "the key could not be acquired within the timeout"
+);
+
+fn py_err(e: Error) -> PyErr {
+    match e {
+        Error::FailedToGetLock(_) | Error::RetriesExhausted { .. } => {
+            FailedToGetLock::new_err(e.to_string())
+        }
+        e => LockError::new_err(e.to_string()),
+    }
+}
+
+// asyncio のデフォルトの executor のスレッドで待つ。
+// tokio のスレッドが Python に触れると、インタプリタの終了と競合して落ちる
+fn run_in_executor<'py, F, T>(py: Python<'py>, f: F) -> PyResult<Bound<'py, PyAny>>
+where
+    F: Fn() -> Result<T> + Send + Sync + 'static,
+    T: for<'a> IntoPyObject<'a> + Send,
+{
+    let call = PyCFunction::new_closure(py, None, None, move |args, _kwargs| {
+        args.py().detach(&f).map_err(py_err)
+    })?;
+
+    py.import("asyncio")?
+        .call_method0("get_running_loop")?
+        .call_method1("run_in_executor", (py.None(), call))
+}
+
+/// Connection pool of a MySQL or PostgreSQL database.
+#[pyclass(name = "Pool", frozen)]
+struct PyPool(Pool);
+
+#[pymethods]
+impl PyPool {
+    /// async context manager holding the key while the block runs
+    ///
+    /// `timeout` is in seconds. fails immediately with `FailedToGetLock` if the key is held and
+    /// it is omitted. the wait occupies a thread of the event loop's default executor.
+    #[pyo3(signature = (key, timeout = None))]
+    fn with_locking(&self, key: String, timeout: Option<f64>) -> PyResult<WithLocking> {
+        let timeout = timeout
+            .map(Duration::try_from_secs_f64)
+            .transpose()
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+
+        Ok(WithLocking {
+            pool: self.0.clone(),
+            key,
+            timeout,
+            held: Arc::default(),
+        })
+    }
+}
+
+/// Context manager returned by `Pool.with_locking`.
+#[pyclass(frozen)]
+struct WithLocking {
+    pool: Pool,
+    key: String,
+    timeout: Option<Duration>,
+    held: Arc<Mutex<Option<HeldLock>>>,
+}
+
+#[pymethods]
+impl WithLocking {
+    fn __aenter__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        if self.held.lock().unwrap().is_some() {
+            return Err(LockError::new_err(format!("{} is already held", self.key)));
+        }
+
+        let pool = self.pool.clone();
+        let key = self.key.clone();
+        let timeout = self.timeout;
+        let held = Arc::clone(&self.held);
+        run_in_executor(py, move || {
+            let lock = runtime().block_on(pool.acquire(&key, timeout))?;
+            *held.lock().unwrap() = Some(lock);
+            Ok(())
+        })
+    }
+
+    fn __aexit__<'py>(
+        &self,
+        py: Python<'py>,
+        _exc_type: Py<PyAny>,
+        _exc: Py<PyAny>,
+        _traceback: Py<PyAny>,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let held = Arc::clone(&self.held);
+        run_in_executor(py, move || {
+            if let Some(lock) = held.lock().unwrap().take() {
+                runtime().block_on(lock.release())?;
+            }
+            // 例外は握りつぶさない
+            Ok(false)
+        })
+    }
+}
+
+/// connect to the mysql://, mariadb:// or postgres:// URL
+#[pyfunction]
+fn connect(py: Python<'_>, url: String) -> PyResult<Bound<'_, PyAny>> {
+    run_in_executor(py, move || {
+        runtime().block_on(Pool::connect(&url)).map(PyPool)
+    })
+}
+
+#[pymodule]
+fn rusty_ad_lock(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(connect, m)?)?;
+    m.add_class::<PyPool>()?;
+    m.add_class::<WithLocking>()?;
+    m.add("LockError", m.py().get_type::<LockError>())?;
+    m.add("FailedToGetLock", m.py().get_type::<FailedToGetLock>())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use pyo3::types::IntoPyDict;
+
+    use super::*;
+
+    #[test]
+    fn held_key_conflicts_until_the_block_exits() {
+        let key = "Hq4Wz6Xs8Cd0Vf2Bg4Nh6Mj8Ku0Yl2Ti4Ro6Ep8Wa0Qz2Sx4Dc6Fv8Gb0Hn2Jm4K";
+        let url = std::env::var("DATABASE_URL").unwrap();
+        Python::initialize();
+
+        Python::attach(|py| {
+            let module = PyModule::new(py, "rusty_ad_lock")?;
+            rusty_ad_lock(&module)?;
+            let globals = [
+                ("ral", module.into_any()),
+                ("url", url.into_pyobject(py)?.into_any()),
+                ("key", key.into_pyobject(py)?.into_any()),
+            ]
+            .into_py_dict(py)?;
+
+            py.run(
+                cr#"
+import asyncio
+
+async def main():
+    pool = await ral.connect(url)
+    async with pool.with_locking(key):
+        try:
+            async with pool.with_locking(key, timeout=0.1):
+                raise AssertionError("acquired the held key")
+        except ral.FailedToGetLock:
+            pass
+    async with pool.with_locking(key):
+        pass
+
+asyncio.run(main())
+"#,
+                Some(&globals),
+                None,
+            )
+        })
+        .unwrap();
+    }
+}
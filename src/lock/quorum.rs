@@ -0,0 +1,128 @@
+use std::time::Duration;
+
+use super::after_release;
+use crate::{Error, LockClient, LockGuard, Locker, Result};
+
+/// Locker holding a key on a majority of independent databases, e.g. the primaries of several
+/// regions.
+///
+/// a key held on a majority can't be held by another caller of the same pools, so one of three
+/// regions may be unavailable without stopping the locks.
+///
+/// ```ignore
+/// let locker = QuorumLocker::new(vec![
+///     LockClient::<MySqlLocker>::new(tokyo),
+///     LockClient::<MySqlLocker>::new(oregon),
+///     LockClient::<MySqlLocker>::new(frankfurt),
+/// ]);
+/// locker.with_locking("settlement", None, async || settle().await).await?;
+/// ```
+pub struct QuorumLocker<L: Locker> {
+    clients: Vec<LockClient<L>>,
+}
+
+impl<L: Locker> Clone for QuorumLocker<L> {
+    fn clone(&self) -> Self {
+        Self {
+            clients: self.clients.clone(),
+        }
+    }
+}
+
+impl<L: Locker> QuorumLocker<L> {
+    /// create a locker over the databases of `clients`
+    pub fn new(clients: Vec<LockClient<L>>) -> Self {
+        Self { clients }
+    }
+
+    /// number of databases a key has to be held on
+    pub fn quorum(&self) -> usize {
+        self.clients.len() / 2 + 1
+    }
+
+    /// execute the given closure while the key is held on a quorum
+    ///
+    /// the key is acquired on the databases one after another, each within `timeout`. fails with
+    /// [`Error::NoQuorum`] if fewer than [`QuorumLocker::quorum`] were acquired, releasing them. a
+    /// release failing after that returns both as [`Error::ReleaseFailed`].
+    pub async fn with_locking<T, F>(&self, key: &str, timeout: Option<Duration>, f: F) -> Result<T>
+    where
+        L: 'static,
+        F: AsyncFnOnce() -> T,
+    {
+        let mut guards = Vec::with_capacity(self.clients.len());
+        for client in &self.clients {
+            // 繋がらないデータベースや競合は過半数に届くかどうかだけで判断する
+            if let Ok(guard) = client.guard(key, timeout).await {
+                guards.push(guard);
+            }
+        }
+
+        let acquired = guards.len();
+        let out = if acquired < self.quorum() {
+            Err(Error::NoQuorum {
+                key: key.to_owned(),
+                acquired,
+                required: self.quorum(),
+            })
+        } else {
+            Ok(f().await)
+        };
+
+        after_release(release_all(guards).await, out)
+    }
+}
+
+// 失敗しても全部解放してから、最初のエラーを返す
+async fn release_all<L: Locker>(guards: Vec<LockGuard<'_, L>>) -> Result<()> {
+    let mut r = Ok(());
+    for guard in guards.into_iter().rev() {
+        r = r.and(guard.release().await);
+    }
+    r
+}
+
+#[cfg(all(test, feature = "sqlx-std-collection"))]
+mod tests {
+    use pretty_assertions::assert_matches;
+
+    use super::*;
+
+    use crate::StdCollectionLocker;
+    use sqlx::{Sqlite, SqlitePool};
+
+    type Collection = StdCollectionLocker<Sqlite>;
+
+    #[sqlx::test]
+    async fn majority_is_enough(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Jc5Rw0Mt7Xb2Kq9Vn4Hz1Ps6Fy3Dl8Ga5Ue0Io7Tk2Sx9Cm4Wr1Bh6Nv3Zf8Ld5E";
+        // 名前空間で別のデータベースに見立てる
+        let regions = ["tokyo", "oregon", "frankfurt"]
+            .map(|region| LockClient::<Collection>::new(pool.clone()).namespace(region));
+        let locker = QuorumLocker::new(regions.to_vec());
+
+        // 1 つ取られていても過半数は取れる
+        let tokyo = regions[0].guard(key, None).await.unwrap();
+        assert_matches!(locker.with_locking(key, None, async || 42).await, Ok(42));
+
+        let oregon = regions[1].guard(key, None).await.unwrap();
+        assert_matches!(
+            locker.with_locking(key, None, async || 42).await,
+            Err(Error::NoQuorum {
+                acquired: 1,
+                required: 2,
+                ..
+            })
+        );
+        // 過半数に届かなかった分も解放している
+        assert_matches!(
+            regions[2].with_locking(key, None, async |_| {}).await,
+            Ok(())
+        );
+
+        assert_matches!(tokyo.release().await, Ok(()));
+        assert_matches!(oregon.release().await, Ok(()));
+
+        Ok(())
+    }
+}
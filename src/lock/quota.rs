@@ -0,0 +1,43 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+};
+
+use crate::{Error, Result};
+
+// 名前空間ごとに、持っているか待っているロックの数
+static IN_USE: LazyLock<Mutex<HashMap<String, usize>>> = LazyLock::new(Mutex::default);
+
+// 名前空間の枠を一つ使っている間だけ生きる。キャンセルされても Drop で戻る
+pub(crate) struct QuotaSlot {
+    namespace: String,
+}
+
+impl QuotaSlot {
+    // 名前空間の使用数が max 未満なら一つ使う
+    pub(crate) fn take(namespace: Option<&str>, max: usize) -> Result<Self> {
+        let namespace = namespace.unwrap_or_default();
+        let mut in_use = IN_USE.lock().unwrap();
+        let n = in_use.entry(namespace.to_owned()).or_default();
+        if *n >= max {
+            return Err(Error::QuotaExceeded(namespace.to_owned()));
+        }
+        *n += 1;
+
+        Ok(Self {
+            namespace: namespace.to_owned(),
+        })
+    }
+}
+
+impl Drop for QuotaSlot {
+    fn drop(&mut self) {
+        let mut in_use = IN_USE.lock().unwrap();
+        if let Some(n) = in_use.get_mut(&self.namespace) {
+            *n -= 1;
+            if *n == 0 {
+                in_use.remove(&self.namespace);
+            }
+        }
+    }
+}
@@ -0,0 +1,92 @@
+/// Primary key of a row locked by [`crate::Locker::with_row_lock`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RowId {
+    /// numeric id, locked on a bigint key by `PostgresLocker` when it fits in 32 bits
+    Int(i64),
+    /// any other id, e.g. a UUID
+    Text(String),
+}
+
+impl RowId {
+    /// key of the row of `table`, distinct for every table and id
+    pub fn key(&self, table: &str) -> String {
+        // テーブル名の長さを付けて、":" を含む名前でも区切りが曖昧にならないようにする
+        match self {
+            Self::Int(id) => format!("row:{}:{table}:{id}", table.len()),
+            Self::Text(id) => format!("row:{}:{table}:{id}", table.len()),
+        }
+    }
+}
+
+macro_rules! from_int {
+    ($($t:ty),*) => {
+        $(
+            impl From<$t> for RowId {
+                fn from(id: $t) -> Self {
+                    Self::Int(id.into())
+                }
+            }
+        )*
+    };
+}
+
+from_int!(i16, i32, i64, u16, u32);
+
+impl From<&str> for RowId {
+    fn from(id: &str) -> Self {
+        Self::Text(id.to_owned())
+    }
+}
+
+impl From<String> for RowId {
+    fn from(id: String) -> Self {
+        Self::Text(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_ne;
+
+    use super::*;
+
+    #[test]
+    fn keys_are_distinct_for_every_table_and_id() {
+        assert_ne!(RowId::from(1).key("a:b"), RowId::from("b:1").key("a"));
+        assert_ne!(RowId::from(12).key("orders"), RowId::from(1).key("orders2"));
+        assert_ne!(RowId::from(1).key("orders"), RowId::from(1).key("invoices"));
+    }
+
+    #[cfg(feature = "sqlx-std-collection")]
+    #[sqlx::test]
+    async fn rows_of_the_same_table_and_id_conflict(pool: sqlx::SqlitePool) -> sqlx::Result<()> {
+        use pretty_assertions::assert_matches;
+        use std::time::Duration;
+        use tokio::time::sleep;
+
+        use crate::{Error, Locker, StdCollectionLocker};
+
+        type Collection = StdCollectionLocker<sqlx::Sqlite>;
+        let table = "Pn5Xc8Vb1Nm4Qa7Ws0Ed3Rf6Tg9Yh2Uj5Ik8Ol1Pz4Xs7Dc0Fv3Gb6Hn9Jm2Kq5L";
+
+        let (r1, r2, r3) = tokio::join!(
+            Collection::with_row_lock(&pool, table, 7, None, async |_| {
+                sleep(Duration::from_millis(200)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(50)).await;
+                Collection::with_row_lock(&pool, table, 7, None, async |_| {}).await
+            },
+            async {
+                sleep(Duration::from_millis(50)).await;
+                Collection::with_row_lock(&pool, table, 8, None, async |_| {}).await
+            }
+        );
+
+        assert_matches!(r1, Ok(()));
+        assert_matches!(r2, Err(Error::FailedToGetLock(_)));
+        assert_matches!(r3, Ok(()));
+
+        Ok(())
+    }
+}
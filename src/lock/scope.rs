@@ -0,0 +1,170 @@
+use std::time::Duration;
+
+use tokio::task::JoinSet;
+
+use crate::{Error, LockClient, Locker, Result, ShutdownPolicy, ShutdownRegistry};
+
+/// Set of tasks each running under a lock, torn down together.
+///
+/// the tasks are spawned on a [`JoinSet`] and register to a [`ShutdownRegistry`] of the scope,
+/// so [`LockScope::shutdown`] cancels them and releases their locks before it returns. dropping
+/// the scope cancels them too, its locks are released in the background.
+///
+/// ```ignore
+/// let mut scope = LockScope::new(LockClient::<MySqlLocker>::new(pool));
+/// for shard in shards {
+///     scope.spawn(format!("worker:{shard}"), None, work(shard));
+/// }
+/// supervisor_stopped().await;
+/// scope.shutdown().await;
+/// ```
+pub struct LockScope<L: Locker, T: 'static> {
+    client: LockClient<L>,
+    registry: ShutdownRegistry,
+    tasks: JoinSet<Result<T>>,
+}
+
+impl<L: Locker + 'static, T: Send + 'static> LockScope<L, T> {
+    /// create a scope locking through `client`, registered to a registry of its own
+    pub fn new(client: LockClient<L>) -> Self {
+        let registry = ShutdownRegistry::new();
+
+        Self {
+            client: client.shutdown_registry(registry.clone()),
+            registry,
+            tasks: JoinSet::new(),
+        }
+    }
+
+    /// spawn a task running `fut` while the key is locked
+    ///
+    /// the task fails with [`Error::ShuttingDown`] when the scope is shut down first.
+    pub fn spawn<F>(&mut self, key: impl Into<String>, timeout: Option<Duration>, fut: F)
+    where
+        F: Future<Output = T> + Send + 'static,
+    {
+        let client = self.client.clone();
+        let key = key.into();
+
+        self.tasks
+            .spawn(async move { client.run_locked(&key, timeout, fut).await });
+    }
+
+    /// number of tasks not joined yet
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    /// whether every task was joined
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    /// wait for the next task to finish, None if there is none
+    ///
+    /// a task that panicked panics here too, and one cancelled by the runtime, e.g. when it shuts
+    /// down, returns [`Error::ShuttingDown`].
+    pub async fn join_next(&mut self) -> Option<Result<T>> {
+        match self.tasks.join_next().await? {
+            Ok(r) => Some(r),
+            Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            Err(_) => Some(Err(Error::ShuttingDown)),
+        }
+    }
+
+    /// cancel the tasks, and return the results of all of them once every lock is released
+    pub async fn shutdown(mut self) -> Vec<Result<T>> {
+        // タスクを abort するとロックが解放されずに残るので、登録の側から打ち切らせる
+        self.registry.shutdown(ShutdownPolicy::Abort).await;
+
+        let mut results = Vec::with_capacity(self.tasks.len());
+        while let Some(r) = self.join_next().await {
+            results.push(r);
+        }
+        results
+    }
+}
+
+impl<L: Locker, T: 'static> Drop for LockScope<L, T> {
+    fn drop(&mut self) {
+        // JoinSet の Drop で abort されないように切り離し、解放まで走らせる
+        self.registry.abort();
+        self.tasks.detach_all();
+    }
+}
+
+#[cfg(all(test, feature = "sqlx-std-collection"))]
+mod tests {
+    use pretty_assertions::assert_matches;
+    use tokio::time::sleep;
+
+    use super::*;
+
+    use crate::StdCollectionLocker;
+    use sqlx::{Sqlite, SqlitePool};
+
+    type Collection = StdCollectionLocker<Sqlite>;
+
+    #[sqlx::test]
+    async fn shutdown_releases_the_locks_of_the_tasks(pool: SqlitePool) -> sqlx::Result<()> {
+        let keys = [
+            "Vb7Nq2Lx9Kd4Tw6Rm1Zc8Hs3Pf5Gy0Ju2Ea7Io4Ul9Ck6Xn1Bv3Md8Qt5Wr0Sz2",
+            "Hy4Fz9Wc2Vr7Kp1Xs6Lm3Qd8Ta0Gn5Ju2Bo7Ek4Ci9Rh6Ns1Pv3Ut8Zl5Yw0Mx7",
+        ];
+        let client = LockClient::<Collection>::new(pool.clone());
+
+        let mut scope = LockScope::new(client.clone());
+        for key in keys {
+            scope.spawn(key, None, std::future::pending::<()>());
+        }
+        scope.spawn(keys[0], None, async {});
+        sleep(Duration::from_millis(100)).await;
+        assert_matches!(
+            client.with_locking(keys[0], None, async |_| {}).await,
+            Err(Error::FailedToGetLock(_))
+        );
+
+        let results = scope.shutdown().await;
+        assert_matches!(
+            results[..],
+            [
+                Err(Error::FailedToGetLock(_)),
+                Err(Error::ShuttingDown),
+                Err(Error::ShuttingDown)
+            ]
+        );
+        for key in keys {
+            assert_matches!(client.with_locking(key, None, async |_| {}).await, Ok(()));
+        }
+
+        // 捨てても解放される
+        let mut scope = LockScope::new(client.clone());
+        scope.spawn(keys[1], None, std::future::pending::<()>());
+        sleep(Duration::from_millis(100)).await;
+        drop(scope);
+        sleep(Duration::from_millis(100)).await;
+        assert_matches!(
+            client.with_locking(keys[1], None, async |_| {}).await,
+            Ok(())
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn cancelled_task_joins_as_shutting_down(pool: SqlitePool) -> sqlx::Result<()> {
+        let mut scope = LockScope::new(LockClient::<Collection>::new(pool));
+        scope.spawn(
+            "Cq3Jn5Ab7Or9Tt1Ed2Sh4Ut6Dw8Nx0Zy2Ka4Lb6Mc8Nd0Oe2Pf4Qg6Rh8Si0Tj2U",
+            None,
+            std::future::pending::<()>(),
+        );
+
+        // ランタイムが止まるときのように打ち切られても、パニックにはしない
+        scope.tasks.abort_all();
+        assert_matches!(scope.join_next().await, Some(Err(Error::ShuttingDown)));
+        assert_matches!(scope.join_next().await, None);
+
+        Ok(())
+    }
+}
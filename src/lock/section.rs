@@ -0,0 +1,117 @@
+use std::time::{Duration, Instant};
+
+use super::shutdown::{Registration, ShutdownRegistry};
+use super::trace;
+use crate::{Clock, Locker, Result};
+
+type Tx<DB> = sqlx::Transaction<'static, DB>;
+
+// Locker の with_locking などに共通の流れ。登録してトランザクションを始め、取得し、持ったまま
+// hold を走らせて解放する。呼び出しごとに違うのは取り方と持ち方と解放の仕方だけ
+//
+// 取得に失敗したら Err、取得できたら hold と解放の結果をそれぞれ返す。待った時間、持った時間と
+// Locker::MAX_HOLD の期限は C で測る
+pub(crate) async fn section<L, C, P, T>(
+    pool: &sqlx::Pool<L::DB>,
+    key: &str,
+    acquire: impl AsyncFnOnce(&mut Tx<L::DB>) -> Result<P>,
+    hold: impl AsyncFnOnce(&mut Registration, Option<Instant>, &mut Tx<L::DB>) -> Result<T>,
+    release: impl AsyncFnOnce(&mut Tx<L::DB>, P) -> Result<()>,
+) -> Result<(Result<T>, Result<()>)>
+where
+    L: Locker + ?Sized,
+    C: Clock,
+{
+    let mut section = Section::start::<L, C>(ShutdownRegistry::global().register()?, key);
+    let mut tx = pool.begin().await?;
+
+    let r = section.registration.acquiring(acquire(&mut tx)).await;
+    let (held, _) = section.acquired(pool, &mut tx, r).await;
+    let held = held?;
+
+    let ran = hold(&mut section.registration, section.hold_until, &mut tx).await;
+
+    let released = release(&mut tx, held).await;
+    section.lifecycle.release(&released);
+
+    Ok((ran, released))
+}
+
+// 1回のロック呼び出しの登録と経過と Locker::MAX_HOLD の期限。section と、持ったまま呼び出し元に
+// 返す LockClient の取得とで共通
+pub(crate) struct Section<'k> {
+    pub(crate) registration: Registration,
+    pub(crate) lifecycle: trace::Lifecycle<'k>,
+    pub(crate) hold_until: Option<Instant>,
+}
+
+impl<'k> Section<'k> {
+    pub(crate) fn start<L: Locker + ?Sized, C: Clock>(
+        registration: Registration,
+        key: &'k str,
+    ) -> Self {
+        Self {
+            registration,
+            lifecycle: trace::Lifecycle::start::<L, C>(key),
+            hold_until: L::MAX_HOLD.map(|max| C::now() + max),
+        }
+    }
+
+    // Registration::acquiring の結果から取得の結果を返し、それと待った時間を記録する。シャットダウン
+    // で打ち切った取得の文は DB で走り続けていてあとでロックが取れてしまうので、そのセッションは閉じる
+    pub(crate) async fn acquired<DB: sqlx::Database, P>(
+        &mut self,
+        pool: &sqlx::Pool<DB>,
+        tx: &mut Tx<DB>,
+        r: Result<Result<P>>,
+    ) -> (Result<P>, Duration) {
+        let r = match r {
+            Ok(r) => r,
+            Err(e) => {
+                close_session(pool, tx).await;
+                Err(e)
+            }
+        };
+        let (r, held) = match r {
+            Ok(held) => (Ok(()), Some(held)),
+            Err(e) => (Err(e), None),
+        };
+        let wait = self.lifecycle.acquisition(&r);
+
+        // NOTE: 取得に成功したなら Some
+        (r.map(|()| held.unwrap()), wait)
+    }
+}
+
+// 文の途中で打ち切ったセッションをプールに戻さずに閉じる
+//
+// トランザクションからは接続を取り出せないので、プールの空いている接続と中身を入れ替えてから
+// 閉じる。入れ替えた接続はトランザクションと一緒にプールに戻り、数は合う。空きが無くて接続が
+// 取れなければ、打ち切ったセッションのままプールに戻すしかない
+pub(crate) async fn close_session<DB: sqlx::Database>(pool: &sqlx::Pool<DB>, tx: &mut Tx<DB>) {
+    let spare = match pool.try_acquire() {
+        Some(spare) => spare,
+        None => match pool.acquire().await {
+            Ok(spare) => spare,
+            Err(_) => return,
+        },
+    };
+
+    let _ = sqlx::Connection::close(swap_session(tx, spare)).await;
+}
+
+// close_session を待てないところで使う。空いている接続があれば入れ替えて、セッションの接続は
+// 捨てて切る。無ければセッションのままプールに戻すしかない
+pub(crate) fn discard_session<DB: sqlx::Database>(pool: &sqlx::Pool<DB>, tx: &mut Tx<DB>) {
+    if let Some(spare) = pool.try_acquire() {
+        drop(swap_session(tx, spare));
+    }
+}
+
+fn swap_session<DB: sqlx::Database>(
+    tx: &mut Tx<DB>,
+    spare: sqlx::pool::PoolConnection<DB>,
+) -> DB::Connection {
+    // 入れ替えた接続ではトランザクションが始まっていないので、ロールバックは何もしない
+    std::mem::replace(&mut **tx, spare.detach())
+}
@@ -0,0 +1,129 @@
+use std::time::Duration;
+
+use crate::{Clock, DefaultClock, Error, Locker, Result};
+
+// 足りなかったときに取り直すまでの間隔
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+// 許可 i を表すキー
+fn permit_key(key: &str, i: u32) -> String {
+    format!("{key}:permit:{i}")
+}
+
+// Locker::with_permits の取得。持てた許可の番号を返す
+pub(crate) async fn acquire<L: Locker>(
+    pool: &sqlx::Pool<L::DB>,
+    tx: &mut sqlx::Transaction<'static, L::DB>,
+    key: &str,
+    weight: u32,
+    capacity: u32,
+    timeout: Option<Duration>,
+) -> Result<Vec<u32>> {
+    // 待っても取れることがないので、競合ではなく設定の誤り
+    if weight > capacity {
+        return Err(Error::InvalidConfig(format!(
+            "weight {weight} exceeds the capacity {capacity} of {key}"
+        )));
+    }
+    let deadline = timeout.map(|timeout| DefaultClock::now() + timeout);
+
+    loop {
+        let mut held = Vec::with_capacity(weight as usize);
+        for i in 0..capacity {
+            if held.len() == weight as usize {
+                break;
+            }
+            match L::acquire(pool, tx, &permit_key(key, i), None).await {
+                Ok(()) => held.push(i),
+                Err(Error::FailedToGetLock(_)) => {}
+                Err(e) => {
+                    let _ = release::<L>(pool, tx, key, &held).await;
+                    return Err(e);
+                }
+            }
+        }
+        if held.len() == weight as usize {
+            return Ok(held);
+        }
+
+        // 一部だけ持ったまま待つと、重い呼び出し同士で取り合いになる
+        release::<L>(pool, tx, key, &held).await?;
+        match deadline {
+            Some(deadline) if DefaultClock::now() + RETRY_INTERVAL < deadline => {
+                DefaultClock::sleep(RETRY_INTERVAL).await;
+            }
+            _ => return Err(Error::FailedToGetLock(key.to_owned())),
+        }
+    }
+}
+
+pub(crate) async fn release<L: Locker>(
+    pool: &sqlx::Pool<L::DB>,
+    tx: &mut sqlx::Transaction<'static, L::DB>,
+    key: &str,
+    permits: &[u32],
+) -> Result<()> {
+    // どれかが失敗しても残りは解放する
+    let mut r = Ok(());
+    for &i in permits {
+        if let Err(e) = L::release(pool, tx, &permit_key(key, i)).await {
+            r = Err(e);
+        }
+    }
+    r
+}
+
+#[cfg(all(test, feature = "sqlx-std-collection"))]
+mod tests {
+    use pretty_assertions::assert_matches;
+    use tokio::time::sleep;
+
+    use super::*;
+
+    use crate::StdCollectionLocker;
+    use sqlx::{Sqlite, SqlitePool};
+
+    type Collection = StdCollectionLocker<Sqlite>;
+
+    #[sqlx::test]
+    async fn weights_share_the_capacity(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Sb8Nc1Vm4Xz7Lq0Kw3Jr6Ht9Gy2Fu5Di8Ao1Ep4Wi7Ru0Ty3Yp6Ua9Is2Od5Pf8G";
+
+        let (heavy, light, too_heavy, waited) = tokio::join!(
+            Collection::with_permits(&pool, key, 3, 4, None, async |_| {
+                sleep(Duration::from_millis(300)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(50)).await;
+                Collection::with_permits(&pool, key, 1, 4, None, async |_| {}).await
+            },
+            async {
+                sleep(Duration::from_millis(50)).await;
+                Collection::with_permits(&pool, key, 2, 4, None, async |_| {}).await
+            },
+            async {
+                sleep(Duration::from_millis(150)).await;
+                Collection::with_permits(
+                    &pool,
+                    key,
+                    4,
+                    4,
+                    Duration::from_secs(1).into(),
+                    async |_| {},
+                )
+                .await
+            }
+        );
+
+        assert_matches!(heavy, Ok(()));
+        assert_matches!(light, Ok(()));
+        assert_matches!(too_heavy, Err(Error::FailedToGetLock(_)));
+        assert_matches!(waited, Ok(()));
+        assert_matches!(
+            Collection::with_permits(&pool, key, 5, 4, None, async |_| {}).await,
+            Err(Error::InvalidConfig(_))
+        );
+
+        Ok(())
+    }
+}
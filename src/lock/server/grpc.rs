@@ -0,0 +1,527 @@
+//! gRPC lock coordination service, defined in `proto/lock.proto`.
+
+use std::{marker::PhantomData, time::Duration};
+
+use tonic::{Code, Request, Response, Status, transport::Channel};
+
+use super::lease::{self, Leases};
+use crate::{
+    Capabilities, Clock, DefaultClock, Error, LeaseLocker, LeaseToken, LockClient, Locker, Result,
+};
+use proto::{
+    AcquireRequest, AcquireResponse, ReleaseRequest, ReleaseResponse, RenewRequest, RenewResponse,
+    lock_service_client::LockServiceClient,
+    lock_service_server::{LockService, LockServiceServer},
+};
+
+/// Messages and stubs generated from `proto/lock.proto`.
+#[allow(clippy::all)]
+pub mod proto {
+    tonic::include_proto!("rusty_ad_lock.v1");
+}
+
+/// Server holding locks of `L` on behalf of gRPC clients.
+///
+/// every key is held under a lease, released on `Release` or when it is not renewed in time.
+///
+/// ```ignore
+/// tonic::transport::Server::builder()
+///     .add_service(LockServer::new(LockClient::<MySqlLocker>::new(pool)).into_service())
+///     .serve(addr)
+///     .await?;
+/// ```
+pub struct LockServer<L: Locker, C: Clock = DefaultClock> {
+    leases: Leases<L, C>,
+}
+
+impl<L: Locker + 'static, C: Clock + 'static> LockServer<L, C> {
+    /// serve the locks of `client`, timing the leases on its clock
+    pub fn new(client: LockClient<L, C>) -> Self {
+        Self {
+            leases: Leases::new(client),
+        }
+    }
+
+    /// tonic service to add to a `tonic::transport::Server`
+    pub fn into_service(self) -> LockServiceServer<Self> {
+        LockServiceServer::new(self)
+    }
+}
+
+fn status(e: Error) -> Status {
+    match e {
+        Error::FailedToGetLock(_) | Error::RetriesExhausted { .. } => {
+            Status::aborted(e.to_string())
+        }
+        Error::ShuttingDown | Error::PoolSaturated { .. } => Status::unavailable(e.to_string()),
+        e => Status::internal(e.to_string()),
+    }
+}
+
+fn unknown_lease(lease_id: &str) -> Status {
+    Status::not_found(format!("unknown or expired lease: {lease_id}"))
+}
+
+#[tonic::async_trait]
+impl<L: Locker + 'static, C: Clock + 'static> LockService for LockServer<L, C> {
+    async fn acquire(
+        &self,
+        request: Request<AcquireRequest>,
+    ) -> std::result::Result<Response<AcquireResponse>, Status> {
+        let AcquireRequest {
+            key,
+            timeout_ms,
+            ttl_ms,
+            max_hold_ms,
+        } = request.into_inner();
+        if ttl_ms == 0 {
+            return Err(Status::invalid_argument("ttl_ms must be positive"));
+        }
+
+        let lease_id = self
+            .leases
+            .acquire(
+                key,
+                timeout_ms.map(Duration::from_millis),
+                Duration::from_millis(ttl_ms),
+                max_hold_ms.map(Duration::from_millis),
+            )
+            .await
+            .map_err(status)?;
+
+        Ok(Response::new(AcquireResponse { lease_id }))
+    }
+
+    async fn renew(
+        &self,
+        request: Request<RenewRequest>,
+    ) -> std::result::Result<Response<RenewResponse>, Status> {
+        let RenewRequest {
+            lease_id,
+            ttl_ms,
+            extend_ms,
+        } = request.into_inner();
+
+        if !self.leases.renew(
+            &lease_id,
+            Duration::from_millis(ttl_ms),
+            extend_ms.map(Duration::from_millis),
+        ) {
+            return Err(unknown_lease(&lease_id));
+        }
+
+        Ok(Response::new(RenewResponse {}))
+    }
+
+    async fn release(
+        &self,
+        request: Request<ReleaseRequest>,
+    ) -> std::result::Result<Response<ReleaseResponse>, Status> {
+        let ReleaseRequest { lease_id } = request.into_inner();
+
+        match self.leases.release(&lease_id).await {
+            Some(r) => r
+                .map(|()| Response::new(ReleaseResponse {}))
+                .map_err(status),
+            None => Err(unknown_lease(&lease_id)),
+        }
+    }
+}
+
+/// [`LockServer`] that a [`GrpcLocker`] holds its keys on.
+pub trait LockEndpoint: Send + Sync + 'static {
+    /// lease requested on acquisition
+    ///
+    /// a watchdog renews it every quarter to third of it, at random, while the key is held, so it
+    /// doesn't have to cover the longest closure.
+    const TTL: Duration = Duration::from_secs(30);
+
+    /// longest a key is held, see [`Locker::MAX_HOLD`]. the server stops renewing the lease then
+    const MAX_HOLD: Option<Duration> = None;
+
+    /// channel to the server
+    fn channel() -> Channel;
+
+    /// called when renewing the lease of `key` failed, as the closure holding it is aborted with
+    /// [`Error::LockLost`]
+    fn on_renewal_failure(key: &str, error: &Error) {
+        let _ = (key, error);
+    }
+}
+
+/// Locker holding its keys on the [`LockServer`] of `E`.
+///
+/// the pool is only used for the closures' transactions.
+///
+/// ```ignore
+/// static CHANNEL: LazyLock<Channel> =
+///     LazyLock::new(|| Channel::from_static("http://locks:50051").connect_lazy());
+///
+/// struct Locks;
+///
+/// impl LockEndpoint for Locks {
+///     fn channel() -> Channel {
+///         CHANNEL.clone()
+///     }
+/// }
+///
+/// let r = GrpcLocker::<MySql, Locks>::with_locking(&pool, "key", None, async |_| {}).await;
+/// ```
+pub struct GrpcLocker<D: sqlx::Database, E: LockEndpoint> {
+    _marker: PhantomData<(D, E)>,
+}
+
+fn client_error(status: Status, key: &str) -> Error {
+    match status.code() {
+        Code::Aborted => Error::FailedToGetLock(key.to_owned()),
+        _ => Error::Grpc(Box::new(status)),
+    }
+}
+
+impl<D: sqlx::Database, E: LockEndpoint> Locker for GrpcLocker<D, E> {
+    type DB = D;
+
+    const NAME: &'static str = "grpc";
+
+    const MAX_HOLD: Option<Duration> = E::MAX_HOLD;
+
+    fn capabilities() -> Capabilities {
+        Capabilities {
+            sub_second_timeouts: true,
+            ttl: Self::MAX_HOLD,
+            ..Capabilities::default()
+        }
+    }
+
+    async fn acquire(
+        _pool: &sqlx::Pool<Self::DB>,
+        _tx: &mut sqlx::Transaction<'static, Self::DB>,
+        key: &str,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        let ttl_ms = E::TTL.as_millis() as u64;
+        let mut client = LockServiceClient::new(E::channel());
+        let requested = DefaultClock::now();
+
+        let (lease_id, max_hold) = match lease::take_resumed(std::any::type_name::<E>(), key) {
+            // 別のプロセスから引き継いだリースは、更新してまだ持っているかを確かめる
+            Some(resumed) => {
+                let r = client
+                    .renew(RenewRequest {
+                        lease_id: resumed.lease_id.clone(),
+                        ttl_ms,
+                        extend_ms: None,
+                    })
+                    .await;
+                match r {
+                    Ok(_) => (resumed.lease_id, resumed.max_hold),
+                    Err(status) if status.code() == Code::NotFound => {
+                        return Err(Error::LockLost(key.to_owned()));
+                    }
+                    Err(status) => return Err(client_error(status, key)),
+                }
+            }
+            None => {
+                let lease_id = client
+                    .acquire(AcquireRequest {
+                        key: key.to_owned(),
+                        timeout_ms: timeout.map(|timeout| timeout.as_millis() as u64),
+                        ttl_ms,
+                        max_hold_ms: E::MAX_HOLD.map(|max_hold| max_hold.as_millis() as u64),
+                    })
+                    .await
+                    .map_err(|status| client_error(status, key))?
+                    .into_inner()
+                    .lease_id;
+                (lease_id, E::MAX_HOLD)
+            }
+        };
+
+        lease::keep_renewing(
+            std::any::type_name::<E>(),
+            key,
+            lease_id,
+            E::TTL,
+            (requested, max_hold),
+            move |lease_id| {
+                let mut client = client.clone();
+                async move {
+                    client
+                        .renew(RenewRequest {
+                            lease_id,
+                            ttl_ms,
+                            extend_ms: None,
+                        })
+                        .await
+                        .map_err(|status| Error::Grpc(Box::new(status)))
+                }
+            },
+            E::on_renewal_failure,
+        );
+
+        Ok(())
+    }
+
+    fn lock_lost(key: &str) -> impl Future<Output = ()> + Send {
+        lease::lost(std::any::type_name::<E>(), key)
+    }
+
+    async fn extend_hold(key: &str, by: Duration) -> Result<()> {
+        let Some(lease_id) = lease::extend(std::any::type_name::<E>(), key, by) else {
+            return Err(Error::LockLost(key.to_owned()));
+        };
+
+        let r = LockServiceClient::new(E::channel())
+            .renew(RenewRequest {
+                lease_id,
+                ttl_ms: E::TTL.as_millis() as u64,
+                extend_ms: Some(by.as_millis() as u64),
+            })
+            .await;
+        match r {
+            Ok(_) => Ok(()),
+            Err(status) if status.code() == Code::NotFound => Err(Error::LockLost(key.to_owned())),
+            Err(status) => Err(client_error(status, key)),
+        }
+    }
+
+    async fn release(
+        _pool: &sqlx::Pool<Self::DB>,
+        _tx: &mut sqlx::Transaction<'static, Self::DB>,
+        key: &str,
+    ) -> Result<()> {
+        let Some(lease) = lease::stop_renewing(std::any::type_name::<E>(), key) else {
+            return Ok(());
+        };
+
+        let r = LockServiceClient::new(E::channel())
+            .release(ReleaseRequest {
+                lease_id: lease.lease_id.clone(),
+            })
+            .await;
+        match r {
+            Ok(_) => Ok(()),
+            // max hold を過ぎたリースはサーバーが先に解放している
+            Err(status) if status.code() == Code::NotFound && lease.max_hold_elapsed() => Ok(()),
+            Err(status) => Err(client_error(status, key)),
+        }
+    }
+}
+
+impl<D: sqlx::Database, E: LockEndpoint> LeaseLocker for GrpcLocker<D, E> {
+    fn export(key: &str) -> Option<LeaseToken> {
+        lease::export(std::any::type_name::<E>(), key)
+    }
+
+    fn resume(key: &str, token: &LeaseToken) {
+        lease::resume(std::any::type_name::<E>(), key, token);
+    }
+
+    async fn release_token(token: &LeaseToken) -> Result<()> {
+        let r = LockServiceClient::new(E::channel())
+            .release(ReleaseRequest {
+                lease_id: token.lease_id.clone(),
+            })
+            .await;
+        match r {
+            Ok(_) => Ok(()),
+            // 期限の切れたリースはサーバーが先に解放している
+            Err(status) if status.code() == Code::NotFound && lease::token_expired(token) => Ok(()),
+            Err(status) if status.code() == Code::NotFound => {
+                Err(Error::LockLost(token.key.clone()))
+            }
+            Err(status) => Err(client_error(status, &token.key)),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "sqlx-std-collection"))]
+mod tests {
+    use pretty_assertions::{assert_eq, assert_matches};
+    use std::sync::OnceLock;
+    use tokio::time::sleep;
+    use tonic::transport::{Server, server::TcpIncoming};
+
+    use super::*;
+
+    use crate::StdCollectionLocker;
+    use sqlx::{Sqlite, SqlitePool};
+
+    async fn serve(pool: SqlitePool) -> Channel {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = LockServer::new(LockClient::<StdCollectionLocker<Sqlite>>::new(pool));
+        tokio::spawn(
+            Server::builder()
+                .add_service(server.into_service())
+                .serve_with_incoming(TcpIncoming::from(listener)),
+        );
+
+        Channel::from_shared(format!("http://{addr}"))
+            .unwrap()
+            .connect_lazy()
+    }
+
+    static CHANNEL: OnceLock<Channel> = OnceLock::new();
+
+    struct TestEndpoint;
+
+    impl LockEndpoint for TestEndpoint {
+        const TTL: Duration = Duration::from_millis(300);
+
+        fn channel() -> Channel {
+            CHANNEL.get().unwrap().clone()
+        }
+    }
+
+    #[sqlx::test]
+    async fn clients_share_the_lock_held_by_the_server(pool: SqlitePool) -> sqlx::Result<()> {
+        type Locker = GrpcLocker<Sqlite, TestEndpoint>;
+        let key = "Zt5Xv7Cb9Nm1Qw3Er5Ty7Ui9Op1As3Df5Gh7Jk9Lz1Xc3Vb5Nm7Qw9Er1Ty3Ui5O";
+        CHANNEL.set(serve(pool.clone()).await).unwrap();
+
+        let (r1, r2, r3) = tokio::join!(
+            // TTL より長く持って、更新されていることを見る
+            Locker::with_locking(&pool, key, None, async |_| {
+                sleep(Duration::from_secs(1)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                Locker::with_locking(&pool, key, Duration::from_millis(100).into(), async |_| {})
+                    .await
+            },
+            async {
+                sleep(Duration::from_millis(700)).await;
+                StdCollectionLocker::with_locking(&pool, key, None, async |_| {}).await
+            }
+        );
+
+        assert_matches!(r1, Ok(()));
+        assert_matches!(r2, Err(Error::FailedToGetLock(_)));
+        assert_matches!(r3, Err(Error::FailedToGetLock(_)));
+        assert_matches!(
+            Locker::with_locking(&pool, key, None, async |_| {}).await,
+            Ok(())
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn unrenewed_lease_expires(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Pl2Ok4Ij6Uh8Yg0Tf2Rd4Es6Wa8Qz0Xs2Cd4Vf6Bg8Nh0Mj2Ku4Yl6Ti8Ro0Ep2W";
+        let mut client = LockServiceClient::new(serve(pool).await);
+        let request = || AcquireRequest {
+            key: key.to_owned(),
+            timeout_ms: None,
+            ttl_ms: 200,
+            max_hold_ms: None,
+        };
+
+        let first = client.acquire(request()).await.unwrap().into_inner();
+        let conflict = client.acquire(request()).await.unwrap_err();
+        assert_eq!(conflict.code(), Code::Aborted);
+
+        sleep(Duration::from_millis(400)).await;
+        let second = client.acquire(request()).await.unwrap().into_inner();
+
+        let renewed = client
+            .renew(RenewRequest {
+                lease_id: first.lease_id,
+                ttl_ms: 200,
+                extend_ms: None,
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(renewed.code(), Code::NotFound);
+        assert_matches!(
+            client
+                .release(ReleaseRequest {
+                    lease_id: second.lease_id,
+                })
+                .await,
+            Ok(_)
+        );
+
+        Ok(())
+    }
+
+    static HANDED_OVER_CHANNEL: OnceLock<Channel> = OnceLock::new();
+
+    struct HandedOverEndpoint;
+
+    impl LockEndpoint for HandedOverEndpoint {
+        const TTL: Duration = Duration::from_millis(300);
+
+        fn channel() -> Channel {
+            HANDED_OVER_CHANNEL.get().unwrap().clone()
+        }
+    }
+
+    #[sqlx::test]
+    async fn exported_lease_is_resumed(pool: SqlitePool) -> sqlx::Result<()> {
+        type HandedOver = GrpcLocker<Sqlite, HandedOverEndpoint>;
+        let key = "Gx4Nc7Vb0Mq3Wr6Et9Yu2Io5Pa8Sd1Fg4Hj7Kl0Zx3Cv6Bn9Mq2We5Rt8Yu1Io4P";
+        HANDED_OVER_CHANNEL.set(serve(pool.clone()).await).unwrap();
+        let locker = LockClient::<HandedOver>::new(pool.clone());
+
+        let token = locker
+            .guard(key, None)
+            .await
+            .unwrap()
+            .export()
+            .await
+            .unwrap();
+        let guard = locker.resume(&token).await.unwrap();
+        assert_matches!(
+            StdCollectionLocker::with_locking(&pool, key, None, async |_| {}).await,
+            Err(Error::FailedToGetLock(_))
+        );
+        guard.release().await.unwrap();
+
+        // 期限の前にサーバーが手放したリースは失われている
+        assert_matches!(
+            HandedOver::release_token(&token).await,
+            Err(Error::LockLost(_))
+        );
+
+        Ok(())
+    }
+
+    static SHORT_CHANNEL: OnceLock<Channel> = OnceLock::new();
+
+    struct ShortEndpoint;
+
+    impl LockEndpoint for ShortEndpoint {
+        const TTL: Duration = Duration::from_millis(300);
+        const MAX_HOLD: Option<Duration> = Some(Duration::from_millis(500));
+
+        fn channel() -> Channel {
+            SHORT_CHANNEL.get().unwrap().clone()
+        }
+    }
+
+    #[sqlx::test]
+    async fn closure_is_aborted_after_the_max_hold(pool: SqlitePool) -> sqlx::Result<()> {
+        type Locker = GrpcLocker<Sqlite, ShortEndpoint>;
+        let key = "Ev6Rb9Tn2Ym5Uq8Iw1Oe4Pr7At0Sy3Du6Fi9Go2Hp5Ja8Ks1Ld4Zf7Xg0Ch3Vj6B";
+        SHORT_CHANNEL.set(serve(pool.clone()).await).unwrap();
+
+        let started = std::time::Instant::now();
+        let (r1, r2) = tokio::join!(
+            Locker::with_locking(&pool, key, None, async |_| {
+                sleep(Duration::from_secs(5)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                Locker::with_locking(&pool, key, Duration::from_secs(2).into(), async |_| {}).await
+            }
+        );
+
+        assert_matches!(r1, Err(Error::LeaseExpired(_)));
+        assert_matches!(r2, Ok(()));
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,746 @@
+//! HTTP lock coordination service.
+//!
+//! | request | response |
+//! | --- | --- |
+//! | `POST /locks/{key}?timeout_ms=&ttl_ms=&max_hold_ms=` | `{"lease_id": ..}` once acquired, 409 if it could not be |
+//! | `POST /leases/{lease_id}/renew?ttl_ms=&extend_ms=` | 204, 404 if the lease is unknown or expired |
+//! | `DELETE /leases/{lease_id}` | 204, 404 if the lease is unknown or expired |
+//! | `GET /locks` | every lease held, `[{"lease_id": .., "key": .., "expires_in_ms": ..}]` |
+//! | `GET /locks/{key}` | the lease holding the key, 404 if the server doesn't hold it |
+//!
+//! an acquisition is answered when the key is acquired or `timeout_ms` has elapsed, so clients
+//! wait by long-polling. `ttl_ms` defaults to 30 seconds. a lease is never renewed past
+//! `max_hold_ms` from its acquisition, pushed later by the `extend_ms` of a renewal.
+
+use std::{marker::PhantomData, sync::LazyLock, time::Duration};
+
+use ::axum::{
+    Json, Router,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+};
+use serde::{Deserialize, Serialize};
+
+use super::lease::{self, Leases};
+use crate::{
+    Capabilities, Clock, DefaultClock, Error, LeaseLocker, LeaseToken, LockClient, Locker, Result,
+};
+
+const DEFAULT_TTL: Duration = Duration::from_secs(30);
+
+/// Server holding locks of `L` on behalf of HTTP clients.
+///
+/// every key is held under a lease, released on `DELETE` or when it is not renewed in time.
+///
+/// ```ignore
+/// let app = HttpLockServer::new(LockClient::<MySqlLocker>::new(pool)).into_router();
+/// axum::serve(TcpListener::bind("0.0.0.0:8080").await?, app).await?;
+/// ```
+pub struct HttpLockServer<L: Locker, C: Clock = DefaultClock> {
+    leases: Leases<L, C>,
+}
+
+impl<L: Locker + 'static, C: Clock + 'static> HttpLockServer<L, C> {
+    /// serve the locks of `client`, timing the leases on its clock
+    pub fn new(client: LockClient<L, C>) -> Self {
+        Self {
+            leases: Leases::new(client),
+        }
+    }
+
+    /// router serving the endpoints of the module documentation
+    pub fn into_router(self) -> Router {
+        Router::new()
+            .route("/locks", get(list::<L, C>))
+            .route("/locks/{*key}", get(status::<L, C>).post(acquire::<L, C>))
+            .route("/leases/{lease_id}/renew", post(renew::<L, C>))
+            .route("/leases/{lease_id}", delete(release::<L, C>))
+            .with_state(self.leases)
+    }
+}
+
+#[derive(Deserialize)]
+struct AcquireQuery {
+    timeout_ms: Option<u64>,
+    ttl_ms: Option<u64>,
+    max_hold_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct RenewQuery {
+    ttl_ms: Option<u64>,
+    extend_ms: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Acquired {
+    lease_id: String,
+}
+
+#[derive(Serialize)]
+struct HeldLease {
+    lease_id: String,
+    key: String,
+    expires_in_ms: u64,
+}
+
+fn ttl(ttl_ms: Option<u64>) -> Duration {
+    ttl_ms.map(Duration::from_millis).unwrap_or(DEFAULT_TTL)
+}
+
+fn error_response(e: &Error) -> Response {
+    match e {
+        Error::FailedToGetLock(_) | Error::RetriesExhausted { .. } => {
+            (StatusCode::CONFLICT, e.to_string()).into_response()
+        }
+        Error::ShuttingDown | Error::PoolSaturated { .. } => {
+            (StatusCode::SERVICE_UNAVAILABLE, e.to_string()).into_response()
+        }
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+fn unknown_lease(lease_id: &str) -> Response {
+    (
+        StatusCode::NOT_FOUND,
+        format!("unknown or expired lease: {lease_id}"),
+    )
+        .into_response()
+}
+
+async fn acquire<L: Locker + 'static, C: Clock + 'static>(
+    State(leases): State<Leases<L, C>>,
+    Path(key): Path<String>,
+    Query(query): Query<AcquireQuery>,
+) -> Response {
+    if query.ttl_ms == Some(0) {
+        return (StatusCode::BAD_REQUEST, "ttl_ms must be positive").into_response();
+    }
+
+    // リクエストが切られてこの Future が捨てられると、取れたロックはすぐに解放される
+    match leases
+        .acquire(
+            key,
+            query.timeout_ms.map(Duration::from_millis),
+            ttl(query.ttl_ms),
+            query.max_hold_ms.map(Duration::from_millis),
+        )
+        .await
+    {
+        Ok(lease_id) => Json(Acquired { lease_id }).into_response(),
+        Err(e) => error_response(&e),
+    }
+}
+
+async fn renew<L: Locker + 'static, C: Clock + 'static>(
+    State(leases): State<Leases<L, C>>,
+    Path(lease_id): Path<String>,
+    Query(query): Query<RenewQuery>,
+) -> Response {
+    let extend = query.extend_ms.map(Duration::from_millis);
+    if leases.renew(&lease_id, ttl(query.ttl_ms), extend) {
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        unknown_lease(&lease_id)
+    }
+}
+
+async fn release<L: Locker + 'static, C: Clock + 'static>(
+    State(leases): State<Leases<L, C>>,
+    Path(lease_id): Path<String>,
+) -> Response {
+    match leases.release(&lease_id).await {
+        Some(Ok(())) => StatusCode::NO_CONTENT.into_response(),
+        Some(Err(e)) => error_response(&e),
+        None => unknown_lease(&lease_id),
+    }
+}
+
+fn held_leases<L: Locker + 'static, C: Clock + 'static>(
+    leases: &Leases<L, C>,
+) -> impl Iterator<Item = HeldLease> {
+    leases.list().into_iter().map(|lease| HeldLease {
+        lease_id: lease.lease_id,
+        key: lease.key,
+        expires_in_ms: lease.expires_in.as_millis() as u64,
+    })
+}
+
+async fn list<L: Locker + 'static, C: Clock + 'static>(
+    State(leases): State<Leases<L, C>>,
+) -> Json<Vec<HeldLease>> {
+    Json(held_leases(&leases).collect())
+}
+
+async fn status<L: Locker + 'static, C: Clock + 'static>(
+    State(leases): State<Leases<L, C>>,
+    Path(key): Path<String>,
+) -> Response {
+    match held_leases(&leases).find(|lease| lease.key == key) {
+        Some(lease) => Json(lease).into_response(),
+        None => (StatusCode::NOT_FOUND, format!("{key} is not held")).into_response(),
+    }
+}
+
+static CLIENT: LazyLock<reqwest::Client> = LazyLock::new(reqwest::Client::new);
+
+/// [`HttpLockServer`] that an [`HttpLocker`] holds its keys on.
+pub trait HttpEndpoint: Send + Sync + 'static {
+    /// lease requested on acquisition
+    ///
+    /// a watchdog renews it every quarter to third of it, at random, while the key is held, so it
+    /// doesn't have to cover the longest closure.
+    const TTL: Duration = DEFAULT_TTL;
+
+    /// longest a key is held, see [`Locker::MAX_HOLD`]. the server stops renewing the lease then
+    const MAX_HOLD: Option<Duration> = None;
+
+    /// base URL the server's router is served at
+    ///
+    /// the locker fails with [`Error::InvalidConfig`] if it isn't an http(s) URL.
+    fn url() -> reqwest::Url;
+
+    /// client the requests are sent with
+    fn client() -> reqwest::Client {
+        CLIENT.clone()
+    }
+
+    /// called when renewing the lease of `key` failed, as the closure holding it is aborted with
+    /// [`Error::LockLost`]
+    fn on_renewal_failure(key: &str, error: &Error) {
+        let _ = (key, error);
+    }
+}
+
+/// Locker holding its keys on the [`HttpLockServer`] of `E`.
+///
+/// the pool is only used for the closures' transactions.
+///
+/// ```ignore
+/// struct Locks;
+///
+/// impl HttpEndpoint for Locks {
+///     fn url() -> reqwest::Url {
+///         "http://locks:8080/".parse().unwrap()
+///     }
+/// }
+///
+/// let r = HttpLocker::<MySql, Locks>::with_locking(&pool, "key", None, async |_| {}).await;
+/// ```
+pub struct HttpLocker<D: sqlx::Database, E: HttpEndpoint> {
+    _marker: PhantomData<(D, E)>,
+}
+
+fn endpoint_url<E: HttpEndpoint>(segments: &[&str]) -> Result<reqwest::Url> {
+    let mut url = E::url();
+    url.path_segments_mut()
+        .map_err(|()| {
+            Error::InvalidConfig(format!(
+                "the URL of an HttpEndpoint must be an http(s) URL: {}",
+                E::url()
+            ))
+        })?
+        .pop_if_empty()
+        .extend(segments);
+    Ok(url)
+}
+
+impl<D: sqlx::Database, E: HttpEndpoint> Locker for HttpLocker<D, E> {
+    type DB = D;
+
+    const NAME: &'static str = "http";
+
+    const MAX_HOLD: Option<Duration> = E::MAX_HOLD;
+
+    fn capabilities() -> Capabilities {
+        Capabilities {
+            sub_second_timeouts: true,
+            ttl: Self::MAX_HOLD,
+            ..Capabilities::default()
+        }
+    }
+
+    async fn acquire(
+        _pool: &sqlx::Pool<Self::DB>,
+        _tx: &mut sqlx::Transaction<'static, Self::DB>,
+        key: &str,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        let ttl_ms = E::TTL.as_millis() as u64;
+        let requested = DefaultClock::now();
+
+        let (lease_id, max_hold) = match lease::take_resumed(std::any::type_name::<E>(), key) {
+            // 別のプロセスから引き継いだリースは、更新してまだ持っているかを確かめる
+            Some(resumed) => {
+                let response = renew_lease::<E>(&resumed.lease_id, ttl_ms).await?;
+                if response.status() == StatusCode::NOT_FOUND {
+                    return Err(Error::LockLost(key.to_owned()));
+                }
+                response.error_for_status()?;
+                (resumed.lease_id, resumed.max_hold)
+            }
+            None => {
+                let mut query = vec![("ttl_ms", ttl_ms)];
+                query.extend(timeout.map(|timeout| ("timeout_ms", timeout.as_millis() as u64)));
+                query.extend(
+                    E::MAX_HOLD.map(|max_hold| ("max_hold_ms", max_hold.as_millis() as u64)),
+                );
+
+                let response = E::client()
+                    .post(endpoint_url::<E>(&["locks", key])?)
+                    .query(&query)
+                    .send()
+                    .await?;
+                if response.status() == StatusCode::CONFLICT {
+                    return Err(Error::FailedToGetLock(key.to_owned()));
+                }
+                let Acquired { lease_id } = response.error_for_status()?.json().await?;
+                (lease_id, E::MAX_HOLD)
+            }
+        };
+
+        lease::keep_renewing(
+            std::any::type_name::<E>(),
+            key,
+            lease_id,
+            E::TTL,
+            (requested, max_hold),
+            move |lease_id| async move {
+                Ok(renew_lease::<E>(&lease_id, ttl_ms)
+                    .await?
+                    .error_for_status()?)
+            },
+            E::on_renewal_failure,
+        );
+
+        Ok(())
+    }
+
+    fn lock_lost(key: &str) -> impl Future<Output = ()> + Send {
+        lease::lost(std::any::type_name::<E>(), key)
+    }
+
+    async fn extend_hold(key: &str, by: Duration) -> Result<()> {
+        let Some(lease_id) = lease::extend(std::any::type_name::<E>(), key, by) else {
+            return Err(Error::LockLost(key.to_owned()));
+        };
+
+        let response = E::client()
+            .post(endpoint_url::<E>(&["leases", &lease_id, "renew"])?)
+            .query(&[
+                ("ttl_ms", E::TTL.as_millis() as u64),
+                ("extend_ms", by.as_millis() as u64),
+            ])
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(Error::LockLost(key.to_owned()));
+        }
+        response.error_for_status()?;
+
+        Ok(())
+    }
+
+    async fn release(
+        _pool: &sqlx::Pool<Self::DB>,
+        _tx: &mut sqlx::Transaction<'static, Self::DB>,
+        key: &str,
+    ) -> Result<()> {
+        let Some(lease) = lease::stop_renewing(std::any::type_name::<E>(), key) else {
+            return Ok(());
+        };
+
+        let response = E::client()
+            .delete(endpoint_url::<E>(&["leases", &lease.lease_id])?)
+            .send()
+            .await?;
+        // max hold を過ぎたリースはサーバーが先に解放している
+        if response.status() == StatusCode::NOT_FOUND && lease.max_hold_elapsed() {
+            return Ok(());
+        }
+        response.error_for_status()?;
+
+        Ok(())
+    }
+}
+
+impl<D: sqlx::Database, E: HttpEndpoint> LeaseLocker for HttpLocker<D, E> {
+    fn export(key: &str) -> Option<LeaseToken> {
+        lease::export(std::any::type_name::<E>(), key)
+    }
+
+    fn resume(key: &str, token: &LeaseToken) {
+        lease::resume(std::any::type_name::<E>(), key, token);
+    }
+
+    async fn release_token(token: &LeaseToken) -> Result<()> {
+        let response = E::client()
+            .delete(endpoint_url::<E>(&["leases", &token.lease_id])?)
+            .send()
+            .await?;
+        // 期限の切れたリースはサーバーが先に解放している
+        if response.status() == StatusCode::NOT_FOUND && lease::token_expired(token) {
+            return Ok(());
+        }
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(Error::LockLost(token.key.clone()));
+        }
+        response.error_for_status()?;
+
+        Ok(())
+    }
+}
+
+async fn renew_lease<E: HttpEndpoint>(lease_id: &str, ttl_ms: u64) -> Result<reqwest::Response> {
+    Ok(E::client()
+        .post(endpoint_url::<E>(&["leases", lease_id, "renew"])?)
+        .query(&[("ttl_ms", ttl_ms)])
+        .send()
+        .await?)
+}
+
+#[cfg(all(test, feature = "sqlx-std-collection"))]
+mod tests {
+    use pretty_assertions::{assert_eq, assert_matches};
+    use std::sync::OnceLock;
+    use tokio::time::sleep;
+
+    use super::*;
+
+    use crate::{NO_WAIT, StdCollectionLocker};
+    use sqlx::{Sqlite, SqlitePool};
+
+    async fn serve(pool: SqlitePool) -> reqwest::Url {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router =
+            HttpLockServer::new(LockClient::<StdCollectionLocker<Sqlite>>::new(pool)).into_router();
+        tokio::spawn(async move { ::axum::serve(listener, router).await });
+
+        format!("http://{addr}/").parse().unwrap()
+    }
+
+    static URL: OnceLock<reqwest::Url> = OnceLock::new();
+
+    struct TestEndpoint;
+
+    impl HttpEndpoint for TestEndpoint {
+        const TTL: Duration = Duration::from_millis(300);
+
+        fn url() -> reqwest::Url {
+            URL.get().unwrap().clone()
+        }
+    }
+
+    #[sqlx::test]
+    async fn clients_share_the_lock_held_by_the_server(pool: SqlitePool) -> sqlx::Result<()> {
+        type Locker = HttpLocker<Sqlite, TestEndpoint>;
+        let key = "Rk7Tj9Yh1Ug3If5Od7Ps9Aa1Sd3Fg5Hj7Kl9Zx1Cv3Bn5Mq7We9Rt1Yu3Io5Pa7S";
+        URL.set(serve(pool.clone()).await).unwrap();
+
+        let (r1, r2, status) = tokio::join!(
+            // TTL より長く持って、更新されていることを見る
+            Locker::with_locking(&pool, key, None, async |_| {
+                sleep(Duration::from_secs(1)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                Locker::with_locking(&pool, key, Duration::from_millis(100).into(), async |_| {})
+                    .await
+            },
+            async {
+                sleep(Duration::from_millis(700)).await;
+                reqwest::get(endpoint_url::<TestEndpoint>(&["locks", key]).unwrap())
+                    .await
+                    .unwrap()
+                    .status()
+            }
+        );
+
+        assert_matches!(r1, Ok(()));
+        assert_matches!(r2, Err(Error::FailedToGetLock(_)));
+        assert_eq!(status, StatusCode::OK);
+        assert_matches!(
+            Locker::with_locking(&pool, key, None, async |_| {}).await,
+            Ok(())
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn unrenewed_lease_expires(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Wl4Vk6Uj8Th0Sg2Rf4Qe6Pd8Oc0Nb2Ma4Lz6Ky8Jx0Iw2Hv4Gu6Ft8Es0Dr2Cq4B";
+        let url = serve(pool).await;
+        let at = |path: &str| url.join(path).unwrap();
+        let client = reqwest::Client::new();
+        let acquire = || {
+            client
+                .post(at(&format!("locks/{key}")))
+                .query(&[("ttl_ms", 200)])
+                .send()
+        };
+
+        let first: Acquired = acquire().await.unwrap().json().await.unwrap();
+        assert_eq!(acquire().await.unwrap().status(), StatusCode::CONFLICT);
+
+        sleep(Duration::from_millis(400)).await;
+        let second: Acquired = acquire().await.unwrap().json().await.unwrap();
+
+        let renewed = client
+            .post(at(&format!("leases/{}/renew", first.lease_id)))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(renewed.status(), StatusCode::NOT_FOUND);
+
+        let held: serde_json::Value = reqwest::get(at("locks"))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(held[0]["lease_id"], second.lease_id.as_str());
+        assert_eq!(held[0]["key"], key);
+
+        let released = client
+            .delete(at(&format!("leases/{}", second.lease_id)))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(released.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            reqwest::get(at(&format!("locks/{key}")))
+                .await
+                .unwrap()
+                .status(),
+            StatusCode::NOT_FOUND
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn lease_is_not_renewed_past_the_max_hold(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Mz7Xn0Cb3Vq6Wr9Et2Yu5Io8Pa1Sd4Fg7Hj0Kl3Zx6Cv9Bn2Mq5We8Rt1Yu4Io7P";
+        let url = serve(pool).await;
+        let at = |path: &str| url.join(path).unwrap();
+        let client = reqwest::Client::new();
+        let acquire = || {
+            client
+                .post(at(&format!("locks/{key}")))
+                .query(&[("ttl_ms", 200), ("max_hold_ms", 500)])
+                .send()
+        };
+
+        let first: Acquired = acquire().await.unwrap().json().await.unwrap();
+        let renew = || {
+            client
+                .post(at(&format!("leases/{}/renew", first.lease_id)))
+                .query(&[("ttl_ms", 200)])
+                .send()
+        };
+
+        // 更新し続けても max_hold_ms を過ぎれば解放される
+        for _ in 0..3 {
+            sleep(Duration::from_millis(100)).await;
+            assert_eq!(renew().await.unwrap().status(), StatusCode::NO_CONTENT);
+        }
+        sleep(Duration::from_millis(400)).await;
+        assert_eq!(renew().await.unwrap().status(), StatusCode::NOT_FOUND);
+        assert_eq!(acquire().await.unwrap().status(), StatusCode::OK);
+
+        Ok(())
+    }
+
+    static HANDED_OVER_URL: OnceLock<reqwest::Url> = OnceLock::new();
+
+    struct HandedOverEndpoint;
+
+    impl HttpEndpoint for HandedOverEndpoint {
+        const TTL: Duration = Duration::from_millis(300);
+
+        fn url() -> reqwest::Url {
+            HANDED_OVER_URL.get().unwrap().clone()
+        }
+    }
+
+    #[sqlx::test]
+    async fn exported_lease_is_resumed_or_released(pool: SqlitePool) -> sqlx::Result<()> {
+        type HandedOver = HttpLocker<Sqlite, HandedOverEndpoint>;
+        let key = "Pv3Lk8Sd1Fg6Hj9Zx2Cv5Bn8Mq1We4Rt7Yu0Io3Pa6Sd9Fg2Hj5Kl8Zx1Cv4Bn7M";
+        HANDED_OVER_URL.set(serve(pool.clone()).await).unwrap();
+        let locker = LockClient::<HandedOver>::new(pool.clone());
+        let held_elsewhere = || HandedOver::with_locking(&pool, key, NO_WAIT, async |_| {});
+
+        // 書き出したトークンは再起動をまたいで読み戻される
+        let token = locker
+            .guard(key, None)
+            .await
+            .unwrap()
+            .export()
+            .await
+            .unwrap();
+        let json = serde_json::to_string(&token).unwrap();
+        let token: LeaseToken = serde_json::from_str(&json).unwrap();
+        assert_eq!(token.key, key);
+        assert_matches!(held_elsewhere().await, Err(Error::FailedToGetLock(_)));
+
+        // 引き継いだリースは TTL を過ぎても更新されている
+        let guard = locker.resume(&token).await.unwrap();
+        sleep(Duration::from_millis(700)).await;
+        assert_matches!(held_elsewhere().await, Err(Error::FailedToGetLock(_)));
+        guard.release().await.unwrap();
+        assert_matches!(held_elsewhere().await, Ok(()));
+
+        let token = locker
+            .guard(key, None)
+            .await
+            .unwrap()
+            .export()
+            .await
+            .unwrap();
+        HandedOver::release_token(&token).await.unwrap();
+        assert_matches!(held_elsewhere().await, Ok(()));
+
+        // 更新されないリースは期限で解放され、引き継げない
+        let token = locker
+            .guard(key, None)
+            .await
+            .unwrap()
+            .export()
+            .await
+            .unwrap();
+        sleep(Duration::from_millis(700)).await;
+        assert_matches!(locker.resume(&token).await.err(), Some(Error::LockLost(_)));
+        assert_matches!(HandedOver::release_token(&token).await, Ok(()));
+        assert_matches!(held_elsewhere().await, Ok(()));
+
+        Ok(())
+    }
+
+    static LOST_URL: OnceLock<reqwest::Url> = OnceLock::new();
+    static RENEWAL_FAILURES: std::sync::Mutex<Vec<String>> = std::sync::Mutex::new(Vec::new());
+
+    struct LostEndpoint;
+
+    impl HttpEndpoint for LostEndpoint {
+        const TTL: Duration = Duration::from_millis(300);
+
+        fn url() -> reqwest::Url {
+            LOST_URL.get().unwrap().clone()
+        }
+
+        fn on_renewal_failure(key: &str, error: &Error) {
+            assert_matches!(error, Error::Http(_));
+            RENEWAL_FAILURES.lock().unwrap().push(key.to_owned());
+        }
+    }
+
+    #[sqlx::test]
+    async fn closure_is_run_again_after_the_lease_is_lost(pool: SqlitePool) -> sqlx::Result<()> {
+        type Lost = HttpLocker<Sqlite, LostEndpoint>;
+        let key = "Hq2Wn5Ec8Rv1Tb4Yn7Um0Ik3Ol6Pz9Ax2Sc5Dv8Fb1Gn4Hm7Jq0Kw3Le6Rr9Tt2Y";
+        LOST_URL.set(serve(pool.clone()).await).unwrap();
+        let mut attempts = Vec::new();
+
+        let (ran, ()) = tokio::join!(
+            Lost::with_locking_retrying(&pool, key, None, 1, async |_, attempt| {
+                attempts.push(attempt);
+                if attempt == 0 {
+                    sleep(Duration::from_secs(2)).await;
+                }
+                attempt
+            }),
+            async {
+                // サーバー側でリースを消すと、次の更新で失われたことに気づく
+                sleep(Duration::from_millis(150)).await;
+                let held: serde_json::Value =
+                    reqwest::get(endpoint_url::<LostEndpoint>(&["locks"]).unwrap())
+                        .await
+                        .unwrap()
+                        .json()
+                        .await
+                        .unwrap();
+                let lease_id = held[0]["lease_id"].as_str().unwrap();
+                reqwest::Client::new()
+                    .delete(endpoint_url::<LostEndpoint>(&["leases", lease_id]).unwrap())
+                    .send()
+                    .await
+                    .unwrap();
+            }
+        );
+
+        assert_matches!(ran, Ok(1));
+        assert_eq!(attempts, vec![0, 1]);
+        assert_eq!(*RENEWAL_FAILURES.lock().unwrap(), [key]);
+        assert_matches!(
+            Lost::with_locking(&pool, key, None, async |_| {}).await,
+            Ok(())
+        );
+
+        Ok(())
+    }
+
+    static EXTEND_URL: OnceLock<reqwest::Url> = OnceLock::new();
+
+    struct ExtendEndpoint;
+
+    impl HttpEndpoint for ExtendEndpoint {
+        const TTL: Duration = Duration::from_millis(300);
+        const MAX_HOLD: Option<Duration> = Some(Duration::from_millis(500));
+
+        fn url() -> reqwest::Url {
+            EXTEND_URL.get().unwrap().clone()
+        }
+    }
+
+    #[sqlx::test]
+    async fn guard_extends_the_max_hold(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Dk9Fv2Lm5Qx8Wt1Bz4Hn7Rc0Jp3Ys6Ga9Ue2Io5Sk8Cw1Nh4Tb7Vl0Xr3Mf6Zq9P";
+        EXTEND_URL.set(serve(pool.clone()).await).unwrap();
+        let client = LockClient::<HttpLocker<Sqlite, ExtendEndpoint>>::new(pool);
+        let held = || async {
+            reqwest::get(endpoint_url::<ExtendEndpoint>(&["locks", key]).unwrap())
+                .await
+                .unwrap()
+                .status()
+        };
+
+        let mut guard = client.guard(key, None).await.unwrap();
+        assert!(guard.remaining().unwrap() <= Duration::from_millis(500));
+        assert_matches!(guard.extend(Duration::from_secs(1)).await, Ok(()));
+        assert!(guard.remaining().unwrap() > Duration::from_secs(1));
+
+        // 延ばしていなければ max hold を過ぎて解放されている
+        sleep(Duration::from_millis(800)).await;
+        assert_eq!(held().await, StatusCode::OK);
+        assert_matches!(guard.release().await, Ok(()));
+        assert_eq!(held().await, StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+
+    struct MailtoEndpoint;
+
+    impl HttpEndpoint for MailtoEndpoint {
+        fn url() -> reqwest::Url {
+            "mailto:locks@example.com".parse().unwrap()
+        }
+    }
+
+    #[sqlx::test]
+    async fn non_http_url_is_invalid_config(pool: SqlitePool) -> sqlx::Result<()> {
+        type Locker = HttpLocker<Sqlite, MailtoEndpoint>;
+        let key = "Ml2To4Ur6Lx8Nq0Ht2Tp4Uv6Rw8Lz0Ab2Cd4Ef6Gh8Ij0Kl2Mn4Op6Qr8St0Uv2W";
+
+        assert_matches!(
+            Locker::with_locking(&pool, key, None, async |_| {}).await,
+            Err(Error::InvalidConfig(_))
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,461 @@
+use std::{
+    collections::HashMap,
+    hash::{BuildHasher, RandomState},
+    sync::{
+        Arc, LazyLock, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use tokio::{
+    sync::{oneshot, watch},
+    task::JoinHandle,
+};
+
+use crate::{Clock, DefaultClock, Error, LockClient, LockGuard, Locker, NO_WAIT, Result};
+
+// サービスが持っているリースと、クライアント側でそれを更新し続ける仕組み
+
+static NEXT_LEASE: AtomicU64 = AtomicU64::new(1);
+
+// deadline に None を送ると解放する
+struct Lease {
+    #[cfg(feature = "http")]
+    key: String,
+    deadline: watch::Sender<Option<Instant>>,
+    // これより後には更新しない
+    max_deadline: Option<Instant>,
+    released: oneshot::Receiver<Result<()>>,
+}
+
+/// lease held by a lock service at the time it was listed
+#[cfg(feature = "http")]
+pub(crate) struct LeaseStatus {
+    pub(crate) lease_id: String,
+    pub(crate) key: String,
+    pub(crate) expires_in: Duration,
+}
+
+/// keys held by a lock service on behalf of its clients
+pub(crate) struct Leases<L: Locker, C: Clock = DefaultClock> {
+    client: LockClient<L, C>,
+    leases: Arc<Mutex<HashMap<String, Lease>>>,
+}
+
+impl<L: Locker, C: Clock> Clone for Leases<L, C> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            leases: Arc::clone(&self.leases),
+        }
+    }
+}
+
+impl<L: Locker + 'static, C: Clock + 'static> Leases<L, C> {
+    pub(crate) fn new(client: LockClient<L, C>) -> Self {
+        Self {
+            client,
+            leases: Arc::default(),
+        }
+    }
+
+    /// wait for the key and hold it under a new lease of `ttl`, returning the lease id
+    ///
+    /// the lease is never renewed past `max_hold` from the acquisition.
+    pub(crate) async fn acquire(
+        &self,
+        key: String,
+        timeout: Option<Duration>,
+        ttl: Duration,
+        max_hold: Option<Duration>,
+    ) -> Result<String> {
+        let lease_id = NEXT_LEASE.fetch_add(1, Ordering::Relaxed).to_string();
+        let (acquired, acquisition) = oneshot::channel();
+        let client = self.client.clone();
+        let leases = Arc::clone(&self.leases);
+        let id = lease_id.clone();
+
+        // ロックはリースが終わるまでこのタスクが持ち続ける
+        tokio::spawn(async move {
+            let (mut held, mut lock_tx) = match client.lock(&key, timeout).await {
+                Ok(locked) => locked,
+                Err(e) => {
+                    let _ = acquired.send(Err(e));
+                    return;
+                }
+            };
+
+            let now = C::now();
+            let max_deadline = max_hold.map(|max_hold| now + max_hold);
+            let (deadline, mut deadline_rx) = watch::channel(Some(cap(now + ttl, max_deadline)));
+            let (released, released_rx) = oneshot::channel();
+            leases.lock().unwrap().insert(
+                id.clone(),
+                Lease {
+                    #[cfg(feature = "http")]
+                    key: key.clone(),
+                    deadline,
+                    max_deadline,
+                    released: released_rx,
+                },
+            );
+
+            // 取得を待っていたリクエストがもう居なければすぐに解放する
+            if acquired.send(Ok(())).is_ok() {
+                let _ = held
+                    .holding(async {
+                        loop {
+                            let Some(deadline) = *deadline_rx.borrow_and_update() else {
+                                return;
+                            };
+                            let left = deadline.saturating_duration_since(C::now());
+                            match C::timeout(left, deadline_rx.changed()).await {
+                                Some(Ok(())) => {}
+                                // 期限切れか、リースが捨てられた
+                                Some(Err(_)) | None => return,
+                            }
+                        }
+                    })
+                    .await;
+            }
+
+            leases.lock().unwrap().remove(&id);
+            let _ = released.send(client.unlock(held, &mut lock_tx).await);
+        });
+
+        acquisition
+            .await
+            .expect("the lock task ended without reporting the acquisition")?;
+
+        Ok(lease_id)
+    }
+
+    /// extend the lease by `ttl` from now, false if it is unknown or expired
+    ///
+    /// * `extend` - also push the max hold of the lease later by this long
+    pub(crate) fn renew(&self, lease_id: &str, ttl: Duration, extend: Option<Duration>) -> bool {
+        match self.leases.lock().unwrap().get_mut(lease_id) {
+            Some(lease) => {
+                if let (Some(max_deadline), Some(extend)) = (&mut lease.max_deadline, extend) {
+                    *max_deadline += extend;
+                }
+                let deadline = cap(C::now() + ttl, lease.max_deadline);
+                lease.deadline.send_replace(Some(deadline));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// release the key held under the lease, None if it is unknown or expired
+    pub(crate) async fn release(&self, lease_id: &str) -> Option<Result<()>> {
+        let lease = self.leases.lock().unwrap().remove(lease_id)?;
+        lease.deadline.send_replace(None);
+
+        Some(
+            lease
+                .released
+                .await
+                .expect("the lock task ended without reporting the release"),
+        )
+    }
+
+    /// leases held at the moment, in no particular order
+    #[cfg(feature = "http")]
+    pub(crate) fn list(&self) -> Vec<LeaseStatus> {
+        let now = C::now();
+
+        self.leases
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(lease_id, lease)| LeaseStatus {
+                lease_id: lease_id.clone(),
+                key: lease.key.clone(),
+                expires_in: lease
+                    .deadline
+                    .borrow()
+                    .map(|deadline| deadline.saturating_duration_since(now))
+                    .unwrap_or_default(),
+            })
+            .collect()
+    }
+}
+
+fn cap(deadline: Instant, max_deadline: Option<Instant>) -> Instant {
+    max_deadline.map_or(deadline, |max_deadline| deadline.min(max_deadline))
+}
+
+/// Lease of a key on a lock server, exported by [`crate::LockGuard::export`].
+///
+/// the server goes on holding the key until the lease expires, so another process can take it
+/// over with [`LockClient::resume`] before [`LeaseToken::expires_at`], e.g. a batch job restarted
+/// from its last checkpoint, or release it with [`LeaseLocker::release_token`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LeaseToken {
+    /// key as given to the client
+    pub key: String,
+    /// id of the lease on the server
+    pub lease_id: String,
+    /// the server releases the key at this time unless the lease is renewed before. counted from
+    /// the last renewal sent, so the server may hold it a little longer
+    pub expires_at: SystemTime,
+    /// the lease can't be renewed past this time, see [`Locker::MAX_HOLD`]
+    pub max_hold_until: Option<SystemTime>,
+}
+
+/// Locker holding its keys under leases of a lock server, which outlive the process holding them.
+pub trait LeaseLocker: Locker {
+    /// stop renewing the lease of `key` without releasing it, None if the key isn't held
+    fn export(key: &str) -> Option<LeaseToken>;
+
+    /// have the next acquisition of `key` in this process take the lease of `token` over
+    fn resume(key: &str, token: &LeaseToken);
+
+    /// release the lease of `token` without taking it over
+    ///
+    /// fails with [`Error::LockLost`] if the server released it before it expired.
+    fn release_token(token: &LeaseToken) -> impl Future<Output = Result<()>> + Send;
+}
+
+impl<L: LeaseLocker + 'static, C: Clock> LockClient<L, C> {
+    /// take over the lease of a guard exported by [`LockGuard::export`], e.g. by the process that
+    /// held it before a restart
+    ///
+    /// the lease is renewed again like that of a guard acquired by [`LockClient::guard`]. fails
+    /// with [`Error::LockLost`] if the server no longer holds it.
+    ///
+    /// ```ignore
+    /// let token: LeaseToken = serde_json::from_str(&checkpoint.lease)?;
+    /// let guard = locker.resume(&token).await?;
+    /// ```
+    pub async fn resume<'c>(&'c self, token: &'c LeaseToken) -> Result<LockGuard<'c, L, C>> {
+        let backend_key = self.backend_key(&token.key)?;
+        L::resume(&backend_key, token);
+
+        // 引き継いだリースは待たずに取れる
+        self.guard(&token.key, NO_WAIT).await
+    }
+}
+
+impl<L: LeaseLocker + 'static, C: Clock> LockGuard<'_, L, C> {
+    /// stop the guard without releasing its lease, to be taken over by [`LockClient::resume`]
+    ///
+    /// the lease is no longer renewed, so the server releases the key at
+    /// [`LeaseToken::expires_at`] unless it is resumed before. the children are released first,
+    /// and the guard is released like when it is dropped if one of them fails.
+    ///
+    /// ```ignore
+    /// let guard = locker.guard("nightly-import", None).await?;
+    /// checkpoint.lease = serde_json::to_string(&guard.export().await?)?;
+    /// ```
+    pub async fn export(self) -> Result<LeaseToken> {
+        let (client, key, (held, _lock_tx)) = self.into_locked().await?;
+        let backend_key = client.backend_key(key)?;
+        let mut token = L::export(&backend_key).ok_or_else(|| Error::LockLost(key.to_owned()))?;
+        client.hand_over(held);
+
+        token.key = key.to_owned();
+        Ok(token)
+    }
+}
+
+// クライアント側で持っているリースと、それを更新し続けるタスク
+struct ClientLease {
+    lease_id: String,
+    renewer: JoinHandle<()>,
+    max_deadline: Option<Instant>,
+    // 最後に成功した更新を送った時刻 + TTL。サーバーはそれより後まで持っている
+    renewed_until: Arc<Mutex<Instant>>,
+    // 更新に失敗すると true になる
+    lost: watch::Receiver<bool>,
+}
+
+/// lease whose renewal was stopped by [`stop_renewing`]
+pub(crate) struct StoppedLease {
+    pub(crate) lease_id: String,
+    max_deadline: Option<Instant>,
+}
+
+impl StoppedLease {
+    /// the max hold of the lease elapsed, so the server may have released it already
+    pub(crate) fn max_hold_elapsed(&self) -> bool {
+        self.max_deadline
+            .is_some_and(|max_deadline| max_deadline <= DefaultClock::now())
+    }
+}
+
+static CLIENT_LEASES: LazyLock<Mutex<HashMap<(&'static str, String), ClientLease>>> =
+    LazyLock::new(Mutex::default);
+
+// 更新の間隔。クライアントが揃って同時に更新しないように、TTL の 1/4 から 1/3 の間でばらす
+fn renewal_interval(ttl: Duration) -> Duration {
+    static RANDOM: LazyLock<RandomState> = LazyLock::new(RandomState::new);
+    static RENEWALS: AtomicU64 = AtomicU64::new(0);
+
+    let n = RANDOM.hash_one(RENEWALS.fetch_add(1, Ordering::Relaxed)) % 1000;
+    ttl / 4 + (ttl / 3 - ttl / 4) * n as u32 / 1000
+}
+
+/// renew the lease every quarter to third of `ttl` until [`stop_renewing`] is called for the key
+///
+/// * `endpoint` - identifies the service, the same key may be held on several of them
+/// * `max_hold` - max hold the lease was acquired with, counted from `requested`
+/// * `on_failure` - called with the key and the error when a renewal fails, once the lease is lost
+pub(crate) fn keep_renewing<F, Fut, T>(
+    endpoint: &'static str,
+    key: &str,
+    lease_id: String,
+    ttl: Duration,
+    (requested, max_hold): (Instant, Option<Duration>),
+    mut renew: F,
+    on_failure: fn(&str, &Error),
+) where
+    F: FnMut(String) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<T>> + Send,
+{
+    let (lost_tx, lost) = watch::channel(false);
+    let renewed_until = Arc::new(Mutex::new(requested + ttl));
+    let renewer = tokio::spawn({
+        let lease_id = lease_id.clone();
+        let key = key.to_owned();
+        let renewed_until = Arc::clone(&renewed_until);
+        async move {
+            loop {
+                DefaultClock::sleep(renewal_interval(ttl)).await;
+                let sent = DefaultClock::now();
+                if let Err(e) = renew(lease_id.clone()).await {
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!(lease_id, error = %e, "failed to renew the lease");
+                    lost_tx.send_replace(true);
+                    on_failure(&key, &e);
+                    return;
+                }
+                *renewed_until.lock().unwrap() = sent + ttl;
+            }
+        }
+    });
+
+    CLIENT_LEASES.lock().unwrap().insert(
+        (endpoint, key.to_owned()),
+        ClientLease {
+            lease_id,
+            renewer,
+            max_deadline: max_hold.map(|max_hold| requested + max_hold),
+            renewed_until,
+            lost,
+        },
+    );
+}
+
+/// complete once renewing the lease of the key failed, never if the key isn't held
+pub(crate) fn lost(endpoint: &'static str, key: &str) -> impl Future<Output = ()> + Send + use<> {
+    let lost = CLIENT_LEASES
+        .lock()
+        .unwrap()
+        .get(&(endpoint, key.to_owned()))
+        .map(|lease| lease.lost.clone());
+
+    async move {
+        if let Some(mut lost) = lost {
+            // 解放されて更新が止まったときは失われていない
+            if lost.wait_for(|lost| *lost).await.is_ok() {
+                return;
+            }
+        }
+        std::future::pending().await
+    }
+}
+
+/// push the max hold of the lease of the key later by `by`, returning its id
+///
+/// None if the key isn't held. the server has to be told separately.
+pub(crate) fn extend(endpoint: &'static str, key: &str, by: Duration) -> Option<String> {
+    let mut leases = CLIENT_LEASES.lock().unwrap();
+    let lease = leases.get_mut(&(endpoint, key.to_owned()))?;
+    if let Some(max_deadline) = &mut lease.max_deadline {
+        *max_deadline += by;
+    }
+
+    Some(lease.lease_id.clone())
+}
+
+/// stop renewing the lease of the key
+pub(crate) fn stop_renewing(endpoint: &'static str, key: &str) -> Option<StoppedLease> {
+    let lease = CLIENT_LEASES
+        .lock()
+        .unwrap()
+        .remove(&(endpoint, key.to_owned()))?;
+    lease.renewer.abort();
+
+    Some(StoppedLease {
+        lease_id: lease.lease_id,
+        max_deadline: lease.max_deadline,
+    })
+}
+
+/// stop renewing the lease of the key and describe it, without releasing it
+///
+/// the key of the token is the backend key, the guard replaces it with its own.
+pub(crate) fn export(endpoint: &'static str, key: &str) -> Option<LeaseToken> {
+    let lease = CLIENT_LEASES
+        .lock()
+        .unwrap()
+        .remove(&(endpoint, key.to_owned()))?;
+    lease.renewer.abort();
+    let renewed_until = *lease.renewed_until.lock().unwrap();
+
+    Some(LeaseToken {
+        key: key.to_owned(),
+        lease_id: lease.lease_id,
+        expires_at: wall_time(cap(renewed_until, lease.max_deadline)),
+        max_hold_until: lease.max_deadline.map(wall_time),
+    })
+}
+
+/// lease taken over from another process by [`resume`]
+pub(crate) struct ResumedLease {
+    pub(crate) lease_id: String,
+    /// max hold left, to pass to [`keep_renewing`] with the time of the renewal
+    pub(crate) max_hold: Option<Duration>,
+}
+
+// resume で渡されて、次の取得が引き継ぐリース
+static RESUMED: LazyLock<Mutex<HashMap<(&'static str, String), ResumedLease>>> =
+    LazyLock::new(Mutex::default);
+
+/// have the next [`take_resumed`] of the key return the lease of `token`
+pub(crate) fn resume(endpoint: &'static str, key: &str, token: &LeaseToken) {
+    let now = SystemTime::now();
+    let left = |at: SystemTime| at.duration_since(now).unwrap_or_default();
+
+    RESUMED.lock().unwrap().insert(
+        (endpoint, key.to_owned()),
+        ResumedLease {
+            lease_id: token.lease_id.clone(),
+            max_hold: token.max_hold_until.map(left),
+        },
+    );
+}
+
+/// the lease passed to [`resume`] for the key, the locker renews it instead of acquiring the key
+pub(crate) fn take_resumed(endpoint: &'static str, key: &str) -> Option<ResumedLease> {
+    RESUMED.lock().unwrap().remove(&(endpoint, key.to_owned()))
+}
+
+/// a lease of `token` the server no longer knows was released on its expiry, not lost
+pub(crate) fn token_expired(token: &LeaseToken) -> bool {
+    let now = SystemTime::now();
+    token.expires_at <= now || token.max_hold_until.is_some_and(|until| until <= now)
+}
+
+// Instant をプロセスをまたいで通じる時刻にする
+fn wall_time(at: Instant) -> SystemTime {
+    let (wall, now) = (SystemTime::now(), DefaultClock::now());
+    match at.checked_duration_since(now) {
+        Some(after) => wall + after,
+        None => wall - now.duration_since(at),
+    }
+}
@@ -0,0 +1,11 @@
+//! Lock coordination services backed by any [`Locker`](crate::Locker).
+
+mod lease;
+
+pub use lease::{LeaseLocker, LeaseToken};
+
+#[cfg(feature = "grpc")]
+pub mod grpc;
+
+#[cfg(feature = "http")]
+pub mod http;
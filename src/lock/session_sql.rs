@@ -0,0 +1,132 @@
+use std::{future::Future, sync::Arc};
+
+use super::client::SessionSql;
+use crate::{Clock, LockClient, Locker, Result};
+
+/// Database the statements of [`LockClient::before_lock_sql`] and
+/// [`LockClient::after_lock_sql`] run on.
+pub trait SessionSqlDatabase: sqlx::Database {
+    /// run `sql` on the session of `tx`
+    fn execute_session_sql(
+        tx: &mut sqlx::Transaction<'static, Self>,
+        sql: &str,
+    ) -> impl Future<Output = Result<()>> + Send;
+}
+
+impl<L: Locker, C: Clock> LockClient<L, C>
+where
+    L::DB: SessionSqlDatabase,
+{
+    /// run `sql` on the lock session before each acquisition, in the order they were added
+    ///
+    /// e.g. `SET LOCAL application_name = 'billing'`, so the waiting session is recognizable in
+    /// `pg_stat_activity`. the call fails with the error of the statement without trying the key.
+    pub fn before_lock_sql(mut self, sql: impl Into<String>) -> Self {
+        self.before_lock_sql.push(session_sql(sql.into()));
+        self
+    }
+
+    /// run `sql` on the lock session right after each acquisition, in the order they were added
+    ///
+    /// e.g. `SET SESSION innodb_lock_wait_timeout = 5` or `SET LOCAL statement_timeout = '5s'`,
+    /// so the closure running on the lock session gets server-side timeouts of its own. the key
+    /// is released again when the statement fails, and the call fails with its error. closures
+    /// running on [`LockClient::lock_pool`] are on another session and unaffected.
+    ///
+    /// ```ignore
+    /// let locker = LockClient::<PostgresLocker>::new(pool)
+    ///     .before_lock_sql("SET LOCAL application_name = 'billing'")
+    ///     .after_lock_sql("SET LOCAL statement_timeout = '5s'");
+    /// ```
+    pub fn after_lock_sql(mut self, sql: impl Into<String>) -> Self {
+        self.after_lock_sql.push(session_sql(sql.into()));
+        self
+    }
+}
+
+fn session_sql<DB: SessionSqlDatabase>(sql: String) -> SessionSql<DB> {
+    let sql: Arc<str> = sql.into();
+    Arc::new(move |tx| {
+        let sql = Arc::clone(&sql);
+        Box::pin(async move { DB::execute_session_sql(tx, &sql).await })
+    })
+}
+
+#[cfg(feature = "sqlx-mysql")]
+impl SessionSqlDatabase for sqlx::MySql {
+    async fn execute_session_sql(
+        tx: &mut sqlx::Transaction<'static, Self>,
+        sql: &str,
+    ) -> Result<()> {
+        sqlx::Executor::execute(&mut **tx, sql).await?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sqlx-postgres")]
+impl SessionSqlDatabase for sqlx::Postgres {
+    async fn execute_session_sql(
+        tx: &mut sqlx::Transaction<'static, Self>,
+        sql: &str,
+    ) -> Result<()> {
+        // 失敗でトランザクションが中断されるとロックを外せないので、セーブポイントの中で流す
+        let mut savepoint = sqlx::Connection::begin(&mut **tx).await?;
+        sqlx::Executor::execute(&mut *savepoint, sql).await?;
+        savepoint.commit().await?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sqlx-sqlite")]
+impl SessionSqlDatabase for sqlx::Sqlite {
+    async fn execute_session_sql(
+        tx: &mut sqlx::Transaction<'static, Self>,
+        sql: &str,
+    ) -> Result<()> {
+        sqlx::Executor::execute(&mut **tx, sql).await?;
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "sqlx-std-collection", feature = "sqlx-sqlite"))]
+mod tests {
+    use pretty_assertions::{assert_eq, assert_matches};
+
+    use super::*;
+
+    use crate::{Error, StdCollectionLocker};
+    use sqlx::{Sqlite, SqlitePool};
+
+    #[sqlx::test]
+    async fn statements_run_on_the_lock_session(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Ss4Tt6Uu8Vv0Ww2Xx4Yy6Zz8Aa0Bb2Cc4Dd6Ee8Ff0Gg2Hh4Ii6Jj8Kk0Ll2Mm4N";
+        let client = LockClient::<StdCollectionLocker<Sqlite>>::new(pool.clone())
+            .before_lock_sql("CREATE TEMP TABLE steps (step TEXT NOT NULL)")
+            .before_lock_sql("INSERT INTO steps VALUES ('before')")
+            .after_lock_sql("INSERT INTO steps VALUES ('after')");
+
+        let mut steps = Vec::new();
+        client
+            .with_locking(key, None, async |tx| {
+                steps = sqlx::query_scalar::<_, String>("SELECT step FROM steps ORDER BY rowid")
+                    .fetch_all(&mut **tx)
+                    .await
+                    .unwrap();
+            })
+            .await
+            .unwrap();
+        assert_eq!(steps, ["before", "after"]);
+
+        // 取得後の SQL が失敗すればキーは外される
+        let failing = LockClient::<StdCollectionLocker<Sqlite>>::new(pool.clone())
+            .after_lock_sql("INSERT INTO missing VALUES (1)");
+        assert_matches!(
+            failing.with_locking(key, None, async |_| {}).await,
+            Err(Error::Sqlx(_))
+        );
+        let plain = LockClient::<StdCollectionLocker<Sqlite>>::new(pool);
+        assert_matches!(plain.with_locking(key, None, async |_| {}).await, Ok(()));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,107 @@
+use std::{
+    hash::{BuildHasher, RandomState},
+    time::Duration,
+};
+
+use crate::{Error, InstanceState, Locker, Result, SingleInstance};
+
+/// Shard claimed by [`claim_shard`], held until it is released or dropped.
+///
+/// the session holding the shard is pinged and acquired again after a reconnect, as for
+/// [`SingleInstance`].
+pub struct ShardClaim {
+    index: u32,
+    instance: SingleInstance,
+}
+
+/// claim one of the `shard_count` shards of `prefix`, trying them from a random one
+///
+/// every worker claiming the same prefix gets another shard, and fails with
+/// [`Error::FailedToGetLock`] when all of them are claimed. the shards are locked as
+/// `{prefix}:{index}`.
+///
+/// ```ignore
+/// let shard = claim_shard::<PostgresLocker>(&pool, "ingest", 8).await?;
+/// consume_partition(shard.index()).await;
+/// shard.release().await?;
+/// ```
+pub async fn claim_shard<L: Locker + 'static>(
+    pool: &sqlx::Pool<L::DB>,
+    prefix: &str,
+    shard_count: u32,
+) -> Result<ShardClaim> {
+    // 同時に起動したワーカーが同じ順に取り合わないよう、始める位置をばらす
+    let start = (RandomState::new().hash_one(prefix) % u64::from(shard_count.max(1))) as u32;
+
+    for i in 0..shard_count {
+        let index = (start + i) % shard_count;
+        match SingleInstance::hold::<L>(pool, format!("{prefix}:{index}"), Duration::from_secs(5))
+            .await
+        {
+            Ok(instance) => return Ok(ShardClaim { index, instance }),
+            Err(Error::FailedToGetLock(_)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(Error::FailedToGetLock(format!("{prefix}:*")))
+}
+
+impl ShardClaim {
+    /// index of the claimed shard, below the shard count
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    /// state of the lock of the shard at the moment
+    pub fn state(&self) -> InstanceState {
+        self.instance.state()
+    }
+
+    /// complete once another worker claimed the shard while it was being acquired again
+    pub async fn lost(&mut self) {
+        self.instance.lost().await
+    }
+
+    /// release the shard
+    pub async fn release(self) -> Result<()> {
+        self.instance.release().await
+    }
+}
+
+#[cfg(all(test, feature = "sqlx-std-collection"))]
+mod tests {
+    use pretty_assertions::{assert_eq, assert_matches};
+
+    use super::*;
+
+    use crate::StdCollectionLocker;
+    use sqlx::{Sqlite, SqlitePool};
+
+    type Collection = StdCollectionLocker<Sqlite>;
+
+    #[sqlx::test]
+    async fn workers_spread_over_the_shards(pool: SqlitePool) -> sqlx::Result<()> {
+        let prefix = "Hk3Jn6Mq9Pt2Sw5Vz8Yc1Bf4Ei7Hl0Ko3Nr6Qu9Tx2Wa5Zd8Cg1Fj4Im7Lp0Os3R";
+
+        let mut claims = Vec::new();
+        for _ in 0..3 {
+            claims.push(claim_shard::<Collection>(&pool, prefix, 3).await.unwrap());
+        }
+        let mut indices: Vec<_> = claims.iter().map(ShardClaim::index).collect();
+        indices.sort();
+        assert_eq!(indices, vec![0, 1, 2]);
+        assert_matches!(
+            claim_shard::<Collection>(&pool, prefix, 3).await.err(),
+            Some(Error::FailedToGetLock(_))
+        );
+
+        let released = claims.pop().unwrap();
+        let index = released.index();
+        assert_matches!(released.release().await, Ok(()));
+        let claim = claim_shard::<Collection>(&pool, prefix, 3).await.unwrap();
+        assert_eq!(claim.index(), index);
+
+        Ok(())
+    }
+}
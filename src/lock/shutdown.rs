@@ -0,0 +1,403 @@
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    pin::pin,
+    sync::{
+        Arc, LazyLock, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    task::Poll,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{Notify, watch};
+
+use crate::{Clock, DefaultClock, Error, Result};
+
+/// What [`ShutdownRegistry::shutdown`] does with the closures running under a lock.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShutdownPolicy {
+    /// let every closure finish
+    Wait,
+    /// drop every closure and release its lock right away, in the increasing
+    /// [`crate::LockClient::shutdown_order`] of the calls
+    Abort,
+    /// let closures finish for up to the given duration, then abort the rest
+    AbortAfter(Duration),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Phase {
+    Running,
+    Draining,
+    // この順番までの呼び出しを打ち切る
+    Aborting(u32),
+}
+
+struct Inner {
+    phase: watch::Sender<Phase>,
+    outstanding: AtomicUsize,
+    // 順番ごとの呼び出しの数
+    orders: Mutex<BTreeMap<u32, usize>>,
+    released: Notify,
+}
+
+/// Registry of the lock calls in flight, released together on shutdown.
+///
+/// [`crate::Locker::with_locking`] registers to [`ShutdownRegistry::global`], and
+/// [`crate::LockClient`] to the registry it was configured with.
+///
+/// ```ignore
+/// tokio::signal::ctrl_c().await?;
+/// rusty_ad_lock::shutdown(ShutdownPolicy::AbortAfter(Duration::from_secs(10))).await;
+/// ```
+#[derive(Clone)]
+pub struct ShutdownRegistry {
+    inner: Arc<Inner>,
+}
+
+static GLOBAL: LazyLock<ShutdownRegistry> = LazyLock::new(ShutdownRegistry::new);
+
+impl Default for ShutdownRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ShutdownRegistry {
+    /// create a registry independent of the global one
+    pub fn new() -> Self {
+        let (phase, _rx) = watch::channel(Phase::Running);
+
+        Self {
+            inner: Arc::new(Inner {
+                phase,
+                outstanding: AtomicUsize::new(0),
+                orders: Mutex::default(),
+                released: Notify::new(),
+            }),
+        }
+    }
+
+    /// registry of the process
+    pub fn global() -> &'static ShutdownRegistry {
+        &GLOBAL
+    }
+
+    /// number of lock calls waiting for or holding a lock
+    pub fn outstanding(&self) -> usize {
+        self.inner.outstanding.load(Ordering::Acquire)
+    }
+
+    /// refuse new lock calls, and return once every registered lock is released
+    ///
+    /// waiting acquisitions fail with [`Error::ShuttingDown`] right away, running closures are
+    /// handled per `policy`. aborted closures are dropped one [`crate::LockClient::shutdown_order`]
+    /// after another, the locks of an order released before the next one is aborted.
+    ///
+    /// the guards of [`crate::LockClient::guard`] can't be aborted: whatever the policy, this
+    /// returns only once they are released or dropped.
+    pub async fn shutdown(&self, policy: ShutdownPolicy) {
+        self.shutdown_with::<DefaultClock>(policy).await
+    }
+
+    /// [`ShutdownRegistry::shutdown`] timing the grace of [`ShutdownPolicy::AbortAfter`] on `C`
+    pub async fn shutdown_with<C: Clock>(&self, policy: ShutdownPolicy) {
+        match policy {
+            ShutdownPolicy::Wait => {
+                self.inner.phase.send_replace(Phase::Draining);
+                self.drained(u32::MAX).await;
+            }
+            ShutdownPolicy::Abort => {
+                self.inner.phase.send_replace(Phase::Draining);
+                self.abort_in_order().await;
+            }
+            ShutdownPolicy::AbortAfter(grace) => {
+                self.inner.phase.send_replace(Phase::Draining);
+                if C::timeout(grace, self.drained(u32::MAX)).await.is_none() {
+                    self.abort_in_order().await;
+                }
+            }
+        }
+    }
+
+    async fn abort_in_order(&self) {
+        // 新しい呼び出しは断っているので、残っている順番は減る一方
+        while let Some(order) = self.lowest_order() {
+            self.inner.phase.send_replace(Phase::Aborting(order));
+            self.drained(order).await;
+        }
+    }
+
+    fn lowest_order(&self) -> Option<u32> {
+        self.inner.orders.lock().unwrap().keys().next().copied()
+    }
+
+    // シャットダウンが始まったら完了する
+    pub(crate) fn started(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut phase = self.inner.phase.subscribe();
+        async move {
+            // NOTE: 送信側は inner が持っているので閉じない
+            let _ = phase.wait_for(|phase| *phase != Phase::Running).await;
+        }
+    }
+
+    // 待たずに打ち切りだけ始める。Drop から呼ぶ
+    pub(crate) fn abort(&self) {
+        self.inner.phase.send_replace(Phase::Aborting(u32::MAX));
+    }
+
+    // order 以下の呼び出しが全部外れたら返る
+    async fn drained(&self, order: u32) {
+        loop {
+            // 数を見る前に待ち受けを作っておかないと、その間の解放を取りこぼす
+            let released = self.inner.released.notified();
+            if self.lowest_order().is_none_or(|lowest| lowest > order) {
+                return;
+            }
+            released.await;
+        }
+    }
+
+    pub(crate) fn register(&self) -> Result<Registration> {
+        self.register_ordered(0)
+    }
+
+    pub(crate) fn register_ordered(&self, order: u32) -> Result<Registration> {
+        let phase = self.inner.phase.subscribe();
+        if *phase.borrow() != Phase::Running {
+            return Err(Error::ShuttingDown);
+        }
+
+        self.inner.outstanding.fetch_add(1, Ordering::AcqRel);
+        *self.inner.orders.lock().unwrap().entry(order).or_default() += 1;
+        Ok(Registration {
+            inner: Arc::clone(&self.inner),
+            phase,
+            order,
+        })
+    }
+}
+
+/// shut down [`ShutdownRegistry::global`], see [`ShutdownRegistry::shutdown`]
+pub async fn shutdown(policy: ShutdownPolicy) {
+    ShutdownRegistry::global().shutdown(policy).await
+}
+
+// 1回のロック呼び出しの登録。Drop で外れる
+pub(crate) struct Registration {
+    inner: Arc<Inner>,
+    phase: watch::Receiver<Phase>,
+    order: u32,
+}
+
+impl Registration {
+    // 取得待ちはシャットダウンが始まったら打ち切る
+    pub(crate) async fn acquiring<F: Future>(&mut self, f: F) -> Result<F::Output> {
+        self.until(f, |phase| phase != Phase::Running).await
+    }
+
+    // ロックを持ったクロージャは Abort になったら打ち切る
+    pub(crate) async fn holding<F: Future>(&mut self, f: F) -> Result<F::Output> {
+        let order = self.order;
+        self.until(
+            f,
+            move |phase| matches!(phase, Phase::Aborting(level) if order <= level),
+        )
+        .await
+    }
+
+    // holding に加えて、deadline を過ぎたら Error::LeaseExpired で打ち切る
+    pub(crate) async fn holding_until<F: Future>(
+        &mut self,
+        deadline: Option<Instant>,
+        key: &str,
+        f: F,
+    ) -> Result<F::Output> {
+        self.holding_until_with::<DefaultClock, F>(deadline, key, f)
+            .await
+    }
+
+    // deadline を C で測る holding_until
+    pub(crate) async fn holding_until_with<C: Clock, F: Future>(
+        &mut self,
+        deadline: Option<Instant>,
+        key: &str,
+        f: F,
+    ) -> Result<F::Output> {
+        let Some(deadline) = deadline else {
+            return self.holding(f).await;
+        };
+
+        let mut f = pin!(f);
+        let mut expired = pin!(C::sleep(deadline.saturating_duration_since(C::now())));
+        let within = std::future::poll_fn(|cx| {
+            if let Poll::Ready(out) = f.as_mut().poll(cx) {
+                return Poll::Ready(Some(out));
+            }
+            expired.as_mut().poll(cx).map(|()| None)
+        });
+
+        self.holding(within)
+            .await?
+            .ok_or_else(|| Error::LeaseExpired(key.to_owned()))
+    }
+
+    async fn until<F: Future>(&mut self, f: F, abort: impl Fn(Phase) -> bool) -> Result<F::Output> {
+        let mut f = pin!(f);
+
+        loop {
+            if abort(*self.phase.borrow_and_update()) {
+                return Err(Error::ShuttingDown);
+            }
+
+            let mut changed = pin!(self.phase.changed());
+            let out = std::future::poll_fn(|cx| {
+                if let Poll::Ready(out) = f.as_mut().poll(cx) {
+                    return Poll::Ready(Some(out));
+                }
+                changed.as_mut().poll(cx).map(|_| None)
+            })
+            .await;
+
+            if let Some(out) = out {
+                return Ok(out);
+            }
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.inner.outstanding.fetch_sub(1, Ordering::AcqRel);
+        {
+            let mut orders = self.inner.orders.lock().unwrap();
+            if let Some(count) = orders.get_mut(&self.order) {
+                *count -= 1;
+                if *count == 0 {
+                    orders.remove(&self.order);
+                }
+            }
+        }
+        self.inner.released.notify_waiters();
+    }
+}
+
+#[cfg(all(test, feature = "sqlx-std-collection"))]
+mod tests {
+    use pretty_assertions::{assert_eq, assert_matches};
+    use tokio::time::sleep;
+
+    use super::*;
+
+    use crate::{LockClient, Locker, StdCollectionLocker};
+    use sqlx::{Sqlite, SqlitePool};
+
+    #[sqlx::test]
+    async fn abort_releases_held_and_waiting_locks(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Zs5Xa7Cw9Vq1Be3Nr5Mt7Ly9Ku1Ji3Ho5Gp7Fa9Ds1Sf3Ag5Qh7Wj9Ek1Rl3Tz5Y";
+        let registry = ShutdownRegistry::new();
+        let locker = LockClient::<StdCollectionLocker<Sqlite>>::new(pool.clone())
+            .shutdown_registry(registry.clone());
+
+        let (held, waiting, ()) = tokio::join!(
+            locker.with_locking(key, None, async |_| {
+                sleep(Duration::from_secs(10)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(50)).await;
+                locker
+                    .with_locking(key, Duration::from_secs(10).into(), async |_| {})
+                    .await
+            },
+            async {
+                sleep(Duration::from_millis(200)).await;
+                registry.shutdown(ShutdownPolicy::Abort).await;
+            }
+        );
+
+        assert_matches!(held, Err(Error::ShuttingDown));
+        assert_matches!(waiting, Err(Error::ShuttingDown));
+        assert_eq!(registry.outstanding(), 0);
+        assert_matches!(
+            locker.with_locking(key, None, async |_| {}).await,
+            Err(Error::ShuttingDown)
+        );
+        assert_matches!(
+            StdCollectionLocker::with_locking(&pool, key, None, async |_| {}).await,
+            Ok(())
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn wait_lets_running_closures_finish(pool: SqlitePool) -> sqlx::Result<()> {
+        let registry = ShutdownRegistry::new();
+        let locker = LockClient::<StdCollectionLocker<Sqlite>>::new(pool)
+            .shutdown_registry(registry.clone());
+
+        let (held, ()) = tokio::join!(
+            locker.with_locking(
+                "Nb8Mv0Cx2Zl4Kj6Hg8Fd0Sa2Pq4Ow6Ie8Ur0Yt2Wq4Ez6Rx8Tc0Yv2Ub4In6Om8P",
+                None,
+                async |_| {
+                    sleep(Duration::from_millis(300)).await;
+                }
+            ),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                registry.shutdown(ShutdownPolicy::Wait).await;
+                assert_eq!(registry.outstanding(), 0);
+            }
+        );
+
+        assert_matches!(held, Ok(()));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn abort_releases_lower_orders_first(pool: SqlitePool) -> sqlx::Result<()> {
+        struct Dropped(&'static str, Arc<Mutex<Vec<&'static str>>>);
+
+        impl Drop for Dropped {
+            fn drop(&mut self) {
+                self.1.lock().unwrap().push(self.0);
+            }
+        }
+
+        let registry = ShutdownRegistry::new();
+        let parents = LockClient::<StdCollectionLocker<Sqlite>>::new(pool.clone())
+            .shutdown_registry(registry.clone())
+            .shutdown_order(1);
+        let children = LockClient::<StdCollectionLocker<Sqlite>>::new(pool)
+            .shutdown_registry(registry.clone());
+        let dropped = Arc::new(Mutex::new(Vec::new()));
+
+        let hold = async |name, client: &LockClient<StdCollectionLocker<Sqlite>>| {
+            let dropped = Dropped(name, Arc::clone(&dropped));
+            client
+                .with_locking(name, None, async |_| {
+                    let _dropped = dropped;
+                    sleep(Duration::from_secs(10)).await;
+                })
+                .await
+        };
+        let (parent, child, ()) = tokio::join!(
+            hold("tenant:42", &parents),
+            hold("tenant:42/order:7", &children),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                registry.shutdown(ShutdownPolicy::Abort).await;
+            }
+        );
+
+        assert_matches!(parent, Err(Error::ShuttingDown));
+        assert_matches!(child, Err(Error::ShuttingDown));
+        assert_eq!(*dropped.lock().unwrap(), ["tenant:42/order:7", "tenant:42"]);
+        assert_eq!(registry.outstanding(), 0);
+
+        Ok(())
+    }
+}
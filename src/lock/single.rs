@@ -0,0 +1,204 @@
+use std::time::Duration;
+
+use sqlx::Connection;
+use tokio::{
+    sync::{oneshot, watch},
+    task::JoinHandle,
+};
+
+use crate::{Clock, DefaultClock, Error, Locker, Result};
+
+/// State of the lock of a [`SingleInstance`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstanceState {
+    /// the lock is held
+    Held,
+    /// the session holding the lock was lost, it is being acquired again
+    Reconnecting,
+    /// another instance acquired the lock while it was being acquired again
+    Lost,
+}
+
+/// Lock guaranteeing that only one instance of an application runs against a database.
+///
+/// the lock is held on a task that pings its session, and acquired again on a new session when
+/// the connection is lost. it is released by [`SingleInstance::release`] or when dropped.
+///
+/// ```ignore
+/// let mut instance = SingleInstance::acquire::<PostgresLocker>(&pool, "billing-daemon").await?;
+/// tokio::select! {
+///     _ = run_daemon() => {}
+///     _ = instance.lost() => eprintln!("another instance took over"),
+/// }
+/// instance.release().await?;
+/// ```
+pub struct SingleInstance {
+    state: watch::Receiver<InstanceState>,
+    release: oneshot::Sender<()>,
+    task: JoinHandle<Result<()>>,
+}
+
+impl SingleInstance {
+    /// acquire the lock of `app_name`, failing immediately if another instance holds it
+    ///
+    /// the session is pinged every 5 seconds.
+    pub async fn acquire<L: Locker + 'static>(
+        pool: &sqlx::Pool<L::DB>,
+        app_name: &str,
+    ) -> Result<Self> {
+        Self::acquire_pinging::<L>(pool, app_name, Duration::from_secs(5)).await
+    }
+
+    /// acquire the lock of `app_name`, pinging its session every `interval`
+    pub async fn acquire_pinging<L: Locker + 'static>(
+        pool: &sqlx::Pool<L::DB>,
+        app_name: &str,
+        interval: Duration,
+    ) -> Result<Self> {
+        Self::hold::<L>(pool, format!("single-instance:{app_name}"), interval).await
+    }
+
+    // key を取って、セッションを確かめ続けるタスクに持たせる
+    pub(crate) async fn hold<L: Locker + 'static>(
+        pool: &sqlx::Pool<L::DB>,
+        key: String,
+        interval: Duration,
+    ) -> Result<Self> {
+        let tx = lock::<L>(pool, &key).await?;
+
+        let (state_tx, state) = watch::channel(InstanceState::Held);
+        let (release, release_rx) = oneshot::channel();
+        let task = tokio::spawn(hold::<L>(
+            pool.clone(),
+            key,
+            tx,
+            interval,
+            state_tx,
+            release_rx,
+        ));
+
+        Ok(Self {
+            state,
+            release,
+            task,
+        })
+    }
+
+    /// state of the lock at the moment
+    pub fn state(&self) -> InstanceState {
+        *self.state.borrow()
+    }
+
+    /// complete once the lock is [`InstanceState::Lost`]
+    pub async fn lost(&mut self) {
+        let _ = self
+            .state
+            .wait_for(|state| *state == InstanceState::Lost)
+            .await;
+    }
+
+    /// release the lock and wait for its task to finish
+    pub async fn release(self) -> Result<()> {
+        let _ = self.release.send(());
+        self.task
+            .await
+            .expect("the single instance task ended without reporting the release")
+    }
+}
+
+async fn lock<L: Locker>(
+    pool: &sqlx::Pool<L::DB>,
+    key: &str,
+) -> Result<sqlx::Transaction<'static, L::DB>> {
+    let mut tx = pool.begin().await?;
+    L::acquire(pool, &mut tx, key, None).await?;
+    Ok(tx)
+}
+
+async fn hold<L: Locker>(
+    pool: sqlx::Pool<L::DB>,
+    key: String,
+    mut tx: sqlx::Transaction<'static, L::DB>,
+    interval: Duration,
+    state: watch::Sender<InstanceState>,
+    mut release: oneshot::Receiver<()>,
+) -> Result<()> {
+    loop {
+        // 解放を頼まれるか、ハンドルが捨てられるまでセッションを確かめ続ける
+        if DefaultClock::timeout(interval, &mut release)
+            .await
+            .is_some()
+        {
+            return L::release(&pool, &mut tx, &key).await;
+        }
+        if tx.ping().await.is_ok() {
+            continue;
+        }
+
+        // セッションと一緒にロックも失われているので、新しいセッションで取り直す
+        state.send_replace(InstanceState::Reconnecting);
+        let _ = L::release(&pool, &mut tx, &key).await;
+        tx = loop {
+            match lock::<L>(&pool, &key).await {
+                Ok(tx) => break tx,
+                Err(Error::FailedToGetLock(_)) => {
+                    state.send_replace(InstanceState::Lost);
+                    return Ok(());
+                }
+                // まだ繋がらない
+                Err(_) => {}
+            }
+            if DefaultClock::timeout(interval, &mut release)
+                .await
+                .is_some()
+            {
+                return Ok(());
+            }
+        };
+        state.send_replace(InstanceState::Held);
+    }
+}
+
+#[cfg(all(test, feature = "sqlx-std-collection"))]
+mod tests {
+    use pretty_assertions::{assert_eq, assert_matches};
+    use tokio::time::sleep;
+
+    use super::*;
+
+    use crate::StdCollectionLocker;
+    use sqlx::{Sqlite, SqlitePool};
+
+    type Locker = StdCollectionLocker<Sqlite>;
+
+    #[sqlx::test]
+    async fn only_one_instance_holds_the_lock(pool: SqlitePool) -> sqlx::Result<()> {
+        let app = "Rb6Tn8Ym0Uq2Iw4Oe6Pr8At0Sy2Du4Fi6Go8Hp0Ja2Ks4Ld6Zf8Xg0Ch2Vj4Bk6N";
+
+        let instance =
+            SingleInstance::acquire_pinging::<Locker>(&pool, app, Duration::from_millis(50))
+                .await
+                .unwrap();
+        sleep(Duration::from_millis(200)).await;
+        assert_eq!(instance.state(), InstanceState::Held);
+        assert_matches!(
+            SingleInstance::acquire::<Locker>(&pool, app).await.err(),
+            Some(Error::FailedToGetLock(_))
+        );
+
+        assert_matches!(instance.release().await, Ok(()));
+        let instance = SingleInstance::acquire::<Locker>(&pool, app).await.unwrap();
+
+        // 捨てても解放される
+        drop(instance);
+        sleep(Duration::from_millis(100)).await;
+        assert_matches!(
+            SingleInstance::acquire::<Locker>(&pool, app)
+                .await
+                .map(drop),
+            Ok(())
+        );
+
+        Ok(())
+    }
+}
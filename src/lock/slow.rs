@@ -0,0 +1,79 @@
+use std::{sync::Arc, time::Duration};
+
+/// Lock call that went over a threshold set on [`crate::LockClient`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SlowLock<'a> {
+    /// the acquisition of `key` waited `wait`, longer than `threshold`
+    Wait {
+        key: &'a str,
+        wait: Duration,
+        threshold: Duration,
+    },
+    /// the closure held `key` for `hold`, longer than `threshold`
+    Hold {
+        key: &'a str,
+        hold: Duration,
+        threshold: Duration,
+    },
+}
+
+type Callback = Arc<dyn Fn(&SlowLock<'_>) + Send + Sync>;
+
+#[derive(Clone, Default)]
+pub(crate) struct SlowLockWarnings {
+    pub(crate) wait: Option<Duration>,
+    pub(crate) hold: Option<Duration>,
+    pub(crate) callback: Option<Callback>,
+}
+
+impl SlowLockWarnings {
+    pub(crate) fn check_wait(&self, key: &str, wait: Duration) {
+        if let Some(threshold) = self.wait.filter(|threshold| wait > *threshold) {
+            self.warn(&SlowLock::Wait {
+                key,
+                wait,
+                threshold,
+            });
+        }
+    }
+
+    pub(crate) fn check_hold(&self, key: &str, hold: Duration) {
+        if let Some(threshold) = self.hold.filter(|threshold| hold > *threshold) {
+            self.warn(&SlowLock::Hold {
+                key,
+                hold,
+                threshold,
+            });
+        }
+    }
+
+    fn warn(&self, slow: &SlowLock<'_>) {
+        #[cfg(feature = "tracing")]
+        match slow {
+            SlowLock::Wait {
+                key,
+                wait,
+                threshold,
+            } => tracing::warn!(
+                key,
+                wait_ms = wait.as_millis() as u64,
+                threshold_ms = threshold.as_millis() as u64,
+                "slow lock acquisition"
+            ),
+            SlowLock::Hold {
+                key,
+                hold,
+                threshold,
+            } => tracing::warn!(
+                key,
+                hold_ms = hold.as_millis() as u64,
+                threshold_ms = threshold.as_millis() as u64,
+                "lock held for too long"
+            ),
+        }
+
+        if let Some(callback) = &self.callback {
+            callback(slow);
+        }
+    }
+}
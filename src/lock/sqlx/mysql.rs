@@ -1,85 +1,85 @@
-use sha1::{Digest, Sha1};
-
-use crate::{Error, Locker};
+use crate::lock::section::{close_session, section};
+use crate::lock::{after_release, trace};
+use crate::{
+    Capabilities, DefaultClock, Error, ForceRelease, Introspect, LockFn, LockInfo, Locker,
+    OverLimit,
+};
 
 /// Advisory lock implementation using MySQL built-in advisor locking functions.
+///
+/// ```ignore
+/// let (r1, r2) = tokio::join!(
+///         MySqlLocker::with_locking(
+///             &pool,
+///             "ivcK1ms0G8xoI5aA40BMkiI2aVlhyM025EGFv1nJxNIC50pJovn2Vn1i7IKlnqYB",
+///             Duration::from_secs(1).into(),
+///             async |_| {
+///                 sleep(Duration::from_secs(2)).await;
+///             },
+///         ),
+///         MySqlLocker::with_locking(
+///             &pool,
+///             "ivcK1ms0G8xoI5aA40BMkiI2aVlhyM025EGFv1nJxNIC50pJovn2Vn1i7IKlnqYB",
+///             Duration::from_secs(1).into(),
+///             async |_| {
+///                 sleep(Duration::from_secs(2)).await;
+///             },
+///         )
+///     );
+///
+///     match (&r1, &r2) {
+///         (Ok(()), Err(_)) | (Err(_), Ok(())) => (),
+///         other => panic!("expected one Ok and one FailedToGetLock, got: {other:?}"),
+///     }
+///
+///     let r = MySqlLocker::with_locking(
+///         &pool,
+///         "ivcK1ms0G8xoI5aA40BMkiI2aVlhyM025EGFv1nJxNIC50pJovn2Vn1i7IKlnqYB",
+///         Duration::from_secs(1).into(),
+///         async |_| {},
+///     )
+///     .await;
+///
+///     assert_matches!(r, Ok(()));
+/// ```
 pub struct MySqlLocker;
 
+fn process_string(s: &str) -> String {
+    // NOTE: TruncateHash は失敗しない
+    OverLimit::TruncateHash
+        .apply(s, MySqlLocker::MAX_KEY_LEN.unwrap_or(usize::MAX))
+        .unwrap()
+        .into_owned()
+}
+
 impl Locker for MySqlLocker {
     type DB = ::sqlx::MySql;
 
-    /// execute the given closure while the key is locked
-    ///
-    /// * `pool` - connection pool
-    /// * `key` - key to get locked
-    /// * `timeout` - timeout duration. if it can't get lock in 1 sec, with_locking will return Err. if None is given and a conflict occurs, it will fail immediately.
-    /// * `f` - closure that executed while the key is locked
-    ///
-    /// ```ignore
-    /// let (r1, r2) = tokio::join!(
-    ///         MySqlLocker::with_locking(
-    ///             &pool,
-    ///             "ivcK1ms0G8xoI5aA40BMkiI2aVlhyM025EGFv1nJxNIC50pJovn2Vn1i7IKlnqYB",
-    ///             Duration::from_secs(1).into(),
-    ///             async |_| {
-    ///                 sleep(Duration::from_secs(2)).await;
-    ///             },
-    ///         ),
-    ///         MySqlLocker::with_locking(
-    ///             &pool,
-    ///             "ivcK1ms0G8xoI5aA40BMkiI2aVlhyM025EGFv1nJxNIC50pJovn2Vn1i7IKlnqYB",
-    ///             Duration::from_secs(1).into(),
-    ///             async |_| {
-    ///                 sleep(Duration::from_secs(2)).await;
-    ///             },
-    ///         )
-    ///     );
-    ///
-    ///     match (&r1, &r2) {
-    ///         (Ok(()), Err(_)) | (Err(_), Ok(())) => (),
-    ///         other => panic!("expected one Ok and one FailedToGetLock, got: {:?}", other),
-    ///     }
-    ///
-    ///     let r = MySqlLocker::with_locking(
-    ///         &pool,
-    ///         "ivcK1ms0G8xoI5aA40BMkiI2aVlhyM025EGFv1nJxNIC50pJovn2Vn1i7IKlnqYB",
-    ///         Duration::from_secs(1).into(),
-    ///         async |_| {},
-    ///     )
-    ///     .await;
-    ///
-    ///     assert_matches!(r, Ok(()));
-    /// ```
-    async fn with_locking<T, F>(
-        pool: &sqlx::Pool<Self::DB>,
+    const NAME: &'static str = "mysql";
+
+    const MAX_KEY_LEN: Option<usize> = Some(64);
+
+    // GET_LOCK は秒単位でしか待てない
+    fn capabilities() -> Capabilities {
+        Capabilities {
+            holder_lookup: true,
+            ..Capabilities::default()
+        }
+    }
+
+    async fn acquire(
+        _pool: &sqlx::Pool<Self::DB>,
+        tx: &mut ::sqlx::Transaction<'static, Self::DB>,
         key: &str,
         timeout: Option<std::time::Duration>,
-        f: F,
-    ) -> crate::Result<()>
-    where
-        F: AsyncFnOnce(&mut ::sqlx::Transaction<'static, Self::DB>) -> T,
-    {
-        fn process_string(s: &str) -> String {
-            if s.len() > 64 {
-                let prefix = &s[..24];
-                let mut hasher = Sha1::new();
-                hasher.update(s.as_bytes());
-                let result = hasher.finalize();
-                let hash_hex = format!("{:x}", result);
-                format!("{}{}", prefix, hash_hex)
-            } else {
-                s.to_string()
-            }
-        }
+    ) -> crate::Result<()> {
         let key = process_string(key);
 
-        let mut tx = pool.begin().await?;
-
         let timeout = timeout.unwrap_or_default().as_secs();
         let signal: Option<i32> = sqlx::query_scalar("SELECT GET_LOCK(?,?)")
             .bind(&key)
             .bind(timeout)
-            .fetch_optional(&mut *tx)
+            .fetch_optional(&mut **tx)
             .await?;
 
         match signal {
@@ -87,22 +87,296 @@ impl Locker for MySqlLocker {
             Some(0) => Err(Error::FailedToGetLock(key.to_string())),
             Some(signal) => Err(Error::MySqlUnknownSignal(signal)),
             None => Err(Error::MySqlReturnedNull),
-        }?;
-
-        f(&mut tx).await;
+        }
+    }
 
+    async fn release(
+        _pool: &sqlx::Pool<Self::DB>,
+        tx: &mut ::sqlx::Transaction<'static, Self::DB>,
+        key: &str,
+    ) -> crate::Result<()> {
         sqlx::query("DO RELEASE_LOCK(?)")
-            .bind(key)
-            .fetch_optional(&mut *tx)
+            .bind(process_string(key))
+            .fetch_optional(&mut **tx)
             .await?;
 
         Ok(())
     }
 }
 
+impl MySqlLocker {
+    /// execute the given closure while all of the keys are locked on one session
+    ///
+    /// MySQL 8 holds several named locks per session, so the keys share one connection instead of
+    /// taking one each. they are acquired in sorted order, each within `timeout`, so callers
+    /// locking overlapping keys can't deadlock, and released together. if one can't be acquired
+    /// the others are released. traces and shutdowns name the call by the quoted list of the keys,
+    /// like `["a", "b"]`.
+    ///
+    /// * `pool` - connection pool
+    /// * `keys` - keys to get locked, duplicates are locked once
+    /// * `timeout` - timeout duration of each key. if None is given and a conflict occurs, it will fail immediately.
+    /// * `f` - closure that executed while the keys are locked
+    pub async fn with_locking_all<T, F>(
+        pool: &sqlx::Pool<::sqlx::MySql>,
+        keys: &[&str],
+        timeout: Option<std::time::Duration>,
+        f: F,
+    ) -> crate::Result<()>
+    where
+        F: LockFn<::sqlx::MySql, T>,
+    {
+        let mut keys = keys.to_vec();
+        keys.sort_unstable();
+        keys.dedup();
+        // "a,b" と "a", "b" を区別できるように、引用符で括って並べる
+        let listed = format!("{keys:?}");
+
+        let fut = async {
+            let (ran, r) = section::<Self, DefaultClock, _, _>(
+                pool,
+                &listed,
+                async |tx| {
+                    for key in &keys {
+                        if let Err(e) = Self::acquire(pool, tx, key, timeout).await {
+                            // 途中まで取れた分を残さない。持っていないキーの RELEASE_LOCK は何もしない
+                            let _ = Self::release_each(pool, tx, &keys).await;
+                            return Err(e);
+                        }
+                    }
+                    Ok(())
+                },
+                async |registration, hold_until, tx| {
+                    registration.holding_until(hold_until, &listed, f(tx)).await
+                },
+                async |tx, ()| Self::release_each(pool, tx, &keys).await,
+            )
+            .await?;
+
+            after_release(r, ran.map(|_| ()))
+        };
+
+        trace::instrument(fut, Self::NAME, &listed).await
+    }
+
+    // 取ったのと逆の順に解放する。どれかが失敗しても残りは解放し、最初のエラーを返す
+    async fn release_each(
+        pool: &sqlx::Pool<::sqlx::MySql>,
+        tx: &mut ::sqlx::Transaction<'static, ::sqlx::MySql>,
+        keys: &[&str],
+    ) -> crate::Result<()> {
+        let mut r = Ok(());
+        for key in keys.iter().rev() {
+            r = r.and(Self::release(pool, tx, key).await);
+        }
+        r
+    }
+
+    /// release every named lock held by the session with `RELEASE_ALL_LOCKS()`, returning how many
+    ///
+    /// for the `after_release` hook of pools whose connections are pinned, so no lock leaks to the
+    /// next checkout.
+    pub async fn release_all(conn: &mut ::sqlx::MySqlConnection) -> crate::Result<u64> {
+        let released: Option<u64> = sqlx::query_scalar("SELECT RELEASE_ALL_LOCKS()")
+            .fetch_one(conn)
+            .await?;
+
+        released.ok_or(Error::MySqlReturnedNull)
+    }
+
+    /// `options` running [`MySqlLocker::release_all`] on every connection returned to the pool
+    ///
+    /// a safety net for locks leaked by any path: the next checkout never inherits them. a
+    /// connection that can't be cleaned is closed instead. replaces the `after_release` hook of
+    /// `options`.
+    ///
+    /// ```ignore
+    /// let pool = MySqlLocker::release_all_on_return(config.pool_options())
+    ///     .connect(&url)
+    ///     .await?;
+    /// ```
+    pub fn release_all_on_return(
+        options: sqlx::pool::PoolOptions<::sqlx::MySql>,
+    ) -> sqlx::pool::PoolOptions<::sqlx::MySql> {
+        options.after_release(|conn, _| {
+            Box::pin(async move {
+                match Self::release_all(conn).await {
+                    Ok(0) => Ok(true),
+                    Ok(_released) => {
+                        #[cfg(feature = "tracing")]
+                        tracing::warn!(
+                            released = _released,
+                            "released locks left on a connection returned to the pool"
+                        );
+                        Ok(true)
+                    }
+                    Err(_) => Ok(false),
+                }
+            })
+        })
+    }
+}
+
+/// Advisory lock implementation for MySQL behind a proxy like ProxySQL or MySQL Router, checking
+/// that the lock statements stay on one session.
+///
+/// the key is locked like [`MySqlLocker`], on the connection of the lock transaction for the whole
+/// hold. right after `GET_LOCK` and before `RELEASE_LOCK`, `IS_USED_LOCK` has to name the
+/// `CONNECTION_ID()` of the statement, otherwise the proxy multiplexed them across backend
+/// connections and the lock fails with [`Error::AffinityLost`].
+///
+/// ```ignore
+/// MySqlPinnedLocker::with_locking(&proxysql, "key", None, async |_| {}).await?;
+/// ```
+pub struct MySqlPinnedLocker;
+
+impl Locker for MySqlPinnedLocker {
+    type DB = ::sqlx::MySql;
+
+    const NAME: &'static str = "mysql-pinned";
+
+    const MAX_KEY_LEN: Option<usize> = MySqlLocker::MAX_KEY_LEN;
+
+    async fn acquire(
+        pool: &sqlx::Pool<Self::DB>,
+        tx: &mut ::sqlx::Transaction<'static, Self::DB>,
+        key: &str,
+        timeout: Option<std::time::Duration>,
+    ) -> crate::Result<()> {
+        MySqlLocker::acquire(pool, tx, key, timeout).await?;
+        // GET_LOCK はどこかのバックエンドのセッションで通っている
+        let checked = check_affinity(tx, key).await;
+        close_unless_checked(pool, tx, key, checked).await
+    }
+
+    async fn release(
+        pool: &sqlx::Pool<Self::DB>,
+        tx: &mut ::sqlx::Transaction<'static, Self::DB>,
+        key: &str,
+    ) -> crate::Result<()> {
+        // 別のセッションで RELEASE_LOCK しても解放されないので、先に確かめる
+        let checked = check_affinity(tx, key).await;
+        close_unless_checked(pool, tx, key, checked).await?;
+        MySqlLocker::release(pool, tx, key).await
+    }
+}
+
+// キーを持つセッションが確かめられなければ、文の流れた先がたまたま持っていれば解放し、それから
+// 接続を閉じて、どこかのバックエンドのセッションに残ったロックも切る。確かめた結果はそのまま返す
+async fn close_unless_checked(
+    pool: &sqlx::Pool<::sqlx::MySql>,
+    tx: &mut ::sqlx::Transaction<'static, ::sqlx::MySql>,
+    key: &str,
+    checked: crate::Result<()>,
+) -> crate::Result<()> {
+    if checked.is_err() {
+        let _ = MySqlLocker::release(pool, tx, key).await;
+        close_session(pool, tx).await;
+    }
+    checked
+}
+
+// 文の流れたセッションが、キーを持っているセッションと同じか
+async fn check_affinity(
+    tx: &mut ::sqlx::Transaction<'static, ::sqlx::MySql>,
+    key: &str,
+) -> crate::Result<()> {
+    let key = process_string(key);
+    let (holder, session): (Option<u64>, u64) =
+        sqlx::query_as("SELECT IS_USED_LOCK(?), CONNECTION_ID()")
+            .bind(&key)
+            .fetch_one(&mut **tx)
+            .await?;
+
+    if holder != Some(session) {
+        return Err(Error::AffinityLost {
+            key,
+            holder,
+            session,
+        });
+    }
+
+    Ok(())
+}
+
+impl Introspect for MySqlLocker {
+    /// keys longer than 64 characters are listed shortened, holders as processlist ids and waiters
+    /// as the `PENDING` rows of the key.
+    /// requires `performance_schema` with the `wait/lock/metadata/sql/mdl` instrument enabled.
+    async fn list_locks(pool: &sqlx::Pool<Self::DB>) -> crate::Result<Vec<LockInfo>> {
+        let rows: Vec<(String, Option<u64>, i64)> = sqlx::query_as(
+            "SELECT m.OBJECT_NAME, \
+                    MAX(CASE WHEN m.LOCK_STATUS = 'GRANTED' THEN t.PROCESSLIST_ID END), \
+                    CAST(SUM(m.LOCK_STATUS = 'PENDING') AS SIGNED) \
+             FROM performance_schema.metadata_locks m \
+             LEFT JOIN performance_schema.threads t ON t.THREAD_ID = m.OWNER_THREAD_ID \
+             WHERE m.OBJECT_TYPE = 'USER LEVEL LOCK' \
+             GROUP BY m.OBJECT_NAME",
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(key, holder, waiters)| LockInfo {
+                key,
+                holder: holder.map(|id| id.to_string()),
+                owner: None,
+                waiters: waiters as usize,
+                held_since: None,
+            })
+            .collect())
+    }
+
+    async fn listed_key(_pool: &sqlx::Pool<Self::DB>, key: &str) -> crate::Result<String> {
+        Ok(process_string(key))
+    }
+
+    /// looked up with `IS_USED_LOCK`, without `performance_schema`. waiters aren't counted.
+    async fn holder(pool: &sqlx::Pool<Self::DB>, key: &str) -> crate::Result<Option<LockInfo>> {
+        let key = process_string(key);
+        let holder: Option<u64> = sqlx::query_scalar("SELECT IS_USED_LOCK(?)")
+            .bind(&key)
+            .fetch_one(pool)
+            .await?;
+
+        Ok(holder.map(|id| LockInfo {
+            key,
+            holder: Some(id.to_string()),
+            owner: None,
+            waiters: 0,
+            held_since: None,
+        }))
+    }
+}
+
+impl ForceRelease for MySqlLocker {
+    /// kills the connection returned by `IS_USED_LOCK`
+    async fn force_release(
+        pool: &sqlx::Pool<Self::DB>,
+        key: &str,
+    ) -> crate::Result<Option<String>> {
+        let holder: Option<u64> = sqlx::query_scalar("SELECT IS_USED_LOCK(?)")
+            .bind(process_string(key))
+            .fetch_one(pool)
+            .await?;
+
+        let Some(holder) = holder else {
+            return Ok(None);
+        };
+
+        // KILL はプレースホルダを受け付けないので、数値をそのまま埋め込む
+        sqlx::raw_sql(&format!("KILL {holder}"))
+            .execute(pool)
+            .await?;
+
+        Ok(Some(holder.to_string()))
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use pretty_assertions::assert_matches;
+    use pretty_assertions::{assert_eq, assert_matches};
     use std::time::Duration;
     use tokio::time::sleep;
 
@@ -133,7 +407,7 @@ mod tests {
 
         match (&r1, &r2) {
             (Ok(()), Err(_)) | (Err(_), Ok(())) => (),
-            other => panic!("expected one Ok and one FailedToGetLock, got: {:?}", other),
+            other => panic!("expected one Ok and one FailedToGetLock, got: {other:?}"),
         }
 
         let r = MySqlLocker::with_locking(
@@ -199,7 +473,7 @@ mod tests {
 
         match (&r1, &r2) {
             (Ok(()), Err(_)) | (Err(_), Ok(())) => (),
-            other => panic!("expected one Ok and one FailedToGetLock, got: {:?}", other),
+            other => panic!("expected one Ok and one FailedToGetLock, got: {other:?}"),
         }
 
         let r = MySqlLocker::with_locking(
@@ -215,6 +489,107 @@ mod tests {
         Ok(())
     }
 
+    #[sqlx::test]
+    async fn pinned_locker_checks_the_session(pool: MySqlPool) -> sqlx::Result<()> {
+        let key = "Pn4Ko6Lp8Za0Sx2Dc4Fv6Gb8Hn0Jm2Kq4We6Rt8Yu0Io2Pa4Sd6Fg8Hj0Kl2Zx4C";
+
+        let (r1, r2) = tokio::join!(
+            MySqlPinnedLocker::with_locking(&pool, key, None, async |_| {
+                sleep(Duration::from_millis(300)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                MySqlLocker::with_locking(&pool, key, None, async |_| {}).await
+            }
+        );
+        assert_matches!(r1, Ok(()));
+        assert_matches!(r2, Err(Error::FailedToGetLock(_)));
+
+        // 別のセッションが持っているキーを解放しにいくと気付く
+        let mut holder = pool.begin().await?;
+        MySqlLocker::acquire(&pool, &mut holder, key, None)
+            .await
+            .unwrap();
+        let mut other = pool.begin().await?;
+        assert_matches!(
+            MySqlPinnedLocker::release(&pool, &mut other, key).await,
+            Err(Error::AffinityLost {
+                holder: Some(_),
+                ..
+            })
+        );
+        MySqlLocker::release(&pool, &mut holder, key).await.unwrap();
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn pinned_release_closes_a_session_it_cannot_trust(pool: MySqlPool) -> sqlx::Result<()> {
+        let key = "Pr5Cl7Os9Es1Th3Es5Es7Si9On1Nt3Ru5St7Ed9Ke1Ya3Bc5De7Fg9Hi1Jk3Lm5N";
+        let other_key = "Pr6Cl8Os0Es2Th4Es6Es8Si0On2Nt4Ru6St8Ed0Ke2Ya4Bc6De8Fg0Hi2Jk4Lm6O";
+        let mut holder = pool.begin().await?;
+        MySqlLocker::acquire(&pool, &mut holder, key, None)
+            .await
+            .unwrap();
+        let mut other = pool.begin().await?;
+        MySqlLocker::acquire(&pool, &mut other, other_key, None)
+            .await
+            .unwrap();
+        let session: u64 = sqlx::query_scalar("SELECT CONNECTION_ID()")
+            .fetch_one(&mut *other)
+            .await?;
+
+        assert_matches!(
+            MySqlPinnedLocker::release(&pool, &mut other, key).await,
+            Err(Error::AffinityLost { .. })
+        );
+
+        // 閉じたセッションのロックは残らず、持っているセッションのロックはそのまま
+        let swapped: u64 = sqlx::query_scalar("SELECT CONNECTION_ID()")
+            .fetch_one(&mut *other)
+            .await?;
+        assert!(swapped != session);
+        assert_matches!(
+            MySqlLocker::with_locking(&pool, other_key, None, async |_| {}).await,
+            Ok(())
+        );
+        assert_matches!(
+            MySqlLocker::with_locking(&pool, key, None, async |_| {}).await,
+            Err(Error::FailedToGetLock(_))
+        );
+        MySqlLocker::release(&pool, &mut holder, key).await.unwrap();
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn pinned_acquire_leaves_no_lock_when_the_check_fails(
+        pool: MySqlPool,
+    ) -> sqlx::Result<()> {
+        let key = "Pa7Cq9Ui1Re3Le5Av7Es9No1Lo3Ck5Be7Hi9Nd1Ch3Ec5Kf7Ai9Ls1Xy3Zq5Wr7P";
+        let mut tx = pool.begin().await?;
+        MySqlLocker::acquire(&pool, &mut tx, key, None)
+            .await
+            .unwrap();
+
+        // GET_LOCK のあとに確かめられなかったときと同じ
+        let lost = Err(Error::AffinityLost {
+            key: key.to_owned(),
+            holder: None,
+            session: 0,
+        });
+        assert_matches!(
+            close_unless_checked(&pool, &mut tx, key, lost).await,
+            Err(Error::AffinityLost { .. })
+        );
+        assert_matches!(
+            MySqlLocker::with_locking(&pool, key, None, async |_| {}).await,
+            Ok(())
+        );
+
+        Ok(())
+    }
+
     #[sqlx::test]
     async fn locck_with_empty_text(pool: MySqlPool) -> sqlx::Result<()> {
         let r = MySqlLocker::with_locking(&pool, "", Duration::from_secs(1).into(), async |_| {
@@ -255,4 +630,221 @@ mod tests {
 
         Ok(())
     }
+
+    #[sqlx::test]
+    async fn lock_on_dedicated_pool(pool: MySqlPool) -> sqlx::Result<()> {
+        let lock_pool = MySqlPool::connect_with((*pool.connect_options()).clone()).await?;
+
+        let (r1, r2) = tokio::join!(
+            MySqlLocker::with_locking_on(
+                &lock_pool,
+                &pool,
+                "5Mytn8ui9N7TttU9NjzXFy7wknx3HpghrZTC7zIaJu7SEVpft9ev6spUdzZGKVi4",
+                Duration::from_secs(1).into(),
+                async |_| {
+                    sleep(Duration::from_secs(2)).await;
+                },
+            ),
+            MySqlLocker::with_locking_on(
+                &lock_pool,
+                &pool,
+                "5Mytn8ui9N7TttU9NjzXFy7wknx3HpghrZTC7zIaJu7SEVpft9ev6spUdzZGKVi4",
+                Duration::from_secs(1).into(),
+                async |_| {
+                    sleep(Duration::from_secs(2)).await;
+                },
+            )
+        );
+
+        match (&r1, &r2) {
+            (Ok(()), Err(_)) | (Err(_), Ok(())) => (),
+            other => panic!("expected one Ok and one FailedToGetLock, got: {other:?}"),
+        }
+
+        let r = MySqlLocker::with_locking(
+            &lock_pool,
+            "5Mytn8ui9N7TttU9NjzXFy7wknx3HpghrZTC7zIaJu7SEVpft9ev6spUdzZGKVi4",
+            Duration::from_secs(1).into(),
+            async |_| {},
+        )
+        .await;
+
+        assert_matches!(r, Ok(()));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn list_locks_shows_the_holder_and_waiters(pool: MySqlPool) -> sqlx::Result<()> {
+        let key = "Hq2Wn5Ep8Rt1Yu4Io7Pa0Sd3Fg6Hj9Kl2Zx5Cv8Bn1Mq4We7Rt0Yu3Io6Pa9Sd2F";
+
+        let (r1, r2, locks) = tokio::join!(
+            MySqlLocker::with_locking(&pool, key, None, async |_| {
+                sleep(Duration::from_secs(1)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(200)).await;
+                MySqlLocker::with_locking(&pool, key, Duration::from_secs(2).into(), async |_| {})
+                    .await
+            },
+            async {
+                sleep(Duration::from_millis(500)).await;
+                MySqlLocker::list_locks(&pool).await
+            }
+        );
+
+        assert_matches!(r1, Ok(()));
+        assert_matches!(r2, Ok(()));
+        let locks = locks.unwrap();
+        let lock = locks.iter().find(|lock| lock.key == key).unwrap();
+        assert_matches!(lock.holder, Some(_));
+        assert_matches!(lock.waiters, 1);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn force_release_kills_the_holder(pool: MySqlPool) -> sqlx::Result<()> {
+        let key = "Ax4Sc6Dv8Fb0Gn2Hm4Jq6Kw8Le0Zr2Xt4Cy6Vu8Bi0No2Mp4Qa6Ws8Ed0Rf2Tg4Y";
+
+        let (r1, (released, r2)) = tokio::join!(
+            MySqlLocker::with_locking(&pool, key, None, async |_| {
+                sleep(Duration::from_secs(1)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(200)).await;
+                let released = MySqlLocker::force_release(&pool, key).await;
+                let r2 = MySqlLocker::with_locking(
+                    &pool,
+                    key,
+                    Duration::from_secs(1).into(),
+                    async |_| {},
+                )
+                .await;
+                (released, r2)
+            }
+        );
+
+        assert_matches!(released, Ok(Some(_)));
+        assert_matches!(r2, Ok(()));
+        assert_matches!(r1, Err(_));
+        assert_matches!(MySqlLocker::force_release(&pool, key).await, Ok(None));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn returned_connections_release_their_locks(pool: MySqlPool) -> sqlx::Result<()> {
+        let key = "Ar8Bs0Ct2Du4Ev6Fw8Gx0Hy2Iz4Ja6Kb8Lc0Md2Ne4Of6Pg8Qh0Ri2Sj4Tk6Ul8V";
+        let cleaned =
+            MySqlLocker::release_all_on_return(sqlx::pool::PoolOptions::new().max_connections(1))
+                .connect_with((*pool.connect_options()).clone())
+                .await?;
+
+        // ロックを持ったままプールに返す
+        let mut conn = cleaned.acquire().await?;
+        sqlx::query("SELECT GET_LOCK(?, 0)")
+            .bind(key)
+            .execute(&mut *conn)
+            .await?;
+        drop(conn);
+
+        // 返すのは別のタスクなので、次に借りられるまで待つ
+        drop(cleaned.acquire().await?);
+        assert_matches!(
+            MySqlLocker::with_locking(&pool, key, None, async |_| {}).await,
+            Ok(())
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn release_all_releases_the_locks_of_the_session(pool: MySqlPool) -> sqlx::Result<()> {
+        let key = "Jn6Bv9Cx2Za5Sd8Fg1Hj4Kl7Qw0Er3Ty6Ui9Op2As5Df8Gh1Jk4Lz7Xc0Vb3Nm6Q";
+        let mut tx = pool.begin().await?;
+        assert_matches!(
+            MySqlLocker::acquire(&pool, &mut tx, key, None).await,
+            Ok(())
+        );
+
+        assert_matches!(MySqlLocker::release_all(&mut tx).await, Ok(1));
+        assert_matches!(
+            MySqlLocker::with_locking(&pool, key, None, async |_| {}).await,
+            Ok(())
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn health_check_passes(pool: MySqlPool) -> sqlx::Result<()> {
+        assert_matches!(MySqlLocker::health_check(&pool).await, Ok(()));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn keys_are_locked_together_on_one_session(pool: MySqlPool) -> sqlx::Result<()> {
+        let keys = [
+            "Pc1Vx4Nz7Lk0Jh3Gf6Ds9Aq2Wr5Ey8Tu1Io4Pl7Km0Nj3Hb6Gv9Fc2Xd5Sz8Aw1E",
+            "Bq8Wm1Ev4Rc7Tx0Yz3Un6Il9Ok2Pj5Ah8Sg1Df4Fk7Gl0Hz3Jx6Kc9Lv2Zb5Xn8M",
+        ];
+
+        let (r1, r2, r3) = tokio::join!(
+            MySqlLocker::with_locking_all(&pool, &keys, None, async |_| {
+                sleep(Duration::from_millis(300)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                MySqlLocker::with_locking(&pool, keys[1], None, async |_| {}).await
+            },
+            async {
+                sleep(Duration::from_millis(100)).await;
+                // 逆の順で渡しても同じ順で取るので、待てば取れる
+                MySqlLocker::with_locking_all(
+                    &pool,
+                    &[keys[1], keys[0], keys[1]],
+                    Duration::from_secs(1).into(),
+                    async |_| {},
+                )
+                .await
+            }
+        );
+
+        assert_matches!(r1, Ok(()));
+        assert_matches!(r2, Err(Error::FailedToGetLock(_)));
+        assert_matches!(r3, Ok(()));
+
+        Ok(())
+    }
+    #[sqlx::test]
+    async fn report_lists_the_keys_over_the_limit(pool: MySqlPool) -> sqlx::Result<()> {
+        let long = format!("invoice:{}", "k".repeat(60));
+        let client = crate::LockClient::<MySqlLocker>::new(pool);
+
+        let report = client.key_report([long.as_str(), "invoice:1"]);
+        assert_eq!(report.too_long, [long.clone()]);
+        assert!(report.rejected.is_empty());
+        assert!(!report.is_clean());
+
+        // 接頭辞だけ残すと、長いキーどうしが同じロックになる
+        let other = format!("{long}-2");
+        let report = client
+            .clone()
+            .over_limit(OverLimit::Truncate {
+                prefix: 64,
+                hash: 0,
+            })
+            .key_report([&long, &other]);
+        assert_eq!(
+            report.collisions.into_values().collect::<Vec<_>>(),
+            [[long.clone(), other]]
+        );
+
+        let report = client.over_limit(OverLimit::Reject).key_report([&long]);
+        assert_eq!(report.rejected, [long]);
+
+        Ok(())
+    }
 }
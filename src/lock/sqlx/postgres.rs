@@ -1,101 +1,539 @@
-use crate::{Error, Locker};
+use sqlx::Executor;
+
+use crate::{Capabilities, Error, ForceRelease, Introspect, KeyRepr, LockInfo, Locker, RowId};
 
 /// Advisory lock implementation using PostgreSQL built-in advisor locking functions.
+///
+/// ```ignore
+/// let (r1, r2) = tokio::join!(
+///         PostgresLocker::with_locking(
+///             &pool,
+///             "ivcK1ms0G8xoI5aA40BMkiI2aVlhyM025EGFv1nJxNIC50pJovn2Vn1i7IKlnqYB",
+///             Duration::from_secs(1).into(),
+///             async |_| {
+///                 sleep(Duration::from_secs(2)).await;
+///             },
+///         ),
+///         PostgresLocker::with_locking(
+///             &pool,
+///             "ivcK1ms0G8xoI5aA40BMkiI2aVlhyM025EGFv1nJxNIC50pJovn2Vn1i7IKlnqYB",
+///             Duration::from_secs(1).into(),
+///             async |_| {
+///                 sleep(Duration::from_secs(2)).await;
+///             },
+///         )
+///     );
+///
+///     match (&r1, &r2) {
+///         (Ok(()), Err(_)) | (Err(_), Ok(())) => (),
+///         other => panic!("expected one Ok and one FailedToGetLock, got: {other:?}"),
+///     }
+///
+///     let r = PostgresLocker::with_locking(
+///         &pool,
+///         "ivcK1ms0G8xoI5aA40BMkiI2aVlhyM025EGFv1nJxNIC50pJovn2Vn1i7IKlnqYB",
+///         Duration::from_secs(1).into(),
+///         async |_| {},
+///     )
+///     .await;
+///
+///     assert_matches!(r, Ok(()));
+/// ```
 pub struct PostgresLocker;
 
+/// SQLSTATE raised when `lock_timeout` expires
+const LOCK_NOT_AVAILABLE: &str = "55P03";
+
+/// savepoint around a wait, so that its timeout doesn't abort the transaction
+const WAIT_SAVEPOINT: &str = "rusty_ad_lock_wait";
+
 impl Locker for PostgresLocker {
     type DB = ::sqlx::Postgres;
 
-    /// execute the given closure while the key is locked
+    const NAME: &'static str = "postgres";
+
+    fn capabilities() -> Capabilities {
+        Capabilities {
+            sub_second_timeouts: true,
+            holder_lookup: true,
+            ..Capabilities::default()
+        }
+    }
+
+    async fn acquire(
+        _pool: &sqlx::Pool<Self::DB>,
+        tx: &mut ::sqlx::Transaction<'static, Self::DB>,
+        key: &str,
+        timeout: Option<std::time::Duration>,
+    ) -> crate::Result<()> {
+        lock(tx, Key::Text(key), key, timeout, Scope::Session).await
+    }
+
+    async fn release(
+        _pool: &sqlx::Pool<Self::DB>,
+        tx: &mut ::sqlx::Transaction<'static, Self::DB>,
+        key: &str,
+    ) -> crate::Result<()> {
+        unlock(tx, Key::Text(key)).await
+    }
+
+    /// ids from 1 to `u32::MAX - 1` are locked on the bigint key `(id << 32) | hashtext(table)`
     ///
-    /// * `pool` - connection pool
-    /// * `key` - key to get locked
-    /// * `timeout` - timeout duration. if it can't get lock in 1 sec, with_locking will return Err. if None is given and a conflict occurs, it will fail immediately.
-    /// * `f` - closure that executed while the key is locked
+    /// the upper 32 bits of the keys of [`Locker::acquire`] are all 0 or all 1, so the row keys
+    /// never collide with them. other ids are locked on [`RowId::key`].
+    async fn acquire_row(
+        _pool: &sqlx::Pool<Self::DB>,
+        tx: &mut ::sqlx::Transaction<'static, Self::DB>,
+        table: &str,
+        id: &RowId,
+        timeout: Option<std::time::Duration>,
+    ) -> crate::Result<()> {
+        let table_key = row_table_key(table);
+        let key = id.key(table);
+        lock(
+            tx,
+            Key::row(&table_key, id, &key),
+            &key,
+            timeout,
+            Scope::Session,
+        )
+        .await
+    }
+
+    async fn release_row(
+        _pool: &sqlx::Pool<Self::DB>,
+        tx: &mut ::sqlx::Transaction<'static, Self::DB>,
+        table: &str,
+        id: &RowId,
+    ) -> crate::Result<()> {
+        let table_key = row_table_key(table);
+        let key = id.key(table);
+        unlock(tx, Key::row(&table_key, id, &key)).await
+    }
+
+    /// numeric keys are locked on `pg_advisory_lock(bigint)` as is
     ///
-    /// ```ignore
-    /// let (r1, r2) = tokio::join!(
-    ///         PostgresLocker::with_locking(
-    ///             &pool,
-    ///             "ivcK1ms0G8xoI5aA40BMkiI2aVlhyM025EGFv1nJxNIC50pJovn2Vn1i7IKlnqYB",
-    ///             Duration::from_secs(1).into(),
-    ///             async |_| {
-    ///                 sleep(Duration::from_secs(2)).await;
-    ///             },
-    ///         ),
-    ///         PostgresLocker::with_locking(
-    ///             &pool,
-    ///             "ivcK1ms0G8xoI5aA40BMkiI2aVlhyM025EGFv1nJxNIC50pJovn2Vn1i7IKlnqYB",
-    ///             Duration::from_secs(1).into(),
-    ///             async |_| {
-    ///                 sleep(Duration::from_secs(2)).await;
-    ///             },
-    ///         )
-    ///     );
+    /// ids within the `int4` range share the key space of the hashed string keys.
+    async fn acquire_key(
+        _pool: &sqlx::Pool<Self::DB>,
+        tx: &mut ::sqlx::Transaction<'static, Self::DB>,
+        key: &KeyRepr<'_>,
+        timeout: Option<std::time::Duration>,
+    ) -> crate::Result<()> {
+        match key {
+            KeyRepr::Text(text) => lock(tx, Key::Text(text), text, timeout, Scope::Session).await,
+            KeyRepr::Int(id) => lock(tx, Key::Int(*id), &key.text(), timeout, Scope::Session).await,
+            KeyRepr::Pair { ids, .. } => {
+                lock(tx, Key::Pair(*ids), &key.text(), timeout, Scope::Session).await
+            }
+        }
+    }
+
+    async fn release_key(
+        _pool: &sqlx::Pool<Self::DB>,
+        tx: &mut ::sqlx::Transaction<'static, Self::DB>,
+        key: &KeyRepr<'_>,
+    ) -> crate::Result<()> {
+        match key {
+            KeyRepr::Text(text) => unlock(tx, Key::Text(text)).await,
+            KeyRepr::Int(id) => unlock(tx, Key::Int(*id)).await,
+            KeyRepr::Pair { ids, .. } => unlock(tx, Key::Pair(*ids)).await,
+        }
+    }
+}
+
+impl PostgresLocker {
+    /// release every advisory lock held by the session with `pg_advisory_unlock_all`
     ///
-    ///     match (&r1, &r2) {
-    ///         (Ok(()), Err(_)) | (Err(_), Ok(())) => (),
-    ///         other => panic!("expected one Ok and one FailedToGetLock, got: {:?}", other),
-    ///     }
+    /// for the `after_release` hook of pools whose connections are pinned, so no lock leaks to the
+    /// next checkout.
+    pub async fn release_all(conn: &mut ::sqlx::PgConnection) -> crate::Result<()> {
+        sqlx::query("SELECT pg_advisory_unlock_all()")
+            .execute(conn)
+            .await?;
+
+        Ok(())
+    }
+
+    /// `options` running [`PostgresLocker::release_all`] on every connection returned to the pool
     ///
-    ///     let r = PostgresLocker::with_locking(
-    ///         &pool,
-    ///         "ivcK1ms0G8xoI5aA40BMkiI2aVlhyM025EGFv1nJxNIC50pJovn2Vn1i7IKlnqYB",
-    ///         Duration::from_secs(1).into(),
-    ///         async |_| {},
-    ///     )
-    ///     .await;
+    /// a safety net for locks leaked by any path: the next checkout never inherits them. a
+    /// connection that can't be cleaned is closed instead. replaces the `after_release` hook of
+    /// `options`.
     ///
-    ///     assert_matches!(r, Ok(()));
+    /// ```ignore
+    /// let pool = PostgresLocker::release_all_on_return(config.pool_options())
+    ///     .connect(&url)
+    ///     .await?;
     /// ```
-    async fn with_locking<T, F>(
-        pool: &sqlx::Pool<Self::DB>,
+    pub fn release_all_on_return(
+        options: sqlx::pool::PoolOptions<::sqlx::Postgres>,
+    ) -> sqlx::pool::PoolOptions<::sqlx::Postgres> {
+        options.after_release(|conn, _| {
+            Box::pin(async move { Ok(Self::release_all(conn).await.is_ok()) })
+        })
+    }
+
+    /// fail with [`Error::TransactionPooling`] if the connections of `pool` are multiplexed by a
+    /// pooler in transaction mode, like PgBouncer's `pool_mode = transaction`
+    ///
+    /// a session lock outlives the transaction it was taken in, so behind such a pooler it stays on
+    /// a server connection other clients get. checks that `pg_backend_pid` doesn't change between
+    /// the statements of one connection, call it at startup and switch to [`PostgresXactLocker`] or
+    /// a direct [`crate::LockClient::lock_pool`] when it fails.
+    pub async fn check_session_pooling(pool: &sqlx::Pool<::sqlx::Postgres>) -> crate::Result<()> {
+        let mut conn = pool.acquire().await?;
+
+        let first: i32 = sqlx::query_scalar("SELECT pg_backend_pid()")
+            .fetch_one(&mut *conn)
+            .await?;
+        // 1 回ではたまたま同じサーバ接続に当たることがあるので、何度か確かめる
+        for _ in 0..3 {
+            let pid: i32 = sqlx::query_scalar("SELECT pg_backend_pid()")
+                .fetch_one(&mut *conn)
+                .await?;
+            if pid != first {
+                return Err(Error::TransactionPooling(first, pid));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Advisory lock implementation using transaction-level locks, safe behind PgBouncer in
+/// transaction pooling mode.
+///
+/// the keys are the keys of [`PostgresLocker`], so both lock each other out. a key is locked with
+/// `pg_advisory_xact_lock` and released when the transaction of the lock is rolled back, as its
+/// connection goes back to the pool after the closure, instead of by `pg_advisory_unlock`.
+///
+/// ```ignore
+/// if PostgresLocker::check_session_pooling(&pool).await.is_err() {
+///     PostgresXactLocker::with_locking(&pool, "key", None, async |_| {}).await?;
+/// }
+/// ```
+pub struct PostgresXactLocker;
+
+impl Locker for PostgresXactLocker {
+    type DB = ::sqlx::Postgres;
+
+    const NAME: &'static str = "postgres-xact";
+
+    fn capabilities() -> Capabilities {
+        Capabilities {
+            sub_second_timeouts: true,
+            ..Capabilities::default()
+        }
+    }
+
+    async fn acquire(
+        _pool: &sqlx::Pool<Self::DB>,
+        tx: &mut ::sqlx::Transaction<'static, Self::DB>,
         key: &str,
         timeout: Option<std::time::Duration>,
-        f: F,
-    ) -> crate::Result<()>
-    where
-        F: AsyncFnOnce(&mut ::sqlx::Transaction<'static, Self::DB>) -> T,
-    {
-        let mut tx = pool.begin().await?;
+    ) -> crate::Result<()> {
+        lock(tx, Key::Text(key), key, timeout, Scope::Transaction).await
+    }
+
+    // トランザクションを閉じたときに解放される
+    async fn release(
+        _pool: &sqlx::Pool<Self::DB>,
+        _tx: &mut ::sqlx::Transaction<'static, Self::DB>,
+        _key: &str,
+    ) -> crate::Result<()> {
+        Ok(())
+    }
+
+    async fn acquire_row(
+        _pool: &sqlx::Pool<Self::DB>,
+        tx: &mut ::sqlx::Transaction<'static, Self::DB>,
+        table: &str,
+        id: &RowId,
+        timeout: Option<std::time::Duration>,
+    ) -> crate::Result<()> {
+        let table_key = row_table_key(table);
+        let key = id.key(table);
+        lock(
+            tx,
+            Key::row(&table_key, id, &key),
+            &key,
+            timeout,
+            Scope::Transaction,
+        )
+        .await
+    }
+
+    async fn release_row(
+        _pool: &sqlx::Pool<Self::DB>,
+        _tx: &mut ::sqlx::Transaction<'static, Self::DB>,
+        _table: &str,
+        _id: &RowId,
+    ) -> crate::Result<()> {
+        Ok(())
+    }
+
+    async fn acquire_key(
+        _pool: &sqlx::Pool<Self::DB>,
+        tx: &mut ::sqlx::Transaction<'static, Self::DB>,
+        key: &KeyRepr<'_>,
+        timeout: Option<std::time::Duration>,
+    ) -> crate::Result<()> {
+        let name = key.text();
+        let key = match key {
+            KeyRepr::Text(text) => Key::Text(text),
+            KeyRepr::Int(id) => Key::Int(*id),
+            KeyRepr::Pair { ids, .. } => Key::Pair(*ids),
+        };
+        lock(tx, key, &name, timeout, Scope::Transaction).await
+    }
+
+    async fn release_key(
+        _pool: &sqlx::Pool<Self::DB>,
+        _tx: &mut ::sqlx::Transaction<'static, Self::DB>,
+        _key: &KeyRepr<'_>,
+    ) -> crate::Result<()> {
+        Ok(())
+    }
+}
+
+// 行キーの下位 32 bit に使うテーブルごとの文字列
+fn row_table_key(table: &str) -> String {
+    format!("row:{}:{table}", table.len())
+}
+
+// アドバイザリロックを取る bigint キー
+enum Key<'a> {
+    // hashtext をそのまま符号拡張したもの
+    Text(&'a str),
+    // 上位 32 bit が行の id、下位がテーブルの hashtext
+    Row(&'a str, i64),
+    // 呼び出し側の数値そのもの
+    Int(i64),
+    // 2 引数の int 版。bigint 版とはキー空間が別になる
+    Pair((i32, i32)),
+}
+
+impl<'a> Key<'a> {
+    fn row(table_key: &'a str, id: &RowId, key: &'a str) -> Self {
+        match id {
+            // 上位 32 bit がすべて 0 か 1 になる id は文字列キーとぶつかり得る
+            RowId::Int(id) if (1..i64::from(u32::MAX)).contains(id) => Self::Row(table_key, *id),
+            _ => Self::Text(key),
+        }
+    }
+
+    fn sql(&self) -> &'static str {
+        match self {
+            Self::Text(_) => "hashtext($1)",
+            Self::Row(..) => "(($2::bigint << 32) | (hashtext($1)::bigint & 4294967295))",
+            Self::Int(_) => "$1::bigint",
+            Self::Pair(_) => "$1::integer, $2::integer",
+        }
+    }
+
+    // プレースホルダに先に束縛する文字列
+    fn text(&self) -> Option<&'a str> {
+        match self {
+            Self::Text(text) | Self::Row(text, _) => Some(text),
+            Self::Int(_) | Self::Pair(_) => None,
+        }
+    }
+
+    fn id(&self) -> Option<i64> {
+        match self {
+            Self::Text(_) | Self::Pair(_) => None,
+            Self::Row(_, id) | Self::Int(id) => Some(*id),
+        }
+    }
+
+    fn ids(&self) -> Option<(i32, i32)> {
+        match self {
+            Self::Pair(ids) => Some(*ids),
+            _ => None,
+        }
+    }
+}
+
+// ロックが効く範囲
+#[derive(Clone, Copy)]
+enum Scope {
+    // pg_advisory_unlock するかセッションが切れるまで
+    Session,
+    // トランザクションが終わるまで
+    Transaction,
+}
+
+impl Scope {
+    fn lock_fn(self) -> &'static str {
+        match self {
+            Self::Session => "pg_advisory_lock",
+            Self::Transaction => "pg_advisory_xact_lock",
+        }
+    }
+
+    fn try_lock_fn(self) -> &'static str {
+        match self {
+            Self::Session => "pg_try_advisory_lock",
+            Self::Transaction => "pg_try_advisory_xact_lock",
+        }
+    }
+}
+
+async fn lock(
+    tx: &mut ::sqlx::Transaction<'static, ::sqlx::Postgres>,
+    key: Key<'_>,
+    name: &str,
+    timeout: Option<std::time::Duration>,
+    scope: Scope,
+) -> crate::Result<()> {
+    match timeout {
+        Some(timeout) => {
+            // lock_timeout が切れるとトランザクションごと失敗するので、セーブポイントまで戻して続ける
+            // 引数の無い文字列は simple query で送られるので、1往復で両方実行できる
+            // lock_timeout = 0 は無制限になってしまうので、1ms 未満は 1ms 待つ
+            let wait = format!(
+                "SAVEPOINT {WAIT_SAVEPOINT}; SET LOCAL lock_timeout = {}",
+                timeout.as_millis().max(1)
+            );
+            (&mut **tx).execute(wait.as_str()).await?;
+
+            let sql = format!("SELECT {}({})", scope.lock_fn(), key.sql());
+            let mut query = sqlx::query(&sql);
+            if let Some(text) = key.text() {
+                query = query.bind(text);
+            }
+            if let Some(id) = key.id() {
+                query = query.bind(id);
+            }
+            if let Some((first, second)) = key.ids() {
+                query = query.bind(first).bind(second);
+            }
+            let r = query.execute(&mut **tx).await;
 
-        match timeout {
-            Some(timeout) => {
-                sqlx::query(&format!("SET LOCAL lock_timeout = {}", timeout.as_millis()))
-                    .execute(&mut *tx)
-                    .await?;
-
-                sqlx::query("SELECT pg_advisory_lock(hashtext($1))")
-                    .bind(key)
-                    .execute(&mut *tx)
-                    .await?;
-            }
-            None => {
-                let b: bool = sqlx::query_scalar("SELECT pg_try_advisory_lock(hashtext($1))")
-                    .bind(key)
-                    .fetch_one(&mut *tx)
-                    .await?;
-
-                if !b {
-                    return Err(Error::FailedToGetLock(key.to_string()));
+            match r {
+                Ok(_) => {
+                    let release = format!("RELEASE SAVEPOINT {WAIT_SAVEPOINT}");
+                    (&mut **tx).execute(release.as_str()).await?;
                 }
+                // lock_timeout が切れた
+                Err(sqlx::Error::Database(e))
+                    if e.code().as_deref() == Some(LOCK_NOT_AVAILABLE) =>
+                {
+                    let rollback = format!("ROLLBACK TO SAVEPOINT {WAIT_SAVEPOINT}");
+                    (&mut **tx).execute(rollback.as_str()).await?;
+                    return Err(Error::FailedToGetLock(name.to_string()));
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        None => {
+            let sql = format!("SELECT {}({})", scope.try_lock_fn(), key.sql());
+            let mut query = sqlx::query_scalar(&sql);
+            if let Some(text) = key.text() {
+                query = query.bind(text);
+            }
+            if let Some(id) = key.id() {
+                query = query.bind(id);
+            }
+            if let Some((first, second)) = key.ids() {
+                query = query.bind(first).bind(second);
+            }
+            let b: bool = query.fetch_one(&mut **tx).await?;
+
+            if !b {
+                return Err(Error::FailedToGetLock(name.to_string()));
             }
         }
+    }
 
-        f(&mut tx).await;
+    Ok(())
+}
 
-        sqlx::query("SELECT pg_advisory_unlock(hashtext($1))")
+async fn unlock(
+    tx: &mut ::sqlx::Transaction<'static, ::sqlx::Postgres>,
+    key: Key<'_>,
+) -> crate::Result<()> {
+    let sql = format!("SELECT pg_advisory_unlock({})", key.sql());
+    let mut query = sqlx::query(&sql);
+    if let Some(text) = key.text() {
+        query = query.bind(text);
+    }
+    if let Some(id) = key.id() {
+        query = query.bind(id);
+    }
+    if let Some((first, second)) = key.ids() {
+        query = query.bind(first).bind(second);
+    }
+    query.fetch_optional(&mut **tx).await?;
+
+    Ok(())
+}
+
+impl Introspect for PostgresLocker {
+    /// keys are listed as the decimal `hashtext` the lock was taken on, holders as backend pids
+    async fn list_locks(pool: &sqlx::Pool<Self::DB>) -> crate::Result<Vec<LockInfo>> {
+        // アドバイザリロックの bigint キーは classid に上位、objid に下位 32 bit が入る
+        let rows: Vec<(String, Option<i32>, i64)> = sqlx::query_as(
+            "SELECT ((classid::bigint << 32) | objid::bigint)::text, \
+                    max(pid) FILTER (WHERE granted), \
+                    count(*) FILTER (WHERE NOT granted) \
+             FROM pg_locks \
+             WHERE locktype = 'advisory' AND objsubid = 1 \
+               AND database = (SELECT oid FROM pg_database WHERE datname = current_database()) \
+             GROUP BY 1",
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(key, holder, waiters)| LockInfo {
+                key,
+                holder: holder.map(|pid| pid.to_string()),
+                owner: None,
+                waiters: waiters as usize,
+                held_since: None,
+            })
+            .collect())
+    }
+
+    async fn listed_key(pool: &sqlx::Pool<Self::DB>, key: &str) -> crate::Result<String> {
+        let hash: i32 = sqlx::query_scalar("SELECT hashtext($1)")
             .bind(key)
-            .fetch_optional(&mut *tx)
+            .fetch_one(pool)
             .await?;
 
-        Ok(())
+        Ok(hash.to_string())
+    }
+}
+
+impl ForceRelease for PostgresLocker {
+    /// terminates the backend holding the key with `pg_terminate_backend`
+    async fn force_release(
+        pool: &sqlx::Pool<Self::DB>,
+        key: &str,
+    ) -> crate::Result<Option<String>> {
+        let holder: Option<(i32, bool)> = sqlx::query_as(
+            "SELECT pid, pg_terminate_backend(pid) \
+             FROM pg_locks \
+             WHERE locktype = 'advisory' AND objsubid = 1 AND granted \
+               AND database = (SELECT oid FROM pg_database WHERE datname = current_database()) \
+               AND ((classid::bigint << 32) | objid::bigint) = hashtext($1)",
+        )
+        .bind(key)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(holder
+            .filter(|(_, terminated)| *terminated)
+            .map(|(pid, _)| pid.to_string()))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use pretty_assertions::assert_matches;
+    use pretty_assertions::{assert_eq, assert_matches};
     use std::time::Duration;
     use tokio::time::sleep;
 
@@ -126,7 +564,7 @@ mod tests {
 
         match (&r1, &r2) {
             (Ok(()), Err(_)) | (Err(_), Ok(())) => (),
-            other => panic!("expected one Ok and one FailedToGetLock, got: {:?}", other),
+            other => panic!("expected one Ok and one FailedToGetLock, got: {other:?}"),
         }
 
         let r = PostgresLocker::with_locking(
@@ -192,7 +630,7 @@ mod tests {
 
         match (&r1, &r2) {
             (Ok(()), Err(_)) | (Err(_), Ok(())) => (),
-            other => panic!("expected one Ok and one FailedToGetLock, got: {:?}", other),
+            other => panic!("expected one Ok and one FailedToGetLock, got: {other:?}"),
         }
 
         let r = PostgresLocker::with_locking(
@@ -248,4 +686,574 @@ mod tests {
 
         Ok(())
     }
+
+    #[sqlx::test]
+    async fn lock_on_dedicated_pool(pool: PgPool) -> sqlx::Result<()> {
+        let lock_pool = PgPool::connect_with((*pool.connect_options()).clone()).await?;
+
+        let (r1, r2) = tokio::join!(
+            PostgresLocker::with_locking_on(
+                &lock_pool,
+                &pool,
+                "5Mytn8ui9N7TttU9NjzXFy7wknx3HpghrZTC7zIaJu7SEVpft9ev6spUdzZGKVi4",
+                Duration::from_secs(1).into(),
+                async |_| {
+                    sleep(Duration::from_secs(2)).await;
+                },
+            ),
+            PostgresLocker::with_locking_on(
+                &lock_pool,
+                &pool,
+                "5Mytn8ui9N7TttU9NjzXFy7wknx3HpghrZTC7zIaJu7SEVpft9ev6spUdzZGKVi4",
+                Duration::from_secs(1).into(),
+                async |_| {
+                    sleep(Duration::from_secs(2)).await;
+                },
+            )
+        );
+
+        match (&r1, &r2) {
+            (Ok(()), Err(_)) | (Err(_), Ok(())) => (),
+            other => panic!("expected one Ok and one FailedToGetLock, got: {other:?}"),
+        }
+
+        let r = PostgresLocker::with_locking(
+            &lock_pool,
+            "5Mytn8ui9N7TttU9NjzXFy7wknx3HpghrZTC7zIaJu7SEVpft9ev6spUdzZGKVi4",
+            Duration::from_secs(1).into(),
+            async |_| {},
+        )
+        .await;
+
+        assert_matches!(r, Ok(()));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn shutdown_closes_the_waiting_session(pool: PgPool) -> sqlx::Result<()> {
+        let key = "Wd3Se5Rf7Tg9Yh1Uj3Ik5Ol7Pa9Zs1Xd3Cf5Vg7Bh9Nj1Mk3Ql5Wz7Ex9Rc1Tv3B";
+        // テスト用のプールはすぐに空いた接続を閉じるので、閉じない普通のプールで待つ
+        let lock_pool = PgPool::connect_with((*pool.connect_options()).clone()).await?;
+        let registry = crate::ShutdownRegistry::new();
+        let client =
+            crate::LockClient::<PostgresLocker>::new(lock_pool).shutdown_registry(registry.clone());
+        let mut holder = pool.begin().await?;
+        PostgresLocker::acquire(&pool, &mut holder, key, None)
+            .await
+            .unwrap();
+
+        let (waiting, ()) = tokio::join!(
+            client.with_locking(key, Duration::from_secs(30).into(), async |_| {}),
+            async {
+                // pg_advisory_lock が待ち始めてから打ち切る
+                while sqlx::query_scalar::<_, i64>(
+                    "SELECT count(*) FROM pg_locks WHERE locktype = 'advisory' AND NOT granted",
+                )
+                .fetch_one(&pool)
+                .await
+                .unwrap()
+                    == 0
+                {
+                    sleep(Duration::from_millis(10)).await;
+                }
+                registry.shutdown(crate::ShutdownPolicy::Abort).await;
+            }
+        );
+        assert_matches!(waiting, Err(Error::ShuttingDown));
+
+        // プールに戻っていれば、待っていたセッションが取ったまま持ち続ける
+        PostgresLocker::release(&pool, &mut holder, key)
+            .await
+            .unwrap();
+        let r =
+            PostgresLocker::with_locking(&pool, key, Duration::from_secs(5).into(), async |_| {})
+                .await;
+        assert_matches!(r, Ok(()));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn breaking_a_wait_closes_the_waiting_session(pool: PgPool) -> sqlx::Result<()> {
+        let key = "Hb6Nj8Mk0Ql2Wz4Ex6Rc8Tv0Yb2Un4Im6Op8As0Df2Gh4Jk6Lz8Xc0Vb2Nm4Qw6E";
+        // テスト用のプールはすぐに空いた接続を閉じるので、閉じない普通のプールで待つ
+        let lock_pool = PgPool::connect_with((*pool.connect_options()).clone()).await?;
+        let client = crate::LockClient::<PostgresLocker>::new(lock_pool);
+        let mut holder = pool.begin().await?;
+        PostgresLocker::acquire(&pool, &mut holder, key, None)
+            .await
+            .unwrap();
+
+        let hooks = crate::LockHooks::new().on_waiting(Duration::from_millis(50), |_| {
+            std::ops::ControlFlow::Break(())
+        });
+        let r = client
+            .with_locking_hooked(key, Duration::from_secs(30).into(), hooks, async |_| {})
+            .await;
+        assert_matches!(r, Err(Error::FailedToGetLock(_)));
+
+        // プールに戻っていれば、待っていたセッションが取ったまま持ち続ける
+        PostgresLocker::release(&pool, &mut holder, key)
+            .await
+            .unwrap();
+        let r =
+            PostgresLocker::with_locking(&pool, key, Duration::from_secs(5).into(), async |_| {})
+                .await;
+        assert_matches!(r, Ok(()));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn retries_after_a_timed_out_wait(pool: PgPool) -> sqlx::Result<()> {
+        let key = "Rt4Yu6Io8Pa0Sd2Fg4Hj6Kl8Zx0Cv2Bn4Mq6We8Rt0Yu2Io4Pa6Sd8Fg0Hj2Kl4Z";
+        let client =
+            crate::LockClient::<PostgresLocker>::new(pool.clone()).retry(crate::RetryPolicy {
+                attempts: 2,
+                backoff: Duration::from_millis(10),
+                attempt_timeout: Some(Duration::from_millis(50)),
+                ..crate::RetryPolicy::default()
+            });
+        let mut holder = pool.begin().await?;
+        PostgresLocker::acquire(&pool, &mut holder, key, None)
+            .await
+            .unwrap();
+
+        // 最初の待ちが切れたあとのトランザクションで次の試行ができる
+        let r = client.with_locking(key, None, async |_| {}).await;
+        assert_matches!(r, Err(Error::RetriesExhausted { attempts: 3, .. }));
+
+        PostgresLocker::release(&pool, &mut holder, key)
+            .await
+            .unwrap();
+        let mut tx = pool.begin().await?;
+        let r =
+            PostgresLocker::acquire(&pool, &mut tx, key, Duration::from_millis(50).into()).await;
+        assert_matches!(r, Ok(()));
+        let n: i32 = sqlx::query_scalar("SELECT 1").fetch_one(&mut *tx).await?;
+        assert_eq!(n, 1);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn timeout_is_reported_as_failed_to_get_lock(pool: PgPool) -> sqlx::Result<()> {
+        let (r1, r2) = tokio::join!(
+            PostgresLocker::with_locking(
+                &pool,
+                "Np3Oq5Rs7Tu9Vw1Xy3Za5Bc7De9Fg1Hi3Jk5Lm7No9Pq1Rs3Tu5Vw7Xy9Za1Bc3",
+                Duration::from_secs(1).into(),
+                async |_| {
+                    sleep(Duration::from_secs(2)).await;
+                },
+            ),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                PostgresLocker::with_locking(
+                    &pool,
+                    "Np3Oq5Rs7Tu9Vw1Xy3Za5Bc7De9Fg1Hi3Jk5Lm7No9Pq1Rs3Tu5Vw7Xy9Za1Bc3",
+                    Duration::from_secs(1).into(),
+                    async |_| {},
+                )
+                .await
+            }
+        );
+
+        assert_matches!(r1, Ok(()));
+        assert_matches!(r2, Err(Error::FailedToGetLock(_)));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn list_locks_shows_the_holder_and_waiters(pool: PgPool) -> sqlx::Result<()> {
+        let key = "Hq2Wn5Ep8Rt1Yu4Io7Pa0Sd3Fg6Hj9Kl2Zx5Cv8Bn1Mq4We7Rt0Yu3Io6Pa9Sd2F";
+        let hashed: String = sqlx::query_scalar("SELECT hashtext($1)::bigint::text")
+            .bind(key)
+            .fetch_one(&pool)
+            .await?;
+
+        let (r1, r2, locks) = tokio::join!(
+            PostgresLocker::with_locking(&pool, key, None, async |_| {
+                sleep(Duration::from_secs(1)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(200)).await;
+                PostgresLocker::with_locking(
+                    &pool,
+                    key,
+                    Duration::from_secs(2).into(),
+                    async |_| {},
+                )
+                .await
+            },
+            async {
+                sleep(Duration::from_millis(500)).await;
+                PostgresLocker::list_locks(&pool).await
+            }
+        );
+
+        assert_matches!(r1, Ok(()));
+        assert_matches!(r2, Ok(()));
+        let locks = locks.unwrap();
+        let lock = locks.iter().find(|lock| lock.key == hashed).unwrap();
+        assert_matches!(lock.holder, Some(_));
+        assert_matches!(lock.waiters, 1);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn force_release_terminates_the_holder(pool: PgPool) -> sqlx::Result<()> {
+        let key = "Ax4Sc6Dv8Fb0Gn2Hm4Jq6Kw8Le0Zr2Xt4Cy6Vu8Bi0No2Mp4Qa6Ws8Ed0Rf2Tg4Y";
+        let hashed: String = sqlx::query_scalar("SELECT hashtext($1)::bigint::text")
+            .bind(key)
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(
+            PostgresLocker::listed_key(&pool, key).await.unwrap(),
+            hashed
+        );
+
+        let (r1, (released, r2)) = tokio::join!(
+            PostgresLocker::with_locking(&pool, key, None, async |_| {
+                sleep(Duration::from_secs(1)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(200)).await;
+                let released = PostgresLocker::force_release(&pool, key).await;
+                let r2 = PostgresLocker::with_locking(
+                    &pool,
+                    key,
+                    Duration::from_millis(500).into(),
+                    async |_| {},
+                )
+                .await;
+                (released, r2)
+            }
+        );
+
+        assert_matches!(released, Ok(Some(_)));
+        assert_matches!(r2, Ok(()));
+        assert_matches!(r1, Err(_));
+        assert_matches!(PostgresLocker::force_release(&pool, key).await, Ok(None));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn row_locks_use_bigint_keys(pool: PgPool) -> sqlx::Result<()> {
+        let table = "Gw4Rk7Tn0Ym3Uq6Iw9Oe2Pr5At8Sy1Du4Fi7Go0Hp3Ja6Ks9Ld2Zf5Xg8Ch1Vj4B";
+        let hashed: String = sqlx::query_scalar(
+            "SELECT ((42::bigint << 32) | (hashtext($1)::bigint & 4294967295))::text",
+        )
+        .bind(format!("row:{}:{table}", table.len()))
+        .fetch_one(&pool)
+        .await?;
+
+        let (r1, r2, r3, locks) = tokio::join!(
+            PostgresLocker::with_row_lock(&pool, table, 42, None, async |_| {
+                sleep(Duration::from_millis(500)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                PostgresLocker::with_row_lock(&pool, table, 42, None, async |_| {}).await
+            },
+            async {
+                sleep(Duration::from_millis(100)).await;
+                PostgresLocker::with_row_lock(&pool, table, 43, None, async |_| {}).await
+            },
+            async {
+                sleep(Duration::from_millis(200)).await;
+                PostgresLocker::list_locks(&pool).await
+            }
+        );
+
+        assert_matches!(r1, Ok(()));
+        assert_matches!(r2, Err(Error::FailedToGetLock(_)));
+        assert_matches!(r3, Ok(()));
+        let locks = locks.unwrap();
+        assert_matches!(locks.iter().find(|lock| lock.key == hashed), Some(_));
+
+        // bigint に収まらない id は文字列キーで取る
+        let r = PostgresLocker::with_row_lock(&pool, table, 0, None, async |_| {}).await;
+        assert_matches!(r, Ok(()));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn returned_connections_release_their_locks(pool: PgPool) -> sqlx::Result<()> {
+        let key = "Ar8Bs0Ct2Du4Ev6Fw8Gx0Hy2Iz4Ja6Kb8Lc0Md2Ne4Of6Pg8Qh0Ri2Sj4Tk6Ul8V";
+        let cleaned = PostgresLocker::release_all_on_return(
+            sqlx::pool::PoolOptions::new().max_connections(1),
+        )
+        .connect_with((*pool.connect_options()).clone())
+        .await?;
+
+        // ロックを持ったままプールに返す
+        let mut conn = cleaned.acquire().await?;
+        sqlx::query("SELECT pg_advisory_lock(hashtext($1))")
+            .bind(key)
+            .execute(&mut *conn)
+            .await?;
+        drop(conn);
+
+        // 返すのは別のタスクなので、次に借りられるまで待つ
+        drop(cleaned.acquire().await?);
+        assert_matches!(
+            PostgresLocker::with_locking(&pool, key, None, async |_| {}).await,
+            Ok(())
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn release_all_releases_the_locks_of_the_session(pool: PgPool) -> sqlx::Result<()> {
+        let key = "Jn6Bv9Cx2Za5Sd8Fg1Hj4Kl7Qw0Er3Ty6Ui9Op2As5Df8Gh1Jk4Lz7Xc0Vb3Nm6Q";
+        let mut tx = pool.begin().await?;
+        assert_matches!(
+            PostgresLocker::acquire(&pool, &mut tx, key, None).await,
+            Ok(())
+        );
+
+        assert_matches!(PostgresLocker::release_all(&mut tx).await, Ok(()));
+        assert_matches!(
+            PostgresLocker::with_locking(&pool, key, None, async |_| {}).await,
+            Ok(())
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn numeric_keys_are_locked_as_is(pool: PgPool) -> sqlx::Result<()> {
+        let id = 8_317_950_442_006_113_i64;
+
+        let (r1, r2, locks) = tokio::join!(
+            PostgresLocker::with_key_lock(&pool, id, None, async |_| {
+                sleep(Duration::from_millis(500)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                PostgresLocker::with_key_lock(&pool, id, None, async |_| {}).await
+            },
+            async {
+                sleep(Duration::from_millis(200)).await;
+                PostgresLocker::list_locks(&pool).await
+            }
+        );
+
+        assert_matches!(r1, Ok(()));
+        assert_matches!(r2, Err(Error::FailedToGetLock(_)));
+        let locks = locks.unwrap();
+        assert_matches!(
+            locks.iter().find(|lock| lock.key == id.to_string()),
+            Some(_)
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn rails_keys_share_the_locks_of_with_advisory_lock(pool: PgPool) -> sqlx::Result<()> {
+        let key = "Rk7Lm2Nq9Pw4Xs1Zt6Vc3Bd8Fh5Gj0Ky7Ue2Io9Pa4Sd1Fg6Hj3Kl8Zx5Cv0Bn7M";
+        let rails = crate::KeyCodec::Rails { prefix: Some(7) };
+        let KeyRepr::Pair { ids: (crc, _), .. } = rails.encode(key) else {
+            unreachable!()
+        };
+
+        let (r1, held) = tokio::join!(
+            PostgresLocker::with_key_lock(&pool, rails.encode(key), None, async |_| {
+                sleep(Duration::from_millis(500)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                // Rails の with_advisory_lock が発行するのと同じ問い合わせ
+                sqlx::query_scalar::<_, bool>("SELECT pg_try_advisory_lock($1::integer, 7)")
+                    .bind(crc)
+                    .fetch_one(&pool)
+                    .await
+            }
+        );
+
+        assert_matches!(r1, Ok(()));
+        assert_matches!(held, Ok(false));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn direct_connections_pass_the_pooling_check(pool: PgPool) -> sqlx::Result<()> {
+        assert_matches!(PostgresLocker::check_session_pooling(&pool).await, Ok(()));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn xact_locks_end_with_the_transaction(pool: PgPool) -> sqlx::Result<()> {
+        let key = "Xt5Ac7Sd9Fg1Hj3Kl5Zx7Cv9Bn1Mq3We5Rt7Yu9Io1Pa3Sd5Fg7Hj9Kl1Zx3Cv5B";
+
+        let (r1, r2) = tokio::join!(
+            PostgresXactLocker::with_locking(&pool, key, None, async |_| {
+                sleep(Duration::from_millis(300)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                // セッションロックとキーを共有している
+                PostgresLocker::with_locking(&pool, key, None, async |_| {}).await
+            }
+        );
+        assert_matches!(r1, Ok(()));
+        assert_matches!(r2, Err(Error::FailedToGetLock(_)));
+
+        // トランザクションのロールバックは接続がプールに戻るときに流れるので、少し待つ
+        let timeout = Duration::from_secs(1).into();
+        assert_matches!(
+            PostgresXactLocker::with_locking(&pool, key, timeout, async |_| {}).await,
+            Ok(())
+        );
+        assert_matches!(
+            PostgresLocker::with_locking(&pool, key, timeout, async |_| {}).await,
+            Ok(())
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn health_check_passes(pool: PgPool) -> sqlx::Result<()> {
+        assert_matches!(PostgresLocker::health_check(&pool).await, Ok(()));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn single_instance_reacquires_after_the_session_is_lost(
+        pool: PgPool,
+    ) -> sqlx::Result<()> {
+        use crate::{InstanceState, SingleInstance};
+
+        let app = "Wd1Ef3Rg5Th7Yj9Uk1Il3Om5Pn7Qb9Av1Sc3Dx5Fz7Ge9Hr1Jt3Ky5Lu7Zi9Xo1C";
+        let instance = SingleInstance::acquire_pinging::<PostgresLocker>(
+            &pool,
+            app,
+            Duration::from_millis(100),
+        )
+        .await
+        .unwrap();
+
+        let key = format!("single-instance:{app}");
+        assert_matches!(
+            PostgresLocker::force_release(&pool, &key).await,
+            Ok(Some(_))
+        );
+        sleep(Duration::from_millis(500)).await;
+
+        assert_eq!(instance.state(), InstanceState::Held);
+        assert_matches!(
+            SingleInstance::acquire::<PostgresLocker>(&pool, app)
+                .await
+                .err(),
+            Some(Error::FailedToGetLock(_))
+        );
+        assert_matches!(instance.release().await, Ok(()));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn closure_is_aborted_when_the_lock_session_is_lost(pool: PgPool) -> sqlx::Result<()> {
+        use crate::LockClient;
+
+        let key = "Fs8Kn3Wb6Qy1Hd4Lv9Xt2Mc7Rj0Ga5Pz8Ue3Io6Bw1Ck4Sx9Nh2Dm7Vf0Tl5Ry8J";
+        let client = LockClient::<PostgresLocker>::new(pool.clone())
+            .lock_pool(pool.clone())
+            .check_session(Duration::from_millis(50));
+
+        let (r, _) = tokio::join!(
+            client.with_locking(key, None, async |_| {
+                sleep(Duration::from_secs(5)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(200)).await;
+                PostgresLocker::force_release(&pool, key).await
+            }
+        );
+
+        assert_matches!(r, Err(Error::LockLost(_)));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn keepalive_pings_the_idle_lock_session(pool: PgPool) -> sqlx::Result<()> {
+        use crate::LockClient;
+
+        let key = "Ka9Lb1Mc3Nd5Oe7Pf9Qg1Rh3Si5Tj7Uk9Vl1Wm3Xn5Yo7Zp9Aq1Br3Cs5Dt7Eu9F";
+        let client =
+            LockClient::<PostgresLocker>::new(pool.clone()).keepalive(Duration::from_millis(50));
+
+        // ロックのセッションで何も走らない呼び出しでも、切れれば気づく
+        let (r, _) = tokio::join!(
+            client.run_locked(key, None, sleep(Duration::from_secs(5))),
+            async {
+                sleep(Duration::from_millis(200)).await;
+                PostgresLocker::force_release(&pool, key).await
+            }
+        );
+        assert_matches!(r, Err(Error::LockLost(_)));
+
+        assert_matches!(
+            client
+                .run_locked(key, None, sleep(Duration::from_millis(200)))
+                .await,
+            Ok(())
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn statements_set_the_lock_session_up(pool: PgPool) -> sqlx::Result<()> {
+        use crate::LockClient;
+
+        let key = "Sq3Tr5Us7Vt9Wu1Xv3Yw5Zx7Ay9Bz1Ca3Db5Ec7Fd9Ge1Hf3Ig5Jh7Ki9Lj1Mk3N";
+        let client = LockClient::<PostgresLocker>::new(pool.clone())
+            .after_lock_sql("SET LOCAL statement_timeout = '5s'");
+
+        let mut timeout = String::new();
+        client
+            .with_locking(key, None, async |tx| {
+                timeout = sqlx::query_scalar("SHOW statement_timeout")
+                    .fetch_one(&mut **tx)
+                    .await
+                    .unwrap();
+            })
+            .await
+            .unwrap();
+        assert_eq!(timeout, "5s");
+
+        // 失敗してもセッションのロックは外されている
+        let failing =
+            LockClient::<PostgresLocker>::new(pool.clone()).after_lock_sql("SELECT * FROM missing");
+        assert_matches!(
+            failing.with_locking(key, None, async |_| {}).await,
+            Err(Error::Sqlx(_))
+        );
+        assert_eq!(PostgresLocker::list_locks(&pool).await.unwrap(), []);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "conformance")]
+    #[sqlx::test]
+    async fn session_and_transaction_locks_conform(pool: PgPool) -> sqlx::Result<()> {
+        crate::conformance::run::<PostgresLocker>(&pool).await;
+        crate::conformance::run::<PostgresXactLocker>(&pool).await;
+
+        Ok(())
+    }
 }
@@ -0,0 +1,309 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::task::JoinHandle;
+
+use crate::{Clock, DefaultClock, LockMetrics, Result};
+
+/// Database the [`ContentionStats`] of a process are added up in.
+///
+/// the `lock_stats` table has to exist beforehand, add [`StatsDatabase::STATS_TABLE`] to the
+/// migrations of the application.
+pub trait StatsDatabase: sqlx::Database {
+    /// DDL of the `lock_stats` table
+    const STATS_TABLE: &'static str;
+
+    /// add the stats of a key to its row of the `lock_stats` table
+    fn add_stats(
+        pool: &sqlx::Pool<Self>,
+        stats: &KeyStats,
+    ) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// Contention of a key since the last flush, added to its row of the `lock_stats` table.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeyStats {
+    pub key: String,
+    /// acquisitions that succeeded
+    pub acquired: u64,
+    /// acquisitions that found the key held
+    pub contended: u64,
+    /// acquisitions that gave up
+    pub timed_out: u64,
+    /// time spent waiting by all acquisitions
+    pub total_wait: Duration,
+    /// longest time the key was held
+    pub max_hold: Duration,
+}
+
+impl KeyStats {
+    fn merge(&mut self, other: &KeyStats) {
+        self.acquired += other.acquired;
+        self.contended += other.contended;
+        self.timed_out += other.timed_out;
+        self.total_wait += other.total_wait;
+        self.max_hold = self.max_hold.max(other.max_hold);
+    }
+}
+
+/// [`LockMetrics`] adding up the contention of each key, flushed to the `lock_stats` table.
+///
+/// every process adds its own numbers to the same rows, so the hotspots of the whole fleet can be
+/// queried in one place.
+///
+/// ```ignore
+/// let stats = ContentionStats::new();
+/// stats.flush_every(pool.clone(), Duration::from_secs(60));
+/// let locker = LockClient::<MySqlLocker>::new(pool).metrics(stats.clone());
+/// ```
+#[derive(Clone, Default)]
+pub struct ContentionStats {
+    keys: Arc<Mutex<HashMap<String, KeyStats>>>,
+}
+
+impl ContentionStats {
+    /// create stats with nothing recorded
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// stats recorded since the last flush, in no particular order
+    pub fn snapshot(&self) -> Vec<KeyStats> {
+        self.keys.lock().unwrap().values().cloned().collect()
+    }
+
+    /// add the recorded stats to the `lock_stats` table and start over
+    ///
+    /// the stats that couldn't be written are kept for the next flush.
+    pub async fn flush<DB: StatsDatabase>(&self, pool: &sqlx::Pool<DB>) -> Result<()> {
+        let taken = std::mem::take(&mut *self.keys.lock().unwrap());
+
+        let mut r = Ok(());
+        for stats in taken.into_values() {
+            if r.is_ok() {
+                r = DB::add_stats(pool, &stats).await;
+                if r.is_ok() {
+                    continue;
+                }
+            }
+            // 書けなかった分は次に回す
+            self.record(&stats.key, |recorded| recorded.merge(&stats));
+        }
+        r
+    }
+
+    /// flush the stats every `interval` on a task, until the handle is aborted
+    pub fn flush_every<DB: StatsDatabase>(
+        &self,
+        pool: sqlx::Pool<DB>,
+        interval: Duration,
+    ) -> JoinHandle<()> {
+        let stats = self.clone();
+
+        tokio::spawn(async move {
+            loop {
+                DefaultClock::sleep(interval).await;
+                if let Err(_e) = stats.flush(&pool).await {
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!(error = %_e, "failed to flush the lock stats");
+                }
+            }
+        })
+    }
+
+    fn record(&self, key: &str, f: impl FnOnce(&mut KeyStats)) {
+        let mut keys = self.keys.lock().unwrap();
+        let stats = keys.entry(key.to_owned()).or_insert_with(|| KeyStats {
+            key: key.to_owned(),
+            ..KeyStats::default()
+        });
+        f(stats);
+    }
+}
+
+impl LockMetrics for ContentionStats {
+    fn on_acquired(&self, key: &str, wait: Duration) {
+        self.record(key, |stats| {
+            stats.acquired += 1;
+            stats.total_wait += wait;
+        });
+    }
+
+    fn on_contended(&self, key: &str) {
+        self.record(key, |stats| stats.contended += 1);
+    }
+
+    fn on_timeout(&self, key: &str, wait: Duration) {
+        self.record(key, |stats| {
+            stats.timed_out += 1;
+            stats.total_wait += wait;
+        });
+    }
+
+    fn on_released(&self, key: &str, hold: Duration) {
+        self.record(key, |stats| stats.max_hold = stats.max_hold.max(hold));
+    }
+}
+
+#[cfg(feature = "sqlx-mysql")]
+impl StatsDatabase for sqlx::MySql {
+    const STATS_TABLE: &'static str = "CREATE TABLE IF NOT EXISTS lock_stats (
+    lock_key VARCHAR(255) PRIMARY KEY,
+    acquired BIGINT NOT NULL,
+    contended BIGINT NOT NULL,
+    timed_out BIGINT NOT NULL,
+    total_wait_ms BIGINT NOT NULL,
+    max_hold_ms BIGINT NOT NULL,
+    updated_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3) ON UPDATE CURRENT_TIMESTAMP(3)
+)";
+
+    async fn add_stats(pool: &sqlx::Pool<Self>, stats: &KeyStats) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO lock_stats \
+               (lock_key, acquired, contended, timed_out, total_wait_ms, max_hold_ms) \
+             VALUES (?, ?, ?, ?, ?, ?) \
+             ON DUPLICATE KEY UPDATE \
+               acquired = acquired + VALUES(acquired), \
+               contended = contended + VALUES(contended), \
+               timed_out = timed_out + VALUES(timed_out), \
+               total_wait_ms = total_wait_ms + VALUES(total_wait_ms), \
+               max_hold_ms = GREATEST(max_hold_ms, VALUES(max_hold_ms))",
+        )
+        .bind(&stats.key)
+        .bind(stats.acquired as i64)
+        .bind(stats.contended as i64)
+        .bind(stats.timed_out as i64)
+        .bind(stats.total_wait.as_millis() as i64)
+        .bind(stats.max_hold.as_millis() as i64)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sqlx-postgres")]
+impl StatsDatabase for sqlx::Postgres {
+    const STATS_TABLE: &'static str = "CREATE TABLE IF NOT EXISTS lock_stats (
+    lock_key TEXT PRIMARY KEY,
+    acquired BIGINT NOT NULL,
+    contended BIGINT NOT NULL,
+    timed_out BIGINT NOT NULL,
+    total_wait_ms BIGINT NOT NULL,
+    max_hold_ms BIGINT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+)";
+
+    async fn add_stats(pool: &sqlx::Pool<Self>, stats: &KeyStats) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO lock_stats \
+               (lock_key, acquired, contended, timed_out, total_wait_ms, max_hold_ms) \
+             VALUES ($1, $2, $3, $4, $5, $6) \
+             ON CONFLICT (lock_key) DO UPDATE SET \
+               acquired = lock_stats.acquired + EXCLUDED.acquired, \
+               contended = lock_stats.contended + EXCLUDED.contended, \
+               timed_out = lock_stats.timed_out + EXCLUDED.timed_out, \
+               total_wait_ms = lock_stats.total_wait_ms + EXCLUDED.total_wait_ms, \
+               max_hold_ms = GREATEST(lock_stats.max_hold_ms, EXCLUDED.max_hold_ms), \
+               updated_at = now()",
+        )
+        .bind(&stats.key)
+        .bind(stats.acquired as i64)
+        .bind(stats.contended as i64)
+        .bind(stats.timed_out as i64)
+        .bind(stats.total_wait.as_millis() as i64)
+        .bind(stats.max_hold.as_millis() as i64)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sqlx-sqlite")]
+impl StatsDatabase for sqlx::Sqlite {
+    const STATS_TABLE: &'static str = "CREATE TABLE IF NOT EXISTS lock_stats (
+    lock_key TEXT PRIMARY KEY,
+    acquired INTEGER NOT NULL,
+    contended INTEGER NOT NULL,
+    timed_out INTEGER NOT NULL,
+    total_wait_ms INTEGER NOT NULL,
+    max_hold_ms INTEGER NOT NULL,
+    updated_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now'))
+)";
+
+    async fn add_stats(pool: &sqlx::Pool<Self>, stats: &KeyStats) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO lock_stats \
+               (lock_key, acquired, contended, timed_out, total_wait_ms, max_hold_ms) \
+             VALUES (?, ?, ?, ?, ?, ?) \
+             ON CONFLICT (lock_key) DO UPDATE SET \
+               acquired = acquired + excluded.acquired, \
+               contended = contended + excluded.contended, \
+               timed_out = timed_out + excluded.timed_out, \
+               total_wait_ms = total_wait_ms + excluded.total_wait_ms, \
+               max_hold_ms = max(max_hold_ms, excluded.max_hold_ms), \
+               updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now')",
+        )
+        .bind(&stats.key)
+        .bind(stats.acquired as i64)
+        .bind(stats.contended as i64)
+        .bind(stats.timed_out as i64)
+        .bind(stats.total_wait.as_millis() as i64)
+        .bind(stats.max_hold.as_millis() as i64)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "sqlx-std-collection", feature = "sqlx-sqlite"))]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use tokio::time::sleep;
+
+    use super::*;
+
+    use crate::{LockClient, StdCollectionLocker};
+    use sqlx::{Sqlite, SqlitePool};
+
+    type Collection = StdCollectionLocker<Sqlite>;
+
+    #[sqlx::test]
+    async fn flushes_add_up_in_the_table(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Tz4Kc7Nm0Qw3Xe6Rv9By2Hu5Ji8Lo1Pa4Sd7Fg0Gh3Jk6Zl9Xc2Vb5Nm8Qw1Er4T";
+        sqlx::raw_sql(Sqlite::STATS_TABLE).execute(&pool).await?;
+        let stats = ContentionStats::new();
+        let client = LockClient::<Collection>::new(pool.clone()).metrics(stats.clone());
+
+        for _ in 0..2 {
+            let _ = tokio::join!(
+                client.with_locking(key, None, async |_| {
+                    sleep(Duration::from_millis(200)).await;
+                }),
+                async {
+                    sleep(Duration::from_millis(50)).await;
+                    client.with_locking(key, None, async |_| {}).await
+                }
+            );
+            stats.flush(&pool).await.unwrap();
+            assert_eq!(stats.snapshot(), []);
+        }
+
+        let (acquired, contended, timed_out, max_hold_ms): (i64, i64, i64, i64) = sqlx::query_as(
+            "SELECT acquired, contended, timed_out, max_hold_ms FROM lock_stats WHERE lock_key = ?",
+        )
+        .bind(key)
+        .fetch_one(&pool)
+        .await?;
+        assert_eq!((acquired, contended, timed_out), (2, 2, 2));
+        assert!(max_hold_ms >= 200);
+
+        Ok(())
+    }
+}
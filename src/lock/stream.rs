@@ -0,0 +1,270 @@
+use std::{
+    collections::HashMap,
+    pin::{Pin, pin},
+    sync::{Arc, LazyLock, Mutex},
+    task::Poll,
+    time::Duration,
+};
+
+use tokio::sync::watch;
+
+use crate::{Clock, DefaultClock, Error, LockClient, LockGuard, LockHooks, Locker, Result};
+
+/// State of an acquisition, yielded by [`AcquireStream::next`].
+pub enum AcquireState<'c, L: Locker + 'static, C: Clock = DefaultClock> {
+    /// the key is held by another session. `position` calls of the process started waiting for
+    /// the key before this one and still wait for it, 0 when this one is first in line
+    Waiting(usize),
+    /// the key was acquired, the last state
+    Acquired(Box<LockGuard<'c, L, C>>),
+    /// the key was still held when the timeout elapsed, the last state
+    TimedOut,
+}
+
+impl<L: Locker + 'static, C: Clock> std::fmt::Debug for AcquireState<'_, L, C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AcquireState::Waiting(position) => f.debug_tuple("Waiting").field(position).finish(),
+            AcquireState::Acquired(guard) => f.debug_tuple("Acquired").field(&guard.key()).finish(),
+            AcquireState::TimedOut => f.write_str("TimedOut"),
+        }
+    }
+}
+
+// (バックエンド, キー) ごとに、待っている呼び出しの番号を待ち始めた順に並べる
+type Queue = HashMap<(&'static str, String), Vec<u64>>;
+
+static QUEUE: LazyLock<Mutex<Queue>> = LazyLock::new(Mutex::default);
+
+// QUEUE が変わったら送る。待っている AcquireStream が順番を数え直す
+static CHANGES: LazyLock<watch::Sender<()>> = LazyLock::new(|| watch::channel(()).0);
+
+// 待っている間だけ QUEUE に並ぶ。取得か諦めたとき、または Drop で外れる
+struct Ticket {
+    id: u64,
+    entry: (&'static str, String),
+}
+
+impl Ticket {
+    fn enter(backend: &'static str, key: String) -> Self {
+        static NEXT: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+        let id = NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let entry = (backend, key);
+        QUEUE
+            .lock()
+            .unwrap()
+            .entry(entry.clone())
+            .or_default()
+            .push(id);
+        CHANGES.send_replace(());
+
+        Self { id, entry }
+    }
+
+    fn position(&self) -> usize {
+        QUEUE
+            .lock()
+            .unwrap()
+            .get(&self.entry)
+            .and_then(|ids| ids.iter().position(|id| *id == self.id))
+            .unwrap_or(0)
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let mut queue = QUEUE.lock().unwrap();
+        if let Some(ids) = queue.get_mut(&self.entry) {
+            ids.retain(|id| *id != self.id);
+            if ids.is_empty() {
+                queue.remove(&self.entry);
+            }
+        }
+        drop(queue);
+        CHANGES.send_replace(());
+    }
+}
+
+type Acquiring<'c, L, C> = Pin<Box<dyn Future<Output = Result<LockGuard<'c, L, C>>> + 'c>>;
+
+/// Acquisition of a key, yielding the states it goes through, returned by
+/// [`LockClient::acquire_stream`].
+///
+/// the acquisition only makes progress while [`AcquireStream::next`] is awaited, and is cancelled
+/// when the stream is dropped.
+///
+/// ```ignore
+/// let mut states = locker.acquire_stream("report", Duration::from_secs(30).into());
+/// while let Some(state) = states.next().await {
+///     match state? {
+///         AcquireState::Waiting(position) => progress.set(format!("{position} ahead")),
+///         AcquireState::Acquired(guard) => return render(guard).await,
+///         AcquireState::TimedOut => progress.set("busy, try again later"),
+///     }
+/// }
+/// ```
+pub struct AcquireStream<'c, L: Locker + 'static, C: Clock = DefaultClock> {
+    acquiring: Option<Acquiring<'c, L, C>>,
+    // 最初に競合したとき、取得の中のフックが並ぶ
+    ticket: Arc<Mutex<Option<Ticket>>>,
+    reported: Option<usize>,
+    changes: watch::Receiver<()>,
+}
+
+impl<'c, L: Locker + 'static, C: Clock> AcquireStream<'c, L, C> {
+    pub(crate) fn new(
+        client: &'c LockClient<L, C>,
+        key: &'c str,
+        timeout: Option<Duration>,
+    ) -> Self {
+        let ticket = Arc::new(Mutex::new(None));
+        // 別の名前空間の同じキーとは並ばないように、バックエンドでのキーで並ぶ
+        let entry = client
+            .backend_key(key)
+            .map_or_else(|_| key.to_owned(), |key| key.into_owned());
+        let hooks = LockHooks::new().on_contention({
+            let ticket = Arc::clone(&ticket);
+            move |_| {
+                ticket
+                    .lock()
+                    .unwrap()
+                    .get_or_insert_with(|| Ticket::enter(L::NAME, entry.clone()));
+            }
+        });
+
+        Self {
+            acquiring: Some(Box::pin(LockGuard::acquire_hooked(
+                client, key, timeout, hooks,
+            ))),
+            ticket,
+            reported: None,
+            changes: CHANGES.subscribe(),
+        }
+    }
+
+    /// wait for the next state, None after [`AcquireState::Acquired`] or [`AcquireState::TimedOut`]
+    ///
+    /// [`AcquireState::Waiting`] is yielded whenever the position changes. fails with the errors of
+    /// [`LockClient::guard`] other than the timeouts, which end the stream too.
+    pub async fn next(&mut self) -> Option<Result<AcquireState<'c, L, C>>> {
+        loop {
+            let acquiring = self.acquiring.as_mut()?;
+            // 見た後の変更だけを待つ
+            self.changes.borrow_and_update();
+
+            let done = {
+                let mut changed = pin!(self.changes.changed());
+                std::future::poll_fn(|cx| {
+                    if let Poll::Ready(r) = acquiring.as_mut().poll(cx) {
+                        return Poll::Ready(Some(r));
+                    }
+                    changed.as_mut().poll(cx).map(|_| None)
+                })
+                .await
+            };
+
+            if let Some(r) = done {
+                self.acquiring = None;
+                self.ticket.lock().unwrap().take();
+                return Some(match r {
+                    Ok(guard) => Ok(AcquireState::Acquired(Box::new(guard))),
+                    Err(Error::FailedToGetLock(_) | Error::RetriesExhausted { .. }) => {
+                        Ok(AcquireState::TimedOut)
+                    }
+                    Err(e) => Err(e),
+                });
+            }
+
+            let position = self.ticket.lock().unwrap().as_ref().map(Ticket::position);
+            if position.is_some() && position != self.reported {
+                self.reported = position;
+                return position.map(|position| Ok(AcquireState::Waiting(position)));
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "sqlx-std-collection"))]
+mod tests {
+    use pretty_assertions::{assert_eq, assert_matches};
+    use tokio::time::sleep;
+
+    use super::*;
+
+    use crate::{Polling, StdCollectionLocker, WaitingLocker};
+    use sqlx::{Sqlite, SqlitePool};
+
+    type Collection = StdCollectionLocker<Sqlite>;
+
+    // Acquired まで状態を集め、取れたら `hold` だけ持って放す
+    async fn states<L: Locker + 'static>(
+        client: &LockClient<L>,
+        key: &str,
+        timeout: Duration,
+        hold: Duration,
+    ) -> Vec<String> {
+        let mut stream = client.acquire_stream(key, timeout.into());
+        let mut states = Vec::new();
+        while let Some(state) = stream.next().await {
+            let state = state.unwrap();
+            states.push(format!("{state:?}"));
+            if let AcquireState::Acquired(guard) = state {
+                sleep(hold).await;
+                guard.release().await.unwrap();
+            }
+        }
+        states
+    }
+
+    #[sqlx::test]
+    async fn stream_reports_the_position_in_line(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "As5Bt7Cu9Dv1Ew3Fx5Gy7Hz9Ia1Jb3Kc5Ld7Me9Nf1Og3Ph5Qi7Rj9Sk1Tl3Um5V";
+        let client = LockClient::<Collection>::new(pool.clone());
+        // 解放されたらすぐ取る方と競らないように、後から並ぶ方は間を空けて試す
+        let polling = LockClient::<WaitingLocker<Collection, Polling<250, 250>>>::new(pool);
+        let second = Duration::from_secs(1);
+        let hold = Duration::from_millis(100);
+
+        let (first, queued, given_up) = tokio::join!(
+            async {
+                let guard = client.guard(key, None).await.unwrap();
+                let states = states(&client, key, second, hold);
+                let states = async {
+                    sleep(Duration::from_millis(50)).await;
+                    states.await
+                };
+                let release = async {
+                    sleep(Duration::from_millis(300)).await;
+                    guard.release().await.unwrap();
+                };
+                tokio::join!(states, release).0
+            },
+            async {
+                sleep(Duration::from_millis(100)).await;
+                states(&polling, key, second, hold).await
+            },
+            async {
+                sleep(Duration::from_millis(150)).await;
+                states(&client, key, Duration::from_millis(50), hold).await
+            }
+        );
+
+        assert_eq!(first, ["Waiting(0)", &format!("Acquired({key:?})")]);
+        assert_eq!(
+            queued,
+            ["Waiting(1)", "Waiting(0)", &format!("Acquired({key:?})")]
+        );
+        assert_eq!(given_up, ["Waiting(2)", "TimedOut"]);
+
+        // 取れれば待たずに Acquired になる
+        let mut stream = client.acquire_stream(key, None);
+        let Some(Ok(AcquireState::Acquired(guard))) = stream.next().await else {
+            panic!("the key is free");
+        };
+        assert_matches!(stream.next().await, None);
+        guard.release().await.unwrap();
+
+        Ok(())
+    }
+}
@@ -0,0 +1,260 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use ::tonic::{Status, codegen::http};
+use ::tower::{Layer, Service};
+
+use crate::{Error, LockClient, Locker};
+
+/// [`Layer`] of a tonic server running each RPC under a lock on a key taken from its metadata.
+///
+/// RPCs the key function returns None for run without a lock. contended RPCs are answered with
+/// the status of [`lock_status`] without reaching the handler.
+///
+/// ```ignore
+/// let layer = GrpcLockLayer::new(LockClient::<PostgresLocker>::new(pool), |req: &http::Request<_>| {
+///     let order = req.headers().get("x-order-id")?.to_str().ok()?;
+///     Some(format!("order:{order}"))
+/// })
+/// .timeout(Duration::from_secs(1));
+///
+/// Server::builder().layer(layer).add_service(orders).serve(addr).await?;
+/// ```
+pub struct GrpcLockLayer<L: Locker, K> {
+    client: LockClient<L>,
+    key: K,
+    timeout: Option<Duration>,
+}
+
+impl<L: Locker, K: Clone> Clone for GrpcLockLayer<L, K> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            key: self.key.clone(),
+            timeout: self.timeout,
+        }
+    }
+}
+
+impl<L: Locker, K> GrpcLockLayer<L, K> {
+    /// lock on the key `key` returns for each RPC
+    ///
+    /// without [`GrpcLockLayer::timeout`], an RPC fails immediately if the key is held.
+    pub fn new(client: LockClient<L>, key: K) -> Self {
+        Self {
+            client,
+            key,
+            timeout: None,
+        }
+    }
+
+    /// wait up to `timeout` for the key
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+impl<S, L: Locker, K: Clone> Layer<S> for GrpcLockLayer<L, K> {
+    type Service = GrpcLockService<S, L, K>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GrpcLockService {
+            inner,
+            client: self.client.clone(),
+            key: self.key.clone(),
+            timeout: self.timeout,
+        }
+    }
+}
+
+/// [`Service`] created by [`GrpcLockLayer`].
+pub struct GrpcLockService<S, L: Locker, K> {
+    inner: S,
+    client: LockClient<L>,
+    key: K,
+    timeout: Option<Duration>,
+}
+
+impl<S: Clone, L: Locker, K: Clone> Clone for GrpcLockService<S, L, K> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            client: self.client.clone(),
+            key: self.key.clone(),
+            timeout: self.timeout,
+        }
+    }
+}
+
+impl<S, L, K, ReqBody, ResBody> Service<http::Request<ReqBody>> for GrpcLockService<S, L, K>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send,
+    S::Error: Send,
+    L: Locker + 'static,
+    L::DB: Send,
+    K: Fn(&http::Request<ReqBody>) -> Option<String>,
+    ReqBody: Send + 'static,
+    ResBody: Default + Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<ReqBody>) -> Self::Future {
+        let key = (self.key)(&req);
+        let client = self.client.clone();
+        let timeout = self.timeout;
+        // poll_ready を通ったのは self.inner の方なので、そちらを持っていく
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            let Some(key) = key else {
+                return inner.call(req).await;
+            };
+
+            match client
+                .run_locked(&key, timeout, async move { inner.call(req).await })
+                .await
+            {
+                Ok(res) => res,
+                // gRPC のエラーはステータスを載せた 200 で返す
+                Err(e) => Ok(lock_status(&e).into_http()),
+            }
+        })
+    }
+}
+
+/// run `fut` while the key is locked, for RPCs keyed by a field of their message
+///
+/// fails with the status of [`lock_status`] if the key couldn't be locked or released.
+///
+/// ```ignore
+/// async fn cancel_order(&self, req: Request<CancelOrder>) -> Result<Response<Order>, Status> {
+///     let key = format!("order:{}", req.get_ref().order_id);
+///     with_rpc_lock(&self.locker, &key, None, self.cancel(req.into_inner())).await?
+/// }
+/// ```
+pub async fn with_rpc_lock<L, Fut>(
+    client: &LockClient<L>,
+    key: &str,
+    timeout: Option<Duration>,
+    fut: Fut,
+) -> Result<Fut::Output, Status>
+where
+    L: Locker,
+    Fut: Future,
+{
+    client
+        .run_locked(key, timeout, fut)
+        .await
+        .map_err(|e| lock_status(&e))
+}
+
+/// status an RPC fails with when its lock couldn't be taken
+///
+/// `ABORTED` when the key is held, `RESOURCE_EXHAUSTED` over the quota of the namespace or with the
+/// lock pool saturated, `UNAVAILABLE` while shutting down and `INTERNAL` on other errors of the
+/// locker.
+pub fn lock_status(e: &Error) -> Status {
+    match e {
+        Error::FailedToGetLock(_)
+        | Error::HeldBy { .. }
+        | Error::RetriesExhausted { .. }
+        | Error::NoQuorum { .. } => Status::aborted(e.to_string()),
+        Error::QuotaExceeded(_) | Error::PoolSaturated { .. } => {
+            Status::resource_exhausted(e.to_string())
+        }
+        Error::ShuttingDown => Status::unavailable(e.to_string()),
+        _ => Status::internal(e.to_string()),
+    }
+}
+
+#[cfg(all(test, feature = "sqlx-std-collection"))]
+mod tests {
+    use pretty_assertions::{assert_eq, assert_matches};
+    use std::convert::Infallible;
+    use tokio::time::sleep;
+    use tonic::Code;
+    use tower::{ServiceExt, service_fn};
+
+    use super::*;
+
+    use crate::StdCollectionLocker;
+    use sqlx::{Sqlite, SqlitePool};
+
+    type Collection = StdCollectionLocker<Sqlite>;
+
+    fn rpc(order: &str) -> http::Request<()> {
+        http::Request::builder()
+            .header("x-order-id", order)
+            .body(())
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn contended_rpc_is_aborted(pool: SqlitePool) -> sqlx::Result<()> {
+        let service = GrpcLockLayer::new(
+            LockClient::<Collection>::new(pool),
+            |req: &http::Request<()>| {
+                let order = req.headers().get("x-order-id")?.to_str().ok()?;
+                Some(format!(
+                    "Gw3Ek5Rl7Tm9Yn1Uo3Ip5Aq7Sr9Dt1Fu3Gv5Hw7Jx9Ky1Lz3Xa5Cb7Vc9Bd1N{order}"
+                ))
+            },
+        )
+        .layer(service_fn(async |_: http::Request<()>| {
+            sleep(Duration::from_millis(300)).await;
+            Ok::<_, Infallible>(http::Response::new("done".to_owned()))
+        }));
+
+        let (r1, r2, r3) = tokio::join!(
+            service.clone().oneshot(rpc("1")),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                service.clone().oneshot(rpc("1")).await
+            },
+            async {
+                sleep(Duration::from_millis(100)).await;
+                service.clone().oneshot(rpc("2")).await
+            }
+        );
+
+        assert_eq!(r1.unwrap().into_body(), "done");
+        let aborted = Status::from_header_map(r2.unwrap().headers()).unwrap();
+        assert_eq!(aborted.code(), Code::Aborted);
+        assert_eq!(r3.unwrap().into_body(), "done");
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn field_keyed_rpc_fails_with_a_status(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Pm8Qn0Ro2Sp4Tq6Ur8Vs0Wt2Xu4Yv6Zw8Ax0By2Cz4Da6Eb8Fc0Gd2He4If6Jg8K";
+        let client = LockClient::<Collection>::new(pool);
+
+        let (r1, r2) = tokio::join!(
+            with_rpc_lock(&client, key, None, sleep(Duration::from_millis(300))),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                with_rpc_lock(&client, key, None, async {}).await
+            }
+        );
+
+        assert_matches!(r1, Ok(()));
+        assert_eq!(r2.unwrap_err().code(), Code::Aborted);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,290 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use ::tower::{Layer, Service};
+
+use crate::{LockClient, Locker, RetryPolicy};
+
+/// [`Layer`] running each request under a lock on a key taken from the request.
+///
+/// ```ignore
+/// let layer = LockLayer::new(LockClient::<PostgresLocker>::new(pool), |req: &Request<Body>| {
+///     format!("order:{}", req.uri().path())
+/// })
+/// .timeout(Duration::from_secs(5));
+///
+/// let service = ServiceBuilder::new().layer(layer).service(inner);
+/// ```
+pub struct LockLayer<L: Locker, K, R = ()> {
+    client: LockClient<L>,
+    key: K,
+    timeout: Option<Duration>,
+    respond: R,
+}
+
+impl<L: Locker, K: Clone, R: Clone> Clone for LockLayer<L, K, R> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            key: self.key.clone(),
+            timeout: self.timeout,
+            respond: self.respond.clone(),
+        }
+    }
+}
+
+/// Response of [`LockService`] to the errors of the locker, set by [`LockLayer::on_error`].
+///
+/// implemented for the functions from the error and the [`LockClient::retry_policy`] of the
+/// client, and for `()` failing with [`LockServiceError::Lock`] on every error.
+pub trait LockErrorResponse<Res> {
+    /// response to `e`, or None to fail with [`LockServiceError::Lock`]
+    fn respond(&self, e: &crate::Error, policy: &RetryPolicy) -> Option<Res>;
+}
+
+impl<Res> LockErrorResponse<Res> for () {
+    fn respond(&self, _: &crate::Error, _: &RetryPolicy) -> Option<Res> {
+        None
+    }
+}
+
+impl<Res, F> LockErrorResponse<Res> for F
+where
+    F: Fn(&crate::Error, &RetryPolicy) -> Option<Res>,
+{
+    fn respond(&self, e: &crate::Error, policy: &RetryPolicy) -> Option<Res> {
+        self(e, policy)
+    }
+}
+
+impl<L: Locker, K> LockLayer<L, K> {
+    /// lock on the key `key` returns for each request
+    ///
+    /// without [`LockLayer::timeout`], a request fails immediately if the key is held.
+    pub fn new(client: LockClient<L>, key: K) -> Self {
+        Self {
+            client,
+            key,
+            timeout: None,
+            respond: (),
+        }
+    }
+}
+
+impl<L: Locker, K, R> LockLayer<L, K, R> {
+    /// wait up to `timeout` for the key
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// respond to the errors of the locker with the response `respond` returns, if any
+    ///
+    /// `respond` is given the [`LockClient::retry_policy`] of the client, e.g. for a
+    /// `Retry-After` header from [`RetryPolicy::retry_after`].
+    ///
+    /// ```ignore
+    /// let layer = LockLayer::new(client, order_key).on_error(|e: &Error, policy: &RetryPolicy| {
+    ///     let status = match e {
+    ///         Error::FailedToGetLock(_) | Error::RetriesExhausted { .. } => StatusCode::LOCKED,
+    ///         Error::ShuttingDown => StatusCode::SERVICE_UNAVAILABLE,
+    ///         _ => return None,
+    ///     };
+    ///     Some(
+    ///         Response::builder()
+    ///             .status(status)
+    ///             .header(RETRY_AFTER, policy.retry_after().as_secs())
+    ///             .body(Body::empty())
+    ///             .unwrap(),
+    ///     )
+    /// });
+    /// ```
+    pub fn on_error<R2>(self, respond: R2) -> LockLayer<L, K, R2> {
+        LockLayer {
+            client: self.client,
+            key: self.key,
+            timeout: self.timeout,
+            respond,
+        }
+    }
+}
+
+impl<S, L: Locker, K: Clone, R: Clone> Layer<S> for LockLayer<L, K, R> {
+    type Service = LockService<S, L, K, R>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        LockService {
+            inner,
+            client: self.client.clone(),
+            key: self.key.clone(),
+            timeout: self.timeout,
+            respond: self.respond.clone(),
+        }
+    }
+}
+
+/// [`Service`] created by [`LockLayer`].
+pub struct LockService<S, L: Locker, K, R = ()> {
+    inner: S,
+    client: LockClient<L>,
+    key: K,
+    timeout: Option<Duration>,
+    respond: R,
+}
+
+impl<S: Clone, L: Locker, K: Clone, R: Clone> Clone for LockService<S, L, K, R> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            client: self.client.clone(),
+            key: self.key.clone(),
+            timeout: self.timeout,
+            respond: self.respond.clone(),
+        }
+    }
+}
+
+/// Error of [`LockService`].
+#[derive(Debug, thiserror::Error)]
+pub enum LockServiceError<E> {
+    /// the key couldn't be locked or released
+    #[error(transparent)]
+    Lock(crate::Error),
+    /// the inner service failed
+    #[error("{0}")]
+    Inner(E),
+}
+
+impl<S, L, K, R, Req> Service<Req> for LockService<S, L, K, R>
+where
+    S: Service<Req> + Clone + Send + 'static,
+    S::Future: Send,
+    S::Response: Send,
+    S::Error: Send,
+    L: Locker + 'static,
+    L::DB: Send,
+    K: Fn(&Req) -> String,
+    R: LockErrorResponse<S::Response> + Clone + Send + 'static,
+    Req: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = LockServiceError<S::Error>;
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(LockServiceError::Inner)
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        let key = (self.key)(&req);
+        let client = self.client.clone();
+        let timeout = self.timeout;
+        let respond = self.respond.clone();
+        // poll_ready を通ったのは self.inner の方なので、そちらを持っていく
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            match client
+                .run_locked(&key, timeout, async move { inner.call(req).await })
+                .await
+            {
+                Ok(r) => r.map_err(LockServiceError::Inner),
+                Err(e) => match respond.respond(&e, &client.retry_policy()) {
+                    Some(res) => Ok(res),
+                    None => Err(LockServiceError::Lock(e)),
+                },
+            }
+        })
+    }
+}
+
+#[cfg(all(test, feature = "sqlx-std-collection"))]
+mod tests {
+    use pretty_assertions::assert_matches;
+    use std::{convert::Infallible, time::Instant};
+    use tokio::time::sleep;
+    use tower::{ServiceExt, service_fn};
+
+    use super::*;
+
+    use crate::{Error, StdCollectionLocker};
+    use sqlx::{Sqlite, SqlitePool};
+
+    #[sqlx::test]
+    async fn requests_on_the_same_key_are_serialized(pool: SqlitePool) -> sqlx::Result<()> {
+        let layer = LockLayer::new(
+            LockClient::<StdCollectionLocker<Sqlite>>::new(pool),
+            |id: &u32| format!("Xe4Rc6Tv8Yb0Un2Im4Ko6Lp8Za0Sx2Dc4Fv6Gb8Hn0Jm2Kq4We6Rt8Yu0Io{id}"),
+        )
+        .timeout(Duration::from_secs(2));
+        let service = layer.layer(service_fn(async |id: u32| {
+            sleep(Duration::from_millis(300)).await;
+            Ok::<_, Infallible>(id * 2)
+        }));
+
+        let started = Instant::now();
+        let (r1, r2) = tokio::join!(service.clone().oneshot(1), service.clone().oneshot(1));
+
+        assert_matches!(r1, Ok(2));
+        assert_matches!(r2, Ok(2));
+        assert!(started.elapsed() >= Duration::from_millis(600));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn contended_request_fails_without_timeout(pool: SqlitePool) -> sqlx::Result<()> {
+        let service = LockLayer::new(
+            LockClient::<StdCollectionLocker<Sqlite>>::new(pool),
+            |_: &u32| "Qz1Wx3Ec5Rv7Tb9Yn1Um3Ik5Ol7Pa9Sz1Xd3Cf5Vg7Bh9Nj1Mk3Lq5Ww7Ee9Rr1T".to_owned(),
+        )
+        .layer(service_fn(async |id: u32| {
+            sleep(Duration::from_millis(300)).await;
+            Ok::<_, Infallible>(id)
+        }));
+        let (r1, r2) = tokio::join!(service.clone().oneshot(1), async {
+            sleep(Duration::from_millis(100)).await;
+            service.clone().oneshot(2).await
+        });
+
+        assert_matches!(r1, Ok(1));
+        assert_matches!(r2, Err(LockServiceError::Lock(Error::FailedToGetLock(_))));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn contended_request_gets_the_error_response(pool: SqlitePool) -> sqlx::Result<()> {
+        let client = LockClient::<StdCollectionLocker<Sqlite>>::new(pool).retry(RetryPolicy {
+            backoff: Duration::from_millis(200),
+            ..RetryPolicy::default()
+        });
+        let service = LockLayer::new(client, |_: &u32| {
+            "Wk7Vl9Um1Tn3So5Rp7Qq9Pr1Os3Nt5Mu7Lv9Kw1Jx3Iy5Hz7Ga9Fb1Ec3Dd5Ce7B".to_owned()
+        })
+        .on_error(|e: &Error, policy: &RetryPolicy| match e {
+            Error::FailedToGetLock(_) => Some(policy.retry_after().as_secs() as u32 + 100),
+            _ => None,
+        })
+        .layer(service_fn(async |id: u32| {
+            sleep(Duration::from_millis(300)).await;
+            Ok::<_, Infallible>(id)
+        }));
+        let (r1, r2) = tokio::join!(service.clone().oneshot(1), async {
+            sleep(Duration::from_millis(100)).await;
+            service.clone().oneshot(2).await
+        });
+
+        assert_matches!(r1, Ok(1));
+        // 0.2 秒の間隔でも、1 秒より短くは言わない
+        assert_matches!(r2, Ok(101));
+
+        Ok(())
+    }
+}
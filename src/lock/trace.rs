@@ -0,0 +1,216 @@
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use super::events::emit;
+use super::held::HeldEntry;
+use crate::{Clock, Error, LockEvent, Locker, Result};
+
+// ロックの各段階を tracing / OpenTelemetry / LockEvent に流す。feature が無ければ何もしない
+
+pub(crate) fn instrument<F: Future>(
+    f: F,
+    backend: &'static str,
+    key: &str,
+) -> impl Future<Output = F::Output> {
+    #[cfg(feature = "tracing")]
+    {
+        use tracing::Instrument;
+        f.instrument(tracing::info_span!("with_locking", backend, key))
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = (backend, key);
+        f
+    }
+}
+
+/// 1回のロック呼び出しの経過
+pub(crate) struct Lifecycle<'a> {
+    backend: &'static str,
+    db: &'static str,
+    key: &'a str,
+    // 待った時間と持った時間を測る Clock::now
+    now: fn() -> Instant,
+    started: Instant,
+    acquired: Option<Instant>,
+    held: Option<HeldEntry>,
+}
+
+impl<'a> Lifecycle<'a> {
+    pub(crate) fn start<L: Locker + ?Sized, C: Clock>(key: &'a str) -> Self {
+        emit(|| LockEvent::Requested {
+            backend: L::NAME,
+            key: key.to_owned(),
+        });
+
+        Self {
+            backend: L::NAME,
+            db: <L::DB as sqlx::Database>::NAME,
+            key,
+            now: C::now,
+            started: C::now(),
+            acquired: None,
+            held: None,
+        }
+    }
+
+    /// record the result of the acquisition, returning how long it waited
+    pub(crate) fn acquisition(&mut self, r: &Result<()>) -> Duration {
+        let wait = (self.now)().saturating_duration_since(self.started);
+
+        #[cfg(feature = "otel")]
+        otel::acquisition(self.db, self.key, r, wait);
+
+        #[cfg(feature = "tracing")]
+        match r {
+            Ok(()) => tracing::debug!(
+                wait_ms = wait.as_millis() as u64,
+                outcome = "acquired",
+                "lock acquired"
+            ),
+            Err(e) => tracing::warn!(
+                wait_ms = wait.as_millis() as u64,
+                outcome = "failed",
+                error = %e,
+                "failed to get lock"
+            ),
+        }
+
+        match r {
+            Ok(()) => {
+                self.acquired = Some((self.now)());
+                self.held = HeldEntry::enter(self.backend, self.key);
+                emit(|| LockEvent::Acquired {
+                    backend: self.backend,
+                    key: self.key.to_owned(),
+                    wait,
+                });
+            }
+            Err(Error::FailedToGetLock(_) | Error::RetriesExhausted { .. }) => {
+                emit(|| LockEvent::TimedOut {
+                    backend: self.backend,
+                    key: self.key.to_owned(),
+                    wait,
+                })
+            }
+            Err(_) => {}
+        }
+
+        #[cfg(not(feature = "otel"))]
+        let _ = self.db;
+
+        wait
+    }
+
+    /// record the result of the release, returning how long the lock was held
+    pub(crate) fn release(&mut self, r: &Result<()>) -> Duration {
+        let hold = self
+            .acquired
+            .take()
+            .map(|acquired| (self.now)().saturating_duration_since(acquired))
+            .unwrap_or_default();
+        self.held = None;
+
+        #[cfg(feature = "tracing")]
+        match r {
+            Ok(()) => tracing::debug!(hold_ms = hold.as_millis() as u64, "lock released"),
+            Err(e) => tracing::warn!(
+                hold_ms = hold.as_millis() as u64,
+                error = %e,
+                "failed to release lock"
+            ),
+        }
+
+        if r.is_ok() {
+            emit(|| LockEvent::Released {
+                backend: self.backend,
+                key: self.key.to_owned(),
+                hold,
+            });
+        }
+
+        hold
+    }
+}
+
+impl Lifecycle<'_> {
+    /// record that the lock is left to a background release
+    pub(crate) fn dropped(&self) {
+        #[cfg(feature = "tracing")]
+        tracing::warn!(
+            backend = self.backend,
+            key = self.key,
+            "lock guard dropped without release, releasing it in the background"
+        );
+
+        emit(|| LockEvent::DroppedUnreleased {
+            backend: self.backend,
+            key: self.key.to_owned(),
+        });
+    }
+}
+
+impl Drop for Lifecycle<'_> {
+    fn drop(&mut self) {
+        // ロックを持ったままクロージャが panic した
+        if self.acquired.is_some() && std::thread::panicking() {
+            #[cfg(feature = "tracing")]
+            tracing::error!(
+                backend = self.backend,
+                key = self.key,
+                "closure panicked while holding the lock"
+            );
+
+            emit(|| LockEvent::Poisoned {
+                backend: self.backend,
+                key: self.key.to_owned(),
+            });
+        }
+    }
+}
+
+#[cfg(feature = "otel")]
+mod otel {
+    use std::{
+        borrow::Cow,
+        time::{Duration, SystemTime},
+    };
+
+    use opentelemetry::{
+        Context, KeyValue, global,
+        trace::{Span, SpanKind, Status, Tracer},
+    };
+
+    use crate::{Error, Result};
+
+    // 待ち始めた時刻を開始時刻にして、現在のコンテキスト (リクエストのスパン) の子として記録する
+    pub(super) fn acquisition(db: &'static str, key: &str, r: &Result<()>, wait: Duration) {
+        let outcome = match r {
+            Ok(()) => "acquired",
+            Err(Error::FailedToGetLock(_) | Error::RetriesExhausted { .. }) => "timeout",
+            Err(_) => "error",
+        };
+
+        let tracer = global::tracer("rusty_ad_lock");
+        let mut span = tracer
+            .span_builder("lock.acquire")
+            .with_kind(SpanKind::Client)
+            .with_start_time(SystemTime::now() - wait)
+            .with_attributes([
+                KeyValue::new("db.system", db.to_lowercase()),
+                KeyValue::new("lock.key", key.to_owned()),
+                KeyValue::new("lock.wait_ms", wait.as_millis() as i64),
+                KeyValue::new("lock.outcome", outcome),
+            ])
+            .start_with_context(&tracer, &Context::current());
+
+        if let Err(e) = r {
+            span.set_status(Status::Error {
+                description: Cow::Owned(e.to_string()),
+            });
+        }
+        span.end();
+    }
+}
@@ -0,0 +1,264 @@
+use std::{marker::PhantomData, pin::pin, sync::LazyLock, task::Poll, time::Duration};
+
+use tokio::sync::broadcast;
+
+use crate::{Capabilities, Clock, DefaultClock, Error, Locker, Result};
+
+/// How a [`WaitingLocker`] waits for a key held by another session.
+///
+/// the strategy only runs for calls with a timeout, and the key has already been found held: a
+/// call without one fails on the first [`Locker::acquire`] of the backend.
+pub trait WaitStrategy {
+    /// acquire `key` on `L`, waiting up to `timeout` for it
+    ///
+    /// fails with [`Error::FailedToGetLock`] if the key is still held when `timeout` elapses.
+    fn wait<L: Locker>(
+        pool: &sqlx::Pool<L::DB>,
+        tx: &mut sqlx::Transaction<'static, L::DB>,
+        key: &str,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// called once `key` was released through the locker
+    fn released(key: &str) {
+        let _ = key;
+    }
+}
+
+/// Wait of the backend itself, like `GET_LOCK` with a timeout or `lock_timeout`.
+///
+/// holds a connection for the whole wait, but is woken up as soon as the key is released.
+pub struct Blocking;
+
+impl WaitStrategy for Blocking {
+    fn wait<L: Locker>(
+        pool: &sqlx::Pool<L::DB>,
+        tx: &mut sqlx::Transaction<'static, L::DB>,
+        key: &str,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<()>> + Send {
+        L::acquire(pool, tx, key, Some(timeout))
+    }
+}
+
+/// Try the key again without waiting, sleeping `INITIAL_MS` between the first tries and twice as
+/// long after each, up to `MAX_MS`.
+///
+/// nothing waits on the database in between, at the cost of getting the key up to `MAX_MS` after
+/// it was released.
+pub struct Polling<const INITIAL_MS: u64 = 10, const MAX_MS: u64 = 1000>;
+
+impl<const INITIAL_MS: u64, const MAX_MS: u64> WaitStrategy for Polling<INITIAL_MS, MAX_MS> {
+    async fn wait<L: Locker>(
+        pool: &sqlx::Pool<L::DB>,
+        tx: &mut sqlx::Transaction<'static, L::DB>,
+        key: &str,
+        timeout: Duration,
+    ) -> Result<()> {
+        let deadline = DefaultClock::now() + timeout;
+        let mut delay = Duration::from_millis(INITIAL_MS);
+
+        loop {
+            let left = deadline.saturating_duration_since(DefaultClock::now());
+            if left.is_zero() {
+                return Err(Error::FailedToGetLock(key.to_owned()));
+            }
+            DefaultClock::sleep(delay.min(left)).await;
+
+            match L::acquire(pool, tx, key, None).await {
+                Err(Error::FailedToGetLock(_)) => {}
+                r => return r,
+            }
+            delay = (delay * 2).min(Duration::from_millis(MAX_MS));
+        }
+    }
+}
+
+// 解放されたキー。同じプロセスで待っている呼び出しを起こす
+static RELEASED: LazyLock<broadcast::Sender<String>> = LazyLock::new(|| broadcast::channel(1024).0);
+
+/// Try the key again whenever it is released through a [`WaitingLocker`] of the process, and
+/// every `FALLBACK_MS` for the releases of other processes.
+///
+/// nothing waits on the database, and a key released in the process is taken over right away.
+pub struct Notified<const FALLBACK_MS: u64 = 1000>;
+
+impl<const FALLBACK_MS: u64> WaitStrategy for Notified<FALLBACK_MS> {
+    async fn wait<L: Locker>(
+        pool: &sqlx::Pool<L::DB>,
+        tx: &mut sqlx::Transaction<'static, L::DB>,
+        key: &str,
+        timeout: Duration,
+    ) -> Result<()> {
+        let deadline = DefaultClock::now() + timeout;
+        // 取れなかった直後の解放を逃さないよう、試す前に購読する
+        let mut rx = RELEASED.subscribe();
+
+        loop {
+            match L::acquire(pool, tx, key, None).await {
+                Err(Error::FailedToGetLock(_)) => {}
+                r => return r,
+            }
+
+            let left = deadline.saturating_duration_since(DefaultClock::now());
+            if left.is_zero() {
+                return Err(Error::FailedToGetLock(key.to_owned()));
+            }
+
+            let mut released = pin!(async {
+                loop {
+                    match rx.recv().await {
+                        Ok(k) if k == key => return,
+                        // 取りこぼした解放の中に目的のキーがあったかもしれない
+                        Err(broadcast::error::RecvError::Lagged(_)) => return,
+                        Ok(_) => {}
+                        // 送信側は static なので閉じない
+                        Err(broadcast::error::RecvError::Closed) => std::future::pending().await,
+                    }
+                }
+            });
+            let mut fallback = pin!(DefaultClock::sleep(
+                left.min(Duration::from_millis(FALLBACK_MS))
+            ));
+            std::future::poll_fn(|cx| {
+                if released.as_mut().poll(cx).is_ready() {
+                    return Poll::Ready(());
+                }
+                fallback.as_mut().poll(cx)
+            })
+            .await;
+        }
+    }
+
+    fn released(key: &str) {
+        // NOTE: エラーが来ても、それは受信者が0なことを表しているだけ
+        let _ = RELEASED.send(key.to_owned());
+    }
+}
+
+/// Locker decorator waiting for the keys of the backend `L` with the strategy `W`, to trade the
+/// latency of getting a released key against the load of waiting.
+///
+/// calls without a timeout fail right away as on `L`.
+///
+/// ```ignore
+/// // no connection blocked in GET_LOCK while waiting, a released key is seen within 500ms
+/// type Locker = WaitingLocker<MySqlLocker, Polling<20, 500>>;
+///
+/// let r = Locker::with_locking(&pool, "key", Duration::from_secs(10).into(), async |_| {}).await;
+/// ```
+pub struct WaitingLocker<L: Locker, W: WaitStrategy> {
+    _marker: PhantomData<(L, W)>,
+}
+
+impl<L: Locker, W: WaitStrategy> Locker for WaitingLocker<L, W> {
+    type DB = L::DB;
+
+    const NAME: &'static str = L::NAME;
+
+    const MAX_HOLD: Option<Duration> = L::MAX_HOLD;
+
+    const MAX_KEY_LEN: Option<usize> = L::MAX_KEY_LEN;
+
+    fn capabilities() -> Capabilities {
+        L::capabilities()
+    }
+
+    async fn acquire(
+        pool: &sqlx::Pool<Self::DB>,
+        tx: &mut sqlx::Transaction<'static, Self::DB>,
+        key: &str,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        match L::acquire(pool, tx, key, None).await {
+            Err(Error::FailedToGetLock(_)) if timeout.is_some() => {}
+            r => return r,
+        }
+
+        // NOTE: 上で timeout が Some なことを確かめている
+        W::wait::<L>(pool, tx, key, timeout.unwrap()).await
+    }
+
+    async fn release(
+        pool: &sqlx::Pool<Self::DB>,
+        tx: &mut sqlx::Transaction<'static, Self::DB>,
+        key: &str,
+    ) -> Result<()> {
+        L::release(pool, tx, key).await?;
+        W::released(key);
+
+        Ok(())
+    }
+
+    fn set_owner(
+        pool: &sqlx::Pool<Self::DB>,
+        tx: &mut sqlx::Transaction<'static, Self::DB>,
+        key: &str,
+        owner: &str,
+    ) -> impl Future<Output = Result<()>> + Send {
+        L::set_owner(pool, tx, key, owner)
+    }
+
+    fn lock_lost(key: &str) -> impl Future<Output = ()> + Send {
+        L::lock_lost(key)
+    }
+
+    fn extend_hold(key: &str, by: Duration) -> impl Future<Output = Result<()>> + Send {
+        L::extend_hold(key, by)
+    }
+}
+
+#[cfg(all(test, feature = "sqlx-std-collection"))]
+mod tests {
+    use pretty_assertions::assert_matches;
+    use tokio::time::{Instant, sleep};
+
+    use super::*;
+
+    use crate::StdCollectionLocker;
+    use sqlx::{Sqlite, SqlitePool};
+
+    type Collection = StdCollectionLocker<Sqlite>;
+
+    #[sqlx::test]
+    async fn strategies_wait_for_the_key(pool: SqlitePool) -> sqlx::Result<()> {
+        let key = "Ws3Xt5Yu7Zv9Aw1Bx3Cy5Dz7Ea9Fb1Gc3Hd5Ie7Jf9Kg1Lh3Mi5Nj7Ok9Pl1Qm3R";
+        type Poll = WaitingLocker<Collection, Polling<50, 50>>;
+        // 別プロセスの解放を拾うまでの間隔より短く済めば、同じプロセスの解放で起きている
+        type Notify = WaitingLocker<Collection, Notified<5000>>;
+
+        let (r1, r2, r3) = tokio::join!(
+            Poll::with_locking(&pool, key, None, async |_| {
+                sleep(Duration::from_millis(300)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                Poll::with_locking(&pool, key, Duration::from_millis(100).into(), async |_| {})
+                    .await
+            },
+            async {
+                sleep(Duration::from_millis(100)).await;
+                Poll::with_locking(&pool, key, Duration::from_secs(1).into(), async |_| {}).await
+            }
+        );
+        assert_matches!(r1, Ok(()));
+        assert_matches!(r2, Err(Error::FailedToGetLock(_)));
+        assert_matches!(r3, Ok(()));
+
+        let started = Instant::now();
+        let (r1, r2) = tokio::join!(
+            Notify::with_locking(&pool, key, None, async |_| {
+                sleep(Duration::from_millis(300)).await;
+            }),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                Notify::with_locking(&pool, key, Duration::from_secs(10).into(), async |_| {}).await
+            }
+        );
+        assert_matches!(r1, Ok(()));
+        assert_matches!(r2, Ok(()));
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        Ok(())
+    }
+}